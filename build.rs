@@ -4,10 +4,30 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use std::process::Command;
+
 fn main() {
+    // Set by cargo-fuzz when building the fuzzing targets
+    println!("cargo:rustc-check-cfg=cfg(fuzzing)");
+
+    // Build identifier reported in the SVSM manifest
+    if let Ok(out) = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+    {
+        if out.status.success() {
+            let id = String::from_utf8_lossy(&out.stdout);
+            println!("cargo:rustc-env=SVSM_BUILD_ID={}", id.trim());
+        }
+    }
+
     // Stage 2
     println!("cargo:rustc-link-arg-bin=stage2=-nostdlib");
     println!("cargo:rustc-link-arg-bin=stage2=-Wl,--build-id=none");
+    println!("cargo:rustc-link-arg-bin=stage2=-Wl,-pie");
+    println!("cargo:rustc-link-arg-bin=stage2=-Wl,--no-dynamic-linker");
+    // The exception table holds absolute addresses and lives in .text
+    println!("cargo:rustc-link-arg-bin=stage2=-Wl,-z,notext");
     println!("cargo:rustc-link-arg-bin=stage2=-Wl,-Tstage2.lds");
 
     // SVSM 2
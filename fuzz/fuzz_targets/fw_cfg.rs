@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Feeds arbitrary data from the host through the fw_cfg interface. All
+// reads from the data port consume the fuzzer input in order, no matter
+// which item was selected, so the input is what a malicious host would
+// return for the sequence of accesses the parsers do.
+
+#![no_main]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use libfuzzer_sys::fuzz_target;
+use svsm::fw_cfg::FwCfg;
+use svsm::io::IOPort;
+use svsm::mm::bootmem::BootMemoryMap;
+use svsm::mm::pagetable::init_test_max_phys_addr;
+use svsm::types::MemoryRegion;
+
+struct FuzzIOPort<'a> {
+    data: &'a [u8],
+    pos: AtomicUsize,
+}
+
+impl<'a> FuzzIOPort<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        FuzzIOPort {
+            data,
+            pos: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl IOPort for FuzzIOPort<'_> {
+    fn outb(&self, _port: u16, _value: u8) {}
+
+    fn outw(&self, _port: u16, _value: u16) {}
+
+    fn outl(&self, _port: u16, _value: u32) {}
+
+    fn inb(&self, _port: u16) -> u8 {
+        let pos = self.pos.fetch_add(1, Ordering::Relaxed);
+        self.data.get(pos).copied().unwrap_or(0)
+    }
+
+    fn inw(&self, port: u16) -> u16 {
+        u16::from_le_bytes([self.inb(port), self.inb(port)])
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        u32::from_le_bytes([
+            self.inb(port),
+            self.inb(port),
+            self.inb(port),
+            self.inb(port),
+        ])
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    init_test_max_phys_addr();
+
+    let io = FuzzIOPort::new(data);
+    let fw_cfg = FwCfg::new(&io);
+
+    let _ = fw_cfg.is_present();
+    if let Ok(file) = fw_cfg.cmdline_file() {
+        let mut buf = [0u8; 256];
+        let _ = fw_cfg.read_file(&file, &mut buf);
+    }
+    let _ = fw_cfg.flash_regions();
+    let _ = fw_cfg.console_ring_region();
+
+    // Same order as in stage2: the memory map, then the kernel region
+    let ram = fw_cfg.get_memory_regions().unwrap_or_default();
+    let mut memmap = BootMemoryMap::new(ram);
+    memmap
+        .reserve(MemoryRegion::new(0, 640 * 1024), "stage2")
+        .unwrap();
+    if let Ok(region) = fw_cfg.find_kernel_region(&mut memmap) {
+        assert!(!region.is_empty());
+        assert!(region.start >= 640 * 1024);
+    }
+});
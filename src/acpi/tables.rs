@@ -35,10 +35,10 @@ impl RSDPDesc {
             return Err(SvsmError::Acpi);
         }
 
-        fw_cfg.select(file.selector());
+        fw_cfg.select(file.selector())?;
         let ptr = buf.as_mut_ptr().cast::<u8>();
         for i in 0..size {
-            let byte: u8 = fw_cfg.read_le();
+            let byte: u8 = fw_cfg.read_le()?;
             unsafe { ptr.add(i).write(byte) };
         }
 
@@ -179,17 +179,19 @@ impl ACPITableBuffer {
         let ptr = unsafe { alloc(layout) };
         let ptr = ptr::NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout));
 
-        fw_cfg.select(file.selector());
-        for i in 0..size {
-            let byte: u8 = fw_cfg.read_le();
-            unsafe { ptr.as_ptr().add(i).write(byte) };
-        }
-
+        // Frees the memory again if reading the tables fails
         let mut buf = Self {
             ptr,
             size,
             tables: Vec::new(),
         };
+
+        fw_cfg.select(file.selector())?;
+        for i in 0..size {
+            let byte: u8 = fw_cfg.read_le()?;
+            unsafe { ptr.as_ptr().add(i).write(byte) };
+        }
+
         buf.load_tables(fw_cfg)?;
         Ok(buf)
     }
@@ -288,12 +290,36 @@ struct RawMADTEntryLocalX2Apic {
     acpi_id: u32,
 }
 
-pub struct ACPICPUInfo {
-    pub apic_id: u32,
-    pub enabled: bool,
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct RawMADTEntryIoApic {
+    header: RawMADTEntryHeader,
+    ioapic_id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
 }
 
-pub fn load_acpi_cpu_info(fw_cfg: &FwCfg) -> Result<Vec<ACPICPUInfo>, SvsmError> {
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct RawMADTEntryIntSrcOverride {
+    header: RawMADTEntryHeader,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_IOAPIC: u8 = 1;
+const MADT_TYPE_INT_SRC_OVERRIDE: u8 = 2;
+const MADT_TYPE_LOCAL_X2APIC: u8 = 9;
+
+// Calls `f` with the type of and a pointer to each MADT entry
+fn for_each_madt_entry<F>(fw_cfg: &FwCfg, mut f: F) -> Result<(), SvsmError>
+where
+    F: FnMut(u8, *const RawMADTEntryHeader),
+{
     let buffer = ACPITableBuffer::from_fwcfg(fw_cfg)?;
 
     let apic_table = buffer.acp_table_by_sig("APIC").ok_or(SvsmError::Acpi)?;
@@ -305,34 +331,150 @@ pub fn load_acpi_cpu_info(fw_cfg: &FwCfg) -> Result<Vec<ACPICPUInfo>, SvsmError>
 
     let content = apic_table.content();
 
-    let mut cpus: Vec<ACPICPUInfo> = Vec::new();
-
     unsafe {
         let mut offset = MADT_HEADER_SIZE;
-        while offset < len {
+        while offset + mem::size_of::<RawMADTEntryHeader>() <= len {
             let entry_ptr = content.add(offset).cast::<RawMADTEntryHeader>();
             let t: u8 = (*entry_ptr).entry_type;
             let l: u8 = (*entry_ptr).entry_len;
+            if (l as usize) < mem::size_of::<RawMADTEntryHeader>() || offset + l as usize > len {
+                return Err(SvsmError::Acpi);
+            }
             offset += l as usize;
-            if t == 0 {
-                let lapic_ptr = entry_ptr.cast::<RawMADTEntryLocalApic>();
-                let apic_id: u32 = (*lapic_ptr).apic_id as u32;
-                let flags: u32 = (*lapic_ptr).flags;
-                cpus.push(ACPICPUInfo {
-                    apic_id,
-                    enabled: (flags & 1) == 1,
-                });
-            } else if t == 9 {
-                let x2apic_ptr = entry_ptr.cast::<RawMADTEntryLocalX2Apic>();
-                let apic_id: u32 = (*x2apic_ptr).apic_id;
-                let flags: u32 = (*x2apic_ptr).flags;
-                cpus.push(ACPICPUInfo {
-                    apic_id,
-                    enabled: (flags & 1) == 1,
+            f(t, entry_ptr);
+        }
+    }
+
+    Ok(())
+}
+
+pub struct ACPICPUInfo {
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+pub fn load_acpi_cpu_info(fw_cfg: &FwCfg) -> Result<Vec<ACPICPUInfo>, SvsmError> {
+    let mut cpus: Vec<ACPICPUInfo> = Vec::new();
+
+    for_each_madt_entry(fw_cfg, |t, entry_ptr| unsafe {
+        if t == MADT_TYPE_LOCAL_APIC {
+            let lapic_ptr = entry_ptr.cast::<RawMADTEntryLocalApic>();
+            let apic_id: u32 = (*lapic_ptr).apic_id as u32;
+            let flags: u32 = (*lapic_ptr).flags;
+            cpus.push(ACPICPUInfo {
+                apic_id,
+                enabled: (flags & 1) == 1,
+            });
+        } else if t == MADT_TYPE_LOCAL_X2APIC {
+            let x2apic_ptr = entry_ptr.cast::<RawMADTEntryLocalX2Apic>();
+            let apic_id: u32 = (*x2apic_ptr).apic_id;
+            let flags: u32 = (*x2apic_ptr).flags;
+            cpus.push(ACPICPUInfo {
+                apic_id,
+                enabled: (flags & 1) == 1,
+            });
+        }
+    })?;
+
+    Ok(cpus)
+}
+
+/// An I/O APIC listed in the MADT
+#[derive(Clone, Copy, Debug)]
+pub struct ACPIIoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// Routing of an ISA interrupt which differs from the identity mapping
+#[derive(Clone, Copy, Debug)]
+pub struct ACPIIrqOverride {
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags (polarity in bits 0-1, trigger mode in bits 2-3)
+    pub flags: u16,
+}
+
+/// Returns the I/O APICs and the ISA interrupt source overrides from the
+/// MADT.
+pub fn load_acpi_ioapic_info(
+    fw_cfg: &FwCfg,
+) -> Result<(Vec<ACPIIoApicInfo>, Vec<ACPIIrqOverride>), SvsmError> {
+    let mut ioapics = Vec::new();
+    let mut overrides = Vec::new();
+
+    for_each_madt_entry(fw_cfg, |t, entry_ptr| unsafe {
+        if t == MADT_TYPE_IOAPIC {
+            let entry = entry_ptr.cast::<RawMADTEntryIoApic>().read_unaligned();
+            ioapics.push(ACPIIoApicInfo {
+                id: entry.ioapic_id,
+                address: entry.address,
+                gsi_base: entry.gsi_base,
+            });
+        } else if t == MADT_TYPE_INT_SRC_OVERRIDE {
+            let entry = entry_ptr
+                .cast::<RawMADTEntryIntSrcOverride>()
+                .read_unaligned();
+            // Only ISA overrides are defined
+            if entry.bus == 0 {
+                overrides.push(ACPIIrqOverride {
+                    source: entry.source,
+                    gsi: entry.gsi,
+                    flags: entry.flags,
                 });
             }
         }
+    })?;
+
+    Ok((ioapics, overrides))
+}
+
+// MCFG content starts with 8 reserved bytes
+const MCFG_HEADER_SIZE: usize = 8;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct RawMCFGEntry {
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+/// A PCI Express memory mapped configuration space listed in the MCFG
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ACPIMcfgInfo {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Returns the ECAM regions from the MCFG, if there is one
+pub fn load_acpi_mcfg_info(fw_cfg: &FwCfg) -> Result<Vec<ACPIMcfgInfo>, SvsmError> {
+    let buffer = ACPITableBuffer::from_fwcfg(fw_cfg)?;
+    let mcfg = buffer.acp_table_by_sig("MCFG").ok_or(SvsmError::Acpi)?;
+    let len = mcfg.content_length();
+
+    let mut regions = Vec::new();
+    let mut offset = MCFG_HEADER_SIZE;
+    while offset + mem::size_of::<RawMCFGEntry>() <= len {
+        let entry = unsafe {
+            mcfg.content()
+                .add(offset)
+                .cast::<RawMCFGEntry>()
+                .read_unaligned()
+        };
+        regions.push(ACPIMcfgInfo {
+            base: entry.base,
+            segment: entry.segment,
+            start_bus: entry.start_bus,
+            end_bus: entry.end_bus,
+        });
+        offset += mem::size_of::<RawMCFGEntry>();
     }
 
-    Ok(cpus)
+    Ok(regions)
 }
@@ -12,18 +12,41 @@ global_asm!(
         .section ".startup.text","ax"
         .code32
 
+        /*
+         * Stage2 may run at a different address than it is linked at, so
+         * the 32bit code only uses addresses relative to its actual load
+         * address in EBP. Symbols in other sections are reached through
+         * PC-relative offsets stored next to the code.
+         */
+        .macro load_addr rel, reg
+        leal (\rel - startup_32)(%ebp), \reg
+        addl (\reg), \reg
+        .endm
+
+        /* Address stage2 is linked at, must match stage2.lds */
+        .set STAGE2_LINK_BASE, 64 * 1024
+        /* Fixed address of the CPUID page, see utils/gen_meta.c */
+        .set CPUID_PAGE, 636 * 1024
+
         .org 0
         .globl startup_32
         startup_32:
 
         /* Save pointer to startup structure in ESI */
         movl %esp, %esi
+
+        call 1f
+    1:  popl %ebp
+        subl $(1b - startup_32), %ebp
+
         /*
          * Load a GDT. Despite the naming, it contains valid
          * entries for both, "legacy" 32bit and long mode each.
          */
-        movl $gdt64_desc, %eax
-        lgdt (%eax)
+        load_addr .Lgdt64_rel, %eax
+        load_addr .Lgdt64_desc_rel, %ecx
+        movl %eax, 2(%ecx)
+        lgdt (%ecx)
 
         movw $0x10, %ax
         movw %ax, %ds
@@ -33,7 +56,7 @@ global_asm!(
         movw %ax, %ss
 
         pushl $0x8
-        movl $.Lon_svsm32_cs, %eax
+        leal (.Lon_svsm32_cs - startup_32)(%ebp), %eax
         pushl %eax
         lret
 
@@ -41,19 +64,21 @@ global_asm!(
         push    %edi
 
         /* Clear out the static page table pages. */
-        movl $pgtable_end, %ecx
-        subl $pgtable, %ecx
+        movl $(pgtable_end - pgtable), %ecx
         shrl $2, %ecx
         xorl %eax, %eax
-        movl $pgtable, %edi
+        load_addr .Lpgtable_rel, %edi
         rep stosl
 
         /* Determine the C-bit position within PTEs. */
         call get_pte_c_bit
         movl %eax, %edx
 
-        /* Populate the static page table pages with an identity mapping. */
-        movl $pgtable, %edi
+        /*
+         * Populate the static page table pages with an identity mapping of
+         * the first 4GB, which covers stage2 wherever it was loaded.
+         */
+        load_addr .Lpgtable_rel, %edi
         leal 0x1007(%edi), %eax
         movl %eax, 0(%edi)
         addl %edx, 4(%edi)
@@ -91,7 +116,7 @@ global_asm!(
         wrmsr
 
         /* Load the static page table root. */
-        movl $pgtable, %eax
+        load_addr .Lpgtable_rel, %eax
         movl %eax, %cr3
 
         /* Enable paging, CR0.PG. */
@@ -102,7 +127,7 @@ global_asm!(
         popl    %edi
 
         pushl $0x18
-        movl $startup_64, %eax
+        leal (startup_64 - startup_32)(%ebp), %eax
         pushl %eax
 
         lret
@@ -162,11 +187,11 @@ global_asm!(
 
         /*
          * Verify that the C-bit position is within reasonable bounds:
-         * >= 32 and < 64.
+         * >= 32 and < 52. Bits 52 and up are PTE software bits and NX.
          */
         cmpl $32, %ebx
         jl .Lno_sev_snp
-        cmpl $64, %ebx
+        cmpl $52, %ebx
         jae .Lno_sev_snp
 
         subl $32, %ebx
@@ -178,6 +203,13 @@ global_asm!(
         hlt
         jmp .Lno_sev_snp
 
+    .Lgdt64_rel:
+        .long gdt64 - .
+    .Lgdt64_desc_rel:
+        .long gdt64_desc - .
+    .Lpgtable_rel:
+        .long pgtable - .
+
         .code64
 
     startup_64:
@@ -189,6 +221,32 @@ global_asm!(
         movw %ax, %gs
         movw %ax, %ss
 
+        /*
+         * Apply the R_X86_64_RELATIVE relocations of the position
+         * independent executable for the address stage2 runs at. There
+         * must not be any other kind.
+         */
+        leaq startup_32(%rip), %rdx
+        subq $STAGE2_LINK_BASE, %rdx
+        leaq _rela_start(%rip), %r8
+        leaq _rela_end(%rip), %r9
+    .Lreloc_loop:
+        cmpq %r9, %r8
+        jae .Lreloc_done
+        cmpl $8, 8(%r8)
+        jne .Lreloc_invalid
+        movq (%r8), %r10
+        addq %rdx, %r10
+        movq 16(%r8), %rax
+        addq %rdx, %rax
+        movq %rax, (%r10)
+        addq $24, %r8
+        jmp .Lreloc_loop
+    .Lreloc_invalid:
+        hlt
+        jmp .Lreloc_invalid
+    .Lreloc_done:
+
         /* Clear out .bss and transfer control to the main stage2 code. */
         xorq %rax, %rax
         leaq _bss(%rip), %rdi
@@ -202,26 +260,6 @@ global_asm!(
 
         .data
 
-    idt32:
-        .rept 32
-        .quad 0
-        .endr
-    idt32_end:
-
-    idt32_desc:
-        .word idt32_end - idt32 - 1
-        .long idt32
-
-    idt64:
-        .rept 32
-        .octa 0
-        .endr
-    idt64_end:
-
-    idt64_desc:
-        .word idt64_end - idt64 - 1
-        .quad idt64
-
         .align 256
     gdt64:
         .quad 0
@@ -231,9 +269,10 @@ global_asm!(
         .quad 0x00cf92000000ffff /* 64 bit data segment */
     gdt64_end:
 
+    /* The base is filled in at runtime */
     gdt64_desc:
         .word gdt64_end - gdt64 - 1
-        .quad gdt64
+        .quad 0
 
         .align 4096
         .globl pgtable
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// SVSM kernel command line. Stage2 reads it from fw_cfg and passes it in
+// the KernelLaunchInfo, the kernel keeps a copy for its whole lifetime.
+// Options are separated by whitespace and are either "key=value" or a bare
+// "key".
+
+use crate::address::VirtAddr;
+use crate::types::PAGE_SIZE;
+use crate::utils::immut_after_init::ImmutAfterInitCell;
+use core::slice;
+
+/// Longest command line stage2 passes to the kernel
+pub const CMDLINE_MAX_LEN: usize = PAGE_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cmdline {
+    buf: [u8; CMDLINE_MAX_LEN],
+    len: usize,
+}
+
+impl Cmdline {
+    pub const fn new() -> Self {
+        Cmdline {
+            buf: [0; CMDLINE_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// Builds a command line from `bytes`, dropping trailing NULs and
+    /// newlines and everything beyond [`CMDLINE_MAX_LEN`].
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut cmdline = Cmdline::new();
+        let bytes = &bytes[..bytes.len().min(CMDLINE_MAX_LEN)];
+        let len = bytes
+            .iter()
+            .rposition(|&b| b != 0 && b != b'\n')
+            .map_or(0, |pos| pos + 1);
+
+        cmdline.buf[..len].copy_from_slice(&bytes[..len]);
+        cmdline.len = len;
+        cmdline
+    }
+
+    /// The whole command line, empty if it is not valid UTF-8
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    /// Value of option `key`, the empty string for a bare `key`. The last
+    /// occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.as_str()
+            .split_ascii_whitespace()
+            .rev()
+            .find_map(|opt| match opt.split_once('=') {
+                Some((k, v)) => (k == key).then_some(v),
+                None => (opt == key).then_some(""),
+            })
+    }
+
+    /// Value of a boolean option. A bare `key` means true, `None` is
+    /// returned if the option is missing or its value is not a boolean.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            "" | "1" | "on" | "yes" | "true" => Some(true),
+            "0" | "off" | "no" | "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Cmdline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static CMDLINE: ImmutAfterInitCell<Cmdline> = ImmutAfterInitCell::new(Cmdline::new());
+
+/// Copies the command line passed by stage2. Must run before the memory it
+/// is in gets reused.
+///
+/// # Safety
+///
+/// `start` must point to `len` readable bytes, and no other CPU may look at
+/// the command line yet.
+pub unsafe fn cmdline_init(start: VirtAddr, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let bytes = slice::from_raw_parts(start.as_ptr::<u8>(), len);
+    CMDLINE.reinit(&Cmdline::from_bytes(bytes));
+}
+
+/// The kernel command line, empty if stage2 passed none
+pub fn cmdline() -> &'static Cmdline {
+    &CMDLINE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmdline() {
+        let cmdline =
+            Cmdline::from_bytes(b"log=debug vtpm=off  debug log=warn,svsm::mm=trace\n\0\0");
+        assert_eq!(cmdline.get("log"), Some("warn,svsm::mm=trace"));
+        assert_eq!(cmdline.get_bool("vtpm"), Some(false));
+        assert_eq!(cmdline.get_bool("debug"), Some(true));
+        assert_eq!(cmdline.get_bool("log"), None);
+        assert_eq!(cmdline.get("vt"), None);
+        assert!(cmdline.as_str().ends_with("trace"));
+    }
+}
@@ -0,0 +1,403 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::mem;
+use core::ops::Range;
+
+type Link<T> = Option<Box<Node<T>>>;
+
+#[derive(Debug)]
+struct Node<T> {
+    range: Range<u64>,
+    value: T,
+    // Highest end of all ranges in this subtree, lets overlap queries skip
+    // whole subtrees
+    max_end: u64,
+    height: i32,
+    left: Link<T>,
+    right: Link<T>,
+}
+
+// Ranges are ordered by start and then by end
+fn key(range: &Range<u64>) -> (u64, u64) {
+    (range.start, range.end)
+}
+
+fn height<T>(link: &Link<T>) -> i32 {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn max_end<T>(link: &Link<T>) -> u64 {
+    link.as_ref().map_or(0, |node| node.max_end)
+}
+
+impl<T> Node<T> {
+    fn new(range: Range<u64>, value: T) -> Box<Self> {
+        Box::new(Node {
+            max_end: range.end,
+            range,
+            value,
+            height: 1,
+            left: None,
+            right: None,
+        })
+    }
+
+    fn update(&mut self) {
+        self.height = 1 + height(&self.left).max(height(&self.right));
+        self.max_end = self
+            .range
+            .end
+            .max(max_end(&self.left))
+            .max(max_end(&self.right));
+    }
+}
+
+fn rotate_right<T>(mut node: Box<Node<T>>) -> Box<Node<T>> {
+    let mut left = node.left.take().unwrap();
+    node.left = left.right.take();
+    node.update();
+    left.right = Some(node);
+    left.update();
+    left
+}
+
+fn rotate_left<T>(mut node: Box<Node<T>>) -> Box<Node<T>> {
+    let mut right = node.right.take().unwrap();
+    node.right = right.left.take();
+    node.update();
+    right.left = Some(node);
+    right.update();
+    right
+}
+
+// Restores the AVL property at `node` after one of its subtrees changed in
+// height by at most one
+fn balance<T>(mut node: Box<Node<T>>) -> Box<Node<T>> {
+    node.update();
+    let factor = height(&node.left) - height(&node.right);
+
+    if factor > 1 {
+        let left = node.left.take().unwrap();
+        node.left = Some(if height(&left.right) > height(&left.left) {
+            rotate_left(left)
+        } else {
+            left
+        });
+        rotate_right(node)
+    } else if factor < -1 {
+        let right = node.right.take().unwrap();
+        node.right = Some(if height(&right.left) > height(&right.right) {
+            rotate_right(right)
+        } else {
+            right
+        });
+        rotate_left(node)
+    } else {
+        node
+    }
+}
+
+fn insert<T>(link: Link<T>, range: Range<u64>, value: T) -> (Box<Node<T>>, Option<T>) {
+    let mut node = match link {
+        Some(node) => node,
+        None => return (Node::new(range, value), None),
+    };
+
+    let old = match key(&range).cmp(&key(&node.range)) {
+        Ordering::Less => {
+            let (left, old) = insert(node.left.take(), range, value);
+            node.left = Some(left);
+            old
+        }
+        Ordering::Greater => {
+            let (right, old) = insert(node.right.take(), range, value);
+            node.right = Some(right);
+            old
+        }
+        Ordering::Equal => Some(mem::replace(&mut node.value, value)),
+    };
+
+    (balance(node), old)
+}
+
+// Detaches the lowest node of the subtree, returns the remaining subtree and
+// the node
+fn remove_min<T>(mut node: Box<Node<T>>) -> (Link<T>, Box<Node<T>>) {
+    match node.left.take() {
+        None => (node.right.take(), node),
+        Some(left) => {
+            let (left, min) = remove_min(left);
+            node.left = left;
+            (Some(balance(node)), min)
+        }
+    }
+}
+
+fn remove<T>(link: Link<T>, k: (u64, u64)) -> (Link<T>, Option<T>) {
+    let mut node = match link {
+        Some(node) => node,
+        None => return (None, None),
+    };
+
+    match k.cmp(&key(&node.range)) {
+        Ordering::Less => {
+            let (left, value) = remove(node.left.take(), k);
+            node.left = left;
+            (Some(balance(node)), value)
+        }
+        Ordering::Greater => {
+            let (right, value) = remove(node.right.take(), k);
+            node.right = right;
+            (Some(balance(node)), value)
+        }
+        Ordering::Equal => {
+            let Node {
+                left, right, value, ..
+            } = *node;
+            let rest = match (left, right) {
+                (None, rest) | (rest, None) => rest,
+                (Some(left), Some(right)) => {
+                    let (right, mut successor) = remove_min(right);
+                    successor.left = Some(left);
+                    successor.right = right;
+                    Some(balance(successor))
+                }
+            };
+            (rest, Some(value))
+        }
+    }
+}
+
+/// Ordered collection of values keyed by half-open `u64` ranges, with
+/// lookups of all ranges overlapping a given one in `O(log n + k)`. Ranges
+/// may overlap each other, each exact range is stored at most once.
+#[derive(Debug)]
+pub struct IntervalTree<T> {
+    root: Link<T>,
+    len: usize,
+}
+
+impl<T> IntervalTree<T> {
+    pub const fn new() -> Self {
+        IntervalTree { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Stores `value` for `range`, which must not be empty. Returns the value
+    /// previously stored for exactly the same range.
+    pub fn insert(&mut self, range: Range<u64>, value: T) -> Option<T> {
+        assert!(range.start < range.end);
+
+        let (root, old) = insert(self.root.take(), range, value);
+        self.root = Some(root);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Removes the value stored for exactly `range`
+    pub fn remove(&mut self, range: &Range<u64>) -> Option<T> {
+        let (root, value) = remove(self.root.take(), key(range));
+        self.root = root;
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Iterates over all ranges overlapping `range`, ordered by their start
+    pub fn overlapping(&self, range: Range<u64>) -> Overlapping<'_, T> {
+        let mut iter = Overlapping {
+            range,
+            stack: Vec::new(),
+        };
+        iter.push_left(self.root.as_deref());
+        iter
+    }
+
+    /// Returns the first range containing `addr`
+    pub fn lookup(&self, addr: u64) -> Option<(Range<u64>, &T)> {
+        self.overlapping(addr..addr.saturating_add(1)).next()
+    }
+
+    /// Iterates over all ranges, ordered by their start
+    pub fn iter(&self) -> Overlapping<'_, T> {
+        self.overlapping(0..u64::MAX)
+    }
+}
+
+impl<T> Default for IntervalTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// In-order iterator over the ranges of an [`IntervalTree`] overlapping a
+/// range
+#[derive(Debug)]
+pub struct Overlapping<'a, T> {
+    range: Range<u64>,
+    stack: Vec<&'a Node<T>>,
+}
+
+impl<'a, T> Overlapping<'a, T> {
+    fn push_left(&mut self, mut link: Option<&'a Node<T>>) {
+        while let Some(node) = link {
+            // Nothing in this subtree reaches into the range
+            if node.max_end <= self.range.start {
+                break;
+            }
+            self.stack.push(node);
+            link = node.left.as_deref();
+        }
+    }
+}
+
+impl<'a, T> Iterator for Overlapping<'a, T> {
+    type Item = (Range<u64>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            // All following ranges start behind this one
+            if node.range.start >= self.range.end {
+                self.stack.clear();
+                return None;
+            }
+
+            self.push_left(node.right.as_deref());
+            if node.range.end > self.range.start {
+                return Some((node.range.clone(), &node.value));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the AVL property and the augmented data, returns the height of
+    // the subtree. The order is checked through the iterator.
+    fn check<T>(link: &Link<T>, lower: Option<(u64, u64)>) -> i32 {
+        let Some(node) = link else {
+            return 0;
+        };
+        if let Some(lower) = lower {
+            assert!(key(&node.range) > lower);
+        }
+        let left = check(&node.left, lower);
+        let right = check(&node.right, Some(key(&node.range)));
+        assert!((left - right).abs() <= 1);
+        assert_eq!(node.height, 1 + left.max(right));
+        assert_eq!(
+            node.max_end,
+            node.range
+                .end
+                .max(max_end(&node.left))
+                .max(max_end(&node.right))
+        );
+        node.height
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut tree = IntervalTree::new();
+        assert_eq!(tree.insert(0x1000..0x2000, 1), None);
+        assert_eq!(tree.insert(0x3000..0x4000, 2), None);
+        assert_eq!(tree.insert(0x1000..0x2000, 3), Some(1));
+        assert_eq!(tree.len(), 2);
+
+        assert_eq!(tree.lookup(0x1fff), Some((0x1000..0x2000, &3)));
+        assert_eq!(tree.lookup(0x2000), None);
+        assert_eq!(tree.lookup(0x3000), Some((0x3000..0x4000, &2)));
+
+        assert_eq!(tree.remove(&(0x1000..0x1800)), None);
+        assert_eq!(tree.remove(&(0x1000..0x2000)), Some(3));
+        assert_eq!(tree.lookup(0x1000), None);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let mut tree = IntervalTree::new();
+        tree.insert(10..20, 'a');
+        tree.insert(15..40, 'b');
+        tree.insert(30..35, 'c');
+        tree.insert(50..60, 'd');
+        tree.insert(0..5, 'e');
+
+        let found: Vec<char> = tree.overlapping(18..31).map(|(_, v)| *v).collect();
+        assert_eq!(found, ['a', 'b', 'c']);
+        let found: Vec<char> = tree.overlapping(40..50).map(|(_, v)| *v).collect();
+        assert!(found.is_empty());
+        let all: Vec<char> = tree.iter().map(|(_, v)| *v).collect();
+        assert_eq!(all, ['e', 'a', 'b', 'c', 'd']);
+    }
+
+    #[test]
+    fn test_against_linear_scan() {
+        let mut tree = IntervalTree::new();
+        let mut reference: Vec<(Range<u64>, u32)> = Vec::new();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut rand = move |limit: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % limit
+        };
+
+        for i in 0..2000 {
+            let start = rand(1000);
+            let range = start..start + 1 + rand(50);
+
+            if rand(3) == 0 {
+                let expected = reference
+                    .iter()
+                    .position(|(r, _)| *r == range)
+                    .map(|index| reference.remove(index).1);
+                assert_eq!(tree.remove(&range), expected);
+            } else {
+                let expected = match reference.iter_mut().find(|(r, _)| *r == range) {
+                    Some((_, value)) => Some(mem::replace(value, i)),
+                    None => {
+                        reference.push((range.clone(), i));
+                        None
+                    }
+                };
+                assert_eq!(tree.insert(range, i), expected);
+            }
+            check(&tree.root, None);
+            assert_eq!(tree.len(), reference.len());
+
+            let query = rand(1000)..rand(1000) + 1;
+            let mut expected: Vec<(Range<u64>, u32)> = reference
+                .iter()
+                .filter(|(r, _)| r.start < query.end && r.end > query.start)
+                .cloned()
+                .collect();
+            expected.sort_by_key(|(r, _)| key(r));
+            let found: Vec<(Range<u64>, u32)> =
+                tree.overlapping(query).map(|(r, v)| (r, *v)).collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -4,35 +4,93 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use crate::error::SvsmError;
+use crate::io::IOPort;
 use crate::locking::SpinLock;
+use crate::log_buffer::LOG_BUFFER;
+use crate::log_filter::{log_enabled, LOG_LEVEL_DEFAULT};
 use crate::serial::DEFAULT_SERIAL_PORT;
 use crate::utils::immut_after_init::ImmutAfterInitCell;
 use core::fmt;
 use log;
 
-pub trait ConsoleWriter {
+pub trait ConsoleWriter: Sync {
     fn put_byte(&self, _ch: u8) {}
+    /// Returns once all bytes written so far have left the device
+    fn flush(&self) {}
+}
+
+/// Port of the hypervisor debug console, like QEMU's isa-debugcon
+pub const DEBUG_CONSOLE_PORT: u16 = 0xe9;
+
+/// Console which writes to the hypervisor debug port. Every byte is a
+/// plain I/O exit through the GHCB and needs no emulated device behind it,
+/// so it also works on hosts which give the SVSM no UART.
+pub struct DebugConsole<'a> {
+    driver: &'a dyn IOPort,
+    port: u16,
+}
+
+impl<'a> DebugConsole<'a> {
+    pub const fn new(driver: &'a dyn IOPort, port: u16) -> Self {
+        DebugConsole { driver, port }
+    }
+}
+
+impl<'a> ConsoleWriter for DebugConsole<'a> {
+    fn put_byte(&self, ch: u8) {
+        self.driver.outb(self.port, ch);
+    }
+}
+
+/// Number of writers which can be added with [`console_add_sink`]
+pub const CONSOLE_MAX_SINKS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ConsoleError {
+    // All CONSOLE_MAX_SINKS slots are in use
+    TooManySinks,
+}
+
+impl From<ConsoleError> for SvsmError {
+    fn from(e: ConsoleError) -> Self {
+        Self::Console(e)
+    }
 }
 
 pub struct Console {
-    writer: *mut dyn ConsoleWriter,
+    writer: &'static dyn ConsoleWriter,
+    // Previous writer which still receives a copy of all output
+    mirror: Option<&'static dyn ConsoleWriter>,
+    // Further writers receiving a copy of all output, independent of
+    // retargeting
+    sinks: [Option<&'static dyn ConsoleWriter>; CONSOLE_MAX_SINKS],
 }
 
 impl Console {
-    pub fn set(&mut self, w: *mut dyn ConsoleWriter) {
-        self.writer = w;
+    fn retarget(&mut self, writer: &'static dyn ConsoleWriter, mirror: bool) {
+        let old = core::mem::replace(&mut self.writer, writer);
+        self.mirror = mirror.then_some(old);
+    }
+
+    fn writers(&self) -> impl Iterator<Item = &'static dyn ConsoleWriter> {
+        core::iter::once(self.writer)
+            .chain(self.mirror)
+            .chain(self.sinks.into_iter().flatten())
+    }
+
+    fn flush(&self) {
+        for writer in self.writers() {
+            writer.flush();
+        }
     }
 }
 
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        if self.writer.is_null() {
-            return Ok(());
-        }
-
         for ch in s.bytes() {
-            unsafe {
-                (*self.writer).put_byte(ch);
+            for writer in self.writers() {
+                writer.put_byte(ch);
             }
         }
 
@@ -40,13 +98,47 @@ impl fmt::Write for Console {
     }
 }
 
-pub static WRITER: SpinLock<Console> = SpinLock::new(unsafe {
-    Console {
-        writer: &mut DEFAULT_SERIAL_PORT,
-    }
+pub static WRITER: SpinLock<Console> = SpinLock::new(Console {
+    writer: &DEFAULT_SERIAL_PORT,
+    mirror: None,
+    sinks: [None; CONSOLE_MAX_SINKS],
 });
 static CONSOLE_INITIALIZED: ImmutAfterInitCell<bool> = ImmutAfterInitCell::new(false);
 
+/// Switches console output to `writer`. Output already written is flushed out
+/// of the current writer first, and no output is lost or interleaved during
+/// the switch. With `mirror` set, the previous writer keeps receiving a copy
+/// of everything written to the console, until the next switch.
+pub fn console_retarget(writer: &'static dyn ConsoleWriter, mirror: bool) {
+    let mut console = WRITER.lock_irqsave();
+    // Nothing reaches the writer before the console is initialized
+    if *CONSOLE_INITIALIZED {
+        console.flush();
+    }
+    console.retarget(writer, mirror);
+}
+
+/// Has `writer` receive a copy of all console output from now on, in
+/// addition to the current writer. Sinks stay in place when the console
+/// is retargeted.
+pub fn console_add_sink(writer: &'static dyn ConsoleWriter) -> Result<(), SvsmError> {
+    let mut console = WRITER.lock_irqsave();
+    let slot = console
+        .sinks
+        .iter_mut()
+        .find(|sink| sink.is_none())
+        .ok_or(ConsoleError::TooManySinks)?;
+    *slot = Some(writer);
+    Ok(())
+}
+
+/// Waits until all console output written so far has left the devices
+pub fn console_flush() {
+    if *CONSOLE_INITIALIZED {
+        WRITER.lock_irqsave().flush();
+    }
+}
+
 pub fn init_console() {
     unsafe { CONSOLE_INITIALIZED.reinit(&true) };
 }
@@ -54,10 +146,13 @@ pub fn init_console() {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    // Interrupt handlers may print too, keep them from interrupting a writer.
+    // A copy of the output is kept even when no console is available.
+    LOG_BUFFER.lock_irqsave().write_fmt(args).unwrap();
     if !*CONSOLE_INITIALIZED {
         return;
     }
-    WRITER.lock().write_fmt(args).unwrap();
+    WRITER.lock_irqsave().write_fmt(args).unwrap();
 }
 
 #[derive(Clone, Copy)]
@@ -79,8 +174,8 @@ impl ConsoleLogger {
 }
 
 impl log::Log for ConsoleLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        log_enabled(metadata.level(), metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -125,7 +220,9 @@ static CONSOLE_LOGGER: ImmutAfterInitCell<ConsoleLogger> = ImmutAfterInitCell::u
 
 pub fn install_console_logger(component: &'static str) {
     let logger = ConsoleLogger::new(component);
-    unsafe { CONSOLE_LOGGER.init(&logger) };
+    CONSOLE_LOGGER
+        .init(&logger)
+        .expect("Console logger installed twice");
 
     if let Err(e) = log::set_logger(&*CONSOLE_LOGGER) {
         // Failed to install the ConsoleLogger, presumably because something had
@@ -137,12 +234,7 @@ pub fn install_console_logger(component: &'static str) {
         ));
     }
 
-    // Log levels are to be configured via the log's library feature configuration.
-    log::set_max_level(log::LevelFilter::Trace);
-}
-
-#[macro_export]
-macro_rules! println {
-    () => (log::info!(""));
-    ($($arg:tt)*) => (log::info!($($arg)*));
+    // The log library's features cap the levels compiled in, the log filter
+    // picks from those at runtime.
+    log::set_max_level(LOG_LEVEL_DEFAULT);
 }
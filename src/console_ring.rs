@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::address::{Address, VirtAddr};
+use crate::console::ConsoleWriter;
+use crate::cpu::percpu::this_cpu_mut;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::mm::memory::{add_guest_memory, remove_guest_memory};
+use crate::mm::pagetable::PageTable;
+use crate::mm::validate::{valid_bitmap_clear_valid_4k, valid_bitmap_valid_addr};
+use crate::mm::vmalloc::map_pages_shared;
+use crate::mm::{valid_phys_address, PerCPUPageMappingGuard};
+use crate::sev::ghcb::PageStateChangeOp;
+use crate::sev::{pvalidate, SevSnpError};
+use crate::types::{MemoryRegion, PAGE_SIZE};
+use alloc::boxed::Box;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// "SVCR" in little-endian byte order
+pub const CONSOLE_RING_MAGIC: u32 = 0x5243_5653;
+pub const CONSOLE_RING_VERSION: u32 = 1;
+/// Offset of the data area from the start of the ring
+pub const CONSOLE_RING_DATA_OFFSET: usize = 64;
+/// Largest console ring the SVSM maps
+pub const CONSOLE_RING_MAX_SIZE: usize = 1024 * 1024;
+
+/// Header at the start of the shared console ring. The SVSM only ever
+/// writes `head` and `overflow`, the host only ever writes `tail`. Both
+/// indices are free-running and taken modulo `size` to get a position in
+/// the data area.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ConsoleRingHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Size of the data area in bytes
+    pub size: u32,
+    /// Number of bytes produced by the SVSM
+    pub head: AtomicU32,
+    /// Number of bytes consumed by the host
+    pub tail: AtomicU32,
+    /// Number of bytes dropped because the ring was full
+    pub overflow: AtomicU32,
+}
+
+/// Console which writes into a ring in memory shared with the host, so
+/// that logging does not need a VMGEXIT per byte.
+#[derive(Debug)]
+pub struct ConsoleRing {
+    base: VirtAddr,
+    size: u32,
+}
+
+impl ConsoleRing {
+    pub const fn new() -> Self {
+        ConsoleRing {
+            base: VirtAddr::null(),
+            size: 0,
+        }
+    }
+
+    fn header(&self) -> &ConsoleRingHeader {
+        unsafe { &*self.base.as_ptr::<ConsoleRingHeader>() }
+    }
+
+    /// Initializes the ring header at `base`. The memory must stay mapped
+    /// for the lifetime of the console.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `len` bytes of mapped memory which nothing else
+    /// in the SVSM uses.
+    pub unsafe fn init(&mut self, base: VirtAddr, len: usize) {
+        assert!(len > CONSOLE_RING_DATA_OFFSET);
+
+        let size = (len - CONSOLE_RING_DATA_OFFSET) as u32;
+        base.as_mut_ptr::<ConsoleRingHeader>()
+            .write_volatile(ConsoleRingHeader {
+                magic: CONSOLE_RING_MAGIC,
+                version: CONSOLE_RING_VERSION,
+                size,
+                head: AtomicU32::new(0),
+                tail: AtomicU32::new(0),
+                overflow: AtomicU32::new(0),
+            });
+
+        self.base = base;
+        self.size = size;
+    }
+}
+
+impl Default for ConsoleRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConsoleWriter for ConsoleRing {
+    fn put_byte(&self, ch: u8) {
+        if self.base.is_null() {
+            return;
+        }
+
+        let hdr = self.header();
+        let head = hdr.head.load(Ordering::Relaxed);
+        // The tail comes from the host and is only trusted as far as it
+        // decides whether there is room
+        let tail = hdr.tail.load(Ordering::Acquire);
+
+        if head.wrapping_sub(tail) >= self.size {
+            hdr.overflow.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let offset = CONSOLE_RING_DATA_OFFSET + (head % self.size) as usize;
+        unsafe {
+            self.base
+                .offset(offset)
+                .as_mut_ptr::<u8>()
+                .write_volatile(ch)
+        };
+        hdr.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// Console backend selected by the host through the `opt/svsm/console`
+/// fw_cfg file. The serial port is used unless the host asks for another
+/// backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleBackend {
+    Serial,
+    Ring,
+    /// Hypervisor debug port
+    Debug,
+    /// Serial port and hypervisor debug port
+    SerialDebug,
+}
+
+pub fn console_backend(fw_cfg: &FwCfg) -> ConsoleBackend {
+    let mut buf = [0u8; 16];
+    let len = match fw_cfg
+        .file_selector("opt/svsm/console")
+        .and_then(|file| fw_cfg.read_file(&file, &mut buf))
+    {
+        Ok(len) => len,
+        Err(_) => return ConsoleBackend::Serial,
+    };
+
+    match buf[..len].strip_suffix(b"\n").unwrap_or(&buf[..len]) {
+        b"ring" => ConsoleBackend::Ring,
+        b"debug" => ConsoleBackend::Debug,
+        b"serial+debug" => ConsoleBackend::SerialDebug,
+        _ => ConsoleBackend::Serial,
+    }
+}
+
+// Rescinds validation of all pages in `region` and changes them to shared
+fn share_console_ring(region: &MemoryRegion) -> Result<(), SvsmError> {
+    let pstart = region.start_phys();
+    let pend = region.end_phys();
+
+    // Pages the guest already validated become invalid, so their contents
+    // never show up in shared memory
+    for offset in (0..pend - pstart).step_by(PAGE_SIZE) {
+        let paddr = pstart.offset(offset);
+        let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+        pvalidate(guard.virt_addr(), false, false).or_else(|err| match err {
+            SvsmError::SevSnp(SevSnpError::FAIL_UNCHANGED(_)) => Ok(()),
+            _ => Err(err),
+        })?;
+
+        if valid_bitmap_valid_addr(paddr) {
+            valid_bitmap_clear_valid_4k(paddr);
+        }
+    }
+
+    this_cpu_mut()
+        .ghcb()
+        .page_state_change(pstart, pend, false, PageStateChangeOp::PscShared)
+}
+
+// Takes the host-provided region away from the guest, changes it to shared
+// and maps it
+fn map_console_ring(region: &MemoryRegion) -> Result<VirtAddr, SvsmError> {
+    let pstart = region.start_phys();
+    let pend = region.end_phys();
+    let len = pend - pstart;
+
+    if !pstart.is_page_aligned()
+        || !pend.is_page_aligned()
+        || len == 0
+        || len > CONSOLE_RING_MAX_SIZE
+    {
+        return Err(SvsmError::InvalidAddress);
+    }
+
+    // The ring must be in guest memory, not in memory owned by the SVSM
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        if !valid_phys_address(pstart.offset(offset)) {
+            return Err(SvsmError::InvalidAddress);
+        }
+    }
+
+    // The guest must never use the ring memory again, otherwise the host
+    // could have it share private data
+    remove_guest_memory(region);
+
+    // The ring is not used if it can't be shared, the guest gets the
+    // region back. Pages rescinded so far can be validated again.
+    if let Err(err) = share_console_ring(region) {
+        add_guest_memory(region);
+        return Err(err);
+    }
+
+    map_pages_shared(pstart, len, PageTable::data_flags())
+}
+
+/// Sets up a console ring in the memory region the host provided for it.
+/// Fails if the host did not provide a usable region.
+pub fn init_console_ring(fw_cfg: &FwCfg) -> Result<&'static ConsoleRing, SvsmError> {
+    let region = fw_cfg.console_ring_region()?;
+    let vaddr = map_console_ring(&region)?;
+    let len = region.len() as usize;
+
+    assert!(len >= size_of::<ConsoleRingHeader>());
+    let mut ring = Box::new(ConsoleRing::new());
+    unsafe { ring.init(vaddr, len) };
+
+    // The ring stays the console until the SVSM terminates
+    Ok(Box::leak(ring))
+}
@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use super::features::cpu_has_x2apic;
+use super::idt::X86Regs;
+use super::irq::IrqGuard;
+use super::msr::{read_apic_base, read_msr_ghcb, write_apic_base, write_msr_ghcb};
+use crate::cmdline::cmdline;
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use crate::sev::hv_doorbell::restricted_injection;
+
+const APIC_BASE_EXTD: u64 = 1 << 10;
+const APIC_BASE_EN: u64 = 1 << 11;
+
+// x2APIC registers
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_TPR: u32 = 0x808;
+const X2APIC_EOI: u32 = 0x80b;
+const X2APIC_SVR: u32 = 0x80f;
+const X2APIC_ICR: u32 = 0x830;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_TIMER_ICR: u32 = 0x838;
+const X2APIC_TIMER_DCR: u32 = 0x83e;
+
+const SVR_APIC_ENABLE: u64 = 1 << 8;
+
+const LVT_TIMER_PERIODIC: u64 = 1 << 17;
+const TIMER_DCR_DIV16: u64 = 0b0011;
+
+// Interrupt command register fields
+const ICR_DM_FIXED: u64 = 0 << 8;
+const ICR_DM_NMI: u64 = 4 << 8;
+const ICR_DM_INIT: u64 = 5 << 8;
+const ICR_DM_STARTUP: u64 = 6 << 8;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_DSH_SELF: u64 = 1 << 18;
+const ICR_DSH_ALL: u64 = 2 << 18;
+const ICR_DSH_ALL_BUT_SELF: u64 = 3 << 18;
+const ICR_DEST_SHIFT: u64 = 32;
+
+/// First vector available for interrupts, lower ones are exceptions
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+/// Vector of spurious interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IRQ_VECTORS: usize = 256 - FIRST_IRQ_VECTOR as usize;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ApicError {
+    // The CPU does not support x2APIC mode
+    NoX2Apic,
+    // The local APIC is the one of the guest, see apic_owned()
+    GuestOwned,
+    // Vector is reserved for exceptions or spurious interrupts
+    InvalidVector(u8),
+    // A handler is already registered for the vector
+    VectorInUse(u8),
+}
+
+impl From<ApicError> for SvsmError {
+    fn from(e: ApicError) -> Self {
+        Self::Apic(e)
+    }
+}
+
+/// Destination of an inter-processor interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiDest {
+    Apic(u32),
+    SelfOnly,
+    All,
+    AllButSelf,
+}
+
+/// Kind of an inter-processor interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiKind {
+    Fixed(u8),
+    Nmi,
+    Init,
+    /// Start-up IPI with the page number of the real-mode entry point
+    Startup(u8),
+}
+
+fn icr_value(dest: IpiDest, kind: IpiKind) -> u64 {
+    let mut icr = match kind {
+        IpiKind::Fixed(vector) => ICR_DM_FIXED | vector as u64,
+        IpiKind::Nmi => ICR_DM_NMI,
+        IpiKind::Init => ICR_DM_INIT,
+        IpiKind::Startup(page) => ICR_DM_STARTUP | page as u64,
+    } | ICR_LEVEL_ASSERT;
+
+    icr |= match dest {
+        IpiDest::Apic(id) => (id as u64) << ICR_DEST_SHIFT,
+        IpiDest::SelfOnly => ICR_DSH_SELF,
+        IpiDest::All => ICR_DSH_ALL,
+        IpiDest::AllButSelf => ICR_DSH_ALL_BUT_SELF,
+    };
+
+    icr
+}
+
+// The local APIC is emulated by the hypervisor, so all accesses go through
+// the GHCB of the current CPU.
+fn apic_read(msr: u32) -> Result<u64, SvsmError> {
+    read_msr_ghcb(msr)
+}
+
+fn apic_write(msr: u32, val: u64) -> Result<(), SvsmError> {
+    write_msr_ghcb(msr, val)
+}
+
+/// Returns whether the SVSM may program the local APIC. Without Restricted
+/// Injection the hypervisor emulates a single local APIC per vCPU, which the
+/// guest uses as well, so the SVSM only touches it if the host asks for that
+/// with the "apic" command line option.
+pub fn apic_owned() -> bool {
+    restricted_injection() || cmdline().get_bool("apic") == Some(true)
+}
+
+/// Switches the local APIC of the current CPU to x2APIC mode and software
+/// enables it. Needs the GHCB of the current CPU. Fails if the local APIC
+/// belongs to the guest, the SVSM then has to poll instead.
+pub fn apic_init() -> Result<(), SvsmError> {
+    if !apic_owned() {
+        return Err(ApicError::GuestOwned.into());
+    }
+    if !cpu_has_x2apic() {
+        return Err(ApicError::NoX2Apic.into());
+    }
+
+    // The APIC must be enabled in xAPIC mode before switching to x2APIC
+    let base = read_apic_base()?;
+    if base & APIC_BASE_EN == 0 {
+        write_apic_base(base | APIC_BASE_EN)?;
+    }
+    if base & APIC_BASE_EXTD == 0 {
+        write_apic_base(base | APIC_BASE_EN | APIC_BASE_EXTD)?;
+    }
+
+    apic_write(X2APIC_TPR, 0)?;
+    apic_write(X2APIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u64)
+}
+
+/// Returns the x2APIC ID of the current CPU
+pub fn apic_id() -> Result<u32, SvsmError> {
+    Ok(apic_read(X2APIC_ID)? as u32)
+}
+
+/// Signals the end of interrupt handling to the local APIC
+pub fn apic_eoi() -> Result<(), SvsmError> {
+    apic_write(X2APIC_EOI, 0)
+}
+
+/// Sends an IPI. In x2APIC mode the ICR is a single register, so there is
+/// no delivery status to wait for.
+pub fn send_ipi(dest: IpiDest, kind: IpiKind) -> Result<(), SvsmError> {
+    if !apic_owned() {
+        return Err(ApicError::GuestOwned.into());
+    }
+    if let IpiKind::Fixed(vector) = kind {
+        if vector < FIRST_IRQ_VECTOR {
+            return Err(ApicError::InvalidVector(vector).into());
+        }
+    }
+
+    apic_write(X2APIC_ICR, icr_value(dest, kind))
+}
+
+/// Starts the local APIC timer of the current CPU in periodic mode. It fires
+/// on `vector` every `count` APIC bus clocks divided by 16.
+pub fn apic_timer_start(vector: u8, count: u32) -> Result<(), SvsmError> {
+    handler_index(vector)?;
+    if !apic_owned() {
+        return Err(ApicError::GuestOwned.into());
+    }
+
+    apic_write(X2APIC_TIMER_DCR, TIMER_DCR_DIV16)?;
+    apic_write(X2APIC_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u64)?;
+    apic_write(X2APIC_TIMER_ICR, count as u64)
+}
+
+/// Handler for an interrupt vector, called with interrupts disabled. The
+/// EOI is sent after it returns.
+pub type IrqHandler = fn(vector: u8);
+
+static IRQ_HANDLERS: RWLock<[Option<IrqHandler>; IRQ_VECTORS]> = RWLock::new([None; IRQ_VECTORS]);
+
+fn handler_index(vector: u8) -> Result<usize, SvsmError> {
+    if vector < FIRST_IRQ_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(ApicError::InvalidVector(vector).into());
+    }
+
+    Ok((vector - FIRST_IRQ_VECTOR) as usize)
+}
+
+/// Registers `handler` for interrupts on `vector`
+pub fn register_irq_handler(vector: u8, handler: IrqHandler) -> Result<(), SvsmError> {
+    let index = handler_index(vector)?;
+
+    // An interrupt on this CPU would deadlock on the lock
+    let _guard = IrqGuard::new();
+    let mut handlers = IRQ_HANDLERS.lock_write();
+    if handlers[index].is_some() {
+        return Err(ApicError::VectorInUse(vector).into());
+    }
+    handlers[index] = Some(handler);
+
+    Ok(())
+}
+
+pub fn unregister_irq_handler(vector: u8) -> Result<(), SvsmError> {
+    let index = handler_index(vector)?;
+
+    let _guard = IrqGuard::new();
+    IRQ_HANDLERS.lock_write()[index] = None;
+
+    Ok(())
+}
+
+/// Dispatches an interrupt taken through the IDT to its handler
+pub fn handle_interrupt(regs: &X86Regs) {
+    dispatch_irq(regs.vector as u8, true);
+}
+
+/// Runs the handler for `vector` and acknowledges the interrupt if `eoi` is
+/// set. Interrupts posted in the #HV doorbell may not need an EOI.
+pub fn dispatch_irq(vector: u8, eoi: bool) {
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+
+    let handler = handler_index(vector)
+        .ok()
+        .and_then(|index| IRQ_HANDLERS.lock_read()[index]);
+    match handler {
+        Some(handler) => handler(vector),
+        None => log::warn!("Unexpected interrupt on vector {:#x}", vector),
+    }
+
+    if !eoi {
+        return;
+    }
+    if let Err(e) = apic_eoi() {
+        log::error!("Failed to send EOI for vector {:#x}: {:?}", vector, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icr_value() {
+        assert_eq!(
+            icr_value(IpiDest::Apic(3), IpiKind::Fixed(0x40)),
+            0x0000_0003_0000_4040
+        );
+        assert_eq!(icr_value(IpiDest::AllButSelf, IpiKind::Init), 0x000c_4500);
+        assert_eq!(
+            icr_value(IpiDest::Apic(1), IpiKind::Startup(0x9f)),
+            0x0000_0001_0000_469f
+        );
+        assert_eq!(icr_value(IpiDest::SelfOnly, IpiKind::Nmi), 0x0004_4400);
+    }
+}
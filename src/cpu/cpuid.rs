@@ -4,7 +4,13 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use crate::cpu::control_regs::{read_cr4, CR4Flags};
+use crate::cpu::percpu::this_cpu_mut;
+use crate::error::SvsmError;
+use crate::sev::ghcb::GhcbError;
+use crate::sev::msr_protocol::{cpuid_msr, CpuidReg};
 use crate::utils::immut_after_init::ImmutAfterInitRef;
+use core::arch::asm;
 use log;
 
 const SNP_CPUID_MAX_COUNT: usize = 64;
@@ -34,10 +40,130 @@ pub struct SnpCpuidTable {
     func: [SnpCpuidFn; SNP_CPUID_MAX_COUNT],
 }
 
-pub fn register_cpuid_table(table: &'static SnpCpuidTable) {
-    unsafe { CPUID_PAGE.init_from_ref(table) };
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidError {
+    // The table lists more functions than fit into the page
+    Count(u32),
+    // A reserved field is not zero
+    Reserved,
 }
 
+impl From<CpuidError> for SvsmError {
+    fn from(e: CpuidError) -> Self {
+        Self::Cpuid(e)
+    }
+}
+
+// Leaves whose output depends on the sub-leaf in ECX. For all other leaves
+// ECX is ignored and the table lists them with ECX 0.
+fn leaf_has_subleaves(leaf: u32) -> bool {
+    matches!(
+        leaf,
+        0x4 | 0x7
+            | 0xb
+            | 0xd
+            | 0xf
+            | 0x10
+            | 0x12
+            | 0x14
+            | 0x17
+            | 0x18
+            | 0x1d
+            | 0x1f
+            | 0x8000_001d
+            | 0x8000_0020
+    )
+}
+
+// Leaves the SNP firmware checks against the platform and the guest policy
+// before it accepts the CPUID page. Values for them are only taken from the
+// table, never from the hypervisor.
+fn leaf_is_policed(leaf: u32) -> bool {
+    matches!(
+        leaf,
+        0x0 | 0x1
+            | 0x7
+            | 0xd
+            | 0x14
+            | 0x8000_0000
+            | 0x8000_0001
+            | 0x8000_0008
+            | 0x8000_001f
+            | 0x8000_0021
+    )
+}
+
+impl SnpCpuidTable {
+    fn functions(&self) -> &[SnpCpuidFn] {
+        let count = (self.count as usize).min(SNP_CPUID_MAX_COUNT);
+        &self.func[..count]
+    }
+
+    /// Checks the table the firmware or host placed in the CPUID page
+    pub fn validate(&self) -> Result<(), CpuidError> {
+        let count = self.count;
+        if count as usize > SNP_CPUID_MAX_COUNT {
+            return Err(CpuidError::Count(count));
+        }
+
+        let (reserved_1, reserved_2) = (self.reserved_1, self.reserved_2);
+        if reserved_1 != 0 || reserved_2 != 0 {
+            return Err(CpuidError::Reserved);
+        }
+        if self.functions().iter().any(|f| f.reserved_1 != 0) {
+            return Err(CpuidError::Reserved);
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&self, eax: u32, ecx: u32, xcr0: u64, xss: u64) -> Option<CpuidResult> {
+        self.functions()
+            .iter()
+            .find(|f| f.eax_in == eax && f.ecx_in == ecx && f.xcr0_in == xcr0 && f.xss_in == xss)
+            .map(|f| CpuidResult {
+                eax: f.eax_out,
+                ebx: f.ebx_out,
+                ecx: f.ecx_out,
+                edx: f.edx_out,
+            })
+    }
+
+    // Looks up a leaf the way the CPU would execute it. Entries matching
+    // the current XCR0 and XSS are preferred over generic entries with both
+    // inputs zero, the XSAVE leaf 0xd is the only one where they differ.
+    fn cpuid(&self, leaf: u32, subleaf: u32, xcr0: u64, xss: u64) -> Option<CpuidResult> {
+        let subleaf = if leaf_has_subleaves(leaf) { subleaf } else { 0 };
+        self.lookup(leaf, subleaf, xcr0, xss)
+            .or_else(|| self.lookup(leaf, subleaf, 0, 0))
+    }
+
+    // Whether a leaf missing from the table may be asked from the
+    // hypervisor. Policed leaves may not, and neither may leaves beyond the
+    // maximum leaf the table reports for their range.
+    fn hv_fallback_allowed(&self, leaf: u32) -> bool {
+        if leaf_is_policed(leaf) {
+            return false;
+        }
+
+        let max = match leaf {
+            0x0..=0x3fff_ffff => self.cpuid(0x0, 0, 0, 0),
+            0x4000_0000..=0x4fff_ffff => return true,
+            0x8000_0000..=0x8fff_ffff => self.cpuid(0x8000_0000, 0, 0, 0),
+            _ => return false,
+        };
+        max.is_some_and(|r| leaf <= r.eax)
+    }
+}
+
+/// Validates and registers the CPUID table all lookups go to
+pub fn register_cpuid_table(table: &'static SnpCpuidTable) -> Result<(), SvsmError> {
+    table.validate()?;
+    CPUID_PAGE.init_from_ref(table)?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct CpuidResult {
     pub eax: u32,
     pub ebx: u32,
@@ -45,29 +171,87 @@ pub struct CpuidResult {
     pub edx: u32,
 }
 
+/// Looks up an exact table entry, without any of the matching rules of
+/// [`cpuid()`]
 pub fn cpuid_table_raw(eax: u32, ecx: u32, xcr0: u64, xss: u64) -> Option<CpuidResult> {
-    let count: usize = CPUID_PAGE.count as usize;
+    CPUID_PAGE.lookup(eax, ecx, xcr0, xss)
+}
 
-    for i in 0..count {
-        if eax == CPUID_PAGE.func[i].eax_in
-            && ecx == CPUID_PAGE.func[i].ecx_in
-            && xcr0 == CPUID_PAGE.func[i].xcr0_in
-            && xss == CPUID_PAGE.func[i].xss_in
-        {
-            return Some(CpuidResult {
-                eax: CPUID_PAGE.func[i].eax_out,
-                ebx: CPUID_PAGE.func[i].ebx_out,
-                ecx: CPUID_PAGE.func[i].ecx_out,
-                edx: CPUID_PAGE.func[i].edx_out,
-            });
-        }
+// XCR0 as seen by CPUID, only x87 state is enabled without OSXSAVE
+fn current_xcr0() -> u64 {
+    if !read_cr4().contains(CR4Flags::OSXSAVE) {
+        return 1;
     }
 
-    None
+    let (eax, edx): (u32, u32);
+    unsafe {
+        asm!("xgetbv",
+             in("ecx") 0,
+             out("eax") eax,
+             out("edx") edx,
+             options(nomem, nostack));
+    }
+    (edx as u64) << 32 | eax as u64
+}
+
+// Sanity checks on values the hypervisor returned for a leaf
+fn hv_result_valid(leaf: u32, subleaf: u32, res: &CpuidResult) -> bool {
+    match leaf {
+        // Extended topology leaves echo the sub-leaf in ECX[7:0]
+        0xb | 0x1f => res.ecx & 0xff == subleaf & 0xff,
+        _ => true,
+    }
 }
 
-pub fn cpuid_table(eax: u32) -> Option<CpuidResult> {
-    cpuid_table_raw(eax, 0, 0, 0)
+// Executes CPUID through the hypervisor, via the GHCB if this CPU has one
+// and via the GHCB MSR protocol otherwise.
+fn cpuid_hv(leaf: u32, subleaf: u32, xcr0: u64) -> Result<CpuidResult, SvsmError> {
+    let cpu = this_cpu_mut();
+    if cpu.has_ghcb() {
+        // Also reached from the #VC handler, which must not wait for the
+        // code it interrupted
+        let mut ghcb = cpu.try_ghcb().ok_or(GhcbError::Busy)?;
+        return ghcb.cpuid(leaf, subleaf, xcr0);
+    }
+
+    // The MSR protocol has no way to pass a sub-leaf
+    if leaf_has_subleaves(leaf) && subleaf != 0 {
+        return Err(GhcbError::Unavailable.into());
+    }
+
+    Ok(CpuidResult {
+        eax: cpuid_msr(leaf, CpuidReg::Eax)?,
+        ebx: cpuid_msr(leaf, CpuidReg::Ebx)?,
+        ecx: cpuid_msr(leaf, CpuidReg::Ecx)?,
+        edx: cpuid_msr(leaf, CpuidReg::Edx)?,
+    })
+}
+
+/// Returns what CPUID `leaf`/`subleaf` yields on this CPU, according to
+/// the validated CPUID page. Leaves missing from the page are asked from
+/// the hypervisor, unless the firmware is expected to police them. The SVSM
+/// does not enable supervisor state components, so XSS is taken as zero.
+pub fn cpuid(leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    let xcr0 = if leaf == 0xd { current_xcr0() } else { 0 };
+    if let Some(res) = CPUID_PAGE.cpuid(leaf, subleaf, xcr0, 0) {
+        return Some(res);
+    }
+
+    if !CPUID_PAGE.hv_fallback_allowed(leaf) {
+        return None;
+    }
+
+    match cpuid_hv(leaf, subleaf, xcr0) {
+        Ok(res) if hv_result_valid(leaf, subleaf, &res) => Some(res),
+        Ok(_) => {
+            log::warn!("Invalid CPUID {:#x}/{:#x} from hypervisor", leaf, subleaf);
+            None
+        }
+        Err(e) => {
+            log::warn!("CPUID {:#x}/{:#x} failed: {:?}", leaf, subleaf, e);
+            None
+        }
+    }
 }
 
 pub fn dump_cpuid_table() {
@@ -88,3 +272,66 @@ pub fn dump_cpuid_table() {
                     eax_in, ecx_in, xcr0_in, xss_in, eax_out, ebx_out, ecx_out, edx_out);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_FN: SnpCpuidFn = SnpCpuidFn {
+        eax_in: 0,
+        ecx_in: 0,
+        xcr0_in: 0,
+        xss_in: 0,
+        eax_out: 0,
+        ebx_out: 0,
+        ecx_out: 0,
+        edx_out: 0,
+        reserved_1: 0,
+    };
+
+    fn entry(eax_in: u32, ecx_in: u32, xcr0_in: u64, ebx_out: u32) -> SnpCpuidFn {
+        SnpCpuidFn {
+            eax_in,
+            ecx_in,
+            xcr0_in,
+            ebx_out,
+            ..EMPTY_FN
+        }
+    }
+
+    #[test]
+    fn test_cpuid_lookup() {
+        let mut table = SnpCpuidTable {
+            count: 4,
+            reserved_1: 0,
+            reserved_2: 0,
+            func: [EMPTY_FN; SNP_CPUID_MAX_COUNT],
+        };
+        table.func[0] = entry(0x1, 0, 0, 1);
+        table.func[1] = entry(0x7, 0, 0, 7);
+        table.func[2] = entry(0xd, 0, 0, 0x240);
+        table.func[3] = entry(0xd, 0, 0x7, 0x340);
+        assert_eq!(table.validate(), Ok(()));
+
+        // ECX is ignored for leaves without sub-leaves only
+        assert_eq!(table.cpuid(0x1, 5, 0, 0).map(|r| r.ebx), Some(1));
+        assert_eq!(table.cpuid(0x7, 0, 0, 0).map(|r| r.ebx), Some(7));
+        assert_eq!(table.cpuid(0x7, 1, 0, 0), None);
+
+        // Entries for the current XCR0 win over generic ones
+        assert_eq!(table.cpuid(0xd, 0, 0x7, 0).map(|r| r.ebx), Some(0x340));
+        assert_eq!(table.cpuid(0xd, 0, 0x3, 0).map(|r| r.ebx), Some(0x240));
+
+        // Only unpoliced leaves in range may come from the hypervisor
+        table.func[0] = entry(0x0, 0, 0, 0);
+        table.func[0].eax_out = 0x16;
+        assert!(table.hv_fallback_allowed(0x16));
+        assert!(table.hv_fallback_allowed(0x4000_0001));
+        assert!(!table.hv_fallback_allowed(0x17));
+        assert!(!table.hv_fallback_allowed(0x14));
+        assert!(!table.hv_fallback_allowed(0x8000_0007));
+
+        table.count = SNP_CPUID_MAX_COUNT as u32 + 1;
+        assert_eq!(table.validate(), Err(CpuidError::Count(65)));
+    }
+}
@@ -4,13 +4,17 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::cpuid::cpuid_table;
+use super::cpuid::cpuid;
 
 const X86_FEATURE_NX: u32 = 20;
 const X86_FEATURE_PGE: u32 = 13;
+// CPUID Fn0000_0001 ECX
+const X86_FEATURE_X2APIC: u32 = 21;
+// CPUID Fn0000_0007_ECX_0 EBX
+const X86_FEATURE_RDSEED: u32 = 18;
 
 pub fn cpu_has_nx() -> bool {
-    let ret = cpuid_table(0x80000001);
+    let ret = cpuid(0x80000001, 0);
 
     match ret {
         None => false,
@@ -19,10 +23,28 @@ pub fn cpu_has_nx() -> bool {
 }
 
 pub fn cpu_has_pge() -> bool {
-    let ret = cpuid_table(0x00000001);
+    let ret = cpuid(0x00000001, 0);
 
     match ret {
         None => false,
         Some(c) => (c.edx >> X86_FEATURE_PGE) & 1 == 1,
     }
 }
+
+pub fn cpu_has_x2apic() -> bool {
+    let ret = cpuid(0x00000001, 0);
+
+    match ret {
+        None => false,
+        Some(c) => (c.ecx >> X86_FEATURE_X2APIC) & 1 == 1,
+    }
+}
+
+pub fn cpu_has_rdseed() -> bool {
+    let ret = cpuid(0x00000007, 0);
+
+    match ret {
+        None => false,
+        Some(c) => (c.ebx >> X86_FEATURE_RDSEED) & 1 == 1,
+    }
+}
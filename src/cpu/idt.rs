@@ -4,19 +4,29 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::control_regs::read_cr2;
-use super::tss::IST_DF;
+use super::apic::{handle_interrupt, FIRST_IRQ_VECTOR};
+use super::control_regs::{read_cr0, read_cr2, read_cr3, read_cr4};
+use super::efer::read_efer;
+use super::percpu::this_cpu;
+use super::tss::{IST_DF, IST_HV, IST_VC};
 use super::vc::handle_vc_exception;
 use crate::address::{Address, VirtAddr};
 use crate::cpu::extable::handle_exception_table;
+#[cfg(feature = "enable-gdb")]
+use crate::debug::gdbstub::handle_debug_exception;
+use crate::debug::softlockup::handle_softlockup_nmi;
+use crate::mm::pagetable::dump_mapping;
+use crate::mm::stack::is_stack_guard;
+use crate::sev::hv_doorbell::handle_hv_exception;
+use crate::sev::integrity::{handle_machine_check, handle_rmp_fault, is_rmp_fault};
 use crate::types::SVSM_CS;
 use core::arch::{asm, global_asm};
 use core::mem;
 
 pub const _DE_VECTOR: usize = 0;
-pub const _DB_VECTOR: usize = 1;
-pub const _NMI_VECTOR: usize = 2;
-pub const _BP_VECTOR: usize = 3;
+pub const DB_VECTOR: usize = 1;
+pub const NMI_VECTOR: usize = 2;
+pub const BP_VECTOR: usize = 3;
 pub const _OF_VECTOR: usize = 4;
 pub const _BR_VECTOR: usize = 5;
 pub const _UD_VECTOR: usize = 6;
@@ -30,10 +40,10 @@ pub const GP_VECTOR: usize = 13;
 pub const PF_VECTOR: usize = 14;
 pub const _MF_VECTOR: usize = 16;
 pub const _AC_VECTOR: usize = 17;
-pub const _MCE_VECTOR: usize = 18;
+pub const MCE_VECTOR: usize = 18;
 pub const _XF_VECTOR: usize = 19;
 pub const _CP_VECTOR: usize = 21;
-pub const _HV_VECTOR: usize = 28;
+pub const HV_VECTOR: usize = 28;
 pub const VC_VECTOR: usize = 29;
 pub const _SX_VECTOR: usize = 30;
 
@@ -63,6 +73,55 @@ pub struct X86Regs {
     pub ss: usize,
 }
 
+// capture() stores registers at fixed offsets
+const _: () = assert!(mem::offset_of!(X86Regs, rax) == 0x70);
+const _: () = assert!(mem::offset_of!(X86Regs, ss) == 0xa8);
+
+impl X86Regs {
+    /// Captures the current register state where there is no exception
+    /// frame, e.g. on panic. Caller-saved registers hold whatever the
+    /// compiler left in them, `vector` and `error_code` are zero.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        // SAFETY: all fields are plain integers
+        let mut regs: X86Regs = unsafe { mem::zeroed() };
+        let ptr: *mut X86Regs = &mut regs;
+
+        unsafe {
+            asm!("movq %r15, 0x00({p})
+                  movq %r14, 0x08({p})
+                  movq %r13, 0x10({p})
+                  movq %r12, 0x18({p})
+                  movq %r11, 0x20({p})
+                  movq %r10, 0x28({p})
+                  movq %r9,  0x30({p})
+                  movq %r8,  0x38({p})
+                  movq %rbp, 0x40({p})
+                  movq %rdi, 0x48({p})
+                  movq %rsi, 0x50({p})
+                  movq %rdx, 0x58({p})
+                  movq %rcx, 0x60({p})
+                  movq %rbx, 0x68({p})
+                  movq %rax, 0x70({p})
+                  leaq 0(%rip), {t}
+                  movq {t}, 0x88({p})
+                  movq %cs, {t}
+                  movq {t}, 0x90({p})
+                  pushfq
+                  popq {t}
+                  movq {t}, 0x98({p})
+                  movq %rsp, 0xa0({p})
+                  movq %ss, {t}
+                  movq {t}, 0xa8({p})",
+                 p = in(reg) ptr,
+                 t = out(reg) _,
+                 options(att_syntax));
+        }
+
+        regs
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 struct IdtEntry {
@@ -147,8 +206,18 @@ fn init_idt(idt: &mut Idt) {
 }
 
 unsafe fn init_ist_vectors(idt: &mut Idt) {
-    let handler = VirtAddr::from(&idt_handler_array as *const u8).offset(32 * DF_VECTOR);
-    idt[DF_VECTOR] = IdtEntry::ist_entry(handler, IST_DF.try_into().unwrap());
+    let handlers = VirtAddr::from(&idt_handler_array as *const u8);
+
+    // #VC and #HV can be raised at any instruction boundary, including right
+    // after a stack switch, so they need a known-good stack. Neither handler
+    // may raise its own exception again while running on the IST stack.
+    for (vector, ist) in [
+        (DF_VECTOR, IST_DF),
+        (VC_VECTOR, IST_VC),
+        (HV_VECTOR, IST_HV),
+    ] {
+        idt[vector] = IdtEntry::ist_entry(handlers.offset(32 * vector), ist.try_into().unwrap());
+    }
 }
 
 fn load_idt(idt: &Idt) {
@@ -176,49 +245,167 @@ pub fn idt_init() {
     }
 }
 
+fn exception_name(vector: usize) -> &'static str {
+    match vector {
+        _DE_VECTOR => "Divide-Error",
+        DB_VECTOR => "Debug",
+        NMI_VECTOR => "NMI",
+        BP_VECTOR => "Breakpoint",
+        _OF_VECTOR => "Overflow",
+        _BR_VECTOR => "Bound-Range",
+        _UD_VECTOR => "Invalid-Opcode",
+        _NM_VECTOR => "Device-Not-Available",
+        DF_VECTOR => "Double-Fault",
+        _TS_VECTOR => "Invalid-TSS",
+        _NP_VECTOR => "Segment-Not-Present",
+        _SS_VECTOR => "Stack-Fault",
+        GP_VECTOR => "General-Protection-Fault",
+        PF_VECTOR => "Page-Fault",
+        _MF_VECTOR => "x87-Floating-Point",
+        _AC_VECTOR => "Alignment-Check",
+        MCE_VECTOR => "Machine-Check",
+        _XF_VECTOR => "SIMD-Floating-Point",
+        _CP_VECTOR => "Control-Protection",
+        HV_VECTOR => "Hypervisor-Injection",
+        VC_VECTOR => "VMM-Communication",
+        _SX_VECTOR => "Security",
+        _ => "Unknown",
+    }
+}
+
+/// Logs the register state of an exception frame
+pub fn dump_regs(regs: &X86Regs) {
+    let X86Regs {
+        r15,
+        r14,
+        r13,
+        r12,
+        r11,
+        r10,
+        r9,
+        r8,
+        rbp,
+        rdi,
+        rsi,
+        rdx,
+        rcx,
+        rbx,
+        rax,
+        rip,
+        cs,
+        flags,
+        rsp,
+        ss,
+        ..
+    } = *regs;
+
+    log::error!(
+        "RIP: {:04x}:{:#018x} RSP: {:04x}:{:#018x} RFLAGS: {:#018x}",
+        cs,
+        rip,
+        ss,
+        rsp,
+        flags
+    );
+    log::error!(
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}",
+        rax,
+        rbx,
+        rcx,
+        rdx
+    );
+    log::error!(
+        "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x} R8:  {:#018x}",
+        rsi,
+        rdi,
+        rbp,
+        r8
+    );
+    log::error!(
+        "R9:  {:#018x} R10: {:#018x} R11: {:#018x} R12: {:#018x}",
+        r9,
+        r10,
+        r11,
+        r12
+    );
+    log::error!("R13: {:#018x} R14: {:#018x} R15: {:#018x}", r13, r14, r15);
+}
+
+/// Logs the control registers and EFER of the current CPU
+pub fn dump_control_regs() {
+    log::error!(
+        "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x}",
+        read_cr0().bits(),
+        read_cr2(),
+        read_cr3()
+    );
+    log::error!(
+        "CR4: {:#018x} EFER: {:#018x}",
+        read_cr4().bits(),
+        read_efer().bits()
+    );
+}
+
+// Reports an exception which could not be handled and stops
+fn unhandled_exception(regs: &X86Regs) -> ! {
+    let vector = regs.vector;
+    let err = regs.error_code;
+    let rip = regs.rip;
+
+    log::error!(
+        "Unhandled {} exception (vector {}) error code: {:#018x}",
+        exception_name(vector),
+        vector,
+        err
+    );
+    if vector == PF_VECTOR || vector == DF_VECTOR {
+        let cr2 = read_cr2();
+        log::error!("CR2: {:#018x}", cr2);
+        if vector == PF_VECTOR {
+            dump_mapping(VirtAddr::from(cr2));
+        }
+        // Overflowing stacks usually end up here as a double fault, as the
+        // CPU can not push the #PF frame onto the same stack
+        if is_stack_guard(VirtAddr::from(cr2)) {
+            dump_regs(regs);
+            panic!("Stack overflow on CPU {}", this_cpu().get_apic_id());
+        }
+    }
+    dump_regs(regs);
+
+    panic!("Unhandled {} at RIP {:#018x}", exception_name(vector), rip);
+}
+
 #[no_mangle]
 fn generic_idt_handler(regs: &mut X86Regs) {
-    if regs.vector == DF_VECTOR {
-        let cr2 = read_cr2();
-        let rip = regs.rip;
-        let rsp = regs.rsp;
-        panic!(
-            "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
-            rip, rsp, cr2
-        );
-    } else if regs.vector == GP_VECTOR {
-        let rip = regs.rip;
-        let err = regs.error_code;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled General-Protection-Fault at RIP {:#018x} error code: {:#018x}",
-                rip, err
-            );
+    let vector = regs.vector;
+
+    match vector {
+        // There is no way to recover from a double fault
+        DF_VECTOR => unhandled_exception(regs),
+        VC_VECTOR => handle_vc_exception(regs),
+        HV_VECTOR => handle_hv_exception(regs),
+        NMI_VECTOR => {
+            if !handle_softlockup_nmi(regs) {
+                unhandled_exception(regs);
+            }
         }
-    } else if regs.vector == PF_VECTOR {
-        let cr2 = read_cr2();
-        let rip = regs.rip;
-        let err = regs.error_code;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled Page-Fault at RIP {:#018x} CR2: {:#018x} error code: {:#018x}",
-                rip, cr2, err
-            );
+        MCE_VECTOR => handle_machine_check(regs),
+        #[cfg(feature = "enable-gdb")]
+        DB_VECTOR | BP_VECTOR => {
+            if !handle_debug_exception(regs) {
+                unhandled_exception(regs);
+            }
         }
-    } else if regs.vector == VC_VECTOR {
-        handle_vc_exception(regs);
-    } else {
-        let err = regs.error_code;
-        let vec = regs.vector;
-        let rip = regs.rip;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled exception {} RIP {:#018x} error code: {:#018x}",
-                vec, rip, err
-            );
+        v if v >= FIRST_IRQ_VECTOR as usize => handle_interrupt(regs),
+        _ => {
+            if handle_exception_table(regs) {
+                return;
+            }
+            if vector == PF_VECTOR && is_rmp_fault(regs.error_code) {
+                handle_rmp_fault(regs);
+            }
+            unhandled_exception(regs);
         }
     }
 }
@@ -295,6 +482,15 @@ global_asm!(
         jmp push_regs
         i = i + 1
         .endr
+
+        /* Interrupts never push an error code */
+        .rept 256 - 32
+        .align 32
+        pushq   $0
+        pushq   $i  /* Vector Number */
+        jmp push_regs
+        i = i + 1
+        .endr
         "#,
     options(att_syntax)
 );
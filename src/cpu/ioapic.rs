@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::acpi::tables::{load_acpi_ioapic_info, ACPIIrqOverride};
+use crate::address::PhysAddr;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::locking::RWLock;
+use crate::mmio::{mmio_read, mmio_write};
+use alloc::vec::Vec;
+
+// Register window, relative to the I/O APIC base address
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect registers
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+// Redirection entry bits
+const REDIR_POLARITY_LOW: u64 = 1 << 13;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+
+// MPS INTI flags in MADT interrupt source overrides
+const INTI_POLARITY_MASK: u16 = 0x3;
+const INTI_POLARITY_LOW: u16 = 0x3;
+const INTI_TRIGGER_MASK: u16 = 0xc;
+const INTI_TRIGGER_LEVEL: u16 = 0xc;
+
+#[derive(Clone, Copy, Debug)]
+pub enum IoApicError {
+    // No I/O APIC handles the given GSI
+    NoIoApic(u32),
+    // Vector is reserved for exceptions
+    InvalidVector(u8),
+    // Destination APIC ID does not fit into a redirection entry
+    InvalidDest(u32),
+}
+
+impl From<IoApicError> for SvsmError {
+    fn from(e: IoApicError) -> Self {
+        Self::IoApic(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqTrigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqPolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Contents of an I/O APIC redirection table entry, with fixed delivery
+/// mode and physical destination mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub dest: u8,
+    pub trigger: IrqTrigger,
+    pub polarity: IrqPolarity,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    fn encode(&self) -> u64 {
+        let mut val = self.vector as u64 | (self.dest as u64) << REDIR_DEST_SHIFT;
+
+        if self.trigger == IrqTrigger::Level {
+            val |= REDIR_TRIGGER_LEVEL;
+        }
+        if self.polarity == IrqPolarity::ActiveLow {
+            val |= REDIR_POLARITY_LOW;
+        }
+        if self.masked {
+            val |= REDIR_MASKED;
+        }
+
+        val
+    }
+}
+
+/// A single I/O APIC. Its registers are emulated by the hypervisor and
+/// accessed through the GHCB of the current CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct IoApic {
+    id: u8,
+    base: PhysAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read_reg(&self, reg: u32) -> Result<u32, SvsmError> {
+        mmio_write(self.base + IOREGSEL, reg)?;
+        mmio_read(self.base + IOWIN)
+    }
+
+    fn write_reg(&self, reg: u32, val: u32) -> Result<(), SvsmError> {
+        mmio_write(self.base + IOREGSEL, reg)?;
+        mmio_write(self.base + IOWIN, val)
+    }
+
+    fn probe(id: u8, base: PhysAddr, gsi_base: u32) -> Result<Self, SvsmError> {
+        let mut ioapic = IoApic {
+            id,
+            base,
+            gsi_base,
+            entries: 0,
+        };
+
+        let ver = ioapic.read_reg(IOAPIC_VER)?;
+        ioapic.entries = ((ver >> 16) & 0xff) + 1;
+
+        Ok(ioapic)
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn set_entry(&self, gsi: u32, entry: &RedirectionEntry) -> Result<(), SvsmError> {
+        let reg = IOAPIC_REDTBL + 2 * (gsi - self.gsi_base);
+        let val = entry.encode();
+
+        // Mask the entry while updating it, so that no interrupt is delivered
+        // with a half-written entry
+        self.write_reg(reg, REDIR_MASKED as u32)?;
+        self.write_reg(reg + 1, (val >> 32) as u32)?;
+        self.write_reg(reg, val as u32)
+    }
+}
+
+struct IoApicInfo {
+    ioapics: Vec<IoApic>,
+    overrides: Vec<ACPIIrqOverride>,
+}
+
+static IOAPICS: RWLock<IoApicInfo> = RWLock::new(IoApicInfo {
+    ioapics: Vec::new(),
+    overrides: Vec::new(),
+});
+
+/// Discovers the I/O APICs listed in the MADT. Redirection entries are left
+/// alone, as they belong to the guest until a device is routed to the SVSM.
+pub fn ioapic_init(fw_cfg: &FwCfg) -> Result<(), SvsmError> {
+    let (entries, overrides) = load_acpi_ioapic_info(fw_cfg)?;
+    let mut ioapics = Vec::new();
+
+    for e in entries {
+        let ioapic = IoApic::probe(e.id, PhysAddr::from(e.address as u64), e.gsi_base)?;
+        log::info!(
+            "IOAPIC {} at {:#010x}: GSIs {}-{}",
+            ioapic.id,
+            ioapic.base,
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.entries - 1
+        );
+        ioapics.push(ioapic);
+    }
+
+    let mut info = IOAPICS.lock_write();
+    info.ioapics = ioapics;
+    info.overrides = overrides;
+
+    Ok(())
+}
+
+// Returns GSI, trigger mode and polarity for an ISA interrupt. ISA
+// interrupts are edge-triggered and active high unless overridden.
+fn legacy_irq_route(irq: u8, overrides: &[ACPIIrqOverride]) -> (u32, IrqTrigger, IrqPolarity) {
+    let Some(o) = overrides.iter().find(|o| o.source == irq) else {
+        return (irq as u32, IrqTrigger::Edge, IrqPolarity::ActiveHigh);
+    };
+
+    let trigger = match o.flags & INTI_TRIGGER_MASK {
+        INTI_TRIGGER_LEVEL => IrqTrigger::Level,
+        _ => IrqTrigger::Edge,
+    };
+    let polarity = match o.flags & INTI_POLARITY_MASK {
+        INTI_POLARITY_LOW => IrqPolarity::ActiveLow,
+        _ => IrqPolarity::ActiveHigh,
+    };
+
+    (o.gsi, trigger, polarity)
+}
+
+/// Routes ISA interrupt `irq` to `vector` on the CPU with APIC ID `dest`,
+/// taking MADT interrupt source overrides into account.
+pub fn route_legacy_irq(irq: u8, vector: u8, dest: u32) -> Result<(), SvsmError> {
+    if vector < 32 {
+        return Err(IoApicError::InvalidVector(vector).into());
+    }
+    let dest = u8::try_from(dest).map_err(|_| IoApicError::InvalidDest(dest))?;
+
+    let info = IOAPICS.lock_read();
+    let (gsi, trigger, polarity) = legacy_irq_route(irq, &info.overrides);
+    let ioapic = info
+        .ioapics
+        .iter()
+        .find(|ioapic| ioapic.handles(gsi))
+        .ok_or(IoApicError::NoIoApic(gsi))?;
+
+    let entry = RedirectionEntry {
+        vector,
+        dest,
+        trigger,
+        polarity,
+        masked: false,
+    };
+    ioapic.set_entry(gsi, &entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_irq_route() {
+        let overrides = [
+            ACPIIrqOverride {
+                source: 0,
+                gsi: 2,
+                flags: 0,
+            },
+            ACPIIrqOverride {
+                source: 9,
+                gsi: 9,
+                flags: 0xf,
+            },
+        ];
+
+        assert_eq!(
+            legacy_irq_route(4, &overrides),
+            (4, IrqTrigger::Edge, IrqPolarity::ActiveHigh)
+        );
+        assert_eq!(
+            legacy_irq_route(0, &overrides),
+            (2, IrqTrigger::Edge, IrqPolarity::ActiveHigh)
+        );
+        assert_eq!(
+            legacy_irq_route(9, &overrides),
+            (9, IrqTrigger::Level, IrqPolarity::ActiveLow)
+        );
+    }
+
+    #[test]
+    fn test_redirection_entry_encode() {
+        let entry = RedirectionEntry {
+            vector: 0x24,
+            dest: 3,
+            trigger: IrqTrigger::Level,
+            polarity: IrqPolarity::ActiveLow,
+            masked: true,
+        };
+        assert_eq!(entry.encode(), 0x0300_0000_0001_a024);
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::sev::hv_doorbell::{hv_doorbell_poll, hv_doorbell_process_pending};
+use core::arch::asm;
+
+const EFLAGS_IF: u64 = 1 << 9;
+
+pub fn irqs_enabled() -> bool {
+    let flags: u64;
+
+    unsafe {
+        asm!("pushfq
+              popq %rax",
+             out("rax") flags,
+             options(att_syntax, preserves_flags));
+    }
+
+    flags & EFLAGS_IF != 0
+}
+
+pub fn irqs_disable() {
+    unsafe {
+        asm!("cli", options(att_syntax, nomem, nostack));
+    }
+}
+
+pub fn irqs_enable() {
+    unsafe {
+        asm!("sti", options(att_syntax, nomem, nostack));
+    }
+}
+
+/// Lets pending interrupts in when called with interrupts disabled. `sti`
+/// only takes effect after the following instruction.
+pub fn irq_window() {
+    unsafe {
+        asm!(
+            "sti
+              nop
+              cli",
+            options(att_syntax, nostack)
+        );
+    }
+
+    // #HV doorbell events posted while interrupts were disabled are not
+    // signalled again
+    hv_doorbell_process_pending();
+}
+
+/// Disables interrupts for its lifetime and restores the previous
+/// interrupt state when dropped.
+#[derive(Debug)]
+pub struct IrqGuard {
+    enabled: bool,
+}
+
+impl IrqGuard {
+    pub fn new() -> Self {
+        let enabled = irqs_enabled();
+        if enabled {
+            irqs_disable();
+        }
+        IrqGuard { enabled }
+    }
+}
+
+impl Default for IrqGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            irqs_enable();
+            // Events posted to the #HV doorbell meanwhile were deferred
+            hv_doorbell_poll();
+        }
+    }
+}
@@ -4,6 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod apic;
 pub mod control_regs;
 pub mod cpuid;
 pub mod efer;
@@ -11,6 +12,8 @@ pub mod extable;
 pub mod features;
 pub mod gdt;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
 pub mod msr;
 pub mod percpu;
 pub mod smp;
@@ -4,8 +4,12 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use super::percpu::this_cpu;
+use crate::address::VirtAddr;
+use crate::error::SvsmError;
 use core::arch::asm;
 
+pub const MSR_APIC_BASE: u32 = 0x1b;
 pub const EFER: u32 = 0xC000_0080;
 pub const SEV_STATUS: u32 = 0xC001_0131;
 pub const SEV_GHCB: u32 = 0xC001_0130;
@@ -37,3 +41,63 @@ pub fn write_msr(msr: u32, val: u64) {
              options(att_syntax));
     }
 }
+
+// MSRs intercepted by the hypervisor under SEV-ES/SNP, like the ones of
+// the local APIC, raise a #VC when accessed directly. These accessors go
+// through the GHCB of the current CPU instead.
+
+/// Reads `msr` through a GHCB MSR exit. Needs the GHCB of the current CPU.
+pub fn read_msr_ghcb(msr: u32) -> Result<u64, SvsmError> {
+    this_cpu().ghcb().rdmsr(msr)
+}
+
+/// Writes `msr` through a GHCB MSR exit. Needs the GHCB of the current CPU.
+pub fn write_msr_ghcb(msr: u32, val: u64) -> Result<(), SvsmError> {
+    this_cpu().ghcb().wrmsr(msr, val)
+}
+
+/// Returns the raw value of the GHCB MSR
+pub fn read_ghcb_msr() -> u64 {
+    read_msr(SEV_GHCB)
+}
+
+/// Sets the GHCB MSR, either to a GHCB MSR protocol request or to the
+/// physical address of the GHCB.
+pub fn write_ghcb_msr(val: u64) {
+    write_msr(SEV_GHCB, val)
+}
+
+/// Returns the raw value of the SEV_STATUS MSR. It is never intercepted.
+pub fn read_sev_status() -> u64 {
+    read_msr(SEV_STATUS)
+}
+
+pub fn read_gs_base() -> VirtAddr {
+    VirtAddr::from(read_msr(MSR_GS_BASE))
+}
+
+pub fn write_gs_base(addr: VirtAddr) {
+    write_msr(MSR_GS_BASE, u64::from(addr))
+}
+
+/// Returns the APIC base MSR, which is emulated by the hypervisor
+pub fn read_apic_base() -> Result<u64, SvsmError> {
+    read_msr_ghcb(MSR_APIC_BASE)
+}
+
+pub fn write_apic_base(val: u64) -> Result<(), SvsmError> {
+    write_msr_ghcb(MSR_APIC_BASE, val)
+}
+
+pub fn rdtsc() -> u64 {
+    let eax: u32;
+    let edx: u32;
+
+    unsafe {
+        asm!("rdtsc",
+             out("eax") eax,
+             out("edx") edx,
+             options(att_syntax, nomem, nostack));
+    }
+    (eax as u64) | (edx as u64) << 32
+}
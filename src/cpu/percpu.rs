@@ -7,29 +7,35 @@
 extern crate alloc;
 
 use super::gdt::load_tss;
-use super::tss::{X86Tss, IST_DF};
+use super::tss::{X86Tss, IST_DF, IST_HV, IST_VC};
 use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::cpu::tlb::flush_address_local;
 use crate::cpu::tss::TSS_LIMIT;
 use crate::cpu::vmsa::init_guest_vmsa;
+use crate::debug::softlockup::SoftLockupState;
+use crate::deferred::DeferredWork;
 use crate::error::SvsmError;
-use crate::locking::{LockGuard, RWLock, SpinLock};
+use crate::locking::{IrqLockGuard, LockGuard, RWLock, SpinLock};
 use crate::mm::alloc::{allocate_page, allocate_zeroed_page};
 use crate::mm::pagetable::{get_init_pgtable_locked, PageTable, PageTableRef};
-use crate::mm::stack::{allocate_stack_addr, stack_base_pointer};
+use crate::mm::quota::{GuestQuota, MemQuota, QuotaCharge};
+use crate::mm::stack::{allocate_stack_addr, allocate_stack_pages, stack_base_pointer};
 use crate::mm::virtualrange::VirtualRange;
 use crate::mm::{
-    virt_to_phys, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_TEMP_BASE_2M,
+    virt_to_phys, STACK_PAGES, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_TEMP_BASE_2M,
     SVSM_PERCPU_TEMP_BASE_4K, SVSM_PERCPU_TEMP_END_2M, SVSM_PERCPU_TEMP_END_4K,
-    SVSM_PERCPU_VMSA_BASE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE,
+    SVSM_PERCPU_VMSA_BASE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE, SVSM_STACK_IST_HV_BASE,
+    SVSM_STACK_IST_VC_BASE,
 };
-use crate::sev::ghcb::GHCB;
-use crate::sev::utils::RMPFlags;
+use crate::sev::ghcb::{GhcbPage, GHCB};
+use crate::sev::hv_doorbell::{hv_doorbell_init, restricted_injection, HVDoorbell};
+use crate::sev::rmpadjust::RMPFlags;
 use crate::sev::vmsa::{allocate_new_vmsa, VMSASegment, VMSA};
 use crate::types::{PAGE_SHIFT, PAGE_SHIFT_2M, PAGE_SIZE, PAGE_SIZE_2M, SVSM_TR_FLAGS, SVSM_TSS};
 use alloc::vec::Vec;
 use core::cell::SyncUnsafeCell;
-use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 struct PerCpuInfo {
     apic_id: u32,
@@ -80,6 +86,20 @@ impl PerCpuAreas {
             unsafe { ptr.as_ref().unwrap() }
         })
     }
+
+    /// Returns the area following the one of `apic_id`, wrapping around at
+    /// the end. Fails if there is no other area.
+    pub fn next(&self, apic_id: u32) -> Option<&'static PerCpu> {
+        let ptr = unsafe { self.areas.get().as_ref().unwrap() };
+        let index = ptr.iter().position(|info| info.apic_id == apic_id)?;
+        let info = &ptr[(index + 1) % ptr.len()];
+        if info.apic_id == apic_id {
+            return None;
+        }
+
+        let ptr = info.addr.as_ptr::<PerCpu>();
+        unsafe { ptr.as_ref() }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -106,12 +126,16 @@ impl VmsaRef {
 
 struct IstStacks {
     double_fault_stack: Option<VirtAddr>,
+    vc_stack: Option<VirtAddr>,
+    hv_stack: Option<VirtAddr>,
 }
 
 impl IstStacks {
     const fn new() -> Self {
         IstStacks {
             double_fault_stack: None,
+            vc_stack: None,
+            hv_stack: None,
         }
     }
 }
@@ -166,17 +190,64 @@ impl GuestVmsaRef {
     }
 }
 
+/// Describes what [`PerCpu::setup_with()`] sets up for a CPU. IST stacks are
+/// always allocated, as the IDT shared by all CPUs refers to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PerCpuConfig {
+    /// Number of pages mapped for the init stack, at most `STACK_PAGES`
+    pub init_stack_pages: usize,
+    /// Whether the CPU gets a GHCB. CPUs without one can not use the
+    /// serial console or any other hypervisor service.
+    pub ghcb: bool,
+    /// Whether the CPU serves protocol requests from a guest VMPL. Other
+    /// CPUs only do background work.
+    pub requests: bool,
+}
+
+impl PerCpuConfig {
+    /// Configuration of CPUs serving a guest
+    pub const fn full() -> Self {
+        PerCpuConfig {
+            init_stack_pages: STACK_PAGES,
+            ghcb: true,
+            requests: true,
+        }
+    }
+
+    /// Configuration of helper CPUs dedicated to background work
+    pub const fn helper() -> Self {
+        PerCpuConfig {
+            init_stack_pages: 2,
+            ghcb: true,
+            requests: false,
+        }
+    }
+}
+
+impl Default for PerCpuConfig {
+    fn default() -> Self {
+        Self::full()
+    }
+}
+
 pub struct PerCpu {
     online: AtomicBool,
+    config: PerCpuConfig,
+    deferred: AtomicU32,
+    softlockup: SoftLockupState,
     apic_id: u32,
     pgtbl: SpinLock<PageTableRef>,
-    ghcb: *mut GHCB,
+    ghcb: SpinLock<GhcbPage>,
+    // Whether the GHCB is set up, readable without taking its lock
+    ghcb_present: bool,
+    hv_doorbell: Option<&'static HVDoorbell>,
     init_stack: Option<VirtAddr>,
     ist: IstStacks,
     tss: X86Tss,
     svsm_vmsa: Option<VmsaRef>,
     guest_vmsa: SpinLock<GuestVmsaRef>,
     reset_ip: u64,
+    quota: GuestQuota,
 
     /// Address allocator for per-cpu 4k temporary mappings
     pub vrange_4k: VirtualRange,
@@ -188,15 +259,21 @@ impl PerCpu {
     pub const fn new() -> Self {
         PerCpu {
             online: AtomicBool::new(false),
+            config: PerCpuConfig::full(),
+            deferred: AtomicU32::new(0),
+            softlockup: SoftLockupState::new(),
             apic_id: 0,
             pgtbl: SpinLock::<PageTableRef>::new(PageTableRef::unset()),
-            ghcb: ptr::null_mut(),
+            ghcb: SpinLock::new(GhcbPage::null()),
+            ghcb_present: false,
+            hv_doorbell: None,
             init_stack: None,
             ist: IstStacks::new(),
             tss: X86Tss::new(),
             svsm_vmsa: None,
             guest_vmsa: SpinLock::new(GuestVmsaRef::new()),
             reset_ip: 0xffff_fff0u64,
+            quota: GuestQuota::new(),
             vrange_4k: VirtualRange::new(),
             vrange_2m: VirtualRange::new(),
         }
@@ -221,6 +298,18 @@ impl PerCpu {
         self.online.load(Ordering::Acquire)
     }
 
+    pub fn defer_work(&self, work: DeferredWork) {
+        self.deferred.fetch_or(work.bits(), Ordering::Relaxed);
+    }
+
+    pub fn take_deferred_work(&self) -> DeferredWork {
+        DeferredWork::from_bits_truncate(self.deferred.swap(0, Ordering::Relaxed))
+    }
+
+    pub fn softlockup(&self) -> &SoftLockupState {
+        &self.softlockup
+    }
+
     pub const fn get_apic_id(&self) -> u32 {
         self.apic_id
     }
@@ -238,7 +327,7 @@ impl PerCpu {
 
     fn allocate_init_stack(&mut self) -> Result<(), SvsmError> {
         let addr = VirtAddr::from(SVSM_STACKS_INIT_TASK);
-        allocate_stack_addr(addr, &mut self.get_pgtable())
+        allocate_stack_pages(addr, self.config.init_stack_pages, &mut self.get_pgtable())
             .expect("Failed to allocate per-cpu init stack");
         self.init_stack = Some(addr);
         Ok(())
@@ -250,21 +339,49 @@ impl PerCpu {
             .expect("Failed to allocate percpu double-fault stack");
 
         self.ist.double_fault_stack = Some(addr);
+
+        let addr = VirtAddr::from(SVSM_STACK_IST_VC_BASE);
+        allocate_stack_addr(addr, &mut self.get_pgtable())
+            .expect("Failed to allocate percpu #VC stack");
+        self.ist.vc_stack = Some(addr);
+
+        let addr = VirtAddr::from(SVSM_STACK_IST_HV_BASE);
+        allocate_stack_addr(addr, &mut self.get_pgtable())
+            .expect("Failed to allocate percpu #HV stack");
+        self.ist.hv_stack = Some(addr);
+
         Ok(())
     }
 
+    /// Resource limits for the guest context served by this CPU
+    pub fn guest_quota(&self) -> &GuestQuota {
+        &self.quota
+    }
+
     pub fn get_pgtable(&self) -> LockGuard<PageTableRef> {
         self.pgtbl.lock()
     }
 
+    /// For use in exception context, where the page-table lock might be held
+    /// by the interrupted code
+    pub fn try_get_pgtable(&self) -> Option<LockGuard<PageTableRef>> {
+        self.pgtbl.try_lock().ok()
+    }
+
     pub fn setup_ghcb(&mut self) -> Result<(), SvsmError> {
         let ghcb_page = allocate_page().expect("Failed to allocate GHCB page");
-        self.ghcb = ghcb_page.as_mut_ptr::<GHCB>();
-        unsafe { (*self.ghcb).init() }
+        // SAFETY: the page was just allocated for the GHCB
+        *self.ghcb.lock() = unsafe { GhcbPage::new(ghcb_page.as_mut_ptr::<GHCB>()) };
+        self.ghcb_present = true;
+        self.ghcb().init()
+    }
+
+    pub fn config(&self) -> &PerCpuConfig {
+        &self.config
     }
 
     pub fn register_ghcb(&self) -> Result<(), SvsmError> {
-        unsafe { self.ghcb.as_ref().unwrap().register() }
+        self.ghcb().register()
     }
 
     pub fn get_top_of_stack(&self) -> VirtAddr {
@@ -273,6 +390,8 @@ impl PerCpu {
 
     fn setup_tss(&mut self) {
         self.tss.ist_stacks[IST_DF] = stack_base_pointer(self.ist.double_fault_stack.unwrap());
+        self.tss.ist_stacks[IST_VC] = stack_base_pointer(self.ist.vc_stack.unwrap());
+        self.tss.ist_stacks[IST_HV] = stack_base_pointer(self.ist.hv_stack.unwrap());
     }
 
     pub fn map_self(&mut self) -> Result<(), SvsmError> {
@@ -285,6 +404,12 @@ impl PerCpu {
     }
 
     pub fn setup(&mut self) -> Result<(), SvsmError> {
+        self.setup_with(&PerCpuConfig::full())
+    }
+
+    pub fn setup_with(&mut self, config: &PerCpuConfig) -> Result<(), SvsmError> {
+        self.config = *config;
+
         // Allocate page-table
         self.allocate_page_table()?;
 
@@ -292,7 +417,9 @@ impl PerCpu {
         self.map_self()?;
 
         // Setup GHCB
-        self.setup_ghcb()?;
+        if config.ghcb {
+            self.setup_ghcb()?;
+        }
 
         // Allocate per-cpu init stack
         self.allocate_init_stack()?;
@@ -310,8 +437,22 @@ impl PerCpu {
     }
 
     // Setup code which needs to run on the target CPU
-    pub fn setup_on_cpu(&self) -> Result<(), SvsmError> {
-        self.register_ghcb()
+    pub fn setup_on_cpu(&mut self) -> Result<(), SvsmError> {
+        if !self.has_ghcb() {
+            return Ok(());
+        }
+        self.register_ghcb()?;
+
+        // With Restricted Injection, interrupts only arrive via the doorbell
+        if restricted_injection() {
+            let doorbell = hv_doorbell_init(&mut self.ghcb())?;
+            self.hv_doorbell = Some(doorbell);
+        }
+        Ok(())
+    }
+
+    pub fn hv_doorbell(&self) -> Option<&'static HVDoorbell> {
+        self.hv_doorbell
     }
 
     pub fn load_pgtable(&mut self) {
@@ -328,19 +469,32 @@ impl PerCpu {
     }
 
     pub fn shutdown(&mut self) -> Result<(), SvsmError> {
-        if self.ghcb.is_null() {
+        if !self.has_ghcb() {
             return Ok(());
         }
 
-        unsafe { (*self.ghcb).shutdown() }
+        self.ghcb().shutdown()
     }
 
     pub fn set_reset_ip(&mut self, reset_ip: u64) {
         self.reset_ip = reset_ip;
     }
 
-    pub fn ghcb(&mut self) -> &'static mut GHCB {
-        unsafe { self.ghcb.as_mut().unwrap() }
+    pub fn has_ghcb(&self) -> bool {
+        self.ghcb_present
+    }
+
+    /// Takes the GHCB of this CPU with interrupts disabled, so interrupt
+    /// handlers can use it as well. Console output written while holding
+    /// the guard, including panic messages, is dropped.
+    pub fn ghcb(&self) -> IrqLockGuard<GhcbPage> {
+        self.ghcb.lock_irqsave()
+    }
+
+    /// For use in exception context, where the interrupted code might hold
+    /// the GHCB
+    pub fn try_ghcb(&self) -> Option<IrqLockGuard<GhcbPage>> {
+        self.ghcb.try_lock_irqsave().ok()
     }
 
     pub fn alloc_svsm_vmsa(&mut self) -> Result<(), SvsmError> {
@@ -372,8 +526,9 @@ impl PerCpu {
 
     pub fn unmap_guest_vmsa(&self) {
         assert!(self.apic_id == this_cpu().get_apic_id());
-        self.get_pgtable()
-            .unmap_4k(VirtAddr::from(SVSM_PERCPU_VMSA_BASE));
+        let vaddr = VirtAddr::from(SVSM_PERCPU_VMSA_BASE);
+        self.get_pgtable().unmap_4k(vaddr);
+        flush_address_local(vaddr);
     }
 
     pub fn map_guest_vmsa(&self, paddr: PhysAddr) -> Result<(), SvsmError> {
@@ -444,8 +599,10 @@ impl PerCpu {
     }
 
     pub fn unmap_caa(&self) {
-        self.get_pgtable()
-            .unmap_4k(VirtAddr::from(SVSM_PERCPU_CAA_BASE));
+        assert!(self.apic_id == this_cpu().get_apic_id());
+        let vaddr = VirtAddr::from(SVSM_PERCPU_CAA_BASE);
+        self.get_pgtable().unmap_4k(vaddr);
+        flush_address_local(vaddr);
     }
 
     pub fn map_guest_caa(&self, paddr: PhysAddr) -> Result<(), SvsmError> {
@@ -519,6 +676,8 @@ pub struct VmsaRegistryEntry {
     pub apic_id: u32,
     pub guest_owned: bool,
     pub in_use: bool,
+    // Deleted, but the page is not scrubbed and handed back yet
+    pub scrubbing: bool,
 }
 
 impl VmsaRegistryEntry {
@@ -528,7 +687,19 @@ impl VmsaRegistryEntry {
             apic_id,
             guest_owned,
             in_use: false,
+            scrubbing: false,
+        }
+    }
+
+    // Entries created by the guest are charged to the guest context of
+    // their CPU
+    fn quota(&self) -> Option<&'static MemQuota> {
+        if !self.guest_owned {
+            return None;
         }
+        PERCPU_AREAS
+            .get(self.apic_id)
+            .map(|cpu| &cpu.guest_quota().heap)
     }
 }
 
@@ -564,7 +735,18 @@ impl PerCpuVmsas {
             return Err(SvsmError::InvalidAddress);
         }
 
-        guard.push(VmsaRegistryEntry::new(paddr, apic_id, guest_owned));
+        let entry = VmsaRegistryEntry::new(paddr, apic_id, guest_owned);
+        let charge = entry
+            .quota()
+            .map(|quota| QuotaCharge::new(quota, size_of::<VmsaRegistryEntry>()))
+            .transpose()?;
+        guard.try_reserve(1).map_err(|_| SvsmError::Mem)?;
+        guard.push(entry);
+
+        // Returned by remove()
+        if let Some(charge) = charge {
+            charge.keep();
+        }
         Ok(())
     }
 
@@ -572,33 +754,75 @@ impl PerCpuVmsas {
         self.vmsas
             .lock_write()
             .iter_mut()
-            .find(|vmsa| vmsa.paddr == paddr && !vmsa.in_use)
+            .find(|vmsa| vmsa.paddr == paddr && !vmsa.in_use && !vmsa.scrubbing)
             .map(|vmsa| {
                 vmsa.in_use = true;
                 vmsa.apic_id
             })
     }
 
-    pub fn unregister(&self, paddr: PhysAddr, in_use: bool) -> Result<VmsaRegistryEntry, u64> {
-        let mut guard = self.vmsas.lock_write();
-        let index = guard
+    // Takes an in-use VMSA away from its CPU
+    fn release_in_use(vmsas: &[VmsaRegistryEntry], paddr: PhysAddr) -> Result<usize, u64> {
+        let index = vmsas
             .iter()
-            .position(|vmsa| vmsa.paddr == paddr && vmsa.in_use == in_use)
+            .position(|vmsa| vmsa.paddr == paddr && vmsa.in_use)
             .ok_or(0u64)?;
 
-        if in_use {
-            let vmsa = &guard[index];
+        let vmsa = &vmsas[index];
+        if vmsa.apic_id == 0 {
+            return Err(0);
+        }
+
+        let target_cpu = PERCPU_AREAS
+            .get(vmsa.apic_id)
+            .expect("Invalid APIC-ID in VMSA registry");
+        target_cpu.clear_guest_vmsa_if_match(paddr);
 
-            if vmsa.apic_id == 0 {
-                return Err(0);
-            }
+        Ok(index)
+    }
 
-            let target_cpu = PERCPU_AREAS
-                .get(vmsa.apic_id)
-                .expect("Invalid APIC-ID in VMSA registry");
-            target_cpu.clear_guest_vmsa_if_match(paddr);
+    fn remove(vmsas: &mut Vec<VmsaRegistryEntry>, index: usize) -> VmsaRegistryEntry {
+        let entry = vmsas.swap_remove(index);
+        if let Some(quota) = entry.quota() {
+            quota.uncharge(size_of::<VmsaRegistryEntry>());
         }
+        entry
+    }
+
+    pub fn unregister(&self, paddr: PhysAddr, in_use: bool) -> Result<VmsaRegistryEntry, u64> {
+        let mut guard = self.vmsas.lock_write();
+        let index = if in_use {
+            Self::release_in_use(&guard, paddr)?
+        } else {
+            guard
+                .iter()
+                .position(|vmsa| vmsa.paddr == paddr && !vmsa.in_use && !vmsa.scrubbing)
+                .ok_or(0u64)?
+        };
+
+        Ok(Self::remove(&mut guard, index))
+    }
+
+    /// Takes an in-use VMSA away from its CPU, but keeps the page registered
+    /// until [`Self::scrub_done`] is called for it. Until then the page can
+    /// neither be registered again nor used by the guest.
+    pub fn start_scrub(&self, paddr: PhysAddr) -> Result<(), u64> {
+        let mut guard = self.vmsas.lock_write();
+        let index = Self::release_in_use(&guard, paddr)?;
+
+        let vmsa = &mut guard[index];
+        vmsa.in_use = false;
+        vmsa.scrubbing = true;
+        Ok(())
+    }
+
+    /// Drops the entry of a deleted VMSA once its page was scrubbed
+    pub fn scrub_done(&self, paddr: PhysAddr) -> Option<VmsaRegistryEntry> {
+        let mut guard = self.vmsas.lock_write();
+        let index = guard
+            .iter()
+            .position(|vmsa| vmsa.paddr == paddr && vmsa.scrubbing)?;
 
-        Ok(guard.swap_remove(index))
+        Some(Self::remove(&mut guard, index))
     }
 }
@@ -7,11 +7,15 @@
 extern crate alloc;
 
 use crate::acpi::tables::ACPICPUInfo;
-use crate::cpu::percpu::{this_cpu_mut, PerCpu};
+use crate::cpu::apic::{apic_init, apic_owned};
+use crate::cpu::percpu::{this_cpu_mut, PerCpu, PerCpuConfig};
 use crate::cpu::vmsa::init_svsm_vmsa;
-use crate::requests::request_loop;
+use crate::requests::{background_loop, request_loop};
+use crate::sev::{hv_features, HvFeatures};
 
-fn start_cpu(apic_id: u32) {
+/// Brings up the CPU with the given APIC ID, setting up only what `config`
+/// asks for. Returns once the CPU is online.
+pub fn start_cpu(apic_id: u32, config: &PerCpuConfig) {
     unsafe {
         let start_rip: u64 = (start_ap as *const u8) as u64;
         let percpu = PerCpu::alloc(apic_id)
@@ -19,7 +23,9 @@ fn start_cpu(apic_id: u32) {
             .as_mut()
             .unwrap();
 
-        percpu.setup().expect("Failed to setup AP per-cpu area");
+        percpu
+            .setup_with(config)
+            .expect("Failed to setup AP per-cpu area");
         percpu
             .alloc_svsm_vmsa()
             .expect("Failed to allocate AP SVSM VMSA");
@@ -32,10 +38,10 @@ fn start_cpu(apic_id: u32) {
         let vmsa_pa = vmsa.paddr;
 
         vmsa.vmsa().enable();
-        this_cpu_mut()
+        let result = this_cpu_mut()
             .ghcb()
-            .ap_create(vmsa_pa, apic_id.into(), 0, sev_features)
-            .expect("Failed to launch secondary CPU");
+            .ap_create(vmsa_pa, apic_id.into(), 0, sev_features);
+        result.expect("Failed to launch secondary CPU");
         loop {
             if percpu.is_online() {
                 break;
@@ -45,10 +51,15 @@ fn start_cpu(apic_id: u32) {
 }
 
 pub fn start_secondary_cpus(cpus: &[ACPICPUInfo]) {
+    if !hv_features().contains(HvFeatures::AP_CREATION) {
+        log::warn!("Hypervisor can not create APs, running on the BSP only");
+        return;
+    }
+
     let mut count: usize = 0;
     for c in cpus.iter().filter(|c| c.apic_id != 0 && c.enabled) {
         log::info!("Launching AP with APIC-ID {}", c.apic_id);
-        start_cpu(c.apic_id);
+        start_cpu(c.apic_id, &PerCpuConfig::full());
         count += 1;
     }
     log::info!("Brough {} AP(s) online", count);
@@ -60,13 +71,24 @@ fn start_ap() {
         .setup_on_cpu()
         .expect("setup_on_cpu() failed");
 
-    // Send a life-sign
-    log::info!("AP with APIC-ID {} is online", this_cpu_mut().get_apic_id());
+    // Send a life-sign, if this CPU can reach the console
+    if this_cpu_mut().has_ghcb() {
+        log::info!("AP with APIC-ID {} is online", this_cpu_mut().get_apic_id());
+
+        if apic_owned() {
+            if let Err(e) = apic_init() {
+                log::warn!("Failed to enable x2APIC: {:?}", e);
+            }
+        }
+    }
 
     // Set CPU online so that BSP can proceed
     this_cpu_mut().set_online();
 
-    // Loop for now
+    if !this_cpu_mut().config().requests {
+        background_loop();
+    }
+
     request_loop();
 
     panic!("Returned from request_loop!");
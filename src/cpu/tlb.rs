@@ -5,8 +5,13 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, VirtAddr};
+use crate::cpu::control_regs::{read_cr3, read_cr4, write_cr3, write_cr4, CR4Flags};
+use crate::types::PAGE_SIZE;
 use core::arch::asm;
 
+// Ranges larger than this are flushed as a whole
+const FLUSH_RANGE_MAX_PAGES: usize = 32;
+
 const INVLPGB_VALID_VA: u64 = 1u64 << 0;
 //const INVLPGB_VALID_PCID: u64 = 1u64 << 1;
 const INVLPGB_VALID_ASID: u64 = 1u64 << 2;
@@ -62,3 +67,53 @@ pub fn flush_address_sync(va: VirtAddr) {
     flush_address(va);
     do_tlbsync();
 }
+
+/// Flushes the TLB entries of all pages in `[start, end)` on all CPUs and
+/// waits for completion. Large ranges flush the whole TLB instead.
+pub fn flush_range(start: VirtAddr, end: VirtAddr) {
+    let start = start.page_align();
+    let end = end.page_align_up();
+
+    if end <= start {
+        return;
+    }
+
+    if (end - start) / PAGE_SIZE > FLUSH_RANGE_MAX_PAGES {
+        flush_tlb_global_sync();
+        return;
+    }
+
+    let mut va = start;
+    while va < end {
+        flush_address(va);
+        va = va.offset(PAGE_SIZE);
+    }
+    do_tlbsync();
+}
+
+/// Flushes all TLB entries, including global ones, on all CPUs and waits
+/// for completion.
+pub fn flush_all() {
+    flush_tlb_global_sync();
+}
+
+/// Flushes the TLB entry of `va` on the current CPU only
+pub fn flush_address_local(va: VirtAddr) {
+    unsafe {
+        asm!("invlpg (%rax)",
+             in("rax") va.bits(),
+             options(att_syntax));
+    }
+}
+
+/// Flushes all TLB entries on the current CPU only. Reloading CR3 keeps
+/// global entries, so toggle CR4.PGE as well when it is enabled.
+pub fn flush_all_local() {
+    let cr4 = read_cr4();
+    if cr4.contains(CR4Flags::PGE) {
+        write_cr4(cr4 - CR4Flags::PGE);
+        write_cr4(cr4);
+    } else {
+        write_cr3(read_cr3());
+    }
+}
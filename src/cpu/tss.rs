@@ -9,6 +9,8 @@ use crate::address::VirtAddr;
 // IST offsets
 pub const _IST_INVALID: usize = 0;
 pub const IST_DF: usize = 1;
+pub const IST_VC: usize = 2;
+pub const IST_HV: usize = 3;
 
 #[repr(C, packed)]
 pub struct X86Tss {
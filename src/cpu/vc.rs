@@ -4,17 +4,282 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::idt::X86Regs;
+use super::idt::{dump_regs, X86Regs};
+use crate::cpu::cpuid::cpuid;
 use crate::cpu::extable::handle_exception_table;
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::locking::IrqLockGuard;
+use crate::sev::ghcb::{GHCBIOSize, GhcbError, GhcbPage};
+use core::fmt;
 
+// SVM exit codes reported as #VC error code
+const SVM_EXIT_CPUID: usize = 0x72;
+const SVM_EXIT_IOIO: usize = 0x7b;
+const SVM_EXIT_MSR: usize = 0x7c;
+
+// Longest x86 instruction
+const MAX_INSN_SIZE: usize = 15;
+
+#[derive(Clone, Copy, Debug)]
+pub enum VcError {
+    // No emulation available for this exit code
+    UnsupportedExit(usize),
+    // The instruction does not match the exit code or is not supported
+    DecodeFailed,
+    // Emulation requires a GHCB, but none is set up yet
+    NoGhcb,
+}
+
+impl From<VcError> for SvsmError {
+    fn from(e: VcError) -> Self {
+        Self::Vc(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IoSize {
+    Size8,
+    Size16,
+    Size32,
+}
+
+impl IoSize {
+    fn mask(&self) -> usize {
+        match self {
+            Self::Size8 => 0xff,
+            Self::Size16 => 0xffff,
+            Self::Size32 => 0xffff_ffff,
+        }
+    }
+}
+
+impl From<IoSize> for GHCBIOSize {
+    fn from(size: IoSize) -> Self {
+        match size {
+            IoSize::Size8 => Self::Size8,
+            IoSize::Size16 => Self::Size16,
+            IoSize::Size32 => Self::Size32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IoPort {
+    Imm(u8),
+    Dx,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IoInsn {
+    is_in: bool,
+    port: IoPort,
+    size: IoSize,
+    len: usize,
+}
+
+// Decodes the non-string forms of IN and OUT. String and REP forms are not
+// supported.
+fn decode_ioio(bytes: &[u8]) -> Result<IoInsn, VcError> {
+    let mut opsize_override = false;
+    let mut idx = 0;
+
+    // Skip prefixes
+    loop {
+        match bytes.get(idx) {
+            Some(0x66) => opsize_override = true,
+            Some(0x40..=0x4f) | Some(0x67) => {}
+            Some(_) => break,
+            None => return Err(VcError::DecodeFailed),
+        }
+        idx += 1;
+    }
+
+    let opcode = bytes[idx];
+    let wide = match opsize_override {
+        true => IoSize::Size16,
+        false => IoSize::Size32,
+    };
+
+    let (is_in, size) = match opcode {
+        0xe4 | 0xec => (true, IoSize::Size8),
+        0xe5 | 0xed => (true, wide),
+        0xe6 | 0xee => (false, IoSize::Size8),
+        0xe7 | 0xef => (false, wide),
+        _ => return Err(VcError::DecodeFailed),
+    };
+
+    let (port, len) = match opcode {
+        0xe4..=0xe7 => {
+            let imm = *bytes.get(idx + 1).ok_or(VcError::DecodeFailed)?;
+            (IoPort::Imm(imm), idx + 2)
+        }
+        _ => (IoPort::Dx, idx + 1),
+    };
+
+    Ok(IoInsn {
+        is_in,
+        port,
+        size,
+        len,
+    })
+}
+
+fn insn_bytes(regs: &X86Regs) -> [u8; MAX_INSN_SIZE] {
+    let rip = regs.rip as *const [u8; MAX_INSN_SIZE];
+    // The #VC was raised by the SVSM itself, so RIP points to mapped code.
+    unsafe { rip.read_unaligned() }
+}
+
+// The #VC might have been raised while the GHCB was in use, which must not
+// be disturbed
+fn vc_ghcb() -> Result<IrqLockGuard<'static, GhcbPage>, SvsmError> {
+    let cpu = this_cpu();
+    if !cpu.has_ghcb() {
+        return Err(VcError::NoGhcb.into());
+    }
+    Ok(cpu.try_ghcb().ok_or(GhcbError::Busy)?)
+}
+
+fn handle_cpuid(regs: &mut X86Regs) -> Result<(), SvsmError> {
+    let bytes = insn_bytes(regs);
+    if bytes[..2] != [0x0f, 0xa2] {
+        return Err(VcError::DecodeFailed.into());
+    }
+
+    let leaf = regs.rax as u32;
+    let subleaf = regs.rcx as u32;
+
+    // Leaves neither in the CPUID page nor safe to take from the hypervisor
+    // read as zero.
+    let res = cpuid(leaf, subleaf).unwrap_or_default();
+
+    regs.rax = res.eax as usize;
+    regs.rbx = res.ebx as usize;
+    regs.rcx = res.ecx as usize;
+    regs.rdx = res.edx as usize;
+    regs.rip += 2;
+
+    Ok(())
+}
+
+fn handle_msr(regs: &mut X86Regs) -> Result<(), SvsmError> {
+    let bytes = insn_bytes(regs);
+    let msr = regs.rcx as u32;
+
+    match bytes[..2] {
+        // RDMSR
+        [0x0f, 0x32] => {
+            let value = vc_ghcb()?.rdmsr(msr)?;
+            regs.rax = (value & 0xffff_ffff) as usize;
+            regs.rdx = (value >> 32) as usize;
+        }
+        // WRMSR
+        [0x0f, 0x30] => {
+            let value = (regs.rdx as u64 & 0xffff_ffff) << 32 | (regs.rax as u64 & 0xffff_ffff);
+            vc_ghcb()?.wrmsr(msr, value)?;
+        }
+        _ => return Err(VcError::DecodeFailed.into()),
+    }
+
+    regs.rip += 2;
+
+    Ok(())
+}
+
+fn handle_ioio(regs: &mut X86Regs) -> Result<(), SvsmError> {
+    let insn = decode_ioio(&insn_bytes(regs))?;
+    let port = match insn.port {
+        IoPort::Imm(imm) => imm as u16,
+        IoPort::Dx => regs.rdx as u16,
+    };
+    let mut ghcb = vc_ghcb()?;
+
+    if insn.is_in {
+        let value = ghcb.ioio_in(port, insn.size.into())? as usize & insn.size.mask();
+        // 32-bit operations clear the upper half of RAX
+        regs.rax = match insn.size {
+            IoSize::Size32 => value,
+            _ => (regs.rax & !insn.size.mask()) | value,
+        };
+    } else {
+        let value = regs.rax & insn.size.mask();
+        ghcb.ioio_out(port, insn.size.into(), value as u64)?;
+    }
+
+    regs.rip += insn.len;
+
+    Ok(())
+}
+
+fn handle_vc(regs: &mut X86Regs) -> Result<(), SvsmError> {
+    match regs.error_code {
+        SVM_EXIT_CPUID => handle_cpuid(regs),
+        SVM_EXIT_MSR => handle_msr(regs),
+        SVM_EXIT_IOIO => handle_ioio(regs),
+        code => Err(VcError::UnsupportedExit(code).into()),
+    }
+}
+
+struct InsnDump([u8; MAX_INSN_SIZE]);
+
+impl fmt::Display for InsnDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x} ", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emulates CPUID, RDMSR/WRMSR and IN/OUT instructions which were
+/// intercepted by the hypervisor. MSR and I/O accesses go through the GHCB of
+/// the current CPU, they fail if it is in use by the interrupted code.
 pub fn handle_vc_exception(regs: &mut X86Regs) {
     let err = regs.error_code;
     let rip = regs.rip;
 
-    if !handle_exception_table(regs) {
-        panic!(
-            "Unhandled #VC exception RIP {:#018x} error code: {:#018x}",
-            rip, err
-        );
+    if let Err(e) = handle_vc(regs) {
+        if !handle_exception_table(regs) {
+            dump_regs(regs);
+            panic!(
+                "Unhandled #VC exception RIP {:#018x} error code: {:#018x} insn: {}: {:?}",
+                rip,
+                err,
+                InsnDump(insn_bytes(regs)),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_ioio() {
+        // in %dx, %al
+        let insn = decode_ioio(&[0xec]).unwrap();
+        assert!(insn.is_in);
+        assert_eq!(insn.port, IoPort::Dx);
+        assert_eq!(insn.size, IoSize::Size8);
+        assert_eq!(insn.len, 1);
+
+        // out %ax, $0x80
+        let insn = decode_ioio(&[0x66, 0xe7, 0x80]).unwrap();
+        assert!(!insn.is_in);
+        assert_eq!(insn.port, IoPort::Imm(0x80));
+        assert_eq!(insn.size, IoSize::Size16);
+        assert_eq!(insn.len, 3);
+
+        // in %dx, %eax
+        let insn = decode_ioio(&[0xed]).unwrap();
+        assert_eq!(insn.size, IoSize::Size32);
+
+        // rep insb is not supported
+        assert!(decode_ioio(&[0xf3, 0x6c]).is_err());
+        // Truncated immediate
+        assert!(decode_ioio(&[0xe4]).is_err());
     }
 }
@@ -12,7 +12,7 @@ use super::control_regs::{read_cr0, read_cr3, read_cr4};
 use super::efer::read_efer;
 use super::gdt::gdt_base_limit;
 use super::idt::idt_base_limit;
-use super::msr::read_msr;
+use super::msr::read_sev_status;
 
 fn svsm_code_segment() -> VMSASegment {
     VMSASegment {
@@ -77,7 +77,7 @@ pub fn init_svsm_vmsa(vmsa: &mut VMSA) {
     vmsa.x87_fcw = 0x0040;
     vmsa.vmpl = 0;
 
-    vmsa.sev_features = read_msr(0xc0010131) >> 2;
+    vmsa.sev_features = read_sev_status() >> 2;
 }
 
 fn real_mode_code_segment(rip: u64) -> VMSASegment {
@@ -131,5 +131,5 @@ pub fn init_guest_vmsa(vmsa: *mut VMSA, rip: u64) {
     v.x87_fcw = 0x0040;
 
     v.vmpl = GUEST_VMPL as u8;
-    v.sev_features = read_msr(0xc0010131) >> 2;
+    v.sev_features = read_sev_status() >> 2;
 }
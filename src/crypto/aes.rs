@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use core::ptr;
+
+pub const AES_BLOCK_SIZE: usize = 16;
+pub const AES256_KEY_SIZE: usize = 32;
+
+const AES256_ROUNDS: usize = 14;
+const AES256_KEY_WORDS: usize = AES256_KEY_SIZE / 4;
+const AES256_SCHEDULE_WORDS: usize = 4 * (AES256_ROUNDS + 1);
+
+const RCON: [u8; 7] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40];
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (0x1b & 0u8.wrapping_sub(b >> 7))
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1
+fn gf256_mul(a: u8, b: u8) -> u8 {
+    let mut a = a;
+    let mut p = 0u8;
+
+    for i in 0..8 {
+        p ^= a & 0u8.wrapping_sub((b >> i) & 1);
+        a = xtime(a);
+    }
+
+    p
+}
+
+// The S-box is computed instead of looked up in a table. Table lookups
+// leak the key through the cache to whoever shares it, like the host.
+fn sbox(x: u8) -> u8 {
+    // x^254 is the multiplicative inverse, and 0 for 0
+    let x2 = gf256_mul(x, x);
+    let x3 = gf256_mul(x2, x);
+    let x6 = gf256_mul(x3, x3);
+    let x12 = gf256_mul(x6, x6);
+    let mut x240 = gf256_mul(x12, x3);
+    for _ in 0..4 {
+        x240 = gf256_mul(x240, x240);
+    }
+    let inv = gf256_mul(gf256_mul(x240, x12), x2);
+
+    inv ^ inv.rotate_left(1) ^ inv.rotate_left(2) ^ inv.rotate_left(3) ^ inv.rotate_left(4) ^ 0x63
+}
+
+fn sub_word(w: u32) -> u32 {
+    u32::from_be_bytes(w.to_be_bytes().map(sbox))
+}
+
+fn sub_bytes(state: &mut [u8; AES_BLOCK_SIZE]) {
+    for b in state.iter_mut() {
+        *b = sbox(*b);
+    }
+}
+
+// The state is stored column by column, row r of column c is at 4 * c + r
+fn shift_rows(state: &mut [u8; AES_BLOCK_SIZE]) {
+    let old = *state;
+    for c in 0..4 {
+        for r in 1..4 {
+            state[4 * c + r] = old[4 * ((c + r) % 4) + r];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; AES_BLOCK_SIZE]) {
+    for col in state.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [col[0], col[1], col[2], col[3]];
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        col[0] ^= all ^ xtime(a0 ^ a1);
+        col[1] ^= all ^ xtime(a1 ^ a2);
+        col[2] ^= all ^ xtime(a2 ^ a3);
+        col[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
+
+/// AES-256 block cipher, encryption direction only. Runs in constant time,
+/// AES-NI can not be used while the SVSM does not enable SSE.
+pub struct Aes256 {
+    round_keys: [u32; AES256_SCHEDULE_WORDS],
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; AES256_KEY_SIZE]) -> Self {
+        let mut w = [0u32; AES256_SCHEDULE_WORDS];
+
+        for (i, chunk) in key.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        for i in AES256_KEY_WORDS..AES256_SCHEDULE_WORDS {
+            let mut temp = w[i - 1];
+            if i.is_multiple_of(AES256_KEY_WORDS) {
+                temp =
+                    sub_word(temp.rotate_left(8)) ^ (RCON[i / AES256_KEY_WORDS - 1] as u32) << 24;
+            } else if i % AES256_KEY_WORDS == 4 {
+                temp = sub_word(temp);
+            }
+            w[i] = w[i - AES256_KEY_WORDS] ^ temp;
+        }
+
+        Aes256 { round_keys: w }
+    }
+
+    fn add_round_key(&self, state: &mut [u8; AES_BLOCK_SIZE], round: usize) {
+        let keys = &self.round_keys[4 * round..4 * round + 4];
+        for (col, key) in state.chunks_exact_mut(4).zip(keys) {
+            for (b, k) in col.iter_mut().zip(key.to_be_bytes()) {
+                *b ^= k;
+            }
+        }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; AES_BLOCK_SIZE]) {
+        self.add_round_key(block, 0);
+
+        for round in 1..AES256_ROUNDS {
+            sub_bytes(block);
+            shift_rows(block);
+            mix_columns(block);
+            self.add_round_key(block, round);
+        }
+
+        sub_bytes(block);
+        shift_rows(block);
+        self.add_round_key(block, AES256_ROUNDS);
+    }
+}
+
+impl Drop for Aes256 {
+    fn drop(&mut self) {
+        // Do not leave the key schedule behind in freed memory
+        for w in self.round_keys.iter_mut() {
+            unsafe { ptr::write_volatile(w, 0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sbox() {
+        // FIPS-197, figure 7
+        assert_eq!(sbox(0x00), 0x63);
+        assert_eq!(sbox(0x01), 0x7c);
+        assert_eq!(sbox(0x53), 0xed);
+        assert_eq!(sbox(0xff), 0x16);
+    }
+
+    // FIPS-197, appendix C.3
+    #[test]
+    fn test_aes256_block() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let mut block: [u8; 16] = core::array::from_fn(|i| (i * 0x11) as u8);
+
+        Aes256::new(&key).encrypt_block(&mut block);
+        assert_eq!(
+            block,
+            [
+                0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49,
+                0x60, 0x89
+            ]
+        );
+    }
+}
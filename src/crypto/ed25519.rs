@@ -0,0 +1,395 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Ed25519 signature verification as specified in RFC 8032. Only public
+// data is processed, so the code does not need to run in constant time.
+// The field and group arithmetic follows TweetNaCl: field elements are
+// sixteen 16 bit limbs kept in i64 values, points use extended
+// coordinates.
+
+use super::sha384::Sha512;
+use super::CryptoError;
+
+pub const ED25519_PUBLIC_KEY_SIZE: usize = 32;
+pub const ED25519_SIGNATURE_SIZE: usize = 64;
+
+type Fe = [i64; 16];
+type Point = [Fe; 4];
+
+const FE_ZERO: Fe = [0; 16];
+const FE_ONE: Fe = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+// Curve constant d = -121665/121666
+const D: Fe = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+    0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+
+// 2 * d
+const D2: Fe = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+    0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+
+// Coordinates of the base point
+const BX: Fe = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+    0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const BY: Fe = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666,
+];
+
+// Square root of -1
+const SQRT_M1: Fe = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+    0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+
+// Order of the base point, little endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+fn fe_carry(o: &mut Fe) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+// Swaps p and q if b is 1
+fn fe_select(p: &mut Fe, q: &mut Fe, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn fe_pack(n: &Fe) -> [u8; 32] {
+    let mut t = *n;
+    fe_carry(&mut t);
+    fe_carry(&mut t);
+    fe_carry(&mut t);
+
+    // Subtract the prime twice if the value is not fully reduced yet
+    for _ in 0..2 {
+        let mut m = FE_ZERO;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        fe_select(&mut t, &mut m, 1 - b);
+    }
+
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = t[i] as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn fe_unpack(n: &[u8; 32]) -> Fe {
+    let mut o = FE_ZERO;
+    for i in 0..16 {
+        o[i] = i64::from(n[2 * i]) + (i64::from(n[2 * i + 1]) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn fe_eq(a: &Fe, b: &Fe) -> bool {
+    fe_pack(a) == fe_pack(b)
+}
+
+fn fe_parity(a: &Fe) -> u8 {
+    fe_pack(a)[0] & 1
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    let mut o = FE_ZERO;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    let mut o = FE_ZERO;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    // 2^256 = 38 mod p
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o = FE_ZERO;
+    o.copy_from_slice(&t[..16]);
+    fe_carry(&mut o);
+    fe_carry(&mut o);
+    o
+}
+
+fn fe_square(a: &Fe) -> Fe {
+    fe_mul(a, a)
+}
+
+// Computes a^((p - 5) / 8)
+fn fe_pow2523(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..=250).rev() {
+        c = fe_square(&c);
+        if i != 1 {
+            c = fe_mul(&c, a);
+        }
+    }
+    c
+}
+
+fn fe_invert(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..=253).rev() {
+        c = fe_square(&c);
+        if i != 2 && i != 4 {
+            c = fe_mul(&c, a);
+        }
+    }
+    c
+}
+
+fn point_add(p: &mut Point, q: &Point) {
+    let a = fe_mul(&fe_sub(&p[1], &p[0]), &fe_sub(&q[1], &q[0]));
+    let b = fe_mul(&fe_add(&p[0], &p[1]), &fe_add(&q[0], &q[1]));
+    let c = fe_mul(&fe_mul(&p[3], &q[3]), &D2);
+    let d = fe_mul(&p[2], &q[2]);
+    let d = fe_add(&d, &d);
+    let e = fe_sub(&b, &a);
+    let f = fe_sub(&d, &c);
+    let g = fe_add(&d, &c);
+    let h = fe_add(&b, &a);
+
+    p[0] = fe_mul(&e, &f);
+    p[1] = fe_mul(&h, &g);
+    p[2] = fe_mul(&g, &f);
+    p[3] = fe_mul(&e, &h);
+}
+
+fn point_select(p: &mut Point, q: &mut Point, b: i64) {
+    for (pi, qi) in p.iter_mut().zip(q.iter_mut()) {
+        fe_select(pi, qi, b);
+    }
+}
+
+fn point_pack(p: &Point) -> [u8; 32] {
+    let zi = fe_invert(&p[2]);
+    let tx = fe_mul(&p[0], &zi);
+    let ty = fe_mul(&p[1], &zi);
+    let mut r = fe_pack(&ty);
+    r[31] ^= fe_parity(&tx) << 7;
+    r
+}
+
+fn scalar_mult(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p = [FE_ZERO, FE_ONE, FE_ONE, FE_ZERO];
+    let mut q = *q;
+    for i in (0..256).rev() {
+        let b = i64::from((s[i / 8] >> (i & 7)) & 1);
+        point_select(&mut p, &mut q, b);
+        point_add(&mut q, &p);
+        let p2 = p;
+        point_add(&mut p, &p2);
+        point_select(&mut p, &mut q, b);
+    }
+    p
+}
+
+fn scalar_base(s: &[u8; 32]) -> Point {
+    scalar_mult(&[BX, BY, FE_ONE, fe_mul(&BX, &BY)], s)
+}
+
+// Decodes a point and negates it, which is what verification needs
+fn point_unpack_neg(p: &[u8; 32]) -> Option<Point> {
+    let y = fe_unpack(p);
+    let num = fe_square(&y);
+    let den = fe_mul(&num, &D);
+    let num = fe_sub(&num, &FE_ONE);
+    let den = fe_add(&FE_ONE, &den);
+
+    // Candidate square root of num / den
+    let den2 = fe_square(&den);
+    let den4 = fe_square(&den2);
+    let den6 = fe_mul(&den4, &den2);
+    let t = fe_mul(&fe_mul(&den6, &num), &den);
+    let t = fe_mul(&fe_pow2523(&t), &num);
+    let t = fe_mul(&fe_mul(&t, &den), &den);
+    let mut x = fe_mul(&t, &den);
+
+    if !fe_eq(&fe_mul(&fe_square(&x), &den), &num) {
+        x = fe_mul(&x, &SQRT_M1);
+    }
+    if !fe_eq(&fe_mul(&fe_square(&x), &den), &num) {
+        return None;
+    }
+
+    if fe_parity(&x) == p[31] >> 7 {
+        x = fe_sub(&FE_ZERO, &x);
+    }
+
+    let t = fe_mul(&x, &y);
+    Some([x, y, FE_ONE, t])
+}
+
+// Reduces a 512 bit little endian value modulo L
+fn scalar_reduce(r: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (xi, ri) in x.iter_mut().zip(r.iter()) {
+        *xi = i64::from(*ri);
+    }
+
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        for j in (i - 32)..(i - 12) {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+        }
+        x[i - 12] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 0xff;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut o = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        o[i] = x[i] as u8;
+    }
+    o
+}
+
+// Returns whether the little endian scalar is below L
+fn scalar_is_canonical(s: &[u8]) -> bool {
+    for i in (0..32).rev() {
+        let l = L[i] as u8;
+        if s[i] != l {
+            return s[i] < l;
+        }
+    }
+    false
+}
+
+/// Verifies the Ed25519 signature `sig` over `msg` with the public key
+/// `key`.
+pub fn ed25519_verify(
+    key: &[u8; ED25519_PUBLIC_KEY_SIZE],
+    msg: &[u8],
+    sig: &[u8; ED25519_SIGNATURE_SIZE],
+) -> Result<(), CryptoError> {
+    let (r, s) = sig.split_at(32);
+    if !scalar_is_canonical(s) {
+        return Err(CryptoError::BadSignature);
+    }
+    let neg_a = point_unpack_neg(key).ok_or(CryptoError::BadSignature)?;
+
+    let mut ctx = Sha512::new();
+    ctx.update(r);
+    ctx.update(key);
+    ctx.update(msg);
+    let h = scalar_reduce(&ctx.finalize());
+
+    // R must equal [S]B - [h]A
+    let mut p = scalar_mult(&neg_a, &h);
+    point_add(&mut p, &scalar_base(s.try_into().unwrap()));
+
+    if point_pack(&p).as_slice() != r {
+        return Err(CryptoError::BadSignature);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    // RFC 8032, section 7.1, TEST 1 and TEST 2
+    const KEY1: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const SIG1: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                        5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+    const KEY2: &str = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+    const SIG2: &str = "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                        085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00";
+
+    #[test]
+    fn test_ed25519_rfc8032() {
+        assert!(ed25519_verify(&unhex(KEY1), b"", &unhex(SIG1)).is_ok());
+        assert!(ed25519_verify(&unhex(KEY2), &[0x72], &unhex(SIG2)).is_ok());
+    }
+
+    #[test]
+    fn test_ed25519_reject() {
+        let key = unhex(KEY2);
+        let sig = unhex(SIG2);
+        assert!(ed25519_verify(&key, &[0x73], &sig).is_err());
+        assert!(ed25519_verify(&unhex(KEY1), &[0x72], &sig).is_err());
+
+        let mut bad = sig;
+        bad[5] ^= 1;
+        assert!(ed25519_verify(&key, &[0x72], &bad).is_err());
+
+        // S + L verifies mathematically but is not a canonical encoding
+        let mut malleable = sig;
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let v = u16::from(malleable[32 + i]) + L[i] as u16 + carry;
+            malleable[32 + i] = v as u8;
+            carry = v >> 8;
+        }
+        assert!(ed25519_verify(&key, &[0x72], &malleable).is_err());
+    }
+}
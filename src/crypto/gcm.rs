@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use super::aes::{Aes256, AES256_KEY_SIZE, AES_BLOCK_SIZE};
+use super::CryptoError;
+use crate::error::SvsmError;
+use core::ptr;
+
+pub const GCM_IV_SIZE: usize = 12;
+pub const GCM_TAG_SIZE: usize = 16;
+
+// Reduction polynomial x^128 + x^7 + x^2 + x + 1 in GCM bit order
+const GF_R: u128 = 0xe1 << 120;
+
+// Multiplication in GF(2^128), SP 800-38D algorithm 1. Masks instead of
+// branches keep the timing independent of the operands.
+fn gf_mul(x: u128, y: u128) -> u128 {
+    let mut z = 0u128;
+    let mut v = y;
+
+    for i in 0..128 {
+        let bit = (x >> (127 - i)) & 1;
+        z ^= v & 0u128.wrapping_sub(bit);
+        let lsb = v & 1;
+        v = (v >> 1) ^ (GF_R & 0u128.wrapping_sub(lsb));
+    }
+
+    z
+}
+
+struct Ghash {
+    h: u128,
+    y: u128,
+}
+
+impl Ghash {
+    fn new(h: u128) -> Self {
+        Ghash { h, y: 0 }
+    }
+
+    // Absorbs `data`, zero-padding the last block
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(AES_BLOCK_SIZE) {
+            let mut block = [0u8; AES_BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.y = gf_mul(self.y ^ u128::from_be_bytes(block), self.h);
+        }
+    }
+
+    fn finish(mut self, aad_len: usize, text_len: usize) -> u128 {
+        let lengths = ((aad_len as u128 * 8) << 64) | (text_len as u128 * 8);
+        self.y = gf_mul(self.y ^ lengths, self.h);
+        self.y
+    }
+}
+
+/// AES-256 in Galois/Counter mode with 96 bit IVs and full 128 bit tags,
+/// as used for SEV-SNP guest messages.
+pub struct Aes256Gcm {
+    aes: Aes256,
+    h: u128,
+}
+
+impl Aes256Gcm {
+    pub fn new(key: &[u8; AES256_KEY_SIZE]) -> Self {
+        let aes = Aes256::new(key);
+        let mut h = [0u8; AES_BLOCK_SIZE];
+        aes.encrypt_block(&mut h);
+
+        Aes256Gcm {
+            aes,
+            h: u128::from_be_bytes(h),
+        }
+    }
+
+    fn j0(iv: &[u8; GCM_IV_SIZE]) -> [u8; AES_BLOCK_SIZE] {
+        let mut j0 = [0u8; AES_BLOCK_SIZE];
+        j0[..GCM_IV_SIZE].copy_from_slice(iv);
+        j0[AES_BLOCK_SIZE - 1] = 1;
+        j0
+    }
+
+    // XORs the key stream starting at inc32(J0) into `buf`
+    fn ctr(&self, j0: &[u8; AES_BLOCK_SIZE], buf: &mut [u8]) {
+        let mut counter = u32::from_be_bytes(j0[GCM_IV_SIZE..].try_into().unwrap());
+
+        for chunk in buf.chunks_mut(AES_BLOCK_SIZE) {
+            counter = counter.wrapping_add(1);
+            let mut block = *j0;
+            block[GCM_IV_SIZE..].copy_from_slice(&counter.to_be_bytes());
+            self.aes.encrypt_block(&mut block);
+
+            for (b, k) in chunk.iter_mut().zip(block) {
+                *b ^= k;
+            }
+        }
+    }
+
+    fn tag(&self, j0: &[u8; AES_BLOCK_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; GCM_TAG_SIZE] {
+        let mut ghash = Ghash::new(self.h);
+        ghash.update(aad);
+        ghash.update(ciphertext);
+        let s = ghash.finish(aad.len(), ciphertext.len());
+
+        let mut tag = *j0;
+        self.aes.encrypt_block(&mut tag);
+        (u128::from_be_bytes(tag) ^ s).to_be_bytes()
+    }
+
+    /// Encrypts `buf` in place and returns the authentication tag over
+    /// `aad` and the ciphertext.
+    pub fn encrypt(
+        &self,
+        iv: &[u8; GCM_IV_SIZE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> [u8; GCM_TAG_SIZE] {
+        let j0 = Self::j0(iv);
+        self.ctr(&j0, buf);
+        self.tag(&j0, aad, buf)
+    }
+
+    /// Checks `tag` and decrypts `buf` in place. `buf` is left untouched
+    /// when authentication fails.
+    pub fn decrypt(
+        &self,
+        iv: &[u8; GCM_IV_SIZE],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; GCM_TAG_SIZE],
+    ) -> Result<(), SvsmError> {
+        let j0 = Self::j0(iv);
+        let expected = self.tag(&j0, aad, buf);
+
+        let diff = expected
+            .iter()
+            .zip(tag)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(CryptoError::AuthFailed.into());
+        }
+
+        self.ctr(&j0, buf);
+        Ok(())
+    }
+}
+
+impl Drop for Aes256Gcm {
+    fn drop(&mut self) {
+        // The hash key allows forging tags, wipe it like the round keys
+        unsafe { ptr::write_volatile(&mut self.h, 0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_gcm_zero_key() {
+        let gcm = Aes256Gcm::new(&[0u8; 32]);
+        let iv = [0u8; GCM_IV_SIZE];
+        let mut buf = [0u8; 16];
+
+        let tag = gcm.encrypt(&iv, &[], &mut buf);
+        assert_eq!(buf, unhex::<16>("cea7403d4d606b6e074ec5d3baf39d18"));
+        assert_eq!(tag, unhex::<16>("d0d1c8a799996bf0265b98b5d48ab919"));
+
+        gcm.decrypt(&iv, &[], &mut buf, &tag).unwrap();
+        assert_eq!(buf, [0u8; 16]);
+    }
+
+    #[test]
+    fn test_gcm_aad_partial_block() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let iv: [u8; GCM_IV_SIZE] = core::array::from_fn(|i| 100 + i as u8);
+        let aad = b"svsm header aad 0123456789abcdefghij";
+        let plain: [u8; 61] = core::array::from_fn(|i| (i * 7) as u8);
+        let gcm = Aes256Gcm::new(&key);
+
+        let mut buf = plain;
+        let tag = gcm.encrypt(&iv, aad, &mut buf);
+        assert_eq!(
+            buf,
+            unhex::<61>(
+                "481cd07365ca7caf065d19a58e3e089432b5788f07ff69d30f7e1af53f68779\
+                 1740eae35b0119115e5ec5e12c97988864b0cc6824c393ba9727e3a4b4b"
+            )
+        );
+        assert_eq!(tag, unhex::<16>("a8846c379751752aefa6a0272a1c7c6b"));
+
+        let mut bad_tag = tag;
+        bad_tag[0] ^= 1;
+        let ciphertext = buf;
+        assert!(gcm.decrypt(&iv, aad, &mut buf, &bad_tag).is_err());
+        assert_eq!(buf, ciphertext);
+
+        gcm.decrypt(&iv, aad, &mut buf, &tag).unwrap();
+        assert_eq!(buf, plain);
+    }
+}
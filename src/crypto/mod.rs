@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+pub mod aes;
+pub mod ed25519;
+pub mod gcm;
+pub mod rng;
+pub mod sha384;
+
+use crate::error::SvsmError;
+
+#[derive(Clone, Copy, Debug)]
+pub enum CryptoError {
+    // Authentication tag did not match the data
+    AuthFailed,
+    // Signature did not verify with the given key
+    BadSignature,
+}
+
+impl From<CryptoError> for SvsmError {
+    fn from(e: CryptoError) -> Self {
+        Self::Crypto(e)
+    }
+}
@@ -0,0 +1,345 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use super::sha384::{Sha384, SHA384_DIGEST_SIZE};
+use crate::cpu::features::cpu_has_rdseed;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::locking::SpinLock;
+use core::arch::asm;
+
+// RDSEED may fail transiently when the entropy pool is drained
+const RDSEED_RETRIES: usize = 1024;
+
+// Bytes read from each source when seeding
+const SEED_SIZE: usize = 48;
+
+// Host-provided seed, larger files are ignored
+const FW_CFG_SEED_FILE: &str = "opt/svsm/seed";
+
+// Domain separation for the DRBG derivations
+const DRBG_TAG_SEED: u8 = 0x00;
+const DRBG_TAG_UPDATE: u8 = 0x01;
+const DRBG_TAG_RESEED: u8 = 0x02;
+const DRBG_TAG_OUTPUT: u8 = 0x03;
+
+#[derive(Clone, Copy, Debug)]
+pub enum RngError {
+    // No trusted entropy source delivered data
+    NoTrustedSource,
+    // The source is not available on this platform
+    Unavailable,
+    // The DRBG was not seeded yet
+    NotSeeded,
+}
+
+impl From<RngError> for SvsmError {
+    fn from(e: RngError) -> Self {
+        Self::Rng(e)
+    }
+}
+
+/// A source of seed material for the DRBG
+pub trait EntropySource {
+    fn name(&self) -> &'static str;
+
+    /// Only trusted sources count towards seeding. Untrusted ones, like
+    /// anything the host provides, are mixed in on top and can not weaken
+    /// the result.
+    fn trusted(&self) -> bool;
+
+    /// Fills `buf` with seed material and returns the number of bytes
+    /// provided, which is zero if the source has nothing to offer.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, SvsmError>;
+}
+
+fn rdseed64() -> Option<u64> {
+    for _ in 0..RDSEED_RETRIES {
+        let val: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {0}",
+                 "setc {1}",
+                 out(reg) val,
+                 out(reg_byte) ok,
+                 options(att_syntax, nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(val);
+        }
+        core::hint::spin_loop();
+    }
+
+    None
+}
+
+/// Entropy from the RDSEED instruction
+#[derive(Clone, Copy, Debug)]
+pub struct RdSeedSource;
+
+impl EntropySource for RdSeedSource {
+    fn name(&self) -> &'static str {
+        "rdseed"
+    }
+
+    fn trusted(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, SvsmError> {
+        if !cpu_has_rdseed() {
+            return Err(RngError::Unavailable.into());
+        }
+
+        for chunk in buf.chunks_mut(8) {
+            let val = rdseed64().ok_or(RngError::Unavailable)?;
+            chunk.copy_from_slice(&val.to_le_bytes()[..chunk.len()]);
+        }
+
+        Ok(buf.len())
+    }
+}
+
+/// Seed provided by the host through fw_cfg
+pub struct FwCfgSeedSource<'a> {
+    fw_cfg: &'a FwCfg<'a>,
+}
+
+impl<'a> FwCfgSeedSource<'a> {
+    pub fn new(fw_cfg: &'a FwCfg<'a>) -> Self {
+        FwCfgSeedSource { fw_cfg }
+    }
+}
+
+impl EntropySource for FwCfgSeedSource<'_> {
+    fn name(&self) -> &'static str {
+        "fw_cfg"
+    }
+
+    fn trusted(&self) -> bool {
+        false
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, SvsmError> {
+        match self.fw_cfg.file_selector(FW_CFG_SEED_FILE) {
+            Ok(file) => self.fw_cfg.read_file(&file, buf),
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+fn derive(tag: u8, parts: &[&[u8]]) -> [u8; SHA384_DIGEST_SIZE] {
+    let mut ctx = Sha384::new();
+    ctx.update(&[tag]);
+    for part in parts {
+        ctx.update(&(part.len() as u64).to_le_bytes());
+        ctx.update(part);
+    }
+    ctx.finalize()
+}
+
+/// Hash-based deterministic random bit generator. Every output block and
+/// state update is a SHA-384 over the state with a distinct tag.
+pub struct Drbg {
+    v: [u8; SHA384_DIGEST_SIZE],
+    counter: u64,
+}
+
+impl Drbg {
+    fn new(seed: &[u8]) -> Self {
+        Drbg {
+            v: derive(DRBG_TAG_SEED, &[seed]),
+            counter: 0,
+        }
+    }
+
+    pub fn reseed(&mut self, seed: &[u8]) {
+        self.v = derive(DRBG_TAG_RESEED, &[&self.v, seed]);
+    }
+
+    pub fn generate(&mut self, out: &mut [u8]) {
+        let counter = self.counter.to_le_bytes();
+
+        for (i, chunk) in out.chunks_mut(SHA384_DIGEST_SIZE).enumerate() {
+            let block = derive(DRBG_TAG_OUTPUT, &[&self.v, &counter, &i.to_le_bytes()]);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        // Backtracking resistance: the old state can not be recovered
+        self.v = derive(DRBG_TAG_UPDATE, &[&self.v, &counter]);
+        self.counter += 1;
+    }
+}
+
+/// Seed material collected from a set of sources, together with a digest
+/// of the mixing policy: which sources contributed how many bytes, but not
+/// what they provided.
+struct Seed {
+    material: [u8; SHA384_DIGEST_SIZE],
+    policy: [u8; SHA384_DIGEST_SIZE],
+}
+
+// Hashes the output of all sources into a seed. Untrusted sources go first,
+// so their input is fixed before trusted material is drawn.
+fn collect_seed(sources: &[&dyn EntropySource]) -> Result<Seed, SvsmError> {
+    let mut material = Sha384::new();
+    let mut policy = Sha384::new();
+    let mut trusted_bytes = 0;
+
+    let untrusted = sources.iter().filter(|s| !s.trusted());
+    let trusted = sources.iter().filter(|s| s.trusted());
+    for source in untrusted.chain(trusted) {
+        let mut buf = [0u8; SEED_SIZE];
+        let len = match source.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                log::warn!("Entropy source {} failed: {:?}", source.name(), e);
+                0
+            }
+        };
+
+        if source.trusted() {
+            trusted_bytes += len;
+        }
+
+        for ctx in [&mut material, &mut policy] {
+            ctx.update(source.name().as_bytes());
+            ctx.update(&[source.trusted() as u8]);
+            ctx.update(&(len as u64).to_le_bytes());
+        }
+        material.update(&buf[..len]);
+    }
+
+    if trusted_bytes < SEED_SIZE {
+        return Err(RngError::NoTrustedSource.into());
+    }
+
+    Ok(Seed {
+        material: material.finalize(),
+        policy: policy.finalize(),
+    })
+}
+
+struct RngState {
+    drbg: Option<Drbg>,
+    policy: Option<[u8; SHA384_DIGEST_SIZE]>,
+}
+
+static RNG: SpinLock<RngState> = SpinLock::new(RngState {
+    drbg: None,
+    policy: None,
+});
+
+/// Seeds the SVSM random number generator from RDSEED, mixing in the seed
+/// the host provides through fw_cfg, if any.
+pub fn rng_init(fw_cfg: &FwCfg) -> Result<(), SvsmError> {
+    let fw_cfg_seed = FwCfgSeedSource::new(fw_cfg);
+    let seed = collect_seed(&[&RdSeedSource, &fw_cfg_seed])?;
+
+    let mut rng = RNG.lock();
+    match rng.drbg.as_mut() {
+        Some(drbg) => drbg.reseed(&seed.material),
+        None => rng.drbg = Some(Drbg::new(&seed.material)),
+    }
+    rng.policy = Some(seed.policy);
+
+    Ok(())
+}
+
+/// Fills `buf` with random bytes
+pub fn rng_fill(buf: &mut [u8]) -> Result<(), SvsmError> {
+    let mut rng = RNG.lock();
+    let drbg = rng.drbg.as_mut().ok_or(RngError::NotSeeded)?;
+    drbg.generate(buf);
+    Ok(())
+}
+
+/// Digest of the sources the generator was seeded from, meant to be
+/// included in measurements. `None` until [`rng_init()`] succeeded.
+pub fn rng_policy_digest() -> Option<[u8; SHA384_DIGEST_SIZE]> {
+    RNG.lock().policy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedSource {
+        name: &'static str,
+        trusted: bool,
+        byte: u8,
+        len: usize,
+    }
+
+    impl EntropySource for FixedSource {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn trusted(&self) -> bool {
+            self.trusted
+        }
+
+        fn read(&self, buf: &mut [u8]) -> Result<usize, SvsmError> {
+            buf[..self.len].fill(self.byte);
+            Ok(self.len)
+        }
+    }
+
+    const TRUSTED: FixedSource = FixedSource {
+        name: "trusted",
+        trusted: true,
+        byte: 0x11,
+        len: SEED_SIZE,
+    };
+
+    #[test]
+    fn test_drbg() {
+        let mut a = Drbg::new(b"seed");
+        let mut b = Drbg::new(b"seed");
+
+        let mut out_a = [0u8; 100];
+        let mut out_b = [0u8; 100];
+        a.generate(&mut out_a);
+        b.generate(&mut out_b);
+        assert_eq!(out_a, out_b);
+
+        // Output differs across calls and after reseeding
+        let first = out_a;
+        a.generate(&mut out_a);
+        assert_ne!(first, out_a);
+        b.reseed(b"more");
+        b.generate(&mut out_b);
+        assert_ne!(out_a, out_b);
+    }
+
+    #[test]
+    fn test_collect_seed() {
+        let host_a = FixedSource {
+            name: "host",
+            trusted: false,
+            byte: 0x22,
+            len: 16,
+        };
+        let host_b = FixedSource {
+            byte: 0x33,
+            ..host_a
+        };
+
+        let plain = collect_seed(&[&TRUSTED]).unwrap();
+        let mixed_a = collect_seed(&[&TRUSTED, &host_a]).unwrap();
+        let mixed_b = collect_seed(&[&host_b, &TRUSTED]).unwrap();
+
+        // Host input changes the seed, but not the recorded policy
+        assert_ne!(plain.material, mixed_a.material);
+        assert_ne!(mixed_a.material, mixed_b.material);
+        assert_ne!(plain.policy, mixed_a.policy);
+        assert_eq!(mixed_a.policy, mixed_b.policy);
+
+        // Host input alone is never enough
+        assert!(collect_seed(&[&host_a]).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+pub const SHA384_DIGEST_SIZE: usize = 48;
+pub const SHA512_DIGEST_SIZE: usize = 64;
+pub const SHA512_BLOCK_SIZE: usize = 128;
+
+const SHA384_IV: [u64; 8] = [
+    0xcbbb9d5dc1059ed8,
+    0x629a292a367cd507,
+    0x9159015a3070dd17,
+    0x152fecd8f70e5939,
+    0x67332667ffc00b31,
+    0x8eb44a8768581511,
+    0xdb0c2e0d64f98fa7,
+    0x47b5481dbefa4fa4,
+];
+
+const SHA512_IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SHA512_K: [u64; 80] = [
+    0x428a2f98d728ae22,
+    0x7137449123ef65cd,
+    0xb5c0fbcfec4d3b2f,
+    0xe9b5dba58189dbbc,
+    0x3956c25bf348b538,
+    0x59f111f1b605d019,
+    0x923f82a4af194f9b,
+    0xab1c5ed5da6d8118,
+    0xd807aa98a3030242,
+    0x12835b0145706fbe,
+    0x243185be4ee4b28c,
+    0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f,
+    0x80deb1fe3b1696b1,
+    0x9bdc06a725c71235,
+    0xc19bf174cf692694,
+    0xe49b69c19ef14ad2,
+    0xefbe4786384f25e3,
+    0x0fc19dc68b8cd5b5,
+    0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275,
+    0x4a7484aa6ea6e483,
+    0x5cb0a9dcbd41fbd4,
+    0x76f988da831153b5,
+    0x983e5152ee66dfab,
+    0xa831c66d2db43210,
+    0xb00327c898fb213f,
+    0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2,
+    0xd5a79147930aa725,
+    0x06ca6351e003826f,
+    0x142929670a0e6e70,
+    0x27b70a8546d22ffc,
+    0x2e1b21385c26c926,
+    0x4d2c6dfc5ac42aed,
+    0x53380d139d95b3df,
+    0x650a73548baf63de,
+    0x766a0abb3c77b2a8,
+    0x81c2c92e47edaee6,
+    0x92722c851482353b,
+    0xa2bfe8a14cf10364,
+    0xa81a664bbc423001,
+    0xc24b8b70d0f89791,
+    0xc76c51a30654be30,
+    0xd192e819d6ef5218,
+    0xd69906245565a910,
+    0xf40e35855771202a,
+    0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8,
+    0x1e376c085141ab53,
+    0x2748774cdf8eeb99,
+    0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63,
+    0x4ed8aa4ae3418acb,
+    0x5b9cca4f7763e373,
+    0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc,
+    0x78a5636f43172f60,
+    0x84c87814a1f0ab72,
+    0x8cc702081a6439ec,
+    0x90befffa23631e28,
+    0xa4506cebde82bde9,
+    0xbef9a3f7b2c67915,
+    0xc67178f2e372532b,
+    0xca273eceea26619c,
+    0xd186b8c721c0c207,
+    0xeada7dd6cde0eb1e,
+    0xf57d4f7fee6ed178,
+    0x06f067aa72176fba,
+    0x0a637dc5a2c898a6,
+    0x113f9804bef90dae,
+    0x1b710b35131c471b,
+    0x28db77f523047d84,
+    0x32caab7b40c72493,
+    0x3c9ebe0a15c9bebc,
+    0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6,
+    0x597f299cfc657e2a,
+    0x5fcb6fab3ad6faec,
+    0x6c44198c4a475817,
+];
+
+// SHA-512 block function, `blocks` must be a multiple of SHA512_BLOCK_SIZE
+// in length. There is deliberately no accelerated variant: SHA-NI does not
+// cover SHA-512, and the SHA512 instructions work on YMM registers, which
+// the SVSM neither enables nor saves on exception entry.
+fn sha512_compress(state: &mut [u64; 8], blocks: &[u8]) {
+    assert!(blocks.len().is_multiple_of(SHA512_BLOCK_SIZE));
+
+    for block in blocks.chunks_exact(SHA512_BLOCK_SIZE) {
+        let mut w = [0u64; 80];
+        for (i, word) in block.chunks_exact(8).enumerate() {
+            w[i] = u64::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+        for i in 0..80 {
+            let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA512_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+// Streaming state shared by SHA-384 and SHA-512, which only differ in
+// their initial hash value and in how much of the final state is output.
+#[derive(Clone, Debug)]
+struct Sha512Core {
+    state: [u64; 8],
+    buf: [u8; SHA512_BLOCK_SIZE],
+    buf_len: usize,
+    total_len: u128,
+}
+
+impl Sha512Core {
+    const fn new(iv: [u64; 8]) -> Self {
+        Sha512Core {
+            state: iv,
+            buf: [0; SHA512_BLOCK_SIZE],
+            buf_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u128;
+
+        if self.buf_len > 0 {
+            let len = data.len().min(SHA512_BLOCK_SIZE - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + len].copy_from_slice(&data[..len]);
+            self.buf_len += len;
+            data = &data[len..];
+
+            if self.buf_len < SHA512_BLOCK_SIZE {
+                return;
+            }
+            sha512_compress(&mut self.state, &self.buf);
+            self.buf_len = 0;
+        }
+
+        let whole = data.len() - data.len() % SHA512_BLOCK_SIZE;
+        if whole > 0 {
+            sha512_compress(&mut self.state, &data[..whole]);
+        }
+
+        let rest = &data[whole..];
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len();
+    }
+
+    fn finalize(mut self, digest: &mut [u8]) {
+        let bit_len = self.total_len << 3;
+
+        // Padding: a single one bit, zeroes and the 128 bit message length
+        self.buf[self.buf_len] = 0x80;
+        self.buf[self.buf_len + 1..].fill(0);
+        if self.buf_len >= SHA512_BLOCK_SIZE - 16 {
+            sha512_compress(&mut self.state, &self.buf);
+            self.buf.fill(0);
+        }
+        self.buf[SHA512_BLOCK_SIZE - 16..].copy_from_slice(&bit_len.to_be_bytes());
+        sha512_compress(&mut self.state, &self.buf);
+
+        for (chunk, word) in digest.chunks_exact_mut(8).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+/// Streaming SHA-384 context
+#[derive(Clone, Debug)]
+pub struct Sha384 {
+    core: Sha512Core,
+}
+
+impl Sha384 {
+    pub const fn new() -> Self {
+        Sha384 {
+            core: Sha512Core::new(SHA384_IV),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.core.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; SHA384_DIGEST_SIZE] {
+        let mut digest = [0u8; SHA384_DIGEST_SIZE];
+        self.core.finalize(&mut digest);
+        digest
+    }
+}
+
+impl Default for Sha384 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn sha384(data: &[u8]) -> [u8; SHA384_DIGEST_SIZE] {
+    let mut ctx = Sha384::new();
+    ctx.update(data);
+    ctx.finalize()
+}
+
+/// Streaming SHA-512 context
+#[derive(Clone, Debug)]
+pub struct Sha512 {
+    core: Sha512Core,
+}
+
+impl Sha512 {
+    pub const fn new() -> Self {
+        Sha512 {
+            core: Sha512Core::new(SHA512_IV),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.core.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; SHA512_DIGEST_SIZE] {
+        let mut digest = [0u8; SHA512_DIGEST_SIZE];
+        self.core.finalize(&mut digest);
+        digest
+    }
+}
+
+impl Default for Sha512 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn sha512(data: &[u8]) -> [u8; SHA512_DIGEST_SIZE] {
+    let mut ctx = Sha512::new();
+    ctx.update(data);
+    ctx.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    fn hex(digest: &[u8]) -> alloc::string::String {
+        use core::fmt::Write;
+        let mut s = alloc::string::String::new();
+        for b in digest {
+            write!(s, "{:02x}", b).unwrap();
+        }
+        s
+    }
+
+    #[test]
+    fn test_sha384_empty() {
+        assert_eq!(
+            hex(&sha384(b"")),
+            "38b060a751ac96384cd9327eb1b1e36a21fdb71114be07434c0cc7bf63f6e1da\
+             274edebfe76f65fbd51ad2f14898b95b"
+        );
+    }
+
+    #[test]
+    fn test_sha384_abc() {
+        assert_eq!(
+            hex(&sha384(b"abc")),
+            "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+             8086072ba1e7cc2358baeca134c825a7"
+        );
+    }
+
+    #[test]
+    fn test_sha384_two_blocks() {
+        let msg = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                    hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+        assert_eq!(
+            hex(&sha384(msg)),
+            "09330c33f71147e83d192fc782cd1b4753111b173b3b05d22fa08086e3b0f712\
+             fcc7c71a557e2db966c3e9fa91746039"
+        );
+    }
+
+    #[test]
+    fn test_sha512_abc() {
+        assert_eq!(
+            hex(&sha512(b"abc")),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+    }
+
+    #[test]
+    fn test_sha384_split_updates() {
+        let data = [0x5au8; 1000];
+        let mut ctx = Sha384::new();
+        for chunk in data.chunks(37) {
+            ctx.update(chunk);
+        }
+        assert_eq!(ctx.finalize(), sha384(&data));
+    }
+}
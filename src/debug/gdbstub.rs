@@ -0,0 +1,491 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Stub for the GDB remote serial protocol on the second UART. Breakpoint
+// and debug exceptions stop the CPU which raised them and hand control to
+// the debugger until it continues or single-steps. Other CPUs keep
+// running. Memory is accessed through temporary mappings of the physical
+// pages, so software breakpoints also work on read-only kernel text.
+//
+// Connect with: target remote <host side of COM2>
+
+use crate::address::{Address, VirtAddr};
+use crate::console::ConsoleWriter;
+use crate::cpu::idt::{X86Regs, BP_VECTOR, DB_VECTOR};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::pagetable::translate_current;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::serial::SerialPort;
+use crate::types::PAGE_SIZE;
+use core::arch::asm;
+use core::cmp::min;
+
+// COM2, the console uses COM1
+pub const GDB_SERIAL_PORT: u16 = 0x2f8;
+
+const MAX_BREAKPOINTS: usize = 32;
+// Advertised to GDB as "PacketSize", must be a hex number
+const PACKET_SIZE: usize = 0x400;
+const INT3: u8 = 0xcc;
+const FLAGS_TF: usize = 1 << 8;
+// SIGTRAP
+const STOP_REPLY: &[u8] = b"S05";
+
+#[derive(Clone, Copy, Debug)]
+struct Breakpoint {
+    addr: VirtAddr,
+    orig: u8,
+}
+
+struct GdbStub {
+    port: &'static SerialPort<'static>,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+static GDB_STUB: SpinLock<Option<GdbStub>> = SpinLock::new(None);
+
+// What the interrupted code does once the stub returns
+enum Resume {
+    Continue,
+    Step,
+}
+
+// Packet payload being assembled on the stack, exception context must not
+// allocate.
+struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Packet {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let n = min(data.len(), PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+    }
+
+    fn push_hex(&mut self, data: &[u8]) {
+        for b in data {
+            self.push(&[hex_digit(b >> 4), hex_digit(b & 0xf)]);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+fn hex_digit(v: u8) -> u8 {
+    b"0123456789abcdef"[(v & 0xf) as usize]
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|v| v as u8)
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |acc, &c| Some(acc << 4 | hex_value(c)? as u64))
+}
+
+// Decodes hex digit pairs into `out` and returns the number of bytes
+fn decode_hex(s: &[u8], out: &mut [u8]) -> Option<usize> {
+    let pairs = s.chunks_exact(2);
+    if !pairs.remainder().is_empty() || pairs.len() > out.len() {
+        return None;
+    }
+    for (i, pair) in pairs.enumerate() {
+        out[i] = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(s.len() / 2)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// Splits "addr,len" into its parts
+fn parse_addr_len(s: &[u8]) -> Option<(VirtAddr, usize)> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    let addr = parse_hex(&s[..comma])?;
+    let len = parse_hex(&s[comma + 1..])?;
+    Some((VirtAddr::from(addr), usize::try_from(len).ok()?))
+}
+
+// Register layout of GDB's amd64 target: the general purpose registers and
+// rip as 64-bit values, eflags and the segment registers as 32-bit ones.
+// GDB treats the x87 and SSE registers missing at the end as unavailable.
+const GDB_GP_REGS: usize = 17;
+const GDB_REGS_SIZE: usize = GDB_GP_REGS * 8 + 7 * 4;
+
+fn gdb_regs(regs: &X86Regs) -> [u8; GDB_REGS_SIZE] {
+    let gp = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    // eflags, cs, ss, ds, es, fs, gs
+    let small = [regs.flags, regs.cs, regs.ss, 0, 0, 0, 0];
+
+    let mut out = [0u8; GDB_REGS_SIZE];
+    for (i, v) in gp.iter().enumerate() {
+        out[i * 8..i * 8 + 8].copy_from_slice(&(*v as u64).to_le_bytes());
+    }
+    for (i, v) in small.iter().enumerate() {
+        let off = GDB_GP_REGS * 8 + i * 4;
+        out[off..off + 4].copy_from_slice(&(*v as u32).to_le_bytes());
+    }
+    out
+}
+
+// Writes back the general purpose registers, rip and eflags. Segment
+// registers can not be changed.
+fn set_gdb_regs(regs: &mut X86Regs, data: &[u8; GDB_REGS_SIZE]) {
+    let gp = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap()) as usize;
+    regs.rax = gp(0);
+    regs.rbx = gp(1);
+    regs.rcx = gp(2);
+    regs.rdx = gp(3);
+    regs.rsi = gp(4);
+    regs.rdi = gp(5);
+    regs.rbp = gp(6);
+    regs.rsp = gp(7);
+    regs.r8 = gp(8);
+    regs.r9 = gp(9);
+    regs.r10 = gp(10);
+    regs.r11 = gp(11);
+    regs.r12 = gp(12);
+    regs.r13 = gp(13);
+    regs.r14 = gp(14);
+    regs.r15 = gp(15);
+    regs.rip = gp(16);
+    let off = GDB_GP_REGS * 8;
+    regs.flags = u32::from_le_bytes(data[off..off + 4].try_into().unwrap()) as usize;
+}
+
+// Copies between `buf` and the memory at `vaddr`, page by page through a
+// temporary mapping of the backing physical page. Fails on unmapped
+// addresses instead of faulting.
+fn access_memory(vaddr: VirtAddr, buf: &mut [u8], write: bool) -> Result<(), SvsmError> {
+    let mut done = 0;
+
+    while done < buf.len() {
+        let addr = vaddr.offset(done);
+        let paddr = translate_current(addr).ok_or(SvsmError::InvalidAddress)?;
+        let offset = addr.page_offset();
+        let len = min(buf.len() - done, PAGE_SIZE - offset);
+
+        let guard = PerCPUPageMappingGuard::create_4k(paddr.page_align())?;
+        let ptr = guard.virt_addr().offset(offset).as_mut_ptr::<u8>();
+        let chunk = &mut buf[done..done + len];
+        unsafe {
+            if write {
+                ptr.copy_from_nonoverlapping(chunk.as_ptr(), len);
+            } else {
+                ptr.copy_to_nonoverlapping(chunk.as_mut_ptr(), len);
+            }
+        }
+
+        done += len;
+    }
+
+    Ok(())
+}
+
+impl GdbStub {
+    fn get_byte(&self) -> u8 {
+        loop {
+            if let Some(b) = self.port.read_byte() {
+                return b;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Waits for a packet with a valid checksum and acknowledges it
+    fn receive(&self, packet: &mut Packet) {
+        loop {
+            while self.get_byte() != b'$' {}
+
+            packet.len = 0;
+            let mut overflow = false;
+            loop {
+                match self.get_byte() {
+                    b'#' => break,
+                    _ if packet.len == PACKET_SIZE => overflow = true,
+                    b => packet.push(&[b]),
+                }
+            }
+
+            let sum = hex_value(self.get_byte())
+                .zip(hex_value(self.get_byte()))
+                .map(|(hi, lo)| hi << 4 | lo);
+            if !overflow && sum == Some(checksum(packet.as_bytes())) {
+                self.port.put_byte(b'+');
+                return;
+            }
+            self.port.put_byte(b'-');
+        }
+    }
+
+    // Sends a packet until GDB acknowledges it
+    fn send(&self, data: &[u8]) {
+        let sum = checksum(data);
+        loop {
+            self.port.put_byte(b'$');
+            for &b in data {
+                self.port.put_byte(b);
+            }
+            self.port.put_byte(b'#');
+            self.port.put_byte(hex_digit(sum >> 4));
+            self.port.put_byte(hex_digit(sum & 0xf));
+
+            if self.get_byte() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn insert_breakpoint(&mut self, addr: VirtAddr) -> Result<(), SvsmError> {
+        if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.is_none())
+            .ok_or(SvsmError::Mem)?;
+
+        let mut orig = [0u8];
+        access_memory(addr, &mut orig, false)?;
+        access_memory(addr, &mut [INT3], true)?;
+        *slot = Some(Breakpoint {
+            addr,
+            orig: orig[0],
+        });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: VirtAddr) -> Result<(), SvsmError> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.is_some_and(|bp| bp.addr == addr))
+            .ok_or(SvsmError::InvalidAddress)?;
+
+        let bp = slot.take().unwrap();
+        access_memory(bp.addr, &mut [bp.orig], true)
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = slot.take() {
+                let _ = access_memory(bp.addr, &mut [bp.orig], true);
+            }
+        }
+    }
+
+    fn read_memory(&self, args: &[u8], reply: &mut Packet) {
+        let mut buf = [0u8; PACKET_SIZE / 2];
+        let Some((addr, len)) = parse_addr_len(args).filter(|(_, len)| *len <= buf.len()) else {
+            return reply.push(b"E01");
+        };
+
+        match access_memory(addr, &mut buf[..len], false) {
+            Ok(()) => reply.push_hex(&buf[..len]),
+            Err(_) => reply.push(b"E14"),
+        }
+    }
+
+    fn write_memory(&self, args: &[u8], reply: &mut Packet) {
+        let mut buf = [0u8; PACKET_SIZE / 2];
+        let decoded = args.iter().position(|&c| c == b':').and_then(|colon| {
+            let (addr, len) = parse_addr_len(&args[..colon])?;
+            let n = decode_hex(&args[colon + 1..], &mut buf)?;
+            (n == len).then_some((addr, len))
+        });
+        let Some((addr, len)) = decoded else {
+            return reply.push(b"E01");
+        };
+
+        match access_memory(addr, &mut buf[..len], true) {
+            Ok(()) => reply.push(b"OK"),
+            Err(_) => reply.push(b"E14"),
+        }
+    }
+
+    // Handles "Z0,addr,kind" and "z0,addr,kind". Only software breakpoints
+    // are supported, an empty reply makes GDB fall back to memory writes
+    // for other kinds.
+    fn breakpoint(&mut self, insert: bool, args: &[u8], reply: &mut Packet) {
+        let Some(args) = args.strip_prefix(b"0,") else {
+            return;
+        };
+        let end = args.iter().position(|&c| c == b',').unwrap_or(args.len());
+        let Some(addr) = parse_hex(&args[..end]).map(VirtAddr::from) else {
+            return reply.push(b"E01");
+        };
+
+        let ret = if insert {
+            self.insert_breakpoint(addr)
+        } else {
+            self.remove_breakpoint(addr)
+        };
+        match ret {
+            Ok(()) => reply.push(b"OK"),
+            Err(_) => reply.push(b"E0e"),
+        }
+    }
+
+    // Talks to GDB until it resumes execution
+    fn session(&mut self, regs: &mut X86Regs, stop_reply: &[u8]) -> Resume {
+        let mut packet = Packet::new();
+        let mut reply = Packet::new();
+
+        self.send(stop_reply);
+        loop {
+            self.receive(&mut packet);
+            let (cmd, args) = match packet.as_bytes().split_first() {
+                Some((cmd, args)) => (*cmd, args),
+                None => (0, &[][..]),
+            };
+
+            reply.len = 0;
+            match cmd {
+                b'?' => reply.push(stop_reply),
+                b'g' => reply.push_hex(&gdb_regs(regs)),
+                b'G' => {
+                    let mut data = gdb_regs(regs);
+                    match decode_hex(args, &mut data) {
+                        Some(_) => {
+                            set_gdb_regs(regs, &data);
+                            reply.push(b"OK");
+                        }
+                        None => reply.push(b"E01"),
+                    }
+                }
+                b'm' => self.read_memory(args, &mut reply),
+                b'M' => self.write_memory(args, &mut reply),
+                b'Z' => self.breakpoint(true, args, &mut reply),
+                b'z' => self.breakpoint(false, args, &mut reply),
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        regs.rip = addr as usize;
+                    }
+                    return match cmd {
+                        b's' => Resume::Step,
+                        _ => Resume::Continue,
+                    };
+                }
+                b'D' => {
+                    self.remove_all_breakpoints();
+                    self.send(b"OK");
+                    return Resume::Continue;
+                }
+                // The SVSM can not be killed, just let it run
+                b'k' => {
+                    self.remove_all_breakpoints();
+                    return Resume::Continue;
+                }
+                b'H' => reply.push(b"OK"),
+                b'q' if args.starts_with(b"Supported") => {
+                    reply.push(b"PacketSize=400;swbreak+");
+                }
+                b'q' if args == b"Attached" => reply.push(b"1"),
+                // Unsupported commands get an empty reply
+                _ => {}
+            }
+            self.send(reply.as_bytes());
+        }
+    }
+
+    fn handle_exception(&mut self, regs: &mut X86Regs) {
+        let mut stop_reply: &[u8] = STOP_REPLY;
+
+        if regs.vector == BP_VECTOR {
+            // Report a breakpoint at its own address, not at the
+            // instruction after the int3
+            let addr = VirtAddr::from(regs.rip - 1);
+            if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+                regs.rip -= 1;
+                stop_reply = b"T05swbreak:;";
+            }
+        }
+
+        match self.session(regs, stop_reply) {
+            Resume::Continue => regs.flags &= !FLAGS_TF,
+            Resume::Step => regs.flags |= FLAGS_TF,
+        }
+    }
+}
+
+/// Handles #BP and #DB exceptions. Returns false if no debugger is attached,
+/// the exception is unexpected then.
+pub fn handle_debug_exception(regs: &mut X86Regs) -> bool {
+    assert!(regs.vector == BP_VECTOR || regs.vector == DB_VECTOR);
+
+    let mut stub = GDB_STUB.lock();
+    let Some(stub) = stub.as_mut() else {
+        return false;
+    };
+    stub.handle_exception(regs);
+    true
+}
+
+/// Sets up the stub on `port` and waits for GDB to connect, so that
+/// breakpoints can be set before the SVSM continues booting.
+pub fn gdbstub_start(port: &'static SerialPort<'static>) -> Result<(), SvsmError> {
+    if !port.is_present() {
+        return Err(SvsmError::MissingDevice("GDB serial port"));
+    }
+    port.init();
+
+    *GDB_STUB.lock() = Some(GdbStub {
+        port,
+        breakpoints: [None; MAX_BREAKPOINTS],
+    });
+
+    log::info!("Waiting for GDB on serial port {:#x}", port.port());
+    unsafe { asm!("int3") };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gdb_packet_parsing() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(parse_hex(b"ffffff8000001000"), Some(0xffff_ff80_0000_1000));
+        assert_eq!(parse_hex(b"12g4"), None);
+        assert_eq!(
+            parse_addr_len(b"ffffff8000001000,40"),
+            Some((VirtAddr::from(0xffff_ff80_0000_1000u64), 0x40))
+        );
+
+        let mut out = [0u8; 4];
+        assert_eq!(decode_hex(b"cc90", &mut out), Some(2));
+        assert_eq!(&out[..2], &[0xcc, 0x90]);
+        assert_eq!(decode_hex(b"cc9", &mut out), None);
+        assert_eq!(decode_hex(b"0011223344", &mut out), None);
+
+        let mut p = Packet::new();
+        p.push_hex(&[0xde, 0xad]);
+        assert_eq!(p.as_bytes(), b"dead");
+    }
+}
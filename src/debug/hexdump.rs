@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Hex dumps of memory to the log. No allocations and no faults on unmapped
+// addresses, so they are safe to use on error and panic paths.
+
+use crate::address::{Address, VirtAddr};
+use crate::mm::pagetable::translate_current;
+use core::fmt;
+
+const BYTES_PER_LINE: usize = 16;
+
+// One line of the dump: offset, the bytes in hex and as ASCII
+struct HexLine<'a> {
+    vaddr: VirtAddr,
+    bytes: &'a [u8],
+}
+
+impl fmt::Display for HexLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}: ", self.vaddr)?;
+        for i in 0..BYTES_PER_LINE {
+            match self.bytes.get(i) {
+                Some(b) => write!(f, "{:02x} ", b)?,
+                None => write!(f, "   ")?,
+            }
+        }
+        write!(f, "|")?;
+        for &b in self.bytes {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            write!(f, "{}", c)?;
+        }
+        write!(f, "|")
+    }
+}
+
+/// Logs `len` bytes of memory starting at `vaddr`. Lines in pages which are
+/// not mapped are reported as such instead of being read.
+pub fn hexdump(vaddr: VirtAddr, len: usize) {
+    let mut line = [0u8; BYTES_PER_LINE];
+    let mut off = 0;
+
+    while off < len {
+        let addr = vaddr.offset(off);
+        let n = BYTES_PER_LINE.min(len - off);
+
+        // A line may cross into the next page
+        let last = addr.offset(n - 1);
+        if translate_current(addr).is_none() || translate_current(last).is_none() {
+            log::info!("{:#018x}: <not mapped>", addr);
+        } else {
+            for (i, b) in line[..n].iter_mut().enumerate() {
+                *b = unsafe { addr.offset(i).as_ptr::<u8>().read_volatile() };
+            }
+            log::info!(
+                "{}",
+                HexLine {
+                    vaddr: addr,
+                    bytes: &line[..n],
+                }
+            );
+        }
+
+        off += n;
+    }
+}
+
+/// Logs a hex dump of memory, either `len` bytes at an address or the
+/// object behind a reference:
+///
+/// ```ignore
+/// hexdump!(vaddr, 64);
+/// hexdump!(&vmsa);
+/// ```
+#[macro_export]
+macro_rules! hexdump {
+    ($addr:expr, $len:expr) => {
+        $crate::debug::hexdump::hexdump($crate::address::VirtAddr::from($addr), $len)
+    };
+    ($obj:expr) => {{
+        let obj = $obj;
+        $crate::debug::hexdump::hexdump(
+            $crate::address::VirtAddr::from(obj as *const _ as *const u8),
+            core::mem::size_of_val(obj),
+        )
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_hexdump_line() {
+        let line = HexLine {
+            vaddr: VirtAddr::from(0xffff_ff80_0000_1000u64),
+            bytes: b"SVSM\x00\x01 ok",
+        };
+        let expected = format!(
+            "0xffffff8000001000: 53 56 53 4d 00 01 20 6f 6b {}|SVSM.. ok|",
+            " ".repeat(7 * 3)
+        );
+        assert_eq!(format!("{}", line), expected);
+    }
+}
@@ -4,4 +4,9 @@
 //
 // Author: Nicolai Stange <nstange@suse.de>
 
+#[cfg(feature = "enable-gdb")]
+pub mod gdbstub;
+pub mod hexdump;
+pub mod softlockup;
 pub mod stacktrace;
+pub mod trace;
@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Soft lockup detection. Every CPU takes a periodic local APIC timer tick
+// while it runs SVSM code and is watched by the CPU following it in the
+// per-cpu area list. When a CPU stops servicing its tick for too long, its
+// watcher reports it and asks for its stack with an NMI. This needs at
+// least two CPUs, and a CPU is only checked while its watcher runs SVSM
+// code itself. The timer ticks must never reach the guest, so the detector
+// only runs with Restricted Injection, and only if the host enables it.
+
+use super::stacktrace::{StackUnwinder, UnwoundStackFrame};
+use crate::address::VirtAddr;
+use crate::cpu::apic::{apic_timer_start, register_irq_handler, send_ipi, IpiDest, IpiKind};
+use crate::cpu::idt::X86Regs;
+use crate::cpu::percpu::{this_cpu, PERCPU_AREAS};
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::sev::hv_doorbell::restricted_injection;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+pub const SOFTLOCKUP_VECTOR: u8 = 0xef;
+
+// Timer ticks without progress before a CPU is reported, 0 disables the
+// detector. Decimal ASCII string.
+const SOFTLOCKUP_TICKS_FILE: &str = "opt/svsm/softlockup-ticks";
+const SOFTLOCKUP_TICKS_DEFAULT: u64 = 0;
+
+// 100ms per tick with the 1GHz APIC bus clock emulated by KVM, the timer
+// divides it by 16
+const SOFTLOCKUP_TIMER_COUNT: u32 = 6_250_000;
+
+const SOFTLOCKUP_MAX_FRAMES: usize = 16;
+
+const DUMP_NONE: u32 = 0;
+const DUMP_REQUESTED: u32 = 1;
+const DUMP_READY: u32 = 2;
+
+static SOFTLOCKUP_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Soft lockup state of a CPU. The progress tracking is only updated by the
+/// CPU watching it.
+#[derive(Debug)]
+pub struct SoftLockupState {
+    ticks: AtomicU64,
+    // Set while the CPU is not expected to take ticks, like while it runs
+    // the guest or before the detector was started on it
+    idle: AtomicBool,
+    last_seen: AtomicU64,
+    stalled: AtomicU64,
+    dump: AtomicU32,
+    frames: [AtomicU64; SOFTLOCKUP_MAX_FRAMES],
+    nframes: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FRAME_INIT: AtomicU64 = AtomicU64::new(0);
+
+impl SoftLockupState {
+    pub const fn new() -> Self {
+        SoftLockupState {
+            ticks: AtomicU64::new(0),
+            idle: AtomicBool::new(true),
+            last_seen: AtomicU64::new(0),
+            stalled: AtomicU64::new(0),
+            dump: AtomicU32::new(DUMP_NONE),
+            frames: [FRAME_INIT; SOFTLOCKUP_MAX_FRAMES],
+            nframes: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns true once per stall, when it reaches `threshold` ticks
+    fn check_progress(&self, threshold: u64) -> bool {
+        let ticks = self.ticks.load(Ordering::Relaxed);
+        if self.idle.load(Ordering::Relaxed)
+            || self.last_seen.swap(ticks, Ordering::Relaxed) != ticks
+        {
+            self.stalled.store(0, Ordering::Relaxed);
+            return false;
+        }
+
+        self.stalled.fetch_add(1, Ordering::Relaxed) + 1 == threshold
+    }
+
+    // Records the stack of the code the NMI interrupted, not the one of the
+    // NMI handler
+    fn record_frames(&self, regs: &X86Regs) {
+        let rbp = VirtAddr::from(regs.rbp);
+        let unwound = StackUnwinder::unwind_from(rbp).filter_map(|frame| match frame {
+            UnwoundStackFrame::Valid(item) => Some(u64::from(item.rip)),
+            UnwoundStackFrame::Invalid => None,
+        });
+
+        let mut n = 0;
+        for (slot, rip) in self
+            .frames
+            .iter()
+            .zip(Some(regs.rip as u64).into_iter().chain(unwound))
+        {
+            slot.store(rip, Ordering::Relaxed);
+            n += 1;
+        }
+        self.nframes.store(n, Ordering::Relaxed);
+    }
+
+    fn print_frames(&self) {
+        let n = self.nframes.load(Ordering::Relaxed);
+        log::error!("---BACKTRACE---:");
+        for frame in &self.frames[..n] {
+            log::error!("  [{:#018x}]", frame.load(Ordering::Relaxed));
+        }
+        log::error!("---END---");
+    }
+}
+
+impl Default for SoftLockupState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks the current CPU as not servicing its tick for its lifetime, for
+/// code which waits outside of the SVSM.
+#[derive(Debug)]
+pub struct SoftLockupIdle {
+    idle: bool,
+}
+
+impl SoftLockupIdle {
+    pub fn new() -> Self {
+        let state = this_cpu().softlockup();
+        SoftLockupIdle {
+            idle: state.idle.swap(true, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for SoftLockupIdle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SoftLockupIdle {
+    fn drop(&mut self) {
+        let state = this_cpu().softlockup();
+        // Whatever the CPU did while idle is no progress
+        state.ticks.fetch_add(1, Ordering::Relaxed);
+        state.idle.store(self.idle, Ordering::Relaxed);
+    }
+}
+
+fn softlockup_tick(_vector: u8) {
+    let cpu = this_cpu();
+    cpu.softlockup().ticks.fetch_add(1, Ordering::Relaxed);
+
+    let Some(buddy) = PERCPU_AREAS.next(cpu.get_apic_id()) else {
+        return;
+    };
+    let apic_id = buddy.get_apic_id();
+    let state = buddy.softlockup();
+
+    if state
+        .dump
+        .compare_exchange(DUMP_READY, DUMP_NONE, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+    {
+        log::error!("Stack of CPU {}:", apic_id);
+        state.print_frames();
+    }
+
+    let threshold = SOFTLOCKUP_TICKS.load(Ordering::Relaxed);
+    if !state.check_progress(threshold) {
+        return;
+    }
+
+    log::error!(
+        "Soft lockup: CPU {} did not service its timer for {} ticks",
+        apic_id,
+        threshold
+    );
+    state.dump.store(DUMP_REQUESTED, Ordering::Release);
+    if let Err(e) = send_ipi(IpiDest::Apic(apic_id), IpiKind::Nmi) {
+        log::error!("Failed to send NMI to CPU {}: {:?}", apic_id, e);
+    }
+}
+
+/// Records the stack of the current CPU when its watcher asked for it.
+/// Returns false for NMIs which were not sent by the detector. Only the
+/// watcher prints, the console or the GHCB might be in use on this CPU.
+pub fn handle_softlockup_nmi(regs: &X86Regs) -> bool {
+    let state = this_cpu().softlockup();
+    if state.dump.load(Ordering::Acquire) != DUMP_REQUESTED {
+        return false;
+    }
+
+    state.record_frames(regs);
+    state.dump.store(DUMP_READY, Ordering::Release);
+    true
+}
+
+fn softlockup_ticks(fw_cfg: &FwCfg) -> u64 {
+    let mut buf = [0u8; 24];
+    let len = match fw_cfg
+        .file_selector(SOFTLOCKUP_TICKS_FILE)
+        .and_then(|file| fw_cfg.read_file(&file, &mut buf))
+    {
+        Ok(len) => len,
+        Err(_) => return SOFTLOCKUP_TICKS_DEFAULT,
+    };
+
+    let ticks = core::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|s| s.trim_end_matches(['\0', '\n']).parse().ok());
+    ticks.unwrap_or_else(|| {
+        log::warn!("Invalid {}, using the default", SOFTLOCKUP_TICKS_FILE);
+        SOFTLOCKUP_TICKS_DEFAULT
+    })
+}
+
+/// Reads the detector configuration from the host. Must run before any CPU
+/// calls [`softlockup_start_cpu`].
+pub fn softlockup_init(fw_cfg: &FwCfg) -> Result<(), SvsmError> {
+    let ticks = softlockup_ticks(fw_cfg);
+    if ticks == 0 {
+        log::info!("Soft lockup detector disabled");
+        return Ok(());
+    }
+
+    // Without the #HV doorbell the hypervisor could deliver the ticks to
+    // the guest
+    if !restricted_injection() {
+        log::warn!("Soft lockup detector needs Restricted Injection, disabled");
+        return Ok(());
+    }
+
+    register_irq_handler(SOFTLOCKUP_VECTOR, softlockup_tick)?;
+    SOFTLOCKUP_TICKS.store(ticks, Ordering::Relaxed);
+    log::info!("Soft lockup detector enabled, threshold {} ticks", ticks);
+
+    Ok(())
+}
+
+/// Starts the timer tick of the current CPU and has its watcher check it.
+/// The CPU must regularly open an interrupt window from now on.
+pub fn softlockup_start_cpu() {
+    // The local APIC can only be reached through the GHCB, and the ticks
+    // are only delivered to the SVSM through the #HV doorbell
+    let cpu = this_cpu();
+    if SOFTLOCKUP_TICKS.load(Ordering::Relaxed) == 0
+        || !cpu.has_ghcb()
+        || cpu.hv_doorbell().is_none()
+    {
+        return;
+    }
+
+    match apic_timer_start(SOFTLOCKUP_VECTOR, SOFTLOCKUP_TIMER_COUNT) {
+        Ok(()) => cpu.softlockup().idle.store(false, Ordering::Relaxed),
+        Err(e) => log::warn!("Failed to start soft lockup timer: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_progress() {
+        let state = SoftLockupState::new();
+        state.idle.store(false, Ordering::Relaxed);
+
+        // Reported once, on the tick reaching the threshold
+        let reports: usize = (0..10).filter(|_| state.check_progress(3)).count();
+        assert_eq!(reports, 1);
+
+        state.ticks.fetch_add(1, Ordering::Relaxed);
+        assert!(!state.check_progress(1));
+        assert!(state.check_progress(1));
+
+        state.idle.store(true, Ordering::Relaxed);
+        assert!(!state.check_progress(1));
+    }
+}
@@ -8,7 +8,10 @@ use crate::address::{Address, VirtAddr};
 #[cfg(feature = "enable-stacktrace")]
 use crate::cpu::idt::{is_exception_handler_return_site, X86Regs};
 #[cfg(feature = "enable-stacktrace")]
-use crate::mm::address_space::{STACK_SIZE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE};
+use crate::mm::address_space::{
+    STACK_SIZE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE, SVSM_STACK_IST_HV_BASE,
+    SVSM_STACK_IST_VC_BASE,
+};
 #[cfg(feature = "enable-stacktrace")]
 use core::arch::asm;
 #[cfg(feature = "enable-stacktrace")]
@@ -47,7 +50,7 @@ pub enum UnwoundStackFrame {
 }
 
 #[cfg(feature = "enable-stacktrace")]
-type StacksBounds = [StackBounds; 2];
+type StacksBounds = [StackBounds; 4];
 
 #[cfg(feature = "enable-stacktrace")]
 pub struct StackUnwinder {
@@ -64,6 +67,12 @@ impl StackUnwinder {
                  options(att_syntax));
         };
 
+        Self::unwind_from(VirtAddr::from(rbp))
+    }
+
+    /// Unwinds the stack of the current CPU starting at the frame pointer
+    /// `rbp`, like the one of code interrupted by an exception.
+    pub fn unwind_from(rbp: VirtAddr) -> Self {
         let stacks: StacksBounds = [
             StackBounds {
                 bottom: VirtAddr::from(SVSM_STACKS_INIT_TASK),
@@ -73,9 +82,17 @@ impl StackUnwinder {
                 bottom: VirtAddr::from(SVSM_STACK_IST_DF_BASE),
                 top: VirtAddr::from(SVSM_STACK_IST_DF_BASE + STACK_SIZE),
             },
+            StackBounds {
+                bottom: VirtAddr::from(SVSM_STACK_IST_VC_BASE),
+                top: VirtAddr::from(SVSM_STACK_IST_VC_BASE + STACK_SIZE),
+            },
+            StackBounds {
+                bottom: VirtAddr::from(SVSM_STACK_IST_HV_BASE),
+                top: VirtAddr::from(SVSM_STACK_IST_HV_BASE + STACK_SIZE),
+            },
         ];
 
-        Self::new(VirtAddr::from(rbp), stacks)
+        Self::new(rbp, stacks)
     }
 
     fn new(rbp: VirtAddr, stacks: StacksBounds) -> Self {
@@ -233,6 +250,10 @@ impl StackUnwinder {
     pub fn unwind_this_cpu() -> Self {
         Self
     }
+
+    pub fn unwind_from(_rbp: VirtAddr) -> Self {
+        Self
+    }
 }
 
 #[cfg(not(feature = "enable-stacktrace"))]
@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+#[cfg(feature = "lock-stats")]
+use crate::console::WRITER;
+#[cfg(feature = "lock-stats")]
+use crate::locking::LockStats;
+use crate::locking::SpinLock;
+#[cfg(feature = "lock-stats")]
+use crate::log_buffer::LOG_BUFFER;
+use crate::utils::FixedBitmap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of protocol requests kept in the trace buffer
+pub const REQUEST_TRACE_ENTRIES: usize = 256;
+
+/// Number of APIC IDs the vCPU filter can tell apart
+pub const TRACE_FILTER_CPUS: usize = 256;
+
+bitflags::bitflags! {
+    /// Parts of the SVSM which record trace events
+    pub struct TraceSubsystems: u64 {
+        const REQUESTS = 1 << 0;
+    }
+}
+
+// Filters are checked on every trace site, so they are plain atomics which
+// can be read without taking a lock. Everything is traced by default.
+static TRACE_SUBSYSTEMS: AtomicU64 = AtomicU64::new(TraceSubsystems::all().bits());
+// Bit n enables protocol n, bit 63 covers all protocols from 63 upwards
+static TRACE_PROTOCOLS: AtomicU64 = AtomicU64::new(u64::MAX);
+// vCPUs which are not traced, by APIC ID
+static TRACE_CPUS_MASKED: FixedBitmap<{ TRACE_FILTER_CPUS / 64 }> = FixedBitmap::new();
+
+/// Checks whether `subsystem` records trace events at all
+pub fn trace_enabled(subsystem: TraceSubsystems) -> bool {
+    TRACE_SUBSYSTEMS.load(Ordering::Relaxed) & subsystem.bits() != 0
+}
+
+pub fn trace_subsystems() -> TraceSubsystems {
+    TraceSubsystems::from_bits_truncate(TRACE_SUBSYSTEMS.load(Ordering::Relaxed))
+}
+
+pub fn trace_set_subsystems(subsystems: TraceSubsystems) {
+    TRACE_SUBSYSTEMS.store(subsystems.bits(), Ordering::Relaxed);
+}
+
+pub fn trace_protocols() -> u64 {
+    TRACE_PROTOCOLS.load(Ordering::Relaxed)
+}
+
+pub fn trace_set_protocols(mask: u64) {
+    TRACE_PROTOCOLS.store(mask, Ordering::Relaxed);
+}
+
+/// Enables or disables tracing for the vCPU with `apic_id`, or for all
+/// vCPUs if it is `None`. APIC IDs must be below [`TRACE_FILTER_CPUS`].
+pub fn trace_set_cpu(apic_id: Option<u32>, enabled: bool) {
+    match (apic_id, enabled) {
+        (Some(id), true) => {
+            TRACE_CPUS_MASKED.clear(id as usize);
+        }
+        (Some(id), false) => {
+            TRACE_CPUS_MASKED.set(id as usize);
+        }
+        (None, true) => TRACE_CPUS_MASKED.clear_range(0, TRACE_FILTER_CPUS),
+        (None, false) => TRACE_CPUS_MASKED.set_range(0, TRACE_FILTER_CPUS),
+    }
+}
+
+/// Checks whether a request of `protocol` issued by the vCPU with `apic_id`
+/// passes the trace filters
+pub fn trace_request_enabled(apic_id: u32, protocol: u32) -> bool {
+    if !trace_enabled(TraceSubsystems::REQUESTS) {
+        return false;
+    }
+
+    let bit = protocol.min(63);
+    if trace_protocols() & (1 << bit) == 0 {
+        return false;
+    }
+
+    (apic_id as usize) >= TRACE_FILTER_CPUS || !TRACE_CPUS_MASKED.test(apic_id as usize)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTraceEntry {
+    /// APIC ID of the vCPU which issued the request
+    pub apic_id: u32,
+    pub protocol: u32,
+    pub request: u32,
+    /// Hash of the request parameters, to spot repeated identical calls
+    /// without recording guest data
+    pub params_hash: u64,
+    pub tsc_start: u64,
+    pub tsc_end: u64,
+    /// Result code returned to the guest in RAX
+    pub result: u64,
+}
+
+impl RequestTraceEntry {
+    pub fn cycles(&self) -> u64 {
+        self.tsc_end.wrapping_sub(self.tsc_start)
+    }
+}
+
+#[derive(Debug)]
+struct RequestTrace {
+    entries: [RequestTraceEntry; REQUEST_TRACE_ENTRIES],
+    // Index of the next entry to overwrite
+    next: usize,
+    // Number of requests recorded since the last reset
+    total: u64,
+}
+
+impl RequestTrace {
+    const fn new() -> Self {
+        const EMPTY: RequestTraceEntry = RequestTraceEntry {
+            apic_id: 0,
+            protocol: 0,
+            request: 0,
+            params_hash: 0,
+            tsc_start: 0,
+            tsc_end: 0,
+            result: 0,
+        };
+
+        RequestTrace {
+            entries: [EMPTY; REQUEST_TRACE_ENTRIES],
+            next: 0,
+            total: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.total.min(REQUEST_TRACE_ENTRIES as u64) as usize
+    }
+
+    // Entries from oldest to newest
+    fn iter(&self) -> impl Iterator<Item = &RequestTraceEntry> {
+        let len = self.len();
+        let first = (self.next + REQUEST_TRACE_ENTRIES - len) % REQUEST_TRACE_ENTRIES;
+        (0..len).map(move |i| &self.entries[(first + i) % REQUEST_TRACE_ENTRIES])
+    }
+}
+
+static REQUEST_TRACE: SpinLock<RequestTrace> = SpinLock::new(RequestTrace::new());
+
+/// FNV-1a hash over request parameter registers
+pub fn trace_params_hash(params: &[u64]) -> u64 {
+    params
+        .iter()
+        .flat_map(|p| p.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+/// Records a request, overwriting the oldest entry if the buffer is full.
+pub fn trace_request(entry: RequestTraceEntry) {
+    let mut trace = REQUEST_TRACE.lock();
+    let next = trace.next;
+
+    trace.entries[next] = entry;
+    trace.next = (next + 1) % REQUEST_TRACE_ENTRIES;
+    trace.total += 1;
+}
+
+/// Writes the recorded requests to the log, oldest first.
+pub fn trace_dump() {
+    let trace = REQUEST_TRACE.lock();
+
+    log::info!(
+        "Request trace: {} requests recorded, showing last {}",
+        trace.total,
+        trace.len()
+    );
+    for e in trace.iter() {
+        log::info!(
+            "  CPU {:3} protocol {} call {:#x} params {:#018x} result {:#x} tsc {:#x} cycles {}",
+            e.apic_id,
+            e.protocol,
+            e.request,
+            e.params_hash,
+            e.result,
+            e.tsc_start,
+            e.cycles()
+        );
+    }
+}
+
+pub fn trace_reset() {
+    let mut trace = REQUEST_TRACE.lock();
+    trace.next = 0;
+    trace.total = 0;
+}
+
+#[cfg(feature = "lock-stats")]
+fn log_lock_stats(name: &str, stats: LockStats) {
+    log::info!(
+        "  {:<14} acquisitions {:10} contended {:10} spins {:12}",
+        name,
+        stats.acquisitions,
+        stats.contended,
+        stats.spins
+    );
+}
+
+/// Writes the contention counters of the most frequently shared global
+/// locks to the log.
+#[cfg(feature = "lock-stats")]
+pub fn trace_dump_lock_stats() {
+    // Collect first, logging takes the console and log buffer locks
+    let stats = [
+        ("console", WRITER.stats()),
+        ("log buffer", LOG_BUFFER.stats()),
+        ("request trace", REQUEST_TRACE.stats()),
+    ];
+
+    log::info!("Lock statistics:");
+    for (name, s) in stats {
+        log_lock_stats(name, s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_filters() {
+        assert!(trace_request_enabled(1, 0));
+
+        trace_set_protocols(1 << 1);
+        assert!(!trace_request_enabled(1, 0));
+        assert!(trace_request_enabled(1, 1));
+        trace_set_protocols(1 << 63);
+        assert!(trace_request_enabled(1, 100));
+        trace_set_protocols(u64::MAX);
+
+        trace_set_cpu(None, false);
+        trace_set_cpu(Some(2), true);
+        assert!(!trace_request_enabled(1, 0));
+        assert!(trace_request_enabled(2, 0));
+        // Beyond the reach of the filter
+        assert!(trace_request_enabled(TRACE_FILTER_CPUS as u32, 0));
+        trace_set_cpu(None, true);
+
+        trace_set_subsystems(TraceSubsystems::empty());
+        assert!(!trace_request_enabled(1, 0));
+        trace_set_subsystems(TraceSubsystems::all());
+        assert!(trace_request_enabled(1, 0));
+    }
+}
@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::console::console_flush;
+use crate::cpu::flush_tlb_global_sync;
+use crate::cpu::percpu::this_cpu;
+use crate::mm::scrub::{scrub_pending, scrub_work, SCRUB_BUDGET};
+
+bitflags::bitflags! {
+    /// Work a CPU postpones until it is about to return to the guest
+    pub struct DeferredWork: u32 {
+        /// Flush TLBs on all CPUs, e.g. after guest access to a page was
+        /// revoked
+        const TLB_FLUSH = 1 << 0;
+        /// Make progress on the scrub queue
+        const SCRUB = 1 << 1;
+        /// Push console output out of the device
+        const LOG_FLUSH = 1 << 2;
+    }
+}
+
+/// Queues `work` on the current CPU. It runs before the CPU next returns
+/// control to the guest, outside of any protocol handler.
+pub fn defer_work(work: DeferredWork) {
+    this_cpu().defer_work(work);
+}
+
+/// Runs all work deferred on the current CPU. Work which can not be
+/// finished in one go is deferred again.
+pub fn run_deferred_work() {
+    let work = this_cpu().take_deferred_work();
+
+    // Guest access changes need to be visible before anything else
+    if work.contains(DeferredWork::TLB_FLUSH) {
+        flush_tlb_global_sync();
+    }
+
+    if work.contains(DeferredWork::SCRUB) {
+        scrub_work(SCRUB_BUDGET);
+        if scrub_pending() {
+            defer_work(DeferredWork::SCRUB);
+        }
+    }
+
+    if work.contains(DeferredWork::LOG_FLUSH) {
+        console_flush();
+    }
+}
@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::address::PhysAddr;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::io::IOPort;
+use crate::mmio::mmio_read;
+use crate::pci::{
+    PciAddress, PciConfigAccess, PciLegacyConfig, PCI_MAX_DEVICES, PCI_REG_ID, PCI_REG_SUBSYSTEM,
+};
+use crate::serial::{SerialPort, SERIAL_PORT};
+use crate::virtio::{
+    VIRTIO_PCI_MODERN_BASE, VIRTIO_PCI_TRANSITIONAL_FIRST, VIRTIO_PCI_TRANSITIONAL_LAST,
+    VIRTIO_PCI_VENDOR,
+};
+use core::fmt;
+
+pub const TPM_CRB_BASE: u64 = 0xfed4_0000;
+const TPM_CRB_INTF_ID: u64 = 0x30;
+const TPM_CRB_INTF_TYPE_MASK: u32 = 0xf;
+const TPM_CRB_INTF_TYPE_CRB: u32 = 1;
+
+/// A device the host is expected to provide and how to find it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// QEMU firmware configuration interface
+    FwCfg,
+    /// 16550 compatible UART at an I/O port
+    Serial(u16),
+    /// TPM with a command response buffer interface at a physical address
+    TpmCrb(u64),
+    /// Virtio PCI device with a virtio device ID, looked for on bus 0
+    Virtio(u16),
+}
+
+// Tells where the device was looked for, so that the VM definition can be
+// fixed without reading code
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FwCfg => write!(f, "fw_cfg I/O interface"),
+            Self::Serial(port) => write!(f, "UART at I/O port {:#x}", port),
+            Self::TpmCrb(base) => write!(f, "TPM CRB interface at {:#x}", base),
+            Self::Virtio(id) => write!(f, "virtio PCI device with ID {} on bus 0", id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExpectedDevice {
+    pub name: &'static str,
+    pub kind: DeviceKind,
+    /// Boot stops when a required device is missing
+    pub required: bool,
+}
+
+/// Devices the SVSM expects from the host. The console can be moved off the
+/// serial port and the TPM is only used when present.
+pub static DEVICE_MANIFEST: &[ExpectedDevice] = &[
+    ExpectedDevice {
+        name: "fw_cfg",
+        kind: DeviceKind::FwCfg,
+        required: true,
+    },
+    ExpectedDevice {
+        name: "COM1",
+        kind: DeviceKind::Serial(SERIAL_PORT),
+        required: false,
+    },
+    ExpectedDevice {
+        name: "TPM",
+        kind: DeviceKind::TpmCrb(TPM_CRB_BASE),
+        required: false,
+    },
+];
+
+fn virtio_present(io: &dyn IOPort, virtio_id: u16) -> bool {
+    let cfg = PciLegacyConfig::new(io);
+    (0..PCI_MAX_DEVICES).any(|slot| {
+        let addr = PciAddress::new(0, slot, 0);
+        let Ok(id) = cfg.read32(addr, PCI_REG_ID) else {
+            return false;
+        };
+        let (vendor, device) = (id as u16, (id >> 16) as u16);
+        if vendor != VIRTIO_PCI_VENDOR {
+            return false;
+        }
+
+        match device {
+            VIRTIO_PCI_TRANSITIONAL_FIRST..=VIRTIO_PCI_TRANSITIONAL_LAST => {
+                matches!(cfg.read16(addr, PCI_REG_SUBSYSTEM + 2), Ok(id) if id == virtio_id)
+            }
+            _ => device.checked_sub(VIRTIO_PCI_MODERN_BASE) == Some(virtio_id),
+        }
+    })
+}
+
+fn tpm_crb_present(base: u64) -> bool {
+    let intf_id = mmio_read::<u32>(PhysAddr::from(base + TPM_CRB_INTF_ID));
+
+    // Reads without a device behind them return all ones
+    matches!(intf_id, Ok(id) if id != 0xffff_ffff
+        && id & TPM_CRB_INTF_TYPE_MASK == TPM_CRB_INTF_TYPE_CRB)
+}
+
+fn device_present(io: &dyn IOPort, kind: DeviceKind) -> bool {
+    match kind {
+        DeviceKind::FwCfg => FwCfg::new(io).is_present(),
+        DeviceKind::Serial(port) => SerialPort::new(io, port).is_present(),
+        DeviceKind::TpmCrb(base) => tpm_crb_present(base),
+        DeviceKind::Virtio(id) => virtio_present(io, id),
+    }
+}
+
+// Reports every device before failing, so one boot shows all that is wrong
+fn check_devices<F>(manifest: &[ExpectedDevice], mut present: F) -> Result<(), SvsmError>
+where
+    F: FnMut(DeviceKind) -> bool,
+{
+    let mut missing = None;
+
+    for dev in manifest {
+        if present(dev.kind) {
+            log::info!("Device {}: found {}", dev.name, dev.kind);
+        } else if dev.required {
+            log::error!(
+                "Device {}: missing {}, add it to the VM",
+                dev.name,
+                dev.kind
+            );
+            missing.get_or_insert(dev.name);
+        } else {
+            log::info!("Device {}: no {}, continuing without", dev.name, dev.kind);
+        }
+    }
+
+    match missing {
+        Some(name) => Err(SvsmError::MissingDevice(name)),
+        None => Ok(()),
+    }
+}
+
+/// Probes the devices in `manifest` through `io` and the GHCB of the current
+/// CPU. Fails with the first required device that is missing.
+pub fn probe_devices(manifest: &[ExpectedDevice], io: &dyn IOPort) -> Result<(), SvsmError> {
+    check_devices(manifest, |kind| device_present(io, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_devices() {
+        assert!(check_devices(DEVICE_MANIFEST, |kind| kind == DeviceKind::FwCfg).is_ok());
+
+        let manifest = [
+            ExpectedDevice {
+                name: "rng",
+                kind: DeviceKind::Virtio(4),
+                required: true,
+            },
+            ExpectedDevice {
+                name: "vsock",
+                kind: DeviceKind::Virtio(19),
+                required: true,
+            },
+        ];
+        let mut probed = 0;
+        let res = check_devices(&manifest, |_| {
+            probed += 1;
+            false
+        });
+        assert!(matches!(res, Err(SvsmError::MissingDevice("rng"))));
+        assert_eq!(probed, 2);
+    }
+}
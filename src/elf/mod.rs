@@ -59,6 +59,8 @@ pub enum ElfError {
     UnrecognizedRelocationType,
     InvalidRelocationOffset,
     RelocationAgainstUndefSymbol,
+
+    InvalidNote,
 }
 
 impl fmt::Display for ElfError {
@@ -168,6 +170,10 @@ impl fmt::Display for ElfError {
             Self::RelocationAgainstUndefSymbol => {
                 write!(f, "ELF relocation against undefined symbol")
             }
+
+            Self::InvalidNote => {
+                write!(f, "invalid ELF note")
+            }
         }
     }
 }
@@ -254,7 +260,6 @@ pub struct Elf64File<'a> {
     elf_hdr: Elf64Hdr,
     load_segments: Elf64LoadSegments,
     max_load_segment_align: Elf64Xword,
-    #[allow(unused)]
     sh_strtab: Option<Elf64Strtab<'a>>,
     dynamic: Option<Elf64Dynamic>,
 }
@@ -465,6 +470,30 @@ impl<'a> Elf64File<'a> {
         Elf64ShdrIterator::new(self)
     }
 
+    /// Returns the file contents of the first section named `name`, if any.
+    pub fn section_by_name(&self, name: &str) -> Result<Option<&'a [u8]>, ElfError> {
+        let sh_strtab = match &self.sh_strtab {
+            Some(sh_strtab) => sh_strtab,
+            None => return Ok(None),
+        };
+
+        for shdr in self.shdrs_iter() {
+            if shdr.sh_type == Elf64Shdr::SHT_NULL {
+                continue;
+            }
+            if sh_strtab.get_str(shdr.sh_name)?.to_bytes() != name.as_bytes() {
+                continue;
+            }
+
+            let file_range = shdr.file_range();
+            return Ok(Some(
+                &self.elf_file_buf[file_range.offset_begin..file_range.offset_end],
+            ));
+        }
+
+        Ok(None)
+    }
+
     fn verify_dynamic(dynamic: &Elf64Dynamic) -> Result<(), ElfError> {
         dynamic.verify()?;
         Ok(())
@@ -1246,7 +1275,6 @@ impl<'a> Elf64Strtab<'a> {
         Self { strtab_buf }
     }
 
-    #[allow(unused)]
     fn get_str(&self, index: Elf64Word) -> Result<&'a ffi::CStr, ElfError> {
         let index = usize::try_from(index).unwrap();
         if index >= self.strtab_buf.len() {
@@ -1404,6 +1432,82 @@ impl<'a> Iterator for Elf64ShdrIterator<'a> {
     }
 }
 
+/// A single entry of an ELF note section or segment.
+#[derive(Debug)]
+pub struct Elf64Note<'a> {
+    pub name: &'a [u8],
+    pub n_type: Elf64Word,
+    pub desc: &'a [u8],
+}
+
+/// Iterates over the entries in the contents of an ELF note section.
+/// Names and descriptors are padded to four bytes.
+pub struct Elf64NoteIterator<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Elf64NoteIterator<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn padded_len(len: Elf64Word) -> Option<usize> {
+        usize::try_from(len).ok()?.checked_add(3).map(|l| l & !3)
+    }
+
+    fn read_note(&mut self) -> Result<Elf64Note<'a>, ElfError> {
+        if self.buf.len() < 12 {
+            return Err(ElfError::InvalidNote);
+        }
+
+        let n_namesz = Elf64Word::from_le_bytes(self.buf[0..4].try_into().unwrap());
+        let n_descsz = Elf64Word::from_le_bytes(self.buf[4..8].try_into().unwrap());
+        let n_type = Elf64Word::from_le_bytes(self.buf[8..12].try_into().unwrap());
+
+        let name_off: usize = 12;
+        let desc_off = Self::padded_len(n_namesz)
+            .and_then(|l| l.checked_add(name_off))
+            .ok_or(ElfError::InvalidNote)?;
+        let end = Self::padded_len(n_descsz)
+            .and_then(|l| l.checked_add(desc_off))
+            .ok_or(ElfError::InvalidNote)?;
+        if end > self.buf.len() {
+            return Err(ElfError::InvalidNote);
+        }
+
+        // The name includes the terminating NUL, which is not part of the
+        // returned name.
+        let name = &self.buf[name_off..(name_off + n_namesz as usize)];
+        let name = match name.split_last() {
+            Some((0, name)) => name,
+            Some(_) => return Err(ElfError::InvalidNote),
+            None => name,
+        };
+        let desc = &self.buf[desc_off..(desc_off + n_descsz as usize)];
+
+        self.buf = &self.buf[end..];
+
+        Ok(Elf64Note { name, n_type, desc })
+    }
+}
+
+impl<'a> Iterator for Elf64NoteIterator<'a> {
+    type Item = Result<Elf64Note<'a>, ElfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let note = self.read_note();
+        if note.is_err() {
+            // Stop after the first malformed entry
+            self.buf = &[];
+        }
+        Some(note)
+    }
+}
+
 #[derive(Debug)]
 pub struct Elf64RelocOp {
     pub dst: Elf64Addr,
@@ -1600,3 +1704,74 @@ impl<'a, RP: Elf64RelocProcessor> Iterator for Elf64AppliedRelaIterator<'a, RP>
         Some(Ok(Some(reloc_op)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(name: &[u8], n_type: Elf64Word, desc: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(name.len() as Elf64Word).to_le_bytes());
+        buf.extend_from_slice(&(desc.len() as Elf64Word).to_le_bytes());
+        buf.extend_from_slice(&n_type.to_le_bytes());
+        buf.extend_from_slice(name);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf.extend_from_slice(desc);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    #[test]
+    fn test_notes() {
+        let mut buf = note(b"SVSM\0", 1, &[1, 2, 3, 4, 5]);
+        buf.extend(note(b"", 2, &[]));
+
+        let mut notes = Elf64NoteIterator::new(&buf);
+        let n = notes.next().unwrap().unwrap();
+        assert_eq!(
+            (n.name, n.n_type, n.desc),
+            (&b"SVSM"[..], 1, &[1, 2, 3, 4, 5][..])
+        );
+        let n = notes.next().unwrap().unwrap();
+        assert_eq!((n.name, n.n_type, n.desc), (&b""[..], 2, &[][..]));
+        assert!(notes.next().is_none());
+    }
+
+    #[test]
+    fn test_notes_truncated() {
+        let buf = note(b"SVSM\0", 1, &[0; 16]);
+
+        // Cut off in the header, the name and the descriptor
+        for len in [4, 14, buf.len() - 4] {
+            let mut notes = Elf64NoteIterator::new(&buf[..len]);
+            assert!(matches!(notes.next(), Some(Err(ElfError::InvalidNote))));
+            assert!(notes.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_notes_malformed() {
+        // Name without terminating NUL
+        let buf = note(b"SVSM", 1, &[]);
+        let mut notes = Elf64NoteIterator::new(&buf);
+        assert!(matches!(notes.next(), Some(Err(ElfError::InvalidNote))));
+
+        // Sizes reaching beyond the end of the note
+        let mut buf = note(b"SVSM\0", 1, &[]);
+        buf[0..4].copy_from_slice(&Elf64Word::MAX.to_le_bytes());
+        let mut notes = Elf64NoteIterator::new(&buf);
+        assert!(matches!(notes.next(), Some(Err(ElfError::InvalidNote))));
+
+        let mut buf = note(b"SVSM\0", 1, &[]);
+        buf[4..8].copy_from_slice(&Elf64Word::MAX.to_le_bytes());
+        let mut notes = Elf64NoteIterator::new(&buf);
+        assert!(matches!(notes.next(), Some(Err(ElfError::InvalidNote))));
+
+        // Nothing is returned after a malformed entry
+        let mut buf = note(b"SVSM", 1, &[]);
+        buf.extend(note(b"SVSM\0", 2, &[]));
+        let mut notes = Elf64NoteIterator::new(&buf);
+        assert!(notes.next().unwrap().is_err());
+        assert!(notes.next().is_none());
+    }
+}
@@ -1,8 +1,25 @@
+use crate::console::ConsoleError;
+use crate::cpu::apic::ApicError;
+use crate::cpu::cpuid::CpuidError;
+use crate::cpu::ioapic::IoApicError;
+use crate::cpu::vc::VcError;
+use crate::crypto::rng::RngError;
+use crate::crypto::CryptoError;
 use crate::fs::FsError;
 use crate::fw_cfg::FwCfgError;
+use crate::log_filter::LogFilterError;
+use crate::mm::bootmem::BootMemError;
+use crate::pci::PciError;
+use crate::serial::SerialError;
 use crate::sev::ghcb::GhcbError;
+use crate::sev::guest_msg::GuestMsgError;
 use crate::sev::msr_protocol::GhcbMsrError;
+use crate::sev::secrets_page::SecretsPageError;
 use crate::sev::SevSnpError;
+use crate::state_store::StateError;
+use crate::utils::immut_after_init::ImmutAfterInitError;
+use crate::virtio::VirtioError;
+use core::fmt;
 
 // As a general rule, functions private to a given module may use the
 // leaf error types. Public functions should return an SvsmError
@@ -23,6 +40,8 @@ pub enum SvsmError {
     MissingVMSA,
     // There is no CAA
     MissingCAA,
+    // A device required by the device manifest was not found
+    MissingDevice(&'static str),
     // Invalid address, usually provided by the guest
     InvalidAddress,
     // Errors related to firmware parsing
@@ -33,4 +52,155 @@ pub enum SvsmError {
     Acpi,
     // Errors from file systems
     FileSystem(FsError),
+    // A guest context exceeded its resource quota
+    QuotaExceeded,
+    // Errors from #VC handler
+    Vc(VcError),
+    // Errors related to the local APIC
+    Apic(ApicError),
+    // Errors related to I/O APIC programming
+    IoApic(IoApicError),
+    // Errors from the random number generator
+    Rng(RngError),
+    // Errors from cryptographic primitives
+    Crypto(CryptoError),
+    // Errors related to the SEV-SNP secrets page
+    SecretsPage(SecretsPageError),
+    // Errors from encrypted messages to the PSP
+    GuestMsg(GuestMsgError),
+    // Invalid log filter specification
+    LogFilter(LogFilterError),
+    // Invalid CPUID page
+    Cpuid(CpuidError),
+    // Errors related to PCI configuration space accesses
+    Pci(PciError),
+    // Errors reported by virtio drivers
+    Virtio(VirtioError),
+    // Errors of the persistent state store
+    State(StateError),
+    // Conflicts in the boot loader's memory map
+    BootMem(BootMemError),
+    // A global was initialized twice or used before its initialization
+    ImmutAfterInit(ImmutAfterInitError),
+    // Invalid serial port configuration
+    Serial(SerialError),
+    // Errors related to console writers
+    Console(ConsoleError),
+}
+
+/// Maximum number of frames an [`ErrorContext`] keeps. Further frames are
+/// only counted.
+pub const ERROR_CONTEXT_DEPTH: usize = 4;
+
+/// An [`SvsmError`] together with a trail of static descriptions of what
+/// each layer it passed through was doing. Needs no allocation, so it is
+/// usable before the heap is set up and on the panic path.
+#[derive(Clone, Copy)]
+pub struct ErrorContext {
+    error: SvsmError,
+    // Innermost frame first
+    frames: [&'static str; ERROR_CONTEXT_DEPTH],
+    len: u8,
+    dropped: u8,
+}
+
+impl ErrorContext {
+    pub fn new(error: SvsmError) -> Self {
+        ErrorContext {
+            error,
+            frames: [""; ERROR_CONTEXT_DEPTH],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn error(&self) -> SvsmError {
+        self.error
+    }
+
+    /// Returns the context frames, innermost first
+    pub fn frames(&self) -> &[&'static str] {
+        &self.frames[..self.len as usize]
+    }
+
+    fn push(mut self, ctx: &'static str) -> Self {
+        if (self.len as usize) < ERROR_CONTEXT_DEPTH {
+            self.frames[self.len as usize] = ctx;
+            self.len += 1;
+        } else {
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        self
+    }
+}
+
+impl From<SvsmError> for ErrorContext {
+    fn from(error: SvsmError) -> Self {
+        Self::new(error)
+    }
+}
+
+impl From<ErrorContext> for SvsmError {
+    fn from(ctx: ErrorContext) -> Self {
+        ctx.error
+    }
+}
+
+// Prints the outermost frame first, e.g.
+// "finding kernel region: reading E820 map: FwCfg(FileNotFound)"
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dropped > 0 {
+            write!(f, "[{} more]: ", self.dropped)?;
+        }
+        for frame in self.frames().iter().rev() {
+            write!(f, "{}: ", frame)?;
+        }
+        write!(f, "{:?}", self.error)
+    }
+}
+
+// Same as Display, so that expect() prints the whole trail
+impl fmt::Debug for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Attaches context to the error of a `Result`, see [`ErrorContext`].
+pub trait Context<T> {
+    fn context(self, ctx: &'static str) -> Result<T, ErrorContext>;
+}
+
+impl<T, E: Into<ErrorContext>> Context<T> for Result<T, E> {
+    fn context(self, ctx: &'static str) -> Result<T, ErrorContext> {
+        self.map_err(|e| e.into().push(ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_error_context() {
+        let res: Result<(), SvsmError> = Err(SvsmError::Mem);
+        let err = res
+            .context("inner")
+            .context("outer")
+            .expect_err("context must keep the error");
+
+        assert_eq!(err.frames(), &["inner", "outer"]);
+        assert!(matches!(err.error(), SvsmError::Mem));
+        assert_eq!(format!("{}", err), "outer: inner: Mem");
+
+        let mut res: Result<(), ErrorContext> = Err(SvsmError::Acpi.into());
+        for _ in 0..ERROR_CONTEXT_DEPTH + 2 {
+            res = res.context("frame");
+        }
+        assert!(format!("{:?}", res.unwrap_err()).starts_with("[2 more]: frame: "));
+    }
 }
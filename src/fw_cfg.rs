@@ -6,23 +6,52 @@
 
 extern crate alloc;
 
-use crate::error::SvsmError;
+use crate::error::{Context, ErrorContext, SvsmError};
+use crate::mm::bootmem::BootMemoryMap;
 use crate::mm::pagetable::max_phys_addr;
+use crate::types::{MemoryRegion, MemoryRegionSet, PAGE_SIZE};
 
 use super::io::IOPort;
-use super::string::FixedString;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::cell::RefCell;
 use core::mem::size_of;
 
 const FW_CFG_CTL: u16 = 0x510;
 const FW_CFG_DATA: u16 = 0x511;
 
+const FW_CFG_SIGNATURE: u16 = 0x00;
 const _FW_CFG_ID: u16 = 0x01;
 const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_NAME_LEN: usize = 56;
 
 // Must be a power-of-2
 const KERNEL_REGION_SIZE: u64 = 16 * 1024 * 1024;
-const KERNEL_REGION_SIZE_MASK: u64 = !(KERNEL_REGION_SIZE - 1);
+// Smallest kernel region size the host may ask for
+const KERNEL_REGION_SIZE_MIN: u64 = 4 * 1024 * 1024;
+
+// Size of an E820 entry as provided by QEMU, and of one with the ACPI 3.0
+// extended attributes appended
+const E820_ENTRY_SIZE: usize = 20;
+const E820_ENTRY_SIZE_EXT: usize = 24;
+// Extended attribute marking the entry as valid, others must be ignored
+const E820_ATTR_ENABLED: u32 = 1 << 0;
+
+// Upper bounds for counts the host provides, far above what QEMU uses. The
+// host is not trusted, so nothing it sends may make the SVSM loop or
+// allocate without bound.
+const FW_CFG_MAX_FILES: u32 = 4096;
+const E820_MAX_ENTRIES: usize = 1024;
+const FLASH_MAX_REGIONS: usize = 16;
+
+// Size of a memory region item: 64 bit start address and size
+const MEMORY_REGION_SIZE: u32 = 16;
+
+// Optional host-provided kernel region size, as a decimal or 0x-prefixed
+// hexadecimal ASCII string in bytes
+const KERNEL_REGION_SIZE_FILE: &str = "opt/svsm/kernel-region-size";
 
 //use crate::println;
 
@@ -30,6 +59,8 @@ const KERNEL_REGION_SIZE_MASK: u64 = !(KERNEL_REGION_SIZE - 1);
 
 pub struct FwCfg<'a> {
     driver: &'a dyn IOPort,
+    // File directory, read on the first lookup
+    files: RefCell<Option<BTreeMap<String, FwCfgFile>>>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -40,6 +71,10 @@ pub enum FwCfgError {
     FileSize(u32),
     // Could not find an appropriate kernel region for the SVSM.
     KernelRegion,
+    // The file directory lists more files than supported.
+    DirectorySize(u32),
+    // A memory region lies outside of the physical address space.
+    InvalidRegion,
 }
 
 impl From<FwCfgError> for SvsmError {
@@ -48,6 +83,7 @@ impl From<FwCfgError> for SvsmError {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct FwCfgFile {
     size: u32,
     selector: u16,
@@ -62,30 +98,65 @@ impl FwCfgFile {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct MemoryRegion {
-    pub start: u64,
-    pub end: u64,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum E820Type {
+    Ram,
+    Reserved,
+    Acpi,
+    Nvs,
+    Unusable,
+    Unknown(u32),
 }
 
-impl MemoryRegion {
-    /// Returns `true` if the region overlaps with another region with given
-    /// start and end.
-    pub fn overlaps(&self, start: u64, end: u64) -> bool {
-        self.start < end && start < self.end
+impl From<u32> for E820Type {
+    fn from(t: u32) -> Self {
+        match t {
+            1 => Self::Ram,
+            2 => Self::Reserved,
+            3 => Self::Acpi,
+            4 => Self::Nvs,
+            5 => Self::Unusable,
+            _ => Self::Unknown(t),
+        }
+    }
+}
+
+/// A single entry of the E820 memory map provided by the host.
+#[derive(Clone, Copy, Debug)]
+pub struct E820Entry {
+    pub region: MemoryRegion,
+    pub entry_type: E820Type,
+}
+
+impl E820Entry {
+    pub fn start(&self) -> u64 {
+        self.region.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.region.len()
     }
 }
 
 impl<'a> FwCfg<'a> {
     pub fn new(driver: &'a dyn IOPort) -> Self {
-        FwCfg { driver }
+        FwCfg {
+            driver,
+            files: RefCell::new(None),
+        }
     }
 
-    pub fn select(&self, cfg: u16) {
-        self.driver.outw(FW_CFG_CTL, cfg);
+    /// Checks the signature QEMU returns in front of all other items
+    pub fn is_present(&self) -> bool {
+        self.select(FW_CFG_SIGNATURE).is_ok()
+            && matches!(self.read_le::<u32>(), Ok(sig) if sig == u32::from_le_bytes(*b"QEMU"))
     }
 
-    pub fn read_le<T>(&self) -> T
+    pub fn select(&self, cfg: u16) -> Result<(), SvsmError> {
+        self.driver.try_outw(FW_CFG_CTL, cfg)
+    }
+
+    pub fn read_le<T>(&self) -> Result<T, SvsmError>
     where
         T: core::ops::Shl<usize, Output = T>
             + core::ops::BitOr<T, Output = T>
@@ -95,12 +166,12 @@ impl<'a> FwCfg<'a> {
         let io = &self.driver;
 
         for i in 0..size_of::<T>() {
-            val = (T::from(io.inb(FW_CFG_DATA)) << (i * 8)) | val;
+            val = (T::from(io.try_inb(FW_CFG_DATA)?) << (i * 8)) | val;
         }
-        val
+        Ok(val)
     }
 
-    pub fn read_be<T>(&self) -> T
+    pub fn read_be<T>(&self) -> Result<T, SvsmError>
     where
         T: core::ops::Shl<usize, Output = T>
             + core::ops::BitOr<T, Output = T>
@@ -110,123 +181,447 @@ impl<'a> FwCfg<'a> {
         let io = &self.driver;
 
         for _ in 0..size_of::<T>() {
-            val = (val << 8) | T::from(io.inb(FW_CFG_DATA));
+            val = (val << 8) | T::from(io.try_inb(FW_CFG_DATA)?);
         }
-        val
+        Ok(val)
     }
 
-    pub fn read_char(&self) -> char {
-        self.driver.inb(FW_CFG_DATA) as char
+    pub fn read_char(&self) -> Result<char, SvsmError> {
+        Ok(self.driver.try_inb(FW_CFG_DATA)? as char)
     }
 
-    pub fn file_selector(&self, name: &str) -> Result<FwCfgFile, SvsmError> {
-        self.select(FW_CFG_FILE_DIR);
-        let n: u32 = self.read_be();
+    fn read_file_dir(&self) -> Result<BTreeMap<String, FwCfgFile>, SvsmError> {
+        self.select(FW_CFG_FILE_DIR)?;
+        let n: u32 = self.read_be()?;
+        if n > FW_CFG_MAX_FILES {
+            return Err(SvsmError::FwCfg(FwCfgError::DirectorySize(n)));
+        }
 
+        let mut files = BTreeMap::new();
         for _ in 0..n {
-            let size: u32 = self.read_be();
-            let selector: u16 = self.read_be();
-            let _unused: u16 = self.read_be();
-            let mut fs = FixedString::<56>::new();
-            for _ in 0..56 {
-                let c = self.read_char();
-                fs.push(c);
+            let size: u32 = self.read_be()?;
+            let selector: u16 = self.read_be()?;
+            let _unused: u16 = self.read_be()?;
+            // File names are NUL-padded to 56 bytes
+            let mut fs = [0u8; FW_CFG_FILE_NAME_LEN];
+            self.driver.try_insb(FW_CFG_DATA, &mut fs)?;
+            let len = fs.iter().position(|&c| c == 0).unwrap_or(fs.len());
+
+            // Names which are no valid UTF-8 can never be looked up
+            if let Ok(name) = core::str::from_utf8(&fs[..len]) {
+                // Keep the first entry like a linear search would
+                files
+                    .entry(String::from(name))
+                    .or_insert(FwCfgFile { size, selector });
             }
+        }
 
-            if fs == name {
-                return Ok(FwCfgFile { size, selector });
-            }
+        Ok(files)
+    }
+
+    /// Looks up `name` in the file directory. The directory is only read
+    /// from the host on the first call.
+    pub fn file_selector(&self, name: &str) -> Result<FwCfgFile, SvsmError> {
+        let mut files = self.files.borrow_mut();
+        if files.is_none() {
+            *files = Some(self.read_file_dir()?);
+        }
+
+        files
+            .as_ref()
+            .and_then(|files| files.get(name))
+            .copied()
+            .ok_or(SvsmError::FwCfg(FwCfgError::FileNotFound))
+    }
+
+    /// Reads the contents of `file` into `buf` and returns the number of
+    /// bytes read. Fails if the file does not fit into `buf`.
+    pub fn read_file(&self, file: &FwCfgFile, buf: &mut [u8]) -> Result<usize, SvsmError> {
+        let len = file.size as usize;
+        if len > buf.len() {
+            return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
         }
 
-        Err(SvsmError::FwCfg(FwCfgError::FileNotFound))
+        self.select(file.selector)?;
+        self.driver.try_insb(FW_CFG_DATA, &mut buf[..len])?;
+
+        Ok(len)
     }
 
     fn find_svsm_region(&self) -> Result<MemoryRegion, SvsmError> {
         let file = self.file_selector("etc/sev/svsm")?;
 
-        if file.size != 16 {
+        if file.size != MEMORY_REGION_SIZE {
             return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
         }
 
-        self.select(file.selector);
-        Ok(self.read_memory_region())
+        self.select(file.selector)?;
+        let region = self.read_memory_region()?;
+        if region.is_empty() || !region.is_aligned(PAGE_SIZE as u64) {
+            return Err(SvsmError::FwCfg(FwCfgError::KernelRegion));
+        }
+
+        Ok(region)
+    }
+
+    /// Returns the file holding the SVSM kernel command line.
+    pub fn cmdline_file(&self) -> Result<FwCfgFile, SvsmError> {
+        self.file_selector("opt/svsm/cmdline")
+            .or_else(|_| self.file_selector("etc/svsm/cmdline"))
+    }
+
+    /// Returns the file holding an additional payload for the SVSM kernel,
+    /// like a file system archive.
+    pub fn payload_file(&self) -> Result<FwCfgFile, SvsmError> {
+        self.file_selector("opt/svsm/payload")
+            .or_else(|_| self.file_selector("etc/svsm/payload"))
     }
 
-    fn read_memory_region(&self) -> MemoryRegion {
-        let start: u64 = self.read_le();
-        let size: u64 = self.read_le();
-        let end = start.saturating_add(size);
+    /// Returns the region the host provided for the shared console ring.
+    pub fn console_ring_region(&self) -> Result<MemoryRegion, SvsmError> {
+        let file = self.file_selector("etc/sev/svsm-console")?;
 
-        assert!(start <= max_phys_addr(), "{start:#018x} is out of range");
-        assert!(end <= max_phys_addr(), "{end:#018x} is out of range");
+        if file.size != MEMORY_REGION_SIZE {
+            return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
+        }
 
-        MemoryRegion { start, end }
+        self.select(file.selector)?;
+        self.read_memory_region()
     }
 
-    pub fn get_memory_regions(&self) -> Result<Vec<MemoryRegion>, SvsmError> {
-        let mut regions: Vec<MemoryRegion> = Vec::new();
-        let file = self.file_selector("etc/e820")?;
-        let entries = file.size / 20;
+    fn read_memory_region(&self) -> Result<MemoryRegion, SvsmError> {
+        let start: u64 = self.read_le()?;
+        let size: u64 = self.read_le()?;
+        let end = start.checked_add(size).ok_or(FwCfgError::InvalidRegion)?;
 
-        self.select(file.selector);
+        if end > max_phys_addr() {
+            log::error!("fw_cfg memory region {start:#018x}-{end:#018x} is out of range");
+            return Err(SvsmError::FwCfg(FwCfgError::InvalidRegion));
+        }
 
-        for _ in 0..entries {
-            let region = self.read_memory_region();
-            let t: u32 = self.read_le();
+        Ok(MemoryRegion::new(start, end))
+    }
 
-            if t == 1 {
-                regions.push(region);
-            }
+    /// Returns all entries of the host-provided E820 memory map, in the
+    /// order the host lists them. Both the plain and the extended entry
+    /// format are supported.
+    pub fn read_e820(&self) -> Result<Vec<E820Entry>, SvsmError> {
+        let file = self.file_selector("etc/e820")?;
+        if file.size as usize > E820_MAX_ENTRIES * E820_ENTRY_SIZE_EXT {
+            return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
+        }
+        let mut buf = vec![0u8; file.size as usize];
+        self.read_file(&file, &mut buf)?;
+
+        let e820 = parse_e820(&buf)?;
+        if let Some(entry) = e820.iter().find(|e| e.region.end > max_phys_addr()) {
+            log::error!("E820 entry {:#018x?} is out of range", entry.region);
+            return Err(SvsmError::FwCfg(FwCfgError::InvalidRegion));
         }
 
-        Ok(regions)
+        Ok(e820)
     }
 
-    fn find_kernel_region_e820(&self) -> Result<MemoryRegion, SvsmError> {
-        let regions = self.get_memory_regions()?;
-        let mut kernel_region = regions
-            .iter()
-            .max_by_key(|region| region.start)
-            .copied()
-            .ok_or(SvsmError::FwCfg(FwCfgError::KernelRegion))?;
+    /// Returns the RAM regions from the E820 map, with adjacent and
+    /// overlapping entries merged.
+    pub fn get_memory_regions(&self) -> Result<MemoryRegionSet, SvsmError> {
+        Ok(self
+            .read_e820()?
+            .into_iter()
+            .filter(|entry| entry.entry_type == E820Type::Ram)
+            .map(|entry| entry.region)
+            .collect())
+    }
+
+    fn kernel_region_size(&self) -> Result<u64, SvsmError> {
+        let file = match self.file_selector(KERNEL_REGION_SIZE_FILE) {
+            Ok(file) => file,
+            Err(_) => return Ok(KERNEL_REGION_SIZE),
+        };
 
-        let start =
-            (kernel_region.end.saturating_sub(KERNEL_REGION_SIZE)) & KERNEL_REGION_SIZE_MASK;
+        let mut buf = [0u8; 24];
+        let len = self.read_file(&file, &mut buf)?;
 
-        if start < kernel_region.start {
+        let size = parse_region_size(&buf[..len]).ok_or(FwCfgError::KernelRegion)?;
+        if !size.is_power_of_two() || size < KERNEL_REGION_SIZE_MIN {
             return Err(SvsmError::FwCfg(FwCfgError::KernelRegion));
         }
 
-        kernel_region.start = start;
+        Ok(size)
+    }
 
-        Ok(kernel_region)
+    /// Returns the kernel region, either the one the host provided or one
+    /// allocated from free RAM in `memmap`. It is registered as used in
+    /// `memmap`, which has to know all ranges used by the loader already.
+    pub fn find_kernel_region(
+        &self,
+        memmap: &mut BootMemoryMap,
+    ) -> Result<MemoryRegion, ErrorContext> {
+        if let Ok(region) = self.find_svsm_region() {
+            memmap
+                .reserve(region, "kernel region")
+                .context("host-provided kernel region")?;
+            return Ok(region);
+        }
+
+        // Place the kernel region at the top of RAM, starting at a
+        // size-aligned address
+        let size = self
+            .kernel_region_size()
+            .context("reading kernel region size")?;
+        memmap
+            .allocate_top(size, size, "kernel region")
+            .context("no free RAM region fits the kernel")
     }
 
-    pub fn find_kernel_region(&self) -> Result<MemoryRegion, SvsmError> {
-        let kernel_region = self
-            .find_svsm_region()
-            .or_else(|_| self.find_kernel_region_e820())?;
+    /// Returns the flash regions of the firmware, or none if the host does
+    /// not describe any.
+    pub fn flash_regions(&self) -> Result<Vec<MemoryRegion>, SvsmError> {
+        let Ok(file) = self.file_selector("etc/flash") else {
+            return Ok(Vec::new());
+        };
 
-        // Make sure that the kernel region doesn't overlap with the loader.
-        if kernel_region.start < 640 * 1024 {
-            return Err(SvsmError::FwCfg(FwCfgError::KernelRegion));
+        let num = file.size / MEMORY_REGION_SIZE;
+        if num * MEMORY_REGION_SIZE != file.size || num as usize > FLASH_MAX_REGIONS {
+            return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
         }
 
-        Ok(kernel_region)
+        self.select(file.selector)?;
+        (0..num).map(|_| self.read_memory_region()).collect()
+    }
+}
+
+// Parses a size given as decimal or 0x-prefixed hexadecimal ASCII string,
+// optionally terminated by a newline or NUL byte.
+fn parse_region_size(buf: &[u8]) -> Option<u64> {
+    let s = core::str::from_utf8(buf).ok()?;
+    let s = s.trim_end_matches(['\0', '\n']);
+
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
     }
+}
+
+fn e820_entries(buf: &[u8], stride: usize) -> impl Iterator<Item = E820Entry> + '_ {
+    buf.chunks_exact(stride).filter_map(move |e| {
+        let start = u64::from_le_bytes(e[0..8].try_into().unwrap());
+        let size = u64::from_le_bytes(e[8..16].try_into().unwrap());
+        let t = u32::from_le_bytes(e[16..20].try_into().unwrap());
 
-    // This needs to be &mut self to prevent iterator invalidation, where the caller
-    // could do fw_cfg.select() while iterating. Having a mutable reference prevents
-    // other references.
-    pub fn iter_flash_regions(&mut self) -> impl Iterator<Item = MemoryRegion> + '_ {
-        let num = match self.file_selector("etc/flash") {
-            Ok(file) => {
-                self.select(file.selector);
-                file.size as usize / 16
+        if stride == E820_ENTRY_SIZE_EXT {
+            let attr = u32::from_le_bytes(e[20..24].try_into().unwrap());
+            if attr & E820_ATTR_ENABLED == 0 {
+                return None;
             }
-            Err(_) => 0,
-        };
+        }
+
+        // Some firmware lists empty entries
+        if size == 0 {
+            return None;
+        }
+
+        Some(E820Entry {
+            region: MemoryRegion::new(start, start.saturating_add(size)),
+            entry_type: E820Type::from(t),
+        })
+    })
+}
+
+// Sizes which are a multiple of both entry sizes are taken as the format
+// for which all entry types are known, preferring plain entries.
+fn e820_stride(buf: &[u8]) -> Option<usize> {
+    let known =
+        |stride| e820_entries(buf, stride).all(|e| !matches!(e.entry_type, E820Type::Unknown(_)));
+
+    match (
+        buf.len().is_multiple_of(E820_ENTRY_SIZE),
+        buf.len().is_multiple_of(E820_ENTRY_SIZE_EXT),
+    ) {
+        (true, true) if !known(E820_ENTRY_SIZE) && known(E820_ENTRY_SIZE_EXT) => {
+            Some(E820_ENTRY_SIZE_EXT)
+        }
+        (true, _) => Some(E820_ENTRY_SIZE),
+        (false, true) => Some(E820_ENTRY_SIZE_EXT),
+        (false, false) => None,
+    }
+}
+
+fn parse_e820(buf: &[u8]) -> Result<Vec<E820Entry>, FwCfgError> {
+    let stride = e820_stride(buf).ok_or(FwCfgError::FileSize(buf.len() as u32))?;
+    Ok(e820_entries(buf, stride).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::MockIOPort;
+    use crate::mm::pagetable::init_test_max_phys_addr;
+
+    // Item selectors QEMU assigns to files start here
+    const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+    // Returns a port backend behaving like QEMU's fw_cfg device with the
+    // given files
+    fn mock_fw_cfg(files: &[(&str, &[u8])]) -> MockIOPort {
+        let mut io = MockIOPort::new(FW_CFG_CTL, FW_CFG_DATA);
+        io.add_stream(FW_CFG_SIGNATURE, b"QEMU");
+
+        let mut dir = Vec::new();
+        dir.extend_from_slice(&(files.len() as u32).to_be_bytes());
+        for (i, (name, data)) in files.iter().enumerate() {
+            let selector = FW_CFG_FILE_FIRST + i as u16;
+            let mut fname = [0u8; FW_CFG_FILE_NAME_LEN];
+            fname[..name.len()].copy_from_slice(name.as_bytes());
+
+            dir.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            dir.extend_from_slice(&selector.to_be_bytes());
+            dir.extend_from_slice(&[0, 0]);
+            dir.extend_from_slice(&fname);
+            io.add_stream(selector, data);
+        }
+        io.add_stream(FW_CFG_FILE_DIR, &dir);
+
+        io
+    }
+
+    fn region_file(start: u64, size: u64) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[..8].copy_from_slice(&start.to_le_bytes());
+        buf[8..].copy_from_slice(&size.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_parse_region_size() {
+        assert_eq!(parse_region_size(b"16777216"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_region_size(b"0x2000000\n"), Some(32 * 1024 * 1024));
+        assert_eq!(parse_region_size(b"0x400000\0"), Some(4 * 1024 * 1024));
+        assert_eq!(parse_region_size(b"16M"), None);
+        assert_eq!(parse_region_size(b""), None);
+    }
+
+    // etc/e820 of QEMU q35 with 6GiB of memory
+    #[rustfmt::skip]
+    const E820_QEMU: [u8; 60] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0xc0, 0xff, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    ];
+
+    // Extended entries with a disabled RAM entry at 2GiB. The size is a
+    // multiple of both entry sizes.
+    #[rustfmt::skip]
+    const E820_EXT: [u8; 120] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfc, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0xfc, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_parse_e820() {
+        let e820 = parse_e820(&E820_QEMU).unwrap();
+        assert_eq!(e820.len(), 3);
+        assert_eq!(e820[0].region, MemoryRegion::new(0, 0x8000_0000));
+        assert_eq!(e820[1].entry_type, E820Type::Reserved);
+        assert_eq!(e820[1].start(), 0xfeff_c000);
+        assert_eq!(e820[2].size(), 0x1_8000_0000);
+
+        let e820 = parse_e820(&E820_EXT).unwrap();
+        assert_eq!(e820.len(), 4);
+        assert_eq!(e820[1].entry_type, E820Type::Reserved);
+        assert_eq!(e820[2].region, MemoryRegion::new(0x10_0000, 0x8000_0000));
+        assert_eq!(e820[3].start(), 0x1_0000_0000);
+
+        assert!(parse_e820(&E820_QEMU[..50]).is_err());
+        assert!(parse_e820(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fw_cfg_files() {
+        let io = mock_fw_cfg(&[
+            ("etc/svsm/payload", b"payload"),
+            ("opt/svsm/cmdline", b"log=debug"),
+        ]);
+        let fw_cfg = FwCfg::new(&io);
+        assert!(fw_cfg.is_present());
+
+        let file = fw_cfg.cmdline_file().unwrap();
+        let mut buf = [0u8; 16];
+        let len = fw_cfg.read_file(&file, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"log=debug");
+
+        // The payload is only found under its fallback name
+        let file = fw_cfg.payload_file().unwrap();
+        assert_eq!(file.size(), 7);
+        assert!(matches!(
+            fw_cfg.read_file(&file, &mut buf[..4]),
+            Err(SvsmError::FwCfg(FwCfgError::FileSize(7)))
+        ));
+
+        assert!(matches!(
+            fw_cfg.file_selector("etc/e820"),
+            Err(SvsmError::FwCfg(FwCfgError::FileNotFound))
+        ));
+
+        // Later lookups are served from the cached directory
+        assert_eq!(fw_cfg.files.borrow().as_ref().map(|f| f.len()), Some(2));
+        assert_eq!(fw_cfg.payload_file().unwrap().selector(), file.selector());
+        assert!(!FwCfg::new(&MockIOPort::new(FW_CFG_CTL, FW_CFG_DATA)).is_present());
+    }
+
+    #[test]
+    fn test_fw_cfg_kernel_region() {
+        init_test_max_phys_addr();
+
+        let io = mock_fw_cfg(&[
+            ("etc/e820", &E820_QEMU),
+            (KERNEL_REGION_SIZE_FILE, b"0x2000000\n"),
+        ]);
+        let fw_cfg = FwCfg::new(&io);
+        let ram = fw_cfg.get_memory_regions().unwrap();
+        assert_eq!(ram.len(), 2);
+
+        let mut memmap = BootMemoryMap::new(ram);
+        let r = fw_cfg.find_kernel_region(&mut memmap).unwrap();
+        assert_eq!(r, MemoryRegion::new(0x2_7e00_0000, 0x2_8000_0000));
+
+        // A host-provided region must not overlap anything in use
+        let svsm = region_file(0x8000, 0x100_0000);
+        let io = mock_fw_cfg(&[("etc/e820", &E820_QEMU), ("etc/sev/svsm", &svsm)]);
+        let fw_cfg = FwCfg::new(&io);
+        let mut memmap = BootMemoryMap::new(fw_cfg.get_memory_regions().unwrap());
+        memmap
+            .reserve(MemoryRegion::new(0, 0x10000), "stage2 stack")
+            .unwrap();
+        assert!(fw_cfg.find_kernel_region(&mut memmap).is_err());
+    }
+
+    #[test]
+    fn test_fw_cfg_hostile() {
+        init_test_max_phys_addr();
+
+        let mut io = MockIOPort::new(FW_CFG_CTL, FW_CFG_DATA);
+        io.add_stream(FW_CFG_FILE_DIR, &[0xff; 4]);
+        assert!(matches!(
+            FwCfg::new(&io).file_selector("etc/e820"),
+            Err(SvsmError::FwCfg(FwCfgError::DirectorySize(u32::MAX)))
+        ));
+
+        // Regions wrapping around or beyond the physical address space
+        for (start, size) in [(u64::MAX - 0xfff, 0x2000), (1 << 52, 0x1000)] {
+            let region = region_file(start, size);
+            let io = mock_fw_cfg(&[("etc/sev/svsm-console", &region)]);
+            assert!(matches!(
+                FwCfg::new(&io).console_ring_region(),
+                Err(SvsmError::FwCfg(FwCfgError::InvalidRegion))
+            ));
+        }
 
-        (0..num).map(|_| self.read_memory_region())
+        let flash = [0u8; 17 * 16];
+        let io = mock_fw_cfg(&[("etc/flash", &flash)]);
+        assert!(FwCfg::new(&io).flash_regions().is_err());
     }
 }
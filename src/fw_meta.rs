@@ -325,10 +325,11 @@ fn validate_fw_mem_region(region: SevPreValidMem) -> Result<(), SvsmError> {
 
     log::info!("Validating {:#018x}-{:#018x}", pstart, pend);
 
-    this_cpu_mut()
-        .ghcb()
-        .page_state_change(pstart, pend, false, PageStateChangeOp::PscPrivate)
-        .expect("GHCB PSC call failed to validate firmware memory");
+    let result =
+        this_cpu_mut()
+            .ghcb()
+            .page_state_change(pstart, pend, false, PageStateChangeOp::PscPrivate);
+    result.expect("GHCB PSC call failed to validate firmware memory");
 
     for paddr in (pstart.bits()..pend.bits())
         .step_by(PAGE_SIZE)
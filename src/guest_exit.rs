@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Guest exit lifecycle. When the guest asks for termination or crashes, the
+// SVSM freezes the vTPM, logs an attestation report binding the exit reason,
+// the kernel measurement, the final PCR state and the persistent state
+// generation, and then terminates the VM or idles, depending on the policy
+// set by the host.
+
+use crate::crypto::sha384::{Sha384, SHA384_DIGEST_SIZE};
+use crate::debug::softlockup::SoftLockupIdle;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::measure::kernel_measurement;
+use crate::mm::scrub::{scrub_work, SCRUB_BUDGET};
+use crate::sev::guest_msg::{get_attestation_report, AttestationReport};
+use crate::sev::integrity::{SVSM_TERM_GUEST_CRASH, SVSM_TERM_GUEST_REQUEST, SVSM_TERM_SET};
+use crate::sev::msr_protocol::request_termination_reason_msr;
+use crate::state_store::state_store_generation;
+use crate::utils::halt;
+use crate::vtpm::vtpm_finalize;
+use core::mem::size_of;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// What to do once the guest exited, "terminate" or "idle"
+const GUEST_EXIT_POLICY_FILE: &str = "opt/svsm/guest-exit-policy";
+
+// Prefix of the report data of the exit audit record
+const GUEST_EXIT_AUDIT_TAG: &[u8] = b"svsm-guest-exit";
+// State generation reported when there is no persistent state
+const AUDIT_NO_STATE_STORE: u64 = u64::MAX;
+
+/// Why the guest stopped running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestExitReason {
+    /// The guest asked for termination with a GHCB reason code
+    Requested { set: u8, code: u8 },
+    /// The guest VMPL shut down, usually after a triple fault
+    Crash,
+}
+
+impl GuestExitReason {
+    fn encode(&self) -> [u8; 3] {
+        match *self {
+            Self::Requested { set, code } => [1, set, code],
+            Self::Crash => [2, 0, 0],
+        }
+    }
+}
+
+/// Action taken after the guest exited
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GuestExitPolicy {
+    /// Ask the hypervisor to terminate the VM
+    Terminate = 0,
+    /// Stop running the guest but keep the SVSM alive, so the host can
+    /// still collect the log
+    Idle = 1,
+}
+
+static GUEST_EXIT_POLICY: AtomicU8 = AtomicU8::new(GuestExitPolicy::Terminate as u8);
+static GUEST_EXITED: AtomicBool = AtomicBool::new(false);
+
+fn guest_exit_policy() -> GuestExitPolicy {
+    match GUEST_EXIT_POLICY.load(Ordering::Relaxed) {
+        1 => GuestExitPolicy::Idle,
+        _ => GuestExitPolicy::Terminate,
+    }
+}
+
+fn parse_policy(s: &str) -> Option<GuestExitPolicy> {
+    match s.trim_end_matches(['\0', '\n']) {
+        "terminate" => Some(GuestExitPolicy::Terminate),
+        "idle" => Some(GuestExitPolicy::Idle),
+        _ => None,
+    }
+}
+
+/// Reads the guest exit policy from the host, terminating the VM is the
+/// default.
+pub fn guest_exit_init(fw_cfg: &FwCfg) -> Result<(), SvsmError> {
+    let Ok(file) = fw_cfg.file_selector(GUEST_EXIT_POLICY_FILE) else {
+        return Ok(());
+    };
+
+    let mut buf = [0u8; 16];
+    let len = fw_cfg.read_file(&file, &mut buf)?;
+    let policy = core::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(parse_policy);
+    match policy {
+        Some(policy) => {
+            GUEST_EXIT_POLICY.store(policy as u8, Ordering::Relaxed);
+            log::info!("Guest exit policy: {:?}", policy);
+        }
+        None => log::warn!(
+            "Invalid {}, terminating on guest exit",
+            GUEST_EXIT_POLICY_FILE
+        ),
+    }
+
+    Ok(())
+}
+
+/// Whether the guest exited and must not be run anymore
+pub fn guest_exited() -> bool {
+    GUEST_EXITED.load(Ordering::Acquire)
+}
+
+fn audit_report_data(reason: GuestExitReason, pcrs: &[u8; SHA384_DIGEST_SIZE]) -> [u8; 64] {
+    let mut ctx = Sha384::new();
+    ctx.update(GUEST_EXIT_AUDIT_TAG);
+    ctx.update(&reason.encode());
+    ctx.update(&kernel_measurement());
+    ctx.update(pcrs);
+
+    let mut data = [0u8; 64];
+    data[..SHA384_DIGEST_SIZE].copy_from_slice(&ctx.finalize());
+    // In the clear, so verifiers can detect a rolled back state disk
+    let generation = state_store_generation().unwrap_or(AUDIT_NO_STATE_STORE);
+    data[SHA384_DIGEST_SIZE..SHA384_DIGEST_SIZE + 8].copy_from_slice(&generation.to_le_bytes());
+    data
+}
+
+fn log_audit_record(report: &AttestationReport) {
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            (report as *const AttestationReport).cast::<u8>(),
+            size_of::<AttestationReport>(),
+        )
+    };
+
+    log::info!("---GUEST EXIT AUDIT RECORD---");
+    for chunk in bytes.chunks(32) {
+        log::info!("  {:02x?}", chunk);
+    }
+    log::info!("---END---");
+}
+
+/// Ends the guest's life. Only the first CPU to get here runs the exit
+/// sequence, others idle right away.
+pub fn guest_exit(reason: GuestExitReason) -> ! {
+    if GUEST_EXITED.swap(true, Ordering::AcqRel) {
+        guest_exit_idle();
+    }
+
+    log::info!("Guest exit: {:?}", reason);
+
+    // No further vTPM commands are accepted from here on
+    let pcrs = vtpm_finalize();
+    log::info!("Final vTPM PCR digest (SHA-384): {:02x?}", pcrs);
+
+    match get_attestation_report(&audit_report_data(reason, &pcrs), 0) {
+        Ok(report) => log_audit_record(&report),
+        Err(e) => log::error!("Failed to get guest exit audit record: {:?}", e),
+    }
+
+    match guest_exit_policy() {
+        GuestExitPolicy::Terminate => {
+            let code = match reason {
+                GuestExitReason::Requested { .. } => SVSM_TERM_GUEST_REQUEST,
+                GuestExitReason::Crash => SVSM_TERM_GUEST_CRASH,
+            };
+            request_termination_reason_msr(SVSM_TERM_SET, code)
+        }
+        GuestExitPolicy::Idle => guest_exit_idle(),
+    }
+}
+
+/// Parks the current CPU for good after the guest exited
+pub fn guest_exit_idle() -> ! {
+    // Pages deleted by the guest must not keep its data around
+    while scrub_work(SCRUB_BUDGET) != 0 {}
+
+    let _idle = SoftLockupIdle::new();
+    loop {
+        halt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(parse_policy("idle\n"), Some(GuestExitPolicy::Idle));
+        assert_eq!(
+            parse_policy("terminate\0"),
+            Some(GuestExitPolicy::Terminate)
+        );
+        assert_eq!(parse_policy("halt"), None);
+    }
+
+    #[test]
+    fn test_audit_report_data() {
+        let pcrs = [0u8; SHA384_DIGEST_SIZE];
+        let crash = audit_report_data(GuestExitReason::Crash, &pcrs);
+        let requested = audit_report_data(GuestExitReason::Requested { set: 0, code: 0 }, &pcrs);
+        assert_ne!(crash, requested);
+        // No state store in tests
+        let tail = &crash[SHA384_DIGEST_SIZE..];
+        assert_eq!(tail[..8], AUDIT_NO_STATE_STORE.to_le_bytes());
+        assert_eq!(tail[8..], [0; 64 - SHA384_DIGEST_SIZE - 8]);
+    }
+}
@@ -0,0 +1,493 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Parser for the Independent Guest Virtual Machine (IGVM) file format. An
+// IGVM file has a fixed header followed by a list of variable headers, each
+// a type, a length and a type-specific body padded to 8 bytes. Bodies refer
+// to data like page contents or VP contexts by file offset. Parsing does
+// not copy anything, all returned data borrows from the file.
+//
+// This is only the parser and nothing uses it yet. The SVSM is still
+// packaged as a raw blob and loaded through fw_cfg. Building IGVM files and
+// booting from them come with a packaging tool and an IGVM launch path,
+// neither of which exists yet.
+
+/// "IGVM" in little-endian byte order
+pub const IGVM_MAGIC: u32 = 0x4d56_4749;
+/// Format versions this parser understands
+pub const IGVM_FORMAT_VERSION_MIN: u32 = 1;
+pub const IGVM_FORMAT_VERSION_MAX: u32 = 2;
+
+const IGVM_FIXED_HEADER_SIZE: usize = 24;
+const IGVM_FIXED_HEADER_CHECKSUM: usize = 20;
+const IGVM_VARIABLE_HEADER_ALIGN: usize = 8;
+
+// Variable header types
+const IGVM_VHT_SUPPORTED_PLATFORM: u32 = 0x001;
+const IGVM_VHT_GUEST_POLICY: u32 = 0x101;
+const IGVM_VHT_PARAMETER_AREA: u32 = 0x301;
+const IGVM_VHT_PAGE_DATA: u32 = 0x302;
+const IGVM_VHT_PARAMETER_INSERT: u32 = 0x303;
+const IGVM_VHT_VP_CONTEXT: u32 = 0x304;
+const IGVM_VHT_REQUIRED_MEMORY: u32 = 0x305;
+const IGVM_VHT_VP_COUNT_PARAMETER: u32 = 0x307;
+const IGVM_VHT_SRAT: u32 = 0x308;
+const IGVM_VHT_MADT: u32 = 0x309;
+const IGVM_VHT_MMIO_RANGES: u32 = 0x30a;
+const IGVM_VHT_MEMORY_MAP: u32 = 0x30c;
+const IGVM_VHT_COMMAND_LINE: u32 = 0x30e;
+
+/// Platform type of [`IgvmDirective::SupportedPlatform`] for SEV-SNP
+pub const IGVM_PLATFORM_SEV_SNP: u8 = 2;
+
+/// Page data flag: the GPA is the start of a 2M page
+pub const IGVM_PAGE_DATA_FLAG_2MB: u32 = 1 << 0;
+/// Page data flag: the page is imported without being measured
+pub const IGVM_PAGE_DATA_FLAG_UNMEASURED: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgvmError {
+    // The file does not start with IGVM_MAGIC
+    UnrecognizedMagic,
+    // The format version is not supported
+    UnsupportedVersion(u32),
+    // A header or data range lies outside of the file
+    InvalidFileRange,
+    // The CRC32 over the headers does not match
+    Checksum,
+    // A variable header of a known type has the wrong length
+    InvalidHeaderSize(u32),
+}
+
+/// Kind of host-provided information written into a parameter area
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgvmParameterKind {
+    VpCount,
+    Srat,
+    Madt,
+    MmioRanges,
+    MemoryMap,
+    CommandLine,
+}
+
+/// Page data type, tells the loader how to import a page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgvmPageDataType {
+    Normal,
+    Secrets,
+    CpuidData,
+    CpuidXf,
+    Unknown(u16),
+}
+
+impl From<u16> for IgvmPageDataType {
+    fn from(val: u16) -> Self {
+        match val {
+            0 => Self::Normal,
+            1 => Self::Secrets,
+            2 => Self::CpuidData,
+            3 => Self::CpuidXf,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+/// A single variable header of an IGVM file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgvmDirective<'a> {
+    SupportedPlatform {
+        compatibility_mask: u32,
+        highest_vtl: u8,
+        platform_type: u8,
+        platform_version: u16,
+        shared_gpa_boundary: u64,
+    },
+    GuestPolicy {
+        policy: u64,
+        compatibility_mask: u32,
+    },
+    /// Area filled with host parameters before it is inserted into the guest
+    ParameterArea {
+        number_of_bytes: u64,
+        parameter_area_index: u32,
+        /// Initial contents, `None` if the area starts zeroed
+        data: Option<&'a [u8]>,
+    },
+    /// A page to import, `data` is `None` for a zero page
+    PageData {
+        gpa: u64,
+        compatibility_mask: u32,
+        flags: u32,
+        data_type: IgvmPageDataType,
+        data: Option<&'a [u8]>,
+    },
+    /// Imports a parameter area into the guest at `gpa`
+    ParameterInsert {
+        gpa: u64,
+        compatibility_mask: u32,
+        parameter_area_index: u32,
+    },
+    /// Initial register state of a VP, in the platform's format (a VMSA
+    /// for SEV-SNP)
+    VpContext {
+        gpa: u64,
+        compatibility_mask: u32,
+        vp_index: u16,
+        data: &'a [u8],
+    },
+    RequiredMemory {
+        gpa: u64,
+        compatibility_mask: u32,
+        number_of_bytes: u32,
+        flags: u32,
+    },
+    /// Host information to write into a parameter area
+    Parameter {
+        kind: IgvmParameterKind,
+        parameter_area_index: u32,
+        byte_offset: u32,
+    },
+    /// A variable header this parser does not interpret
+    Unknown { header_type: u32, body: &'a [u8] },
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// CRC32 as used by zlib (reflected, polynomial 0xedb88320). Headers are
+// only checked once, so the bitwise variant is fast enough.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// A parsed IGVM file
+#[derive(Clone, Copy, Debug)]
+pub struct IgvmFile<'a> {
+    data: &'a [u8],
+    format_version: u32,
+    variable_headers: &'a [u8],
+}
+
+impl<'a> IgvmFile<'a> {
+    /// Checks the fixed header and the checksum of `data`. Variable headers
+    /// are only parsed when iterating over them.
+    pub fn read(data: &'a [u8]) -> Result<Self, IgvmError> {
+        if data.len() < IGVM_FIXED_HEADER_SIZE {
+            return Err(IgvmError::InvalidFileRange);
+        }
+
+        if read_u32(data, 0) != IGVM_MAGIC {
+            return Err(IgvmError::UnrecognizedMagic);
+        }
+
+        let format_version = read_u32(data, 4);
+        if !(IGVM_FORMAT_VERSION_MIN..=IGVM_FORMAT_VERSION_MAX).contains(&format_version) {
+            return Err(IgvmError::UnsupportedVersion(format_version));
+        }
+
+        let vh_offset = read_u32(data, 8) as usize;
+        let vh_size = read_u32(data, 12) as usize;
+        let total_size = read_u32(data, 16) as usize;
+        if total_size > data.len() || total_size < IGVM_FIXED_HEADER_SIZE {
+            return Err(IgvmError::InvalidFileRange);
+        }
+        let data = &data[..total_size];
+
+        let variable_headers = vh_offset
+            .checked_add(vh_size)
+            .filter(|_| vh_offset >= IGVM_FIXED_HEADER_SIZE)
+            .and_then(|end| data.get(vh_offset..end))
+            .ok_or(IgvmError::InvalidFileRange)?;
+
+        // The checksum covers the fixed header, with the checksum field
+        // treated as zero, and all variable headers
+        let mut crc = !0u32;
+        crc = crc32_update(crc, &data[..IGVM_FIXED_HEADER_CHECKSUM]);
+        crc = crc32_update(crc, &[0; 4]);
+        crc = crc32_update(crc, variable_headers);
+        if !crc != read_u32(data, IGVM_FIXED_HEADER_CHECKSUM) {
+            return Err(IgvmError::Checksum);
+        }
+
+        Ok(IgvmFile {
+            data,
+            format_version,
+            variable_headers,
+        })
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Iterates over the variable headers in file order
+    pub fn directives(&self) -> IgvmDirectiveIter<'a> {
+        IgvmDirectiveIter {
+            file: *self,
+            offset: 0,
+        }
+    }
+
+    // File data referenced by a header, offset zero means no data
+    fn file_data(&self, offset: u32, len: usize) -> Result<Option<&'a [u8]>, IgvmError> {
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let start = offset as usize;
+        start
+            .checked_add(len)
+            .and_then(|end| self.data.get(start..end))
+            .map(Some)
+            .ok_or(IgvmError::InvalidFileRange)
+    }
+
+    fn parse_directive(
+        &self,
+        header_type: u32,
+        body: &'a [u8],
+    ) -> Result<IgvmDirective<'a>, IgvmError> {
+        let expected_len = match header_type {
+            IGVM_VHT_SUPPORTED_PLATFORM => 16,
+            IGVM_VHT_GUEST_POLICY => 16,
+            IGVM_VHT_PARAMETER_AREA => 16,
+            IGVM_VHT_PAGE_DATA => 24,
+            IGVM_VHT_PARAMETER_INSERT => 16,
+            IGVM_VHT_VP_CONTEXT => 24,
+            IGVM_VHT_REQUIRED_MEMORY => 24,
+            IGVM_VHT_VP_COUNT_PARAMETER
+            | IGVM_VHT_SRAT
+            | IGVM_VHT_MADT
+            | IGVM_VHT_MMIO_RANGES
+            | IGVM_VHT_MEMORY_MAP
+            | IGVM_VHT_COMMAND_LINE => 8,
+            _ => {
+                return Ok(IgvmDirective::Unknown { header_type, body });
+            }
+        };
+        if body.len() != expected_len {
+            return Err(IgvmError::InvalidHeaderSize(header_type));
+        }
+
+        let directive = match header_type {
+            IGVM_VHT_SUPPORTED_PLATFORM => IgvmDirective::SupportedPlatform {
+                compatibility_mask: read_u32(body, 0),
+                highest_vtl: body[4],
+                platform_type: body[5],
+                platform_version: read_u16(body, 6),
+                shared_gpa_boundary: read_u64(body, 8),
+            },
+            IGVM_VHT_GUEST_POLICY => IgvmDirective::GuestPolicy {
+                policy: read_u64(body, 0),
+                compatibility_mask: read_u32(body, 8),
+            },
+            IGVM_VHT_PARAMETER_AREA => {
+                let number_of_bytes = read_u64(body, 0);
+                let len =
+                    usize::try_from(number_of_bytes).map_err(|_| IgvmError::InvalidFileRange)?;
+                IgvmDirective::ParameterArea {
+                    number_of_bytes,
+                    parameter_area_index: read_u32(body, 8),
+                    data: self.file_data(read_u32(body, 12), len)?,
+                }
+            }
+            IGVM_VHT_PAGE_DATA => {
+                let flags = read_u32(body, 16);
+                let page_size = if flags & IGVM_PAGE_DATA_FLAG_2MB != 0 {
+                    0x20_0000
+                } else {
+                    0x1000
+                };
+                IgvmDirective::PageData {
+                    gpa: read_u64(body, 0),
+                    compatibility_mask: read_u32(body, 8),
+                    flags,
+                    data_type: IgvmPageDataType::from(read_u16(body, 20)),
+                    data: self.file_data(read_u32(body, 12), page_size)?,
+                }
+            }
+            IGVM_VHT_PARAMETER_INSERT => IgvmDirective::ParameterInsert {
+                gpa: read_u64(body, 0),
+                compatibility_mask: read_u32(body, 8),
+                parameter_area_index: read_u32(body, 12),
+            },
+            IGVM_VHT_VP_CONTEXT => IgvmDirective::VpContext {
+                gpa: read_u64(body, 0),
+                compatibility_mask: read_u32(body, 8),
+                vp_index: read_u16(body, 16),
+                // The context is one page in the platform's format
+                data: self
+                    .file_data(read_u32(body, 12), 0x1000)?
+                    .ok_or(IgvmError::InvalidFileRange)?,
+            },
+            IGVM_VHT_REQUIRED_MEMORY => IgvmDirective::RequiredMemory {
+                gpa: read_u64(body, 0),
+                compatibility_mask: read_u32(body, 8),
+                number_of_bytes: read_u32(body, 12),
+                flags: read_u32(body, 16),
+            },
+            _ => {
+                let kind = match header_type {
+                    IGVM_VHT_VP_COUNT_PARAMETER => IgvmParameterKind::VpCount,
+                    IGVM_VHT_SRAT => IgvmParameterKind::Srat,
+                    IGVM_VHT_MADT => IgvmParameterKind::Madt,
+                    IGVM_VHT_MMIO_RANGES => IgvmParameterKind::MmioRanges,
+                    IGVM_VHT_MEMORY_MAP => IgvmParameterKind::MemoryMap,
+                    _ => IgvmParameterKind::CommandLine,
+                };
+                IgvmDirective::Parameter {
+                    kind,
+                    parameter_area_index: read_u32(body, 0),
+                    byte_offset: read_u32(body, 4),
+                }
+            }
+        };
+
+        Ok(directive)
+    }
+}
+
+/// Iterator over the variable headers of an [`IgvmFile`]. Stops after the
+/// first malformed header.
+#[derive(Clone, Copy, Debug)]
+pub struct IgvmDirectiveIter<'a> {
+    file: IgvmFile<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for IgvmDirectiveIter<'a> {
+    type Item = Result<IgvmDirective<'a>, IgvmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let headers = self.file.variable_headers;
+        if self.offset >= headers.len() {
+            return None;
+        }
+
+        let Some(hdr) = headers.get(self.offset..self.offset + 8) else {
+            self.offset = headers.len();
+            return Some(Err(IgvmError::InvalidFileRange));
+        };
+        let header_type = read_u32(hdr, 0);
+        let len = read_u32(hdr, 4) as usize;
+
+        let start = self.offset + 8;
+        let Some(body) = headers.get(start..start + len) else {
+            self.offset = headers.len();
+            return Some(Err(IgvmError::InvalidFileRange));
+        };
+        self.offset = (start + len).next_multiple_of(IGVM_VARIABLE_HEADER_ALIGN);
+
+        let directive = self.file.parse_directive(header_type, body);
+        if directive.is_err() {
+            self.offset = headers.len();
+        }
+        Some(directive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn push_header(buf: &mut Vec<u8>, header_type: u32, body: &[u8]) {
+        buf.extend_from_slice(&header_type.to_le_bytes());
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(body);
+        buf.resize(buf.len().next_multiple_of(8), 0);
+    }
+
+    fn build_file() -> Vec<u8> {
+        let mut headers = Vec::new();
+        let mut platform = Vec::new();
+        platform.extend_from_slice(&1u32.to_le_bytes());
+        platform.extend_from_slice(&[2, IGVM_PLATFORM_SEV_SNP, 1, 0]);
+        platform.extend_from_slice(&0u64.to_le_bytes());
+        push_header(&mut headers, IGVM_VHT_SUPPORTED_PLATFORM, &platform);
+
+        let data_offset = (IGVM_FIXED_HEADER_SIZE + headers.len() + 32) as u32;
+        let mut page = Vec::new();
+        page.extend_from_slice(&0x80_0000u64.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&data_offset.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        push_header(&mut headers, IGVM_VHT_PAGE_DATA, &page);
+
+        let total = data_offset as usize + 0x1000;
+        let mut file = Vec::new();
+        file.extend_from_slice(&IGVM_MAGIC.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&(IGVM_FIXED_HEADER_SIZE as u32).to_le_bytes());
+        file.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(total as u32).to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&headers);
+        file.resize(total, 0xaa);
+
+        let crc = !crc32_update(!0, &file[..IGVM_FIXED_HEADER_SIZE + headers.len()]);
+        file[IGVM_FIXED_HEADER_CHECKSUM..IGVM_FIXED_HEADER_SIZE]
+            .copy_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_igvm_directives() {
+        let file = build_file();
+        let igvm = IgvmFile::read(&file).unwrap();
+        let directives: Vec<_> = igvm.directives().collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(directives.len(), 2);
+        assert!(matches!(
+            directives[0],
+            IgvmDirective::SupportedPlatform {
+                platform_type: IGVM_PLATFORM_SEV_SNP,
+                ..
+            }
+        ));
+        match directives[1] {
+            IgvmDirective::PageData {
+                gpa,
+                data_type,
+                data: Some(data),
+                ..
+            } => {
+                assert_eq!(gpa, 0x80_0000);
+                assert_eq!(data_type, IgvmPageDataType::Normal);
+                assert_eq!(data.len(), 0x1000);
+                assert!(data.iter().all(|&b| b == 0xaa));
+            }
+            d => panic!("unexpected directive {:?}", d),
+        }
+
+        let mut corrupt = file.clone();
+        corrupt[IGVM_FIXED_HEADER_SIZE + 8] ^= 1;
+        assert_eq!(IgvmFile::read(&corrupt).unwrap_err(), IgvmError::Checksum);
+    }
+}
@@ -4,9 +4,11 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use crate::error::SvsmError;
 use core::arch::asm;
 
-pub trait IOPort {
+// Port drivers are shared by all CPUs through the console
+pub trait IOPort: Sync {
     fn outb(&self, port: u16, value: u8) {
         unsafe { asm!("outb %al, %dx", in("al") value, in("dx") port, options(att_syntax)) }
     }
@@ -30,6 +32,73 @@ pub trait IOPort {
             ret
         }
     }
+
+    fn outl(&self, port: u16, value: u32) {
+        unsafe { asm!("outl %eax, %dx", in("eax") value, in("dx") port, options(att_syntax)) }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        unsafe {
+            let ret: u32;
+            asm!("inl %dx, %eax", in("dx") port, out("eax") ret, options(att_syntax));
+            ret
+        }
+    }
+
+    /// Fills `buf` with bytes read from `port`, like `rep insb`
+    fn insb(&self, port: u16, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            *b = self.inb(port);
+        }
+    }
+
+    /// Writes all bytes of `buf` to `port`, like `rep outsb`
+    fn outsb(&self, port: u16, buf: &[u8]) {
+        for b in buf.iter() {
+            self.outb(port, *b);
+        }
+    }
+
+    // Fallible variants of the accessors above. Port accesses which go
+    // through the hypervisor can fail, the accessors above do not return
+    // in this case. Native port I/O always succeeds.
+
+    fn try_outb(&self, port: u16, value: u8) -> Result<(), SvsmError> {
+        self.outb(port, value);
+        Ok(())
+    }
+
+    fn try_inb(&self, port: u16) -> Result<u8, SvsmError> {
+        Ok(self.inb(port))
+    }
+
+    fn try_outw(&self, port: u16, value: u16) -> Result<(), SvsmError> {
+        self.outw(port, value);
+        Ok(())
+    }
+
+    fn try_inw(&self, port: u16) -> Result<u16, SvsmError> {
+        Ok(self.inw(port))
+    }
+
+    fn try_outl(&self, port: u16, value: u32) -> Result<(), SvsmError> {
+        self.outl(port, value);
+        Ok(())
+    }
+
+    fn try_inl(&self, port: u16) -> Result<u32, SvsmError> {
+        Ok(self.inl(port))
+    }
+
+    fn try_insb(&self, port: u16, buf: &mut [u8]) -> Result<(), SvsmError> {
+        self.insb(port, buf);
+        Ok(())
+    }
+
+    fn try_outsb(&self, port: u16, buf: &[u8]) -> Result<(), SvsmError> {
+        self.outsb(port, buf);
+        Ok(())
+    }
 }
 
 pub struct DefaultIOPort {}
@@ -37,3 +106,79 @@ pub struct DefaultIOPort {}
 impl IOPort for DefaultIOPort {}
 
 pub static DEFAULT_IO_DRIVER: DefaultIOPort = DefaultIOPort {};
+
+#[cfg(test)]
+extern crate alloc;
+
+/// Port I/O backend for unit tests, which replays canned byte streams
+/// instead of touching real ports. A 16 bit write to the select port picks
+/// the stream registered for the written value, byte reads from the data
+/// port then return its contents and 0 past its end. Accesses to other
+/// ports are ignored and read as all ones.
+#[cfg(test)]
+pub struct MockIOPort {
+    select_port: u16,
+    data_port: u16,
+    streams: alloc::vec::Vec<(u16, alloc::vec::Vec<u8>)>,
+    // Selected stream and read position in it
+    cursor: crate::locking::SpinLock<Option<(usize, usize)>>,
+}
+
+#[cfg(test)]
+impl MockIOPort {
+    pub fn new(select_port: u16, data_port: u16) -> Self {
+        MockIOPort {
+            select_port,
+            data_port,
+            streams: alloc::vec::Vec::new(),
+            cursor: crate::locking::SpinLock::new(None),
+        }
+    }
+
+    pub fn add_stream(&mut self, key: u16, data: &[u8]) {
+        self.streams.push((key, data.to_vec()));
+    }
+}
+
+#[cfg(test)]
+impl IOPort for MockIOPort {
+    fn outb(&self, _port: u16, _value: u8) {}
+
+    fn outw(&self, port: u16, value: u16) {
+        if port == self.select_port {
+            let idx = self.streams.iter().position(|(key, _)| *key == value);
+            *self.cursor.lock() = idx.map(|idx| (idx, 0));
+        }
+    }
+
+    fn outl(&self, _port: u16, _value: u32) {}
+
+    fn inb(&self, port: u16) -> u8 {
+        if port != self.data_port {
+            return 0xff;
+        }
+
+        let mut cursor = self.cursor.lock();
+        match cursor.as_mut() {
+            Some((idx, pos)) => {
+                let b = self.streams[*idx].1.get(*pos).copied().unwrap_or(0);
+                *pos += 1;
+                b
+            }
+            None => 0,
+        }
+    }
+
+    fn inw(&self, port: u16) -> u16 {
+        u16::from_le_bytes([self.inb(port), self.inb(port)])
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        u32::from_le_bytes([
+            self.inb(port),
+            self.inb(port),
+            self.inb(port),
+            self.inb(port),
+        ])
+    }
+}
@@ -4,9 +4,42 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-#[derive(Copy, Clone)]
+use crate::crypto::sha384::SHA384_DIGEST_SIZE;
+use crate::elf::{Elf64File, Elf64NoteIterator, ElfError};
+use core::mem::size_of;
+use core::slice;
+
+/// "SVLI" in little-endian byte order
+pub const KERNEL_LAUNCH_INFO_MAGIC: u32 = 0x494c_5653;
+/// Version of [`KernelLaunchInfo`] handed over by this stage2
+pub const KERNEL_LAUNCH_INFO_VERSION: u32 = 6;
+/// Largest number of memory regions passed in [`KernelLaunchInfo`]
+pub const KERNEL_LAUNCH_MAX_REGIONS: usize = 64;
+// Upper bound for the size field, newer stage2s may append fields
+const KERNEL_LAUNCH_INFO_MAX_SIZE: u32 = 4096;
+
+/// Guest RAM region from the host's E820 map, `end` is exclusive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct LaunchMemoryRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Boot information stage2 hands over to the kernel. The header lets the
+/// kernel check that it got what it expects. Fields are only ever appended,
+/// so a kernel can run on a newer stage2 as long as the version is at
+/// least the one it was built for.
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct KernelLaunchInfo {
+    pub magic: u32,
+    pub version: u32,
+    /// Size of the structure as written by stage2
+    pub size: u32,
+    /// Makes all `size` bytes of the structure add up to zero
+    pub checksum: u8,
+    pub reserved: [u8; 3],
     /// Start of the kernel in physical memory.
     pub kernel_region_phys_start: u64,
     /// Exclusive end of the kernel in physical memory.
@@ -18,11 +51,89 @@ pub struct KernelLaunchInfo {
     pub kernel_elf_stage2_virt_end: u64,
     pub kernel_fs_start: u64,
     pub kernel_fs_end: u64,
+    /// Addresses of the CPUID and secrets pages in the stage2 mapping
     pub cpuid_page: u64,
     pub secrets_page: u64,
+    /// Virtual address of the kernel command line in the kernel mapping,
+    /// zero if there is none. Not NUL-terminated.
+    pub cmdline_start: u64,
+    pub cmdline_len: u64,
+    /// Heap of stage2, part of the stage2 memory the kernel invalidates
+    pub stage2_heap_start: u64,
+    pub stage2_heap_end: u64,
+    /// GPA of the GHCB stage2 used, zero if it had none. Stage2 unregisters
+    /// it before jumping to the kernel.
+    pub stage2_ghcb: u64,
+    /// I/O port of the serial console
+    pub console_io_port: u16,
+    pub reserved2: u16,
+    pub nr_memory_regions: u32,
+    /// Guest RAM as reported by the host, including the kernel region
+    pub memory_regions: [LaunchMemoryRegion; KERNEL_LAUNCH_MAX_REGIONS],
+    /// SHA-384 digest of the kernel ELF file, taken by stage2 before
+    /// loading it
+    pub kernel_elf_digest: [u8; SHA384_DIGEST_SIZE],
+    /// Physical location of the payload stage2 loaded from fw_cfg, inside
+    /// the kernel region and already validated. Zero length if there is none.
+    pub payload_phys_start: u64,
+    pub payload_len: u64,
+    /// Physical memory taken by the stage2 image and heap, which is not
+    /// necessarily inside the low 640K
+    pub stage2_phys_start: u64,
+    pub stage2_phys_end: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelLaunchInfoError {
+    // The structure does not start with KERNEL_LAUNCH_INFO_MAGIC
+    Magic,
+    // The structure is older than the kernel
+    Version(u32),
+    // The size field is out of range
+    Size(u32),
+    // The bytes of the structure do not add up to zero
+    Checksum,
+    // More memory regions than fit into the structure
+    TooManyRegions,
 }
 
 impl KernelLaunchInfo {
+    /// An empty launch info with a valid header but no checksum yet
+    pub const fn new() -> Self {
+        KernelLaunchInfo {
+            magic: KERNEL_LAUNCH_INFO_MAGIC,
+            version: KERNEL_LAUNCH_INFO_VERSION,
+            size: size_of::<KernelLaunchInfo>() as u32,
+            checksum: 0,
+            reserved: [0; 3],
+            kernel_region_phys_start: 0,
+            kernel_region_phys_end: 0,
+            heap_area_phys_start: 0,
+            kernel_region_virt_start: 0,
+            heap_area_virt_start: 0,
+            kernel_elf_stage2_virt_start: 0,
+            kernel_elf_stage2_virt_end: 0,
+            kernel_fs_start: 0,
+            kernel_fs_end: 0,
+            cpuid_page: 0,
+            secrets_page: 0,
+            cmdline_start: 0,
+            cmdline_len: 0,
+            stage2_heap_start: 0,
+            stage2_heap_end: 0,
+            stage2_ghcb: 0,
+            console_io_port: 0,
+            reserved2: 0,
+            nr_memory_regions: 0,
+            memory_regions: [LaunchMemoryRegion { start: 0, end: 0 }; KERNEL_LAUNCH_MAX_REGIONS],
+            kernel_elf_digest: [0; SHA384_DIGEST_SIZE],
+            payload_phys_start: 0,
+            payload_len: 0,
+            stage2_phys_start: 0,
+            stage2_phys_end: 0,
+        }
+    }
+
     pub fn heap_area_size(&self) -> u64 {
         self.kernel_region_phys_end - self.heap_area_phys_start
     }
@@ -30,4 +141,311 @@ impl KernelLaunchInfo {
     pub fn heap_area_virt_end(&self) -> u64 {
         self.heap_area_virt_start + self.heap_area_size()
     }
+
+    pub fn add_memory_region(&mut self, start: u64, end: u64) -> Result<(), KernelLaunchInfoError> {
+        let idx = self.nr_memory_regions as usize;
+        if idx == KERNEL_LAUNCH_MAX_REGIONS {
+            return Err(KernelLaunchInfoError::TooManyRegions);
+        }
+        self.memory_regions[idx] = LaunchMemoryRegion { start, end };
+        self.nr_memory_regions += 1;
+        Ok(())
+    }
+
+    pub fn memory_regions(&self) -> &[LaunchMemoryRegion] {
+        let nr = (self.nr_memory_regions as usize).min(KERNEL_LAUNCH_MAX_REGIONS);
+        &self.memory_regions[..nr]
+    }
+
+    // The first `size` bytes of the structure, `size` must have been checked
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((self as *const Self).cast::<u8>(), self.size as usize) }
+    }
+
+    /// Sets the checksum, must be called after the last change
+    pub fn seal(&mut self) {
+        self.checksum = 0;
+        let sum = self.bytes().iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        self.checksum = 0u8.wrapping_sub(sum);
+    }
+
+    /// Checks the header written by stage2. Bytes beyond the structure
+    /// this kernel knows about are included in the checksum, so `self`
+    /// must be the launch info as placed in memory by stage2.
+    pub fn check(&self) -> Result<(), KernelLaunchInfoError> {
+        if self.magic != KERNEL_LAUNCH_INFO_MAGIC {
+            return Err(KernelLaunchInfoError::Magic);
+        }
+        if self.version < KERNEL_LAUNCH_INFO_VERSION {
+            return Err(KernelLaunchInfoError::Version(self.version));
+        }
+        if self.size < size_of::<Self>() as u32 || self.size > KERNEL_LAUNCH_INFO_MAX_SIZE {
+            return Err(KernelLaunchInfoError::Size(self.size));
+        }
+        if self.bytes().iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(KernelLaunchInfoError::Checksum);
+        }
+        Ok(())
+    }
+}
+
+impl Default for KernelLaunchInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stage2 passes the valid-bitmap of the kernel region in %r9
+pub const STAGE2_FEATURE_VALID_BITMAP: u64 = 1 << 0;
+/// Stage2 passes the location of the kernel file system image
+pub const STAGE2_FEATURE_KERNEL_FS: u64 = 1 << 1;
+/// Stage2 passes the kernel command line from fw_cfg
+pub const STAGE2_FEATURE_CMDLINE: u64 = 1 << 2;
+/// Stage2 loads an additional payload from fw_cfg
+pub const STAGE2_FEATURE_PAYLOAD: u64 = 1 << 3;
+/// Features provided by this stage2
+pub const STAGE2_FEATURES: u64 = STAGE2_FEATURE_VALID_BITMAP
+    | STAGE2_FEATURE_KERNEL_FS
+    | STAGE2_FEATURE_CMDLINE
+    | STAGE2_FEATURE_PAYLOAD;
+
+/// Section holding the notes which describe what the SVSM kernel expects
+/// from the loader.
+pub const SVSM_NOTES_SECTION: &str = ".note.svsm";
+/// Owner name of all SVSM kernel notes
+pub const SVSM_NOTE_NAME: &[u8] = b"SVSM";
+
+/// u64: Stage2 features required by the kernel
+pub const SVSM_NOTE_STAGE2_FEATURES: u32 = 1;
+/// u32: Minimum version of [`KernelLaunchInfo`] the kernel understands
+pub const SVSM_NOTE_LAUNCH_INFO_VERSION: u32 = 2;
+/// u64, u64: Virtual base address and alignment the kernel wants to be
+/// loaded at
+pub const SVSM_NOTE_VIRT_BASE: u32 = 3;
+/// u32: Non-zero if the kernel can run at a randomized virtual base
+pub const SVSM_NOTE_KASLR: u32 = 4;
+
+/// In-memory layout of an ELF note with the SVSM owner name, used by the
+/// kernel to emit its notes. The descriptor size must be a multiple of four.
+#[derive(Debug)]
+#[repr(C, align(4))]
+pub struct SvsmNote<const N: usize> {
+    namesz: u32,
+    descsz: u32,
+    n_type: u32,
+    name: [u8; 8],
+    desc: [u8; N],
+}
+
+impl<const N: usize> SvsmNote<N> {
+    pub const fn new(n_type: u32, desc: [u8; N]) -> Self {
+        assert!(N & 3 == 0);
+        SvsmNote {
+            namesz: SVSM_NOTE_NAME.len() as u32 + 1,
+            descsz: N as u32,
+            n_type,
+            name: *b"SVSM\0\0\0\0",
+            desc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum KernelNotesError {
+    // The kernel requires stage2 features which are not available
+    MissingFeatures(u64),
+    // The kernel does not understand the launch info passed by stage2
+    LaunchInfoVersion(u32),
+    // The kernel can not be loaded at the chosen virtual address
+    VirtBase(u64),
+}
+
+/// Loader contract of the SVSM kernel, as read from its ELF notes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelNotes {
+    pub required_features: u64,
+    pub min_launch_info_version: u32,
+    pub virt_base: Option<(u64, u64)>,
+    pub kaslr: bool,
+}
+
+impl KernelNotes {
+    /// Parses the SVSM notes of a kernel ELF file. Returns `None` if the
+    /// kernel has no notes section.
+    pub fn read(kernel_elf: &Elf64File) -> Result<Option<Self>, ElfError> {
+        match kernel_elf.section_by_name(SVSM_NOTES_SECTION)? {
+            Some(buf) => Self::parse(buf).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Parses the contents of the notes section
+    fn parse(buf: &[u8]) -> Result<Self, ElfError> {
+        let mut notes = KernelNotes::default();
+        for note in Elf64NoteIterator::new(buf) {
+            let note = note?;
+            if note.name != SVSM_NOTE_NAME {
+                continue;
+            }
+
+            match (note.n_type, note.desc.len()) {
+                (SVSM_NOTE_STAGE2_FEATURES, 8) => {
+                    notes.required_features = u64::from_le_bytes(note.desc.try_into().unwrap());
+                }
+                (SVSM_NOTE_LAUNCH_INFO_VERSION, 4) => {
+                    notes.min_launch_info_version =
+                        u32::from_le_bytes(note.desc.try_into().unwrap());
+                }
+                (SVSM_NOTE_VIRT_BASE, 16) => {
+                    let base = u64::from_le_bytes(note.desc[0..8].try_into().unwrap());
+                    let align = u64::from_le_bytes(note.desc[8..16].try_into().unwrap());
+                    notes.virt_base = Some((base, align));
+                }
+                (SVSM_NOTE_KASLR, 4) => {
+                    notes.kaslr = u32::from_le_bytes(note.desc.try_into().unwrap()) != 0;
+                }
+                (SVSM_NOTE_STAGE2_FEATURES, _)
+                | (SVSM_NOTE_LAUNCH_INFO_VERSION, _)
+                | (SVSM_NOTE_VIRT_BASE, _)
+                | (SVSM_NOTE_KASLR, _) => return Err(ElfError::InvalidNote),
+                // Unknown notes are informational only
+                _ => {}
+            }
+        }
+
+        Ok(notes)
+    }
+
+    /// Checks whether a kernel with these notes can be launched by this
+    /// stage2 at virtual address `virt_base`.
+    pub fn check(&self, virt_base: u64) -> Result<(), KernelNotesError> {
+        let missing = self.required_features & !STAGE2_FEATURES;
+        if missing != 0 {
+            return Err(KernelNotesError::MissingFeatures(missing));
+        }
+
+        if self.min_launch_info_version > KERNEL_LAUNCH_INFO_VERSION {
+            return Err(KernelNotesError::LaunchInfoVersion(
+                self.min_launch_info_version,
+            ));
+        }
+
+        if let Some((base, align)) = self.virt_base {
+            let misaligned =
+                align != 0 && (!align.is_power_of_two() || virt_base & (align - 1) != 0);
+            if misaligned || (!self.kaslr && virt_base != base) {
+                return Err(KernelNotesError::VirtBase(virt_base));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launch_info_check() {
+        let mut li = KernelLaunchInfo::new();
+        li.kernel_region_phys_start = 0x800_0000;
+        li.add_memory_region(0, 0xa_0000).unwrap();
+        li.seal();
+        assert_eq!(li.check(), Ok(()));
+        assert_eq!(li.memory_regions().len(), 1);
+
+        li.kernel_region_phys_end = 0x1000_0000;
+        assert_eq!(li.check(), Err(KernelLaunchInfoError::Checksum));
+
+        li.version = 1;
+        assert_eq!(li.check(), Err(KernelLaunchInfoError::Version(1)));
+    }
+
+    fn note_bytes<const N: usize>(note: &SvsmNote<N>) -> &[u8] {
+        let ptr = note as *const SvsmNote<N> as *const u8;
+        unsafe { slice::from_raw_parts(ptr, size_of::<SvsmNote<N>>()) }
+    }
+
+    #[test]
+    fn test_kernel_notes() {
+        let mut virt_base = [0u8; 16];
+        virt_base[..8].copy_from_slice(&0xffff_ff80_0000_0000u64.to_le_bytes());
+        virt_base[8..].copy_from_slice(&0x1000u64.to_le_bytes());
+
+        let mut buf = [0u8; 128];
+        let mut len = 0;
+        for note in [
+            note_bytes(&SvsmNote::new(
+                SVSM_NOTE_STAGE2_FEATURES,
+                3u64.to_le_bytes(),
+            )),
+            note_bytes(&SvsmNote::new(
+                SVSM_NOTE_LAUNCH_INFO_VERSION,
+                2u32.to_le_bytes(),
+            )),
+            note_bytes(&SvsmNote::new(SVSM_NOTE_VIRT_BASE, virt_base)),
+            // Unknown notes are skipped
+            note_bytes(&SvsmNote::new(100, [0xff; 4])),
+        ] {
+            buf[len..len + note.len()].copy_from_slice(note);
+            len += note.len();
+        }
+
+        let notes = KernelNotes::parse(&buf[..len]).unwrap();
+        assert_eq!(notes.required_features, 3);
+        assert_eq!(notes.min_launch_info_version, 2);
+        assert_eq!(notes.virt_base, Some((0xffff_ff80_0000_0000, 0x1000)));
+        assert!(!notes.kaslr);
+
+        assert!(notes.check(0xffff_ff80_0000_0000).is_ok());
+        assert!(matches!(
+            notes.check(0xffff_ff80_0020_0000),
+            Err(KernelNotesError::VirtBase(_))
+        ));
+
+        // Truncated in the last note
+        assert!(matches!(
+            KernelNotes::parse(&buf[..len - 4]),
+            Err(ElfError::InvalidNote)
+        ));
+    }
+
+    #[test]
+    fn test_kernel_notes_bad_size() {
+        let note = SvsmNote::new(SVSM_NOTE_LAUNCH_INFO_VERSION, [0u8; 8]);
+        assert!(matches!(
+            KernelNotes::parse(note_bytes(&note)),
+            Err(ElfError::InvalidNote)
+        ));
+    }
+
+    #[test]
+    fn test_kernel_notes_check() {
+        let mut notes = KernelNotes {
+            required_features: 1 << 63,
+            ..Default::default()
+        };
+        assert!(matches!(
+            notes.check(0),
+            Err(KernelNotesError::MissingFeatures(_))
+        ));
+
+        notes.required_features = STAGE2_FEATURES;
+        notes.min_launch_info_version = KERNEL_LAUNCH_INFO_VERSION + 1;
+        assert!(matches!(
+            notes.check(0),
+            Err(KernelNotesError::LaunchInfoVersion(_))
+        ));
+
+        // With KASLR any suitably aligned base works
+        notes.min_launch_info_version = KERNEL_LAUNCH_INFO_VERSION;
+        notes.virt_base = Some((0xffff_ff80_0000_0000, 0x20_0000));
+        notes.kaslr = true;
+        assert!(notes.check(0xffff_ff80_0040_0000).is_ok());
+        assert!(matches!(
+            notes.check(0xffff_ff80_0040_1000),
+            Err(KernelNotesError::VirtBase(_))
+        ));
+    }
 }
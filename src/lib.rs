@@ -12,25 +12,44 @@
 
 pub mod acpi;
 pub mod address;
+pub mod cmdline;
+pub mod collections;
 pub mod console;
+pub mod console_ring;
 pub mod cpu;
+pub mod crypto;
 pub mod debug;
+pub mod deferred;
+pub mod device_manifest;
 pub mod elf;
 pub mod error;
 pub mod fs;
 pub mod fw_cfg;
 pub mod fw_meta;
+pub mod guest_exit;
+pub mod igvm;
 pub mod io;
 pub mod kernel_launch;
 pub mod locking;
+pub mod log_buffer;
+pub mod log_filter;
+pub mod manifest;
+pub mod measure;
 pub mod mm;
+pub mod mmio;
+pub mod pci;
+pub mod protocols;
 pub mod requests;
 pub mod serial;
 pub mod sev;
+pub mod state_store;
 pub mod string;
 pub mod svsm_console;
+pub mod time;
 pub mod types;
 pub mod utils;
+pub mod virtio;
+pub mod vtpm;
 
 #[test]
 fn test_nop() {}
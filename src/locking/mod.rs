@@ -8,4 +8,6 @@ pub mod rwlock;
 pub mod spinlock;
 
 pub use rwlock::{RWLock, ReadLockGuard, WriteLockGuard};
-pub use spinlock::{LockGuard, SpinLock};
+#[cfg(feature = "lock-stats")]
+pub use spinlock::LockStats;
+pub use spinlock::{IrqLockGuard, LockGuard, SpinLock};
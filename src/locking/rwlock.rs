@@ -10,7 +10,7 @@ use core::sync::atomic::{AtomicU64, Ordering};
 
 pub struct ReadLockGuard<'a, T> {
     rwlock: &'a AtomicU64,
-    data: &'a mut T,
+    data: &'a T,
 }
 
 impl<'a, T> Drop for ReadLockGuard<'a, T> {
@@ -118,9 +118,11 @@ impl<T> RWLock<T> {
             core::hint::spin_loop();
         }
 
+        // Readers only ever get shared references, so multiple readers can
+        // not hand out aliasing mutable references
         ReadLockGuard {
             rwlock: &self.rwlock,
-            data: unsafe { &mut *self.data.get() },
+            data: unsafe { &*self.data.get() },
         }
     }
 
@@ -151,3 +153,29 @@ impl<T> RWLock<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rwlock_readers_and_writer() {
+        let lock = RWLock::new(5);
+
+        {
+            let r1 = lock.lock_read();
+            let r2 = lock.lock_read();
+            assert_eq!(*r1 + *r2, 10);
+            assert_eq!(split_val(lock.rwlock.load(Ordering::Relaxed)), (2, 0));
+        }
+
+        {
+            let mut w = lock.lock_write();
+            *w = 7;
+            assert_eq!(split_val(lock.rwlock.load(Ordering::Relaxed)), (0, 1));
+        }
+
+        assert_eq!(*lock.lock_read(), 7);
+        assert_eq!(lock.rwlock.load(Ordering::Relaxed), 0);
+    }
+}
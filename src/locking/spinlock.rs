@@ -4,6 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use crate::cpu::irq::IrqGuard;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -19,9 +20,88 @@ impl<'a, T> Drop for LockGuard<'a, T> {
     }
 }
 
+/// Contention counters of a [`SpinLock`]
+#[cfg(feature = "lock-stats")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LockStats {
+    /// Number of times the lock was taken
+    pub acquisitions: u64,
+    /// Number of acquisitions which had to wait for another holder
+    pub contended: u64,
+    /// Total number of spin iterations while waiting
+    pub spins: u64,
+}
+
+#[cfg(feature = "lock-stats")]
+#[derive(Debug)]
+struct LockCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+}
+
+#[cfg(feature = "lock-stats")]
+impl LockCounters {
+    const fn new() -> Self {
+        LockCounters {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+        }
+    }
+
+    // Called with the lock held
+    fn account(&self, spins: u64) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins > 0 {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+}
+
+// Without the lock-stats feature nothing is counted, taking a lock stays a
+// single atomic operation on the uncontended path
+#[cfg(not(feature = "lock-stats"))]
+#[derive(Debug)]
+struct LockCounters;
+
+#[cfg(not(feature = "lock-stats"))]
+impl LockCounters {
+    const fn new() -> Self {
+        LockCounters
+    }
+
+    #[inline(always)]
+    fn account(&self, _spins: u64) {}
+}
+
+/// Guard returned by [`SpinLock::lock_irqsave()`]. The lock is released
+/// before interrupts are enabled again.
+pub struct IrqLockGuard<'a, T> {
+    // Fields are dropped in declaration order
+    guard: LockGuard<'a, T>,
+    _irq: IrqGuard,
+}
+
+impl<'a, T> Deref for IrqLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// Ticket lock, waiters get the lock in the order they started waiting
 pub struct SpinLock<T> {
     current: AtomicU64,
     holder: AtomicU64,
+    counters: LockCounters,
     data: UnsafeCell<T>,
 }
 
@@ -32,25 +112,50 @@ impl<T> SpinLock<T> {
         SpinLock {
             current: AtomicU64::new(0),
             holder: AtomicU64::new(0),
+            counters: LockCounters::new(),
             data: UnsafeCell::new(data),
         }
     }
 
     pub fn lock(&self) -> LockGuard<T> {
         let ticket = self.current.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
         loop {
             let h = self.holder.load(Ordering::Acquire);
             if h == ticket {
                 break;
             }
+            spins += 1;
             core::hint::spin_loop();
         }
+        self.counters.account(spins);
         LockGuard {
             holder: &self.holder,
             data: unsafe { &mut *self.data.get() },
         }
     }
 
+    /// Takes the lock with interrupts disabled, for data which is also
+    /// accessed from interrupt or exception handlers. Interrupts are
+    /// restored to their previous state when the guard is dropped.
+    pub fn lock_irqsave(&self) -> IrqLockGuard<T> {
+        let irq = IrqGuard::new();
+        IrqLockGuard {
+            guard: self.lock(),
+            _irq: irq,
+        }
+    }
+
+    /// Like [`Self::lock_irqsave()`], but fails instead of waiting if the
+    /// lock is held
+    pub fn try_lock_irqsave(&self) -> Result<IrqLockGuard<T>, ()> {
+        let irq = IrqGuard::new();
+        Ok(IrqLockGuard {
+            guard: self.try_lock()?,
+            _irq: irq,
+        })
+    }
+
     pub fn try_lock(&self) -> Result<LockGuard<T>, ()> {
         let current = self.current.load(Ordering::Relaxed);
         let holder = self.holder.load(Ordering::Acquire);
@@ -63,6 +168,7 @@ impl<T> SpinLock<T> {
                 Ordering::Relaxed,
             );
             if result.is_ok() {
+                self.counters.account(0);
                 return Ok(LockGuard {
                     holder: &self.holder,
                     data: unsafe { &mut *self.data.get() },
@@ -73,6 +179,15 @@ impl<T> SpinLock<T> {
         Err(())
     }
 
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.counters.acquisitions.load(Ordering::Relaxed),
+            contended: self.counters.contended.load(Ordering::Relaxed),
+            spins: self.counters.spins.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn unlock(&mut self) {
         self.holder.fetch_add(1, Ordering::Release);
     }
@@ -90,3 +205,54 @@ impl<'a, T> DerefMut for LockGuard<'a, T> {
         self.data
     }
 }
+
+#[cfg(all(test, feature = "lock-stats"))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_spinlock_stats() {
+        let lock = SpinLock::new(0);
+
+        *lock.lock() += 1;
+        {
+            let _guard = lock.lock();
+            assert!(lock.try_lock().is_err());
+        }
+        *lock.try_lock().unwrap() += 1;
+
+        let stats = lock.stats();
+        assert_eq!(stats.acquisitions, 3);
+        assert_eq!(stats.contended, 0);
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    fn test_spinlock_stats_contended() {
+        let lock = Arc::new(SpinLock::new(0));
+
+        let guard = lock.lock();
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.lock() += 1)
+        };
+
+        // Keep the lock until the other thread has drawn its ticket, it has
+        // to spin at least once then
+        while lock.current.load(Ordering::Relaxed) < 2 {
+            core::hint::spin_loop();
+        }
+        drop(guard);
+        waiter.join().unwrap();
+
+        let stats = lock.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contended, 1);
+        assert!(stats.spins > 0);
+        assert_eq!(*lock.lock(), 1);
+    }
+}
@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::locking::SpinLock;
+use core::fmt;
+
+/// Size of the in-memory log, older output is overwritten
+pub const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Identifies the log buffer in a dump of SVSM memory
+pub const LOG_BUFFER_MAGIC: [u8; 8] = *b"SVSMLOG1";
+
+/// Header in front of the log data. It lets tools which only have a memory
+/// dump find the log and tell where the newest output ends.
+#[derive(Debug)]
+#[repr(C)]
+pub struct LogBufferHeader {
+    magic: [u8; 8],
+    // Size of the data area following the header
+    size: u32,
+    reserved: u32,
+    // Total number of bytes written
+    head: u64,
+}
+
+/// Ring of the most recent console output. Positions handed out to readers
+/// count all bytes ever written, so a reader can tell when output it has not
+/// seen yet was overwritten.
+#[derive(Debug)]
+#[repr(C)]
+pub struct LogBuffer {
+    header: LogBufferHeader,
+    buf: [u8; LOG_BUFFER_SIZE],
+}
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        LogBuffer {
+            header: LogBufferHeader {
+                magic: LOG_BUFFER_MAGIC,
+                size: LOG_BUFFER_SIZE as u32,
+                reserved: 0,
+                head: 0,
+            },
+            buf: [0; LOG_BUFFER_SIZE],
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.buf[(self.header.head % LOG_BUFFER_SIZE as u64) as usize] = *b;
+            self.header.head += 1;
+        }
+    }
+
+    /// Oldest position which is still available
+    pub fn tail(&self) -> u64 {
+        self.header.head.saturating_sub(LOG_BUFFER_SIZE as u64)
+    }
+
+    pub fn head(&self) -> u64 {
+        self.header.head
+    }
+
+    /// Copies log data starting at position `pos` into `out`. If `pos` was
+    /// already overwritten, copying starts at the oldest available byte.
+    /// Returns the number of bytes copied and the position to continue
+    /// reading from.
+    pub fn read(&self, pos: u64, out: &mut [u8]) -> (usize, u64) {
+        let start = pos.clamp(self.tail(), self.header.head);
+        let len = out.len().min((self.header.head - start) as usize);
+
+        for (i, b) in out[..len].iter_mut().enumerate() {
+            *b = self.buf[((start + i as u64) % LOG_BUFFER_SIZE as u64) as usize];
+        }
+
+        (len, start + len as u64)
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub static LOG_BUFFER: SpinLock<LogBuffer> = SpinLock::new(LogBuffer::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_buffer_read() {
+        let mut log = LogBuffer::new();
+        let mut out = [0u8; 8];
+
+        log.write_bytes(b"hello world");
+        assert_eq!(log.read(0, &mut out), (8, 8));
+        assert_eq!(&out, b"hello wo");
+        assert_eq!(log.read(8, &mut out), (3, 11));
+        assert_eq!(&out[..3], b"rld");
+        assert_eq!(log.read(11, &mut out), (0, 11));
+    }
+
+    #[test]
+    fn test_log_buffer_wrap() {
+        let mut log = LogBuffer::new();
+        let mut out = [0u8; 4];
+
+        for _ in 0..LOG_BUFFER_SIZE / 4 {
+            log.write_bytes(b"abcd");
+        }
+        log.write_bytes(b"wxyz");
+
+        // Position 0 was overwritten, reading restarts at the oldest byte
+        assert_eq!(log.tail(), 4);
+        assert_eq!(log.read(0, &mut out), (4, 8));
+        assert_eq!(&out, b"abcd");
+
+        let head = log.head();
+        assert_eq!(log.read(head - 4, &mut out), (4, head));
+        assert_eq!(&out, b"wxyz");
+    }
+
+    #[test]
+    fn test_log_buffer_header() {
+        let mut log = LogBuffer::new();
+        log.write_bytes(b"abc");
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (&log as *const LogBuffer).cast::<u8>(),
+                core::mem::size_of::<LogBufferHeader>() + 3,
+            )
+        };
+        assert_eq!(bytes[..8], LOG_BUFFER_MAGIC);
+        assert_eq!(bytes[8..12], (LOG_BUFFER_SIZE as u32).to_le_bytes());
+        assert_eq!(bytes[16..24], 3u64.to_le_bytes());
+        assert_eq!(&bytes[24..], b"abc");
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Runtime log filter. Messages pass when their level is at or below the
+// level of the longest rule matching their module path, or the default
+// level when no rule matches. The filter is set with a string like
+// "info,svsm::fw_cfg=warn,svsm::mm=debug".
+
+use crate::cmdline::cmdline;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::locking::SpinLock;
+use core::str::FromStr;
+use log::LevelFilter;
+
+/// Level used until the host configures a filter
+pub const LOG_LEVEL_DEFAULT: LevelFilter = LevelFilter::Info;
+
+// Filter set by the host at boot
+const LOG_FILTER_FILE: &str = "opt/svsm/log-filter";
+
+const LOG_FILTER_MAX_RULES: usize = 16;
+const LOG_TARGET_MAX_LEN: usize = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFilterError {
+    // A level name is not one of off/error/warn/info/debug/trace
+    InvalidLevel,
+    // A module path is empty or longer than LOG_TARGET_MAX_LEN
+    InvalidTarget,
+    // More than LOG_FILTER_MAX_RULES rules
+    TooManyRules,
+}
+
+impl From<LogFilterError> for SvsmError {
+    fn from(e: LogFilterError) -> Self {
+        Self::LogFilter(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LogRule {
+    target: [u8; LOG_TARGET_MAX_LEN],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl LogRule {
+    const EMPTY: LogRule = LogRule {
+        target: [0; LOG_TARGET_MAX_LEN],
+        len: 0,
+        level: LevelFilter::Off,
+    };
+
+    fn target(&self) -> &[u8] {
+        &self.target[..self.len]
+    }
+
+    // A rule for "svsm::mm" matches "svsm::mm" and "svsm::mm::alloc", but
+    // not "svsm::mmio"
+    fn matches(&self, target: &str) -> bool {
+        let rule = self.target();
+        let target = target.as_bytes();
+        target.starts_with(rule)
+            && (target.len() == rule.len() || target[rule.len()..].starts_with(b"::"))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LogFilter {
+    default: LevelFilter,
+    rules: [LogRule; LOG_FILTER_MAX_RULES],
+    nr_rules: usize,
+}
+
+impl LogFilter {
+    pub const fn new(default: LevelFilter) -> Self {
+        LogFilter {
+            default,
+            rules: [LogRule::EMPTY; LOG_FILTER_MAX_RULES],
+            nr_rules: 0,
+        }
+    }
+
+    /// Parses a comma separated list of `level` and `module=level` entries.
+    /// The last bare level becomes the default.
+    pub fn parse(spec: &str) -> Result<Self, LogFilterError> {
+        let mut filter = LogFilter::new(LOG_LEVEL_DEFAULT);
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((target, level)) = entry.split_once('=') else {
+                filter.default = parse_level(entry)?;
+                continue;
+            };
+            filter.add_rule(target.trim(), parse_level(level)?)?;
+        }
+
+        Ok(filter)
+    }
+
+    fn add_rule(&mut self, target: &str, level: LevelFilter) -> Result<(), LogFilterError> {
+        if target.is_empty() || target.len() > LOG_TARGET_MAX_LEN {
+            return Err(LogFilterError::InvalidTarget);
+        }
+
+        // Later rules for the same module replace earlier ones
+        let idx = self.rules[..self.nr_rules]
+            .iter()
+            .position(|r| r.target() == target.as_bytes())
+            .unwrap_or(self.nr_rules);
+        if idx == LOG_FILTER_MAX_RULES {
+            return Err(LogFilterError::TooManyRules);
+        }
+
+        let rule = &mut self.rules[idx];
+        rule.target[..target.len()].copy_from_slice(target.as_bytes());
+        rule.len = target.len();
+        rule.level = level;
+        self.nr_rules = self.nr_rules.max(idx + 1);
+        Ok(())
+    }
+
+    /// Level that applies to messages from module path `target`
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.rules[..self.nr_rules]
+            .iter()
+            .filter(|r| r.matches(target))
+            .max_by_key(|r| r.len)
+            .map_or(self.default, |r| r.level)
+    }
+
+    /// Most verbose level of any rule, messages above it never pass
+    pub fn max_level(&self) -> LevelFilter {
+        self.rules[..self.nr_rules]
+            .iter()
+            .map(|r| r.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, LogFilterError> {
+    LevelFilter::from_str(s.trim()).map_err(|_| LogFilterError::InvalidLevel)
+}
+
+static LOG_FILTER: SpinLock<LogFilter> = SpinLock::new(LogFilter::new(LOG_LEVEL_DEFAULT));
+
+/// Whether a message at `level` from module path `target` is logged
+pub fn log_enabled(level: log::Level, target: &str) -> bool {
+    level <= LOG_FILTER.lock_irqsave().level(target)
+}
+
+/// Replaces the current log filter with the one described by `spec`. The
+/// current filter stays in place when `spec` is invalid.
+pub fn log_set_filter(spec: &str) -> Result<(), LogFilterError> {
+    let filter = LogFilter::parse(spec)?;
+    *LOG_FILTER.lock_irqsave() = filter;
+    log::set_max_level(filter.max_level());
+    Ok(())
+}
+
+/// Applies the log filter configured by the host, if any. A filter on the
+/// kernel command line takes precedence.
+pub fn log_filter_init(fw_cfg: &FwCfg) -> Result<(), SvsmError> {
+    if cmdline().get("log").is_some() {
+        return Ok(());
+    }
+
+    let Ok(file) = fw_cfg.file_selector(LOG_FILTER_FILE) else {
+        return Ok(());
+    };
+
+    let mut buf = [0u8; 256];
+    let len = fw_cfg.read_file(&file, &mut buf)?;
+    let spec = core::str::from_utf8(&buf[..len])
+        .map_err(|_| LogFilterError::InvalidTarget)?
+        .trim_end_matches(['\0', '\n']);
+    log_set_filter(spec)?;
+    log::info!("Log filter: {}", spec);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let filter = LogFilter::parse("warn, svsm::mm=debug,svsm::mm::alloc=off").unwrap();
+        assert_eq!(filter.level("svsm::fw_cfg"), LevelFilter::Warn);
+        assert_eq!(filter.level("svsm::mm"), LevelFilter::Debug);
+        assert_eq!(filter.level("svsm::mm::pagetable"), LevelFilter::Debug);
+        assert_eq!(filter.level("svsm::mm::alloc"), LevelFilter::Off);
+        assert_eq!(filter.level("svsm::mmio"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!(
+            LogFilter::parse("svsm=loud").unwrap_err(),
+            LogFilterError::InvalidLevel
+        );
+        assert_eq!(
+            LogFilter::parse("=info").unwrap_err(),
+            LogFilterError::InvalidTarget
+        );
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::crypto::sha384::{sha384, SHA384_DIGEST_SIZE};
+use crate::protocols::{ProtocolInfo, SVSM_PROTOCOLS};
+use crate::sev::secrets_page::vmpck_retired;
+use alloc::vec::Vec;
+
+pub const SVSM_MANIFEST_MAGIC: [u8; 8] = *b"SVSMMFST";
+pub const SVSM_MANIFEST_VERSION: u32 = 1;
+
+// Build features and launch options which change the surface exposed to
+// the guest
+pub const SVSM_MANIFEST_FEATURE_STACKTRACE: u64 = 1 << 0;
+pub const SVSM_MANIFEST_FEATURE_LOG_EXPORT: u64 = 1 << 1;
+pub const SVSM_MANIFEST_FEATURE_VMPCK_RETIRED: u64 = 1 << 2;
+
+/// Version control identifier of the build, if it was known at build time
+pub const SVSM_BUILD_ID: &str = match option_env!("SVSM_BUILD_ID") {
+    Some(id) => id,
+    None => "unknown",
+};
+
+fn manifest_features() -> u64 {
+    let mut features = 0;
+
+    if cfg!(feature = "enable-stacktrace") {
+        features |= SVSM_MANIFEST_FEATURE_STACKTRACE;
+    }
+    if cfg!(feature = "enable-log-export") {
+        features |= SVSM_MANIFEST_FEATURE_LOG_EXPORT;
+    }
+    if vmpck_retired() {
+        features |= SVSM_MANIFEST_FEATURE_VMPCK_RETIRED;
+    }
+
+    features
+}
+
+/// Serializes a manifest describing the SVSM build. All integers are little
+/// endian:
+///
+/// ```text
+/// magic           [u8; 8]
+/// version         u32
+/// protocol count  u32
+/// features        u64
+/// build id length u32
+/// build id        [u8; build id length]
+/// protocols       [{ id: u32, version_min: u32, version_max: u32 }; count]
+/// ```
+fn serialize_manifest(protocols: &[ProtocolInfo], features: u64, build_id: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.extend_from_slice(&SVSM_MANIFEST_MAGIC);
+    buf.extend_from_slice(&SVSM_MANIFEST_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(protocols.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&features.to_le_bytes());
+    buf.extend_from_slice(&(build_id.len() as u32).to_le_bytes());
+    buf.extend_from_slice(build_id.as_bytes());
+
+    for p in protocols {
+        buf.extend_from_slice(&p.id.to_le_bytes());
+        buf.extend_from_slice(&p.version_min.to_le_bytes());
+        buf.extend_from_slice(&p.version_max.to_le_bytes());
+    }
+
+    buf
+}
+
+/// Returns the manifest of this SVSM build
+pub fn svsm_manifest() -> Vec<u8> {
+    serialize_manifest(SVSM_PROTOCOLS, manifest_features(), SVSM_BUILD_ID)
+}
+
+/// Digest of the manifest, bound into the attestation reports the SVSM
+/// requests for the guest so verifiers know which protocols and features
+/// the SVSM exposes.
+pub fn svsm_manifest_digest() -> [u8; SHA384_DIGEST_SIZE] {
+    sha384(&svsm_manifest())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_layout() {
+        let protocols = [ProtocolInfo {
+            id: 1,
+            version_min: 1,
+            version_max: 2,
+        }];
+        let buf = serialize_manifest(&protocols, 3, "abc");
+
+        assert_eq!(buf.len(), 8 + 4 + 4 + 8 + 4 + 3 + 12);
+        assert_eq!(&buf[..8], b"SVSMMFST");
+        assert_eq!(&buf[12..16], &1u32.to_le_bytes());
+        assert_eq!(&buf[16..24], &3u64.to_le_bytes());
+        assert_eq!(&buf[28..31], b"abc");
+        assert_eq!(&buf[31..], &[1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::crypto::sha384::{Sha384, SHA384_DIGEST_SIZE};
+use crate::locking::SpinLock;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::types::{MemoryRegion, PAGE_SIZE};
+use crate::utils::immut_after_init::ImmutAfterInitCell;
+use alloc::vec::Vec;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of pages hashed per call to [`fw_measure_work()`]
+pub const FW_MEASURE_BUDGET: usize = 64;
+
+struct FwMeasureState {
+    regions: Vec<MemoryRegion>,
+    region: usize,
+    next: PhysAddr,
+    ctx: Sha384,
+    digest: Option<[u8; SHA384_DIGEST_SIZE]>,
+}
+
+impl FwMeasureState {
+    const fn new() -> Self {
+        FwMeasureState {
+            regions: Vec::new(),
+            region: 0,
+            next: PhysAddr::null(),
+            ctx: Sha384::new(),
+            digest: None,
+        }
+    }
+
+    fn finish(&mut self) {
+        let ctx = core::mem::take(&mut self.ctx);
+        self.digest = Some(ctx.finalize());
+        FW_MEASURE_DONE.store(true, Ordering::Release);
+    }
+
+    // Hashes up to `budget` pages and returns the number of pages hashed
+    fn work(&mut self, budget: usize) -> usize {
+        let mut done = 0;
+
+        while done < budget {
+            let Some(region) = self.regions.get(self.region) else {
+                self.finish();
+                break;
+            };
+
+            let end = region.end_phys();
+            if self.next >= end {
+                self.region += 1;
+                if let Some(r) = self.regions.get(self.region) {
+                    self.next = r.start_phys();
+                }
+                continue;
+            }
+
+            let guard = PerCPUPageMappingGuard::create_4k(self.next)
+                .expect("Failed to map firmware page for measurement");
+            let page =
+                unsafe { slice::from_raw_parts(guard.virt_addr().as_ptr::<u8>(), PAGE_SIZE) };
+            self.ctx.update(page);
+
+            self.next = self.next.offset(PAGE_SIZE);
+            done += 1;
+        }
+
+        done
+    }
+}
+
+static FW_MEASURE: SpinLock<FwMeasureState> = SpinLock::new(FwMeasureState::new());
+static FW_MEASURE_ACTIVE: AtomicBool = AtomicBool::new(false);
+static FW_MEASURE_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Starts measuring the given firmware regions in the background. Idle CPUs
+/// pick up the work through [`fw_measure_work()`]. The regions must stay
+/// unchanged until [`fw_measure_wait()`] returns.
+pub fn fw_measure_start(mut regions: Vec<MemoryRegion>) {
+    // Measure in a well-defined order, no matter how the host listed them
+    regions.sort_unstable_by_key(|r| r.start);
+
+    let mut state = FW_MEASURE.lock();
+    assert!(!FW_MEASURE_ACTIVE.load(Ordering::Acquire));
+
+    state.next = regions
+        .first()
+        .map(MemoryRegion::start_phys)
+        .unwrap_or(PhysAddr::null());
+    state.regions = regions;
+    FW_MEASURE_ACTIVE.store(true, Ordering::Release);
+}
+
+/// Makes progress on a pending firmware measurement. Returns true if there
+/// was work to do.
+pub fn fw_measure_work(budget: usize) -> bool {
+    if !FW_MEASURE_ACTIVE.load(Ordering::Acquire) || FW_MEASURE_DONE.load(Ordering::Acquire) {
+        return false;
+    }
+
+    // Hashing is sequential, so only one CPU can make progress at a time
+    match FW_MEASURE.try_lock() {
+        Ok(mut state) => {
+            if state.digest.is_none() {
+                state.work(budget);
+            }
+            true
+        }
+        Err(()) => false,
+    }
+}
+
+/// Completion barrier for the firmware measurement. Helps with the remaining
+/// work and returns the digest once all regions are hashed.
+pub fn fw_measure_wait() -> [u8; SHA384_DIGEST_SIZE] {
+    assert!(FW_MEASURE_ACTIVE.load(Ordering::Acquire));
+
+    while !FW_MEASURE_DONE.load(Ordering::Acquire) {
+        if !fw_measure_work(FW_MEASURE_BUDGET) {
+            core::hint::spin_loop();
+        }
+    }
+
+    fw_measurement().unwrap()
+}
+
+/// Returns the firmware digest if the measurement has completed.
+pub fn fw_measurement() -> Option<[u8; SHA384_DIGEST_SIZE]> {
+    if !FW_MEASURE_DONE.load(Ordering::Acquire) {
+        return None;
+    }
+
+    FW_MEASURE.lock().digest
+}
+
+static KERNEL_MEASUREMENT: ImmutAfterInitCell<[u8; SHA384_DIGEST_SIZE]> =
+    ImmutAfterInitCell::new([0; SHA384_DIGEST_SIZE]);
+
+/// Records the kernel digest stage2 took before loading the kernel.
+///
+/// # Safety
+///
+/// Must be called before any other CPU runs or reads the measurement.
+pub unsafe fn kernel_measurement_init(digest: &[u8; SHA384_DIGEST_SIZE]) {
+    KERNEL_MEASUREMENT.reinit(digest);
+}
+
+/// SHA-384 digest of the kernel ELF file this SVSM was loaded from
+pub fn kernel_measurement() -> [u8; SHA384_DIGEST_SIZE] {
+    *KERNEL_MEASUREMENT
+}
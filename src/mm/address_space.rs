@@ -5,6 +5,9 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
+#[cfg(not(test))]
+use crate::mm::pagetable::translate_current;
+use crate::mm::virtualrange::VirtualRange;
 use crate::utils::immut_after_init::ImmutAfterInitCell;
 
 #[derive(Copy, Clone)]
@@ -15,18 +18,7 @@ struct KernelMapping {
     phys_start: PhysAddr,
 }
 
-impl KernelMapping {
-    pub const fn new() -> Self {
-        KernelMapping {
-            virt_start: VirtAddr::null(),
-            virt_end: VirtAddr::null(),
-            phys_start: PhysAddr::null(),
-        }
-    }
-}
-
-static KERNEL_MAPPING: ImmutAfterInitCell<KernelMapping> =
-    ImmutAfterInitCell::new(KernelMapping::new());
+static KERNEL_MAPPING: ImmutAfterInitCell<KernelMapping> = ImmutAfterInitCell::uninit();
 
 pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: PhysAddr) {
     let km = KernelMapping {
@@ -34,15 +26,18 @@ pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: PhysAd
         virt_end: vend,
         phys_start: pstart,
     };
-    unsafe {
-        KERNEL_MAPPING.init(&km);
-    }
+    KERNEL_MAPPING
+        .init(&km)
+        .expect("Kernel mapping initialized twice");
 }
 
 #[cfg(not(test))]
 pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
+    // Addresses outside of the kernel mapping, like per-CPU or vmalloc
+    // mappings, have no fixed offset to their physical address
     if vaddr < KERNEL_MAPPING.virt_start || vaddr >= KERNEL_MAPPING.virt_end {
-        panic!("Invalid physical address {:#018x}", vaddr);
+        return translate_current(vaddr)
+            .unwrap_or_else(|| panic!("Invalid virtual address {:#018x}", vaddr));
     }
 
     let offset: usize = vaddr - KERNEL_MAPPING.virt_start;
@@ -116,6 +111,11 @@ pub const SVSM_SHARED_BASE: usize = sign_extend(PGTABLE_LVL3_IDX_SHARED << ((3 *
 pub const SVSM_SHARED_STACK_BASE: usize = SVSM_SHARED_BASE + (256 * SIZE_1G);
 pub const SVSM_SHARED_STACK_END: usize = SVSM_SHARED_STACK_BASE + SIZE_1G;
 
+/// Mapping range for dynamically assigned mappings, see mm::vmalloc
+pub const SVSM_SHARED_VMALLOC_BASE: usize = SVSM_SHARED_STACK_END;
+pub const SVSM_SHARED_VMALLOC_END: usize =
+    SVSM_SHARED_VMALLOC_BASE + VirtualRange::CAPACITY * PAGE_SIZE;
+
 /// PerCPU mappings level 3 index
 pub const PGTABLE_LVL3_IDX_PERCPU: usize = 510;
 
@@ -131,8 +131,9 @@ pub const SVSM_PERCPU_VMSA_BASE: usize = SVSM_PERCPU_BASE + (4 * SIZE_LEVEL0);
 /// Region for PerCPU Stacks
 pub const SVSM_PERCPU_STACKS_BASE: usize = SVSM_PERCPU_BASE + SIZE_LEVEL1;
 
-/// Stack address of the per-cpu init task
-pub const SVSM_STACKS_INIT_TASK: usize = SVSM_PERCPU_STACKS_BASE;
+/// Stack address of the per-cpu init task, there is a guard area below it
+/// like below every other stack
+pub const SVSM_STACKS_INIT_TASK: usize = SVSM_PERCPU_STACKS_BASE + STACK_GUARD_SIZE;
 
 ///  IST Stacks base address
 pub const SVSM_STACKS_IST_BASE: usize = SVSM_STACKS_INIT_TASK + STACK_TOTAL_SIZE;
@@ -140,6 +141,15 @@ pub const SVSM_STACKS_IST_BASE: usize = SVSM_STACKS_INIT_TASK + STACK_TOTAL_SIZE
 /// DoubleFault IST stack base address
 pub const SVSM_STACK_IST_DF_BASE: usize = SVSM_STACKS_IST_BASE;
 
+/// #VC IST stack base address
+pub const SVSM_STACK_IST_VC_BASE: usize = SVSM_STACK_IST_DF_BASE + STACK_TOTAL_SIZE;
+
+/// #HV IST stack base address
+pub const SVSM_STACK_IST_HV_BASE: usize = SVSM_STACK_IST_VC_BASE + STACK_TOTAL_SIZE;
+
+/// End of the PerCPU stacks
+pub const SVSM_PERCPU_STACKS_END: usize = SVSM_STACK_IST_HV_BASE + STACK_TOTAL_SIZE;
+
 /// Base Address for temporary mappings - used by page-table guards
 pub const SVSM_PERCPU_TEMP_BASE: usize = SVSM_PERCPU_BASE + SIZE_LEVEL2;
 
@@ -5,6 +5,8 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
+#[cfg(not(test))]
+use crate::debug::stacktrace::{StackUnwinder, UnwoundStackFrame};
 use crate::error::SvsmError;
 use crate::locking::SpinLock;
 use crate::mm::virt_to_phys;
@@ -17,8 +19,8 @@ use log;
 
 struct PageStorageType(u64);
 
-// Support allocations up to order-5 (128kb)
-pub const MAX_ORDER: usize = 6;
+// Support allocations up to order-9 (2MB)
+pub const MAX_ORDER: usize = 10;
 
 pub fn get_order(size: usize) -> usize {
     let mut val = (size - 1) >> PAGE_SHIFT;
@@ -641,6 +643,23 @@ impl MemoryRegion {
         }
     }
 
+    /// Frees the order-`order` block at `vaddr`, which must have been
+    /// allocated with the same order. Free buddies are coalesced into
+    /// higher-order blocks.
+    pub fn free_pages(&mut self, vaddr: VirtAddr, order: usize) {
+        match self.get_page_info(vaddr) {
+            Ok(Page::Allocated(ai)) if ai.order == order => {}
+            Ok(Page::Allocated(ai)) => panic!(
+                "Freeing order-{} pages at {:#018x} as order-{}",
+                ai.order, vaddr, order
+            ),
+            _ => panic!("Freeing unallocated pages at {:#018x}", vaddr),
+        }
+
+        let pfn = (vaddr - self.start_virt) / PAGE_SIZE;
+        self.free_page_order(pfn, order);
+    }
+
     pub fn memory_info(&self) -> MemInfo {
         MemInfo {
             total_pages: self.nr_pages,
@@ -783,6 +802,10 @@ pub fn free_page(vaddr: VirtAddr) {
     ROOT_MEM.lock().free_page(vaddr)
 }
 
+pub fn free_pages(vaddr: VirtAddr, order: usize) {
+    ROOT_MEM.lock().free_pages(vaddr, order)
+}
+
 pub fn memory_info() -> MemInfo {
     ROOT_MEM.lock().memory_info()
 }
@@ -896,6 +919,23 @@ impl SlabPage {
     }
 }
 
+/// Usage of a single slab cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Size of the objects served by the cache
+    pub item_size: usize,
+    /// Pages backing the cache
+    pub pages: usize,
+    /// Pages without free slots
+    pub full_pages: usize,
+    /// Pages without allocated slots
+    pub free_pages: usize,
+    /// Total number of slots
+    pub capacity: usize,
+    /// Number of free slots
+    pub free: usize,
+}
+
 #[repr(align(16))]
 struct SlabCommon {
     item_size: u16,
@@ -983,6 +1023,17 @@ impl SlabCommon {
         }
     }
 
+    fn stats(&self) -> SlabStats {
+        SlabStats {
+            item_size: self.item_size as usize,
+            pages: self.pages as usize,
+            full_pages: self.full_pages as usize,
+            free_pages: self.free_pages as usize,
+            capacity: self.capacity as usize,
+            free: self.free as usize,
+        }
+    }
+
     fn deallocate_slot(&mut self, vaddr: VirtAddr) {
         let mut page = &mut self.page;
         loop {
@@ -1182,11 +1233,156 @@ impl Slab {
         self.common.deallocate_slot(vaddr);
         self.shrink_slab();
     }
+
+    fn stats(&self) -> SlabStats {
+        self.common.stats()
+    }
 }
 
 static SLAB_PAGE_SLAB: SpinLock<SlabPageSlab> = SpinLock::new(SlabPageSlab::new());
 
-pub struct SvsmAllocator {
+/// Interface between the global allocator and the code which actually hands
+/// out heap memory. Backends are selected at build time, see
+/// [`SvsmAllocator`].
+pub trait AllocBackend {
+    fn allocate(&self, layout: Layout) -> Result<VirtAddr, SvsmError>;
+    fn deallocate(&self, vaddr: VirtAddr, layout: Layout);
+
+    /// Returns usage information for slab cache number `idx`, or `None` if
+    /// the backend has no such cache.
+    fn slab_stats(&self, _idx: usize) -> Option<SlabStats> {
+        None
+    }
+
+    /// Logs the outstanding allocations, if the backend keeps track of them
+    fn dump_allocations(&self) {
+        log::info!("Heap allocations are only tracked with the alloc-hardened feature");
+    }
+
+    /// Number of bytes usable at `vaddr`, which was allocated with `layout`.
+    /// At least `layout.size()`.
+    fn usable_size(&self, _vaddr: VirtAddr, layout: Layout) -> usize {
+        layout.size()
+    }
+
+    /// Resizes the allocation at `vaddr` to `new_layout`, which has the
+    /// alignment of `layout`. The allocation stays in place if it is large
+    /// enough and a smaller block would not do, otherwise the contents move
+    /// to a new allocation.
+    fn reallocate(
+        &self,
+        vaddr: VirtAddr,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> Result<VirtAddr, SvsmError> {
+        let usable = self.usable_size(vaddr, layout);
+        if new_layout.size() <= usable && new_layout.size() > usable / 2 {
+            return Ok(vaddr);
+        }
+
+        let new_vaddr = self.allocate(new_layout)?;
+        let len = layout.size().min(new_layout.size());
+        unsafe {
+            ptr::copy_nonoverlapping(vaddr.as_ptr::<u8>(), new_vaddr.as_mut_ptr::<u8>(), len);
+        }
+        self.deallocate(vaddr, layout);
+        Ok(new_vaddr)
+    }
+}
+
+// Size of the memory block needed to serve `layout`. Slab objects and
+// compound pages are naturally aligned to their size, so rounding the size up
+// to the alignment takes care of alignment as well.
+fn heap_block_size(layout: Layout) -> usize {
+    layout.pad_to_align().size().max(layout.align())
+}
+
+fn allocate_heap_pages(layout: Layout) -> Result<VirtAddr, SvsmError> {
+    let order = get_order(heap_block_size(layout));
+    if order >= MAX_ORDER {
+        return Err(SvsmError::Mem);
+    }
+
+    let vaddr = allocate_pages(order)?;
+    if vaddr.bits() & (layout.align() - 1) == 0 {
+        return Ok(vaddr);
+    }
+
+    // Compound pages are only aligned relative to the start of the heap.
+    // Allocate a block with room to move the start to the next aligned
+    // address inside of it. Freeing a page in the middle of a compound page
+    // frees the whole compound page.
+    free_page(vaddr);
+    let size = align_up(layout.size().max(1), PAGE_SIZE) + layout.align() - PAGE_SIZE;
+    let order = get_order(size);
+    if order >= MAX_ORDER {
+        return Err(SvsmError::Mem);
+    }
+
+    let vaddr = allocate_pages(order)?;
+    Ok(VirtAddr::from(align_up(vaddr.bits(), layout.align())))
+}
+
+fn free_heap_page(vaddr: VirtAddr) {
+    match ROOT_MEM.lock().get_page_info(vaddr) {
+        Ok(Page::Allocated(_) | Page::CompoundPage(_)) => {}
+        Ok(_) => panic!("Freeing memory on unsupported page type"),
+        Err(_e) => panic!("Freeing unknown memory"),
+    }
+    free_page(vaddr);
+}
+
+// Bytes from `vaddr` to the end of the slab object or the page block it
+// was allocated from
+fn heap_usable_size(vaddr: VirtAddr) -> usize {
+    let root = ROOT_MEM.lock();
+    let pfn = (vaddr - root.start_virt) / PAGE_SIZE;
+    let (start_pfn, order) = match root.get_page_info(vaddr) {
+        Ok(Page::Allocated(ai)) => (pfn, ai.order),
+        Ok(Page::CompoundPage(ci)) => (pfn & !((1usize << ci.order) - 1), ci.order),
+        Ok(Page::SlabPage(si)) => {
+            assert!(!si.slab.is_null());
+            let slab = si.slab.as_ptr::<Slab>();
+            return unsafe { (*slab).common.item_size as usize };
+        }
+        Ok(_) => panic!("Resizing memory on unsupported page type"),
+        Err(_e) => panic!("Resizing unknown memory"),
+    };
+
+    root.start_virt
+        .offset((start_pfn + (1 << order)) * PAGE_SIZE)
+        - vaddr
+}
+
+/// Serves every allocation with whole pages from the page allocator. Wastes
+/// memory on small objects, but needs no metadata besides the page
+/// allocator's own.
+#[derive(Debug, Default)]
+pub struct PageAllocBackend;
+
+impl PageAllocBackend {
+    pub const fn new() -> Self {
+        PageAllocBackend
+    }
+}
+
+impl AllocBackend for PageAllocBackend {
+    fn allocate(&self, layout: Layout) -> Result<VirtAddr, SvsmError> {
+        allocate_heap_pages(layout)
+    }
+
+    fn deallocate(&self, vaddr: VirtAddr, _layout: Layout) {
+        free_heap_page(vaddr);
+    }
+
+    fn usable_size(&self, vaddr: VirtAddr, _layout: Layout) -> usize {
+        heap_usable_size(vaddr)
+    }
+}
+
+/// Serves objects up to 2048 bytes from size-class slabs and larger ones
+/// from the page allocator.
+pub struct SlabAllocBackend {
     slab_size_32: SpinLock<Slab>,
     slab_size_64: SpinLock<Slab>,
     slab_size_128: SpinLock<Slab>,
@@ -1196,9 +1392,9 @@ pub struct SvsmAllocator {
     slab_size_2048: SpinLock<Slab>,
 }
 
-impl SvsmAllocator {
+impl SlabAllocBackend {
     pub const fn new() -> Self {
-        SvsmAllocator {
+        SlabAllocBackend {
             slab_size_32: SpinLock::new(Slab::new(32)),
             slab_size_64: SpinLock::new(Slab::new(64)),
             slab_size_128: SpinLock::new(Slab::new(128)),
@@ -1210,71 +1406,334 @@ impl SvsmAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for SvsmAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let ret: Result<VirtAddr, SvsmError>;
-        let size = layout.size();
+impl SlabAllocBackend {
+    fn slab(&self, idx: usize) -> Option<&SpinLock<Slab>> {
+        match idx {
+            0 => Some(&self.slab_size_32),
+            1 => Some(&self.slab_size_64),
+            2 => Some(&self.slab_size_128),
+            3 => Some(&self.slab_size_256),
+            4 => Some(&self.slab_size_512),
+            5 => Some(&self.slab_size_1024),
+            6 => Some(&self.slab_size_2048),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SlabAllocBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocBackend for SlabAllocBackend {
+    fn allocate(&self, layout: Layout) -> Result<VirtAddr, SvsmError> {
+        let size = heap_block_size(layout);
 
         if size <= 32 {
-            ret = self.slab_size_32.lock().allocate();
+            self.slab_size_32.lock().allocate()
         } else if size <= 64 {
-            ret = self.slab_size_64.lock().allocate();
+            self.slab_size_64.lock().allocate()
         } else if size <= 128 {
-            ret = self.slab_size_128.lock().allocate();
+            self.slab_size_128.lock().allocate()
         } else if size <= 256 {
-            ret = self.slab_size_256.lock().allocate();
+            self.slab_size_256.lock().allocate()
         } else if size <= 512 {
-            ret = self.slab_size_512.lock().allocate();
+            self.slab_size_512.lock().allocate()
         } else if size <= 1024 {
-            ret = self.slab_size_1024.lock().allocate();
+            self.slab_size_1024.lock().allocate()
         } else if size <= 2048 {
-            ret = self.slab_size_2048.lock().allocate();
-        } else if size <= 4096 {
-            ret = allocate_page();
+            self.slab_size_2048.lock().allocate()
         } else {
-            let order = get_order(size);
-            if order >= MAX_ORDER {
-                return ptr::null_mut();
-            }
-            ret = allocate_pages(order);
-        }
-
-        if let Err(_e) = ret {
-            return ptr::null_mut();
+            allocate_heap_pages(layout)
         }
-
-        ret.unwrap().as_mut_ptr::<u8>()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let virt_addr = VirtAddr::from(ptr);
-
-        let result = ROOT_MEM.lock().get_page_info(virt_addr);
+    fn deallocate(&self, vaddr: VirtAddr, _layout: Layout) {
+        let result = ROOT_MEM.lock().get_page_info(vaddr);
 
         if let Err(_e) = result {
             panic!("Freeing unknown memory");
         }
 
-        let info = result.unwrap();
-
-        match info {
-            Page::Allocated(_ai) => {
-                free_page(virt_addr);
+        match result.unwrap() {
+            Page::Allocated(_) | Page::CompoundPage(_) => {
+                free_page(vaddr);
             }
             Page::SlabPage(si) => {
                 assert!(!si.slab.is_null());
                 let slab = si.slab.as_mut_ptr::<Slab>();
 
-                (*slab).deallocate(virt_addr);
+                unsafe { (*slab).deallocate(vaddr) };
             }
             _ => {
                 panic!("Freeing memory on unsupported page type");
             }
         }
     }
+
+    fn slab_stats(&self, idx: usize) -> Option<SlabStats> {
+        self.slab(idx).map(|slab| slab.lock().stats())
+    }
+
+    fn usable_size(&self, vaddr: VirtAddr, _layout: Layout) -> usize {
+        heap_usable_size(vaddr)
+    }
+}
+
+/// Pattern written to memory when it is handed out by
+/// [`HardenedAllocBackend`]
+pub const ALLOC_POISON: u8 = 0xa5;
+/// Pattern written to memory when it is returned to [`HardenedAllocBackend`]
+pub const FREE_POISON: u8 = 0x6b;
+
+/// Number of live allocations [`HardenedAllocBackend`] keeps track of
+pub const ALLOC_TRACK_ENTRIES: usize = 512;
+/// Return addresses recorded for each tracked allocation
+pub const ALLOC_TRACK_CALLERS: usize = 4;
+// Frames of the allocator itself at the top of the stack when an allocation
+// is recorded
+const ALLOC_TRACK_SKIP: usize = 2;
+
+#[derive(Clone, Copy, Debug)]
+struct AllocRecord {
+    vaddr: VirtAddr,
+    size: usize,
+    callers: [VirtAddr; ALLOC_TRACK_CALLERS],
+}
+
+// Side table of live allocations
+struct AllocTracker {
+    records: [Option<AllocRecord>; ALLOC_TRACK_ENTRIES],
+    // Allocations which did not fit into the table. Unknown pointers can
+    // only be reported as double frees while there are none.
+    untracked: usize,
+}
+
+impl AllocTracker {
+    const fn new() -> Self {
+        AllocTracker {
+            records: [None; ALLOC_TRACK_ENTRIES],
+            untracked: 0,
+        }
+    }
+
+    fn insert(&mut self, record: AllocRecord) {
+        match self.records.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => *slot = Some(record),
+            None => self.untracked += 1,
+        }
+    }
+
+    // Forgets the allocation at `vaddr`, false if it is not known
+    fn remove(&mut self, vaddr: VirtAddr) -> bool {
+        let slot = self
+            .records
+            .iter_mut()
+            .find(|r| r.is_some_and(|r| r.vaddr == vaddr));
+        match slot {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None if self.untracked > 0 => {
+                self.untracked -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn live(&self) -> impl Iterator<Item = &AllocRecord> {
+        self.records.iter().flatten()
+    }
+}
+
+// Return addresses of the code which asked for an allocation
+#[cfg(not(test))]
+fn alloc_callers() -> [VirtAddr; ALLOC_TRACK_CALLERS] {
+    let mut callers = [VirtAddr::null(); ALLOC_TRACK_CALLERS];
+    let frames = StackUnwinder::unwind_this_cpu()
+        .map_while(|frame| match frame {
+            UnwoundStackFrame::Valid(frame) => Some(frame.rip),
+            UnwoundStackFrame::Invalid => None,
+        })
+        .skip(ALLOC_TRACK_SKIP);
+    for (caller, rip) in callers.iter_mut().zip(frames) {
+        *caller = rip;
+    }
+    callers
+}
+
+// Unit tests do not run on SVSM stacks, which the unwinder expects
+#[cfg(test)]
+fn alloc_callers() -> [VirtAddr; ALLOC_TRACK_CALLERS] {
+    [VirtAddr::null(); ALLOC_TRACK_CALLERS]
+}
+
+/// Debugging wrapper around another backend. Fills fresh allocations and
+/// freed memory with poison patterns, so that uses of uninitialized or freed
+/// heap memory show up as recognizable garbage, and checks the alignment of
+/// the pointers going in and out. Live allocations are recorded together
+/// with their call sites, which catches double frees and allows to dump
+/// outstanding allocations when looking for leaks.
+pub struct HardenedAllocBackend<B: AllocBackend> {
+    inner: B,
+    tracker: SpinLock<AllocTracker>,
+}
+
+impl<B: AllocBackend> HardenedAllocBackend<B> {
+    pub const fn new(inner: B) -> Self {
+        HardenedAllocBackend {
+            inner,
+            tracker: SpinLock::new(AllocTracker::new()),
+        }
+    }
+}
+
+impl<B: AllocBackend> AllocBackend for HardenedAllocBackend<B> {
+    fn allocate(&self, layout: Layout) -> Result<VirtAddr, SvsmError> {
+        let vaddr = self.inner.allocate(layout)?;
+        assert!(
+            vaddr.bits() & (layout.align() - 1) == 0,
+            "Heap allocation {:#018x} not aligned to {:#x}",
+            vaddr,
+            layout.align()
+        );
+        unsafe { ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), ALLOC_POISON, layout.size()) };
+        self.tracker.lock().insert(AllocRecord {
+            vaddr,
+            size: layout.size(),
+            callers: alloc_callers(),
+        });
+        Ok(vaddr)
+    }
+
+    fn deallocate(&self, vaddr: VirtAddr, layout: Layout) {
+        assert!(
+            vaddr.bits() & (layout.align() - 1) == 0,
+            "Freeing misaligned heap pointer {:#018x}",
+            vaddr
+        );
+        let known = self.tracker.lock().remove(vaddr);
+        if !known {
+            panic!("Double free of heap pointer {:#018x}", vaddr);
+        }
+        unsafe { ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), FREE_POISON, layout.size()) };
+        self.inner.deallocate(vaddr, layout);
+    }
+
+    fn slab_stats(&self, idx: usize) -> Option<SlabStats> {
+        self.inner.slab_stats(idx)
+    }
+
+    // Always move, so that stale pointers to the old location run into
+    // poisoned memory
+    fn reallocate(
+        &self,
+        vaddr: VirtAddr,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> Result<VirtAddr, SvsmError> {
+        let new_vaddr = self.allocate(new_layout)?;
+        let len = layout.size().min(new_layout.size());
+        unsafe {
+            ptr::copy_nonoverlapping(vaddr.as_ptr::<u8>(), new_vaddr.as_mut_ptr::<u8>(), len);
+        }
+        self.deallocate(vaddr, layout);
+        Ok(new_vaddr)
+    }
+
+    fn dump_allocations(&self) {
+        let tracker = self.tracker.lock();
+        log::info!("---HEAP ALLOCATIONS---");
+        for record in tracker.live() {
+            let [c0, c1, c2, c3] = record.callers;
+            log::info!(
+                "  {:#018x} size {:#8x} from [{:#x}] [{:#x}] [{:#x}] [{:#x}]",
+                record.vaddr,
+                record.size,
+                c0,
+                c1,
+                c2,
+                c3
+            );
+        }
+        if tracker.untracked > 0 {
+            log::info!("  {} more allocations not tracked", tracker.untracked);
+        }
+        log::info!("---END---");
+    }
+}
+
+#[cfg(not(feature = "alloc-page-only"))]
+type BaseAllocBackend = SlabAllocBackend;
+#[cfg(feature = "alloc-page-only")]
+type BaseAllocBackend = PageAllocBackend;
+
+#[cfg(not(feature = "alloc-hardened"))]
+type SelectedAllocBackend = BaseAllocBackend;
+#[cfg(feature = "alloc-hardened")]
+type SelectedAllocBackend = HardenedAllocBackend<BaseAllocBackend>;
+
+/// The global allocator. Which backend it uses is decided by cargo features:
+/// `alloc-page-only` replaces the slab stack with [`PageAllocBackend`] for
+/// memory-constrained builds, and `alloc-hardened` wraps the selected backend
+/// in a [`HardenedAllocBackend`].
+pub struct SvsmAllocator {
+    backend: SelectedAllocBackend,
+}
+
+impl SvsmAllocator {
+    pub const fn new() -> Self {
+        #[cfg(not(feature = "alloc-page-only"))]
+        let base = SlabAllocBackend::new();
+        #[cfg(feature = "alloc-page-only")]
+        let base = PageAllocBackend::new();
+
+        #[cfg(feature = "alloc-hardened")]
+        let backend = HardenedAllocBackend::new(base);
+        #[cfg(not(feature = "alloc-hardened"))]
+        let backend = base;
+
+        SvsmAllocator { backend }
+    }
+
+    pub fn slab_stats(&self, idx: usize) -> Option<SlabStats> {
+        self.backend.slab_stats(idx)
+    }
+
+    pub fn dump_allocations(&self) {
+        self.backend.dump_allocations()
+    }
+}
+
+unsafe impl GlobalAlloc for SvsmAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.backend.allocate(layout) {
+            Ok(vaddr) => vaddr.as_mut_ptr::<u8>(),
+            Err(_e) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.backend.deallocate(VirtAddr::from(ptr), layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match self
+            .backend
+            .reallocate(VirtAddr::from(ptr), layout, new_layout)
+        {
+            Ok(vaddr) => vaddr.as_mut_ptr::<u8>(),
+            Err(_e) => ptr::null_mut(),
+        }
+    }
 }
 
-#[cfg_attr(not(test), global_allocator)]
+#[cfg_attr(not(any(test, fuzzing)), global_allocator)]
 pub static mut ALLOCATOR: SvsmAllocator = SvsmAllocator::new();
 
 pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
@@ -1292,6 +1751,43 @@ pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
     }
 }
 
+/// Returns usage information for slab cache number `idx` of the global
+/// allocator. Caches are numbered from the smallest object size up.
+pub fn slab_stats(idx: usize) -> Option<SlabStats> {
+    unsafe { ALLOCATOR.slab_stats(idx) }
+}
+
+/// Logs the outstanding heap allocations and where they were made from.
+/// Only available with the `alloc-hardened` feature.
+pub fn print_heap_allocations() {
+    unsafe { ALLOCATOR.dump_allocations() }
+}
+
+pub fn print_slab_info() {
+    for stats in (0..).map_while(slab_stats) {
+        log::info!(
+            "Slab-{:#04}: pages: {:#4} full: {:#4} free: {:#4} objects: {:#5}/{:#5}",
+            stats.item_size,
+            stats.pages,
+            stats.full_pages,
+            stats.free_pages,
+            stats.capacity - stats.free,
+            stats.capacity
+        );
+    }
+}
+
+/// Logs the state of the page allocator and the slab caches. Meant for error
+/// and panic paths, so it does not wait for the page allocator lock but
+/// reports it as held.
+pub fn dump_allocator_state() {
+    match ROOT_MEM.try_lock() {
+        Ok(root_mem) => print_memory_info(&root_mem.memory_info()),
+        Err(()) => log::info!("Page allocator is locked, no page statistics"),
+    }
+    print_slab_info();
+}
+
 pub fn print_alloc_info() {
     for i in 0..MAX_ORDER {
         let nr_pages = ROOT_MEM.lock().nr_pages[i];
@@ -1415,6 +1911,29 @@ fn test_page_alloc_all_compound() {
     destroy_test_root_mem(test_mem_lock);
 }
 
+#[test]
+// Verify that a 2M block split up into single pages is coalesced again when
+// the pages are freed.
+fn test_page_alloc_coalesce() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let mut root_mem = ROOT_MEM.lock();
+    let info_before = root_mem.memory_info();
+
+    let block = root_mem.allocate_pages(MAX_ORDER - 1).unwrap();
+    root_mem.free_pages(block, MAX_ORDER - 1);
+    assert_eq!(info_before.free_pages, root_mem.memory_info().free_pages);
+
+    let pages: [VirtAddr; 4] = core::array::from_fn(|_| root_mem.allocate_page().unwrap());
+    assert_ne!(info_before.free_pages, root_mem.memory_info().free_pages);
+    for page in pages {
+        root_mem.free_pages(page, 0);
+    }
+    assert_eq!(info_before.free_pages, root_mem.memory_info().free_pages);
+
+    drop(root_mem);
+    destroy_test_root_mem(test_mem_lock);
+}
+
 #[test]
 // Allocate and free all available 4k pages, verify that memory_info()
 // reflects it.
@@ -1654,3 +2173,133 @@ fn test_slab_oom() {
 
     destroy_test_root_mem(test_mem_lock);
 }
+
+#[test]
+// Run allocations through the page-only backend wrapped in the hardened one
+// and verify poisoning and that all pages are returned.
+fn test_hardened_page_backend() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let backend = HardenedAllocBackend::new(PageAllocBackend::new());
+    let info_before = memory_info();
+
+    let layout = Layout::from_size_align(3 * PAGE_SIZE, 64).unwrap();
+    let vaddr = backend.allocate(layout).unwrap();
+    assert!(vaddr.is_page_aligned());
+    let mem = unsafe { core::slice::from_raw_parts(vaddr.as_ptr::<u8>(), layout.size()) };
+    assert!(mem.iter().all(|b| *b == ALLOC_POISON));
+    assert_ne!(info_before.free_pages, memory_info().free_pages);
+
+    backend.deallocate(vaddr, layout);
+    assert_eq!(info_before.free_pages, memory_info().free_pages);
+
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Verify that slab statistics track allocations.
+fn test_slab_stats() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let layout = Layout::from_size_align(64, 64).unwrap();
+
+    let before = slab_stats(1).unwrap();
+    assert_eq!(before.item_size, 64);
+    let p = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!p.is_null());
+
+    let stats = slab_stats(1).unwrap();
+    assert_eq!(
+        stats.capacity - stats.free,
+        before.capacity - before.free + 1
+    );
+    assert!(slab_stats(7).is_none());
+
+    unsafe { ALLOCATOR.dealloc(p, layout) };
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Verify that small allocations with large alignment are aligned correctly.
+fn test_slab_alloc_alignment() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let backend = SlabAllocBackend::new();
+
+    for align in [64, 256, 2048, PAGE_SIZE] {
+        let layout = Layout::from_size_align(8, align).unwrap();
+        let vaddr = backend.allocate(layout).unwrap();
+        assert_eq!(vaddr.bits() & (align - 1), 0);
+        backend.deallocate(vaddr, layout);
+    }
+
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Verify that page allocations with alignments above a page are aligned
+// and return all their memory when freed.
+fn test_page_alloc_large_alignment() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let backend = PageAllocBackend::new();
+    let info_before = memory_info();
+
+    for align in [2 * PAGE_SIZE, 8 * PAGE_SIZE, 16 * PAGE_SIZE] {
+        let layout = Layout::from_size_align(PAGE_SIZE + 8, align).unwrap();
+        let vaddr = backend.allocate(layout).unwrap();
+        assert_eq!(vaddr.bits() & (align - 1), 0);
+        assert!(backend.usable_size(vaddr, layout) >= layout.size());
+        backend.deallocate(vaddr, layout);
+    }
+
+    assert_eq!(info_before.free_pages, memory_info().free_pages);
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Verify that reallocation keeps the contents and only moves when the
+// object outgrows its slab or page block.
+fn test_slab_realloc() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let backend = SlabAllocBackend::new();
+
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let vaddr = backend.allocate(layout).unwrap();
+    unsafe { ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0x5a, layout.size()) };
+
+    // 40 bytes come from the 64 byte slab, which fits 60 bytes as well
+    let grown = Layout::from_size_align(60, 8).unwrap();
+    assert_eq!(backend.reallocate(vaddr, layout, grown).unwrap(), vaddr);
+
+    let large = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+    let moved = backend.reallocate(vaddr, grown, large).unwrap();
+    assert_ne!(moved, vaddr);
+    let mem = unsafe { core::slice::from_raw_parts(moved.as_ptr::<u8>(), layout.size()) };
+    assert!(mem.iter().all(|b| *b == 0x5a));
+
+    // A 3 page object lives in a 4 page block
+    let larger = Layout::from_size_align(4 * PAGE_SIZE, 8).unwrap();
+    assert_eq!(backend.reallocate(moved, large, larger).unwrap(), moved);
+
+    backend.deallocate(moved, larger);
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Verify that the hardened backend tracks live allocations and would catch
+// double frees.
+fn test_hardened_alloc_tracking() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let backend = HardenedAllocBackend::new(SlabAllocBackend::new());
+
+    let layout = Layout::from_size_align(48, 16).unwrap();
+    let a = backend.allocate(layout).unwrap();
+    let b = backend.allocate(layout).unwrap();
+    assert_eq!(backend.tracker.lock().live().count(), 2);
+
+    backend.deallocate(a, layout);
+    assert_eq!(backend.tracker.lock().live().count(), 1);
+    assert!(backend.tracker.lock().live().all(|r| r.vaddr == b));
+    // Freeing `a` again would panic
+    assert!(!backend.tracker.lock().remove(a));
+
+    backend.deallocate(b, layout);
+    destroy_test_root_mem(test_mem_lock);
+}
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::error::SvsmError;
+use crate::types::{MemoryRegion, MemoryRegionSet};
+use alloc::vec::Vec;
+
+#[derive(Clone, Copy, Debug)]
+pub enum BootMemError {
+    // A range overlaps one which is already in use
+    Overlap {
+        name: &'static str,
+        used_by: &'static str,
+    },
+    // No free RAM range is large enough
+    NoSpace,
+}
+
+impl From<BootMemError> for SvsmError {
+    fn from(err: BootMemError) -> Self {
+        Self::BootMem(err)
+    }
+}
+
+/// Memory map of the boot loader. Every range the loader knows to be in
+/// use is registered with a name, and new regions are only handed out from
+/// RAM not used by anything else.
+#[derive(Debug)]
+pub struct BootMemoryMap {
+    ram: MemoryRegionSet,
+    used: Vec<(MemoryRegion, &'static str)>,
+}
+
+impl BootMemoryMap {
+    pub fn new(ram: MemoryRegionSet) -> Self {
+        BootMemoryMap {
+            ram,
+            used: Vec::new(),
+        }
+    }
+
+    /// Returns the name of the first used range overlapping `region`.
+    pub fn conflict(&self, region: &MemoryRegion) -> Option<&'static str> {
+        self.used
+            .iter()
+            .find(|(r, _)| r.overlaps(region))
+            .map(|(_, name)| *name)
+    }
+
+    /// Registers `region` as used. It does not need to be RAM, but must not
+    /// overlap any range registered before.
+    pub fn reserve(&mut self, region: MemoryRegion, name: &'static str) -> Result<(), SvsmError> {
+        if let Some(used_by) = self.conflict(&region) {
+            log::error!(
+                "Boot memory range {:#x}-{:#x} for {} overlaps {}",
+                region.start,
+                region.end,
+                name,
+                used_by
+            );
+            return Err(BootMemError::Overlap { name, used_by }.into());
+        }
+
+        if !region.is_empty() {
+            self.used.push((region, name));
+        }
+        Ok(())
+    }
+
+    /// Returns the RAM not registered as used.
+    pub fn free(&self) -> MemoryRegionSet {
+        let mut free = self.ram.clone();
+        for (region, _) in self.used.iter() {
+            free.remove(region);
+        }
+        free
+    }
+
+    /// Allocates a region at the top of the highest free RAM range with
+    /// room for it. The region starts at an `align`-aligned address at
+    /// least `size` bytes below the end of that range and extends up to the
+    /// end.
+    pub fn allocate_top(
+        &mut self,
+        size: u64,
+        align: u64,
+        name: &'static str,
+    ) -> Result<MemoryRegion, SvsmError> {
+        let region = self
+            .free()
+            .iter()
+            .rev()
+            .find_map(|free| {
+                let start = free.end.checked_sub(size)? & !(align - 1);
+                let region = MemoryRegion::new(start, free.end);
+                free.contains_region(&region).then_some(region)
+            })
+            .ok_or(BootMemError::NoSpace)?;
+
+        self.reserve(region, name)?;
+        Ok(region)
+    }
+
+    pub fn iter_used(&self) -> impl Iterator<Item = &(MemoryRegion, &'static str)> {
+        self.used.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 16 * 1024 * 1024;
+
+    fn map(ram: &[(u64, u64)]) -> BootMemoryMap {
+        BootMemoryMap::new(
+            ram.iter()
+                .map(|(start, end)| MemoryRegion::new(*start, *end))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_allocate_top() {
+        let mut m = map(&[(0, 0xa0000), (0x100000, 0x7fff_f000)]);
+        let r = m.allocate_top(SIZE, SIZE, "kernel").unwrap();
+        assert_eq!(r, MemoryRegion::new(0x7e00_0000, 0x7fff_f000));
+
+        let mut small = map(&[(0x100000, 0x800000)]);
+        assert!(small.allocate_top(SIZE, SIZE, "kernel").is_err());
+    }
+
+    #[test]
+    fn test_allocate_above_4g() {
+        let mut m = map(&[(0, 0x8000_0000), (0x1_0000_0000, 0x2_8000_0000)]);
+        let r = m.allocate_top(SIZE, SIZE, "kernel").unwrap();
+        assert_eq!(r, MemoryRegion::new(0x2_8000_0000 - SIZE, 0x2_8000_0000));
+
+        // Only RAM above 4GiB, ending on an unaligned address
+        let mut highmem = map(&[(0x10_0000_0000, 0x10_0123_4000)]);
+        let r = highmem.allocate_top(SIZE, SIZE, "kernel").unwrap();
+        assert_eq!(r, MemoryRegion::new(0x10_0000_0000, 0x10_0123_4000));
+        assert!(r.start > u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_reserve_conflicts() {
+        let mut m = map(&[(0, 0x8000_0000)]);
+        m.reserve(MemoryRegion::new(0x10000, 0x20000), "stage2")
+            .unwrap();
+        m.reserve(MemoryRegion::new(0x9e000, 0x9f000), "secrets")
+            .unwrap();
+        assert!(matches!(
+            m.reserve(MemoryRegion::new(0x1f000, 0x30000), "heap"),
+            Err(SvsmError::BootMem(BootMemError::Overlap {
+                name: "heap",
+                used_by: "stage2"
+            }))
+        ));
+
+        // Used ranges at the top of RAM push the allocation down
+        m.reserve(MemoryRegion::new(0x7f00_0000, 0x8000_0000), "firmware")
+            .unwrap();
+        let r = m.allocate_top(SIZE, SIZE, "kernel").unwrap();
+        assert_eq!(r, MemoryRegion::new(0x7e00_0000, 0x7f00_0000));
+        assert_eq!(
+            m.conflict(&MemoryRegion::new(0x7e80_0000, 0x7e80_1000)),
+            Some("kernel")
+        );
+    }
+}
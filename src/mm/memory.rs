@@ -4,30 +4,28 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-extern crate alloc;
-
 use crate::address::{Address, PhysAddr};
 use crate::cpu::percpu::PERCPU_VMSAS;
-use crate::error::SvsmError;
-use crate::fw_cfg::{FwCfg, MemoryRegion};
 use crate::kernel_launch::KernelLaunchInfo;
 use crate::locking::RWLock;
-use alloc::vec::Vec;
+use crate::types::{MemoryRegion, MemoryRegionSet};
 use log;
 
-static MEMORY_MAP: RWLock<Vec<MemoryRegion>> = RWLock::new(Vec::new());
+static MEMORY_MAP: RWLock<MemoryRegionSet> = RWLock::new(MemoryRegionSet::new());
 
-pub fn init_memory_map(fwcfg: &FwCfg, launch_info: &KernelLaunchInfo) -> Result<(), SvsmError> {
-    let mut regions = fwcfg.get_memory_regions()?;
+/// Sets up the guest memory map from the E820 map stage2 passed on
+pub fn init_memory_map(launch_info: &KernelLaunchInfo) {
+    let mut regions: MemoryRegionSet = launch_info
+        .memory_regions()
+        .iter()
+        .map(|r| MemoryRegion::new(r.start, r.end))
+        .collect();
 
     // Remove SVSM memory from guest memory map
-    for mut region in regions.iter_mut() {
-        if (launch_info.kernel_region_phys_start > region.start)
-            && (launch_info.kernel_region_phys_start < region.end)
-        {
-            region.end = launch_info.kernel_region_phys_start;
-        }
-    }
+    regions.remove(&MemoryRegion::new(
+        launch_info.kernel_region_phys_start,
+        launch_info.kernel_region_phys_end,
+    ));
 
     log::info!("Guest Memory Regions:");
     for r in regions.iter() {
@@ -36,8 +34,17 @@ pub fn init_memory_map(fwcfg: &FwCfg, launch_info: &KernelLaunchInfo) -> Result<
 
     let mut map = MEMORY_MAP.lock_write();
     *map = regions;
+}
 
-    Ok(())
+/// Takes `region` out of guest memory. The guest can not have pages in it
+/// validated through the SVSM anymore.
+pub fn remove_guest_memory(region: &MemoryRegion) {
+    MEMORY_MAP.lock_write().remove(region);
+}
+
+/// Gives `region` back to the guest after [`remove_guest_memory()`]
+pub fn add_guest_memory(region: &MemoryRegion) {
+    MEMORY_MAP.lock_write().insert(*region);
 }
 
 pub fn valid_phys_address(paddr: PhysAddr) -> bool {
@@ -48,8 +55,30 @@ pub fn valid_phys_address(paddr: PhysAddr) -> bool {
         return false;
     }
 
-    MEMORY_MAP
-        .lock_read()
-        .iter()
-        .any(|region| addr >= region.start && addr < region.end)
+    MEMORY_MAP.lock_read().contains(addr)
+}
+
+/// What the SVSM knows about a guest-physical page. The validation state of
+/// guest memory is not tracked, it lives in the RMP only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestPageState {
+    /// Not part of guest memory, this includes memory owned by the SVSM
+    NotGuestMemory,
+    /// Guest memory, which gets full access for the guest VMPL when
+    /// validated through the SVSM
+    Guest,
+    /// Registered as a guest VMSA, which the guest VMPL can only read
+    Vmsa,
+}
+
+pub fn guest_page_state(paddr: PhysAddr) -> GuestPageState {
+    let page_addr = paddr.page_align();
+
+    if PERCPU_VMSAS.exists(page_addr) {
+        GuestPageState::Vmsa
+    } else if MEMORY_MAP.lock_read().contains(page_addr.bits() as u64) {
+        GuestPageState::Guest
+    } else {
+        GuestPageState::NotGuestMemory
+    }
 }
@@ -6,17 +6,21 @@
 
 pub mod address_space;
 pub mod alloc;
+pub mod bootmem;
 pub mod guestmem;
 pub mod memory;
 pub mod pagetable;
 pub mod ptguards;
+pub mod quota;
+pub mod scrub;
 pub mod stack;
 pub mod validate;
 pub mod virtualrange;
+pub mod vmalloc;
 
 pub use address_space::*;
 pub use guestmem::GuestPtr;
-pub use memory::valid_phys_address;
+pub use memory::{guest_page_state, valid_phys_address, GuestPageState};
 pub use ptguards::*;
 
 pub use alloc::{
@@ -5,10 +5,10 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
-use crate::cpu::control_regs::write_cr3;
-use crate::cpu::cpuid::cpuid_table;
+use crate::cpu::control_regs::{read_cr3, write_cr3};
+use crate::cpu::cpuid::cpuid;
 use crate::cpu::features::{cpu_has_nx, cpu_has_pge};
-use crate::cpu::flush_tlb_global_sync;
+use crate::cpu::tlb::{flush_address_sync, flush_range, flush_tlb_global_sync};
 use crate::error::SvsmError;
 use crate::locking::{LockGuard, SpinLock};
 use crate::mm::alloc::allocate_zeroed_page;
@@ -47,15 +47,33 @@ pub fn paging_init() {
     unsafe { FEATURE_MASK.reinit(&feature_mask) };
 }
 
+// Physical address bits in a PTE end at bit 51, the bits above are
+// available to software and bit 63 is NX
+const PTE_PHYS_ADDR_BITS: u32 = 52;
+
+// The C-bit is always one of the upper physical address bits. Anything else
+// would alias a PTE flag, a software bit or a low address bit. It is not
+// bounded by the physical address size from CPUID 0x80000008, which already
+// accounts for the bits taken away by memory encryption and is usually
+// below the C-bit.
+fn c_bit_valid(c_bit: u32) -> bool {
+    (32..PTE_PHYS_ADDR_BITS).contains(&c_bit)
+}
+
 fn init_encrypt_mask() {
     // Find C bit position
-    let res = cpuid_table(0x8000001f).expect("Can not get C-Bit position from CPUID table");
+    let res = cpuid(0x8000001f, 0).expect("Can not get C-Bit position from CPUID table");
     let c_bit = res.ebx & 0x3f;
+    assert!(
+        c_bit_valid(c_bit),
+        "Invalid C-bit position {} in CPUID table",
+        c_bit
+    );
     let mask = 1u64 << c_bit;
     unsafe { ENCRYPT_MASK.reinit(&(mask as usize)) };
 
     // Find physical address size.
-    let res = cpuid_table(0x80000008).expect("Can not get physical address size from CPUID table");
+    let res = cpuid(0x80000008, 0).expect("Can not get physical address size from CPUID table");
     let guest_phys_addr_size = (res.eax >> 16) & 0xff;
     let host_phys_addr_size = res.eax & 0xff;
     let phys_addr_size = if guest_phys_addr_size == 0 {
@@ -87,6 +105,19 @@ pub fn max_phys_addr() -> u64 {
     *MAX_PHYS_ADDR
 }
 
+#[cfg(any(test, fuzzing))]
+// Unit tests and fuzzers run without a CPUID page. Sets up a 52 bit
+// physical address space for the code checking addresses against it.
+pub fn init_test_max_phys_addr() {
+    static INIT: SpinLock<bool> = SpinLock::new(false);
+
+    let mut done = INIT.lock();
+    if !*done {
+        unsafe { MAX_PHYS_ADDR.reinit(&(1 << 52)) };
+        *done = true;
+    }
+}
+
 fn supported_flags(flags: PTEntryFlags) -> PTEntryFlags {
     flags & *FEATURE_MASK
 }
@@ -104,6 +135,8 @@ bitflags! {
         const PRESENT       = 1 << 0;
         const WRITABLE      = 1 << 1;
         const USER      = 1 << 2;
+        const WRITETHRU     = 1 << 3;
+        const NOCACHE       = 1 << 4;
         const ACCESSED      = 1 << 5;
         const DIRTY     = 1 << 6;
         const HUGE      = 1 << 7;
@@ -112,6 +145,27 @@ bitflags! {
     }
 }
 
+/// Access permissions of a mapping, see [`PageTable::set_region_perms()`].
+/// Mappings which are both writable and executable are not supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagePerms {
+    ReadOnly,
+    ReadWrite,
+    ReadExec,
+}
+
+impl PagePerms {
+    fn apply(self, flags: PTEntryFlags) -> PTEntryFlags {
+        let mut flags = flags - (PTEntryFlags::WRITABLE | PTEntryFlags::NX);
+        match self {
+            PagePerms::ReadOnly => flags |= PTEntryFlags::NX,
+            PagePerms::ReadWrite => flags |= PTEntryFlags::WRITABLE | PTEntryFlags::NX,
+            PagePerms::ReadExec => {}
+        }
+        flags
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct PTEntry(PhysAddr);
@@ -147,6 +201,12 @@ impl PTEntry {
         let addr = PhysAddr::from(self.0.bits() & 0x000f_ffff_ffff_f000);
         strip_c_bit(addr)
     }
+
+    fn set_perms(&mut self, perms: PagePerms) {
+        // Keep the address including the C-bit
+        let addr = PhysAddr::from(self.0.bits() & 0x000f_ffff_ffff_f000);
+        self.set(addr, perms.apply(self.flags()));
+    }
 }
 
 #[repr(C)]
@@ -168,6 +228,24 @@ impl IndexMut<usize> for PTPage {
     }
 }
 
+/// Result of translating a virtual address with [`PageTable::translate()`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    /// Physical address of the mapped frame, without C-bit
+    pub frame: PhysAddr,
+    /// Size of the mapping: 4KiB, 2MiB or 1GiB
+    pub size: usize,
+    /// Flags of the leaf entry
+    pub flags: PTEntryFlags,
+}
+
+impl Translation {
+    /// Returns the physical address `vaddr` translates to
+    pub fn phys_addr(&self, vaddr: VirtAddr) -> PhysAddr {
+        self.frame.offset(vaddr.bits() & (self.size - 1))
+    }
+}
+
 pub enum Mapping<'a> {
     Level3(&'a mut PTEntry),
     Level2(&'a mut PTEntry),
@@ -226,6 +304,18 @@ impl PageTable {
             | PTEntryFlags::DIRTY
     }
 
+    // PCD and PWT together select the uncached memory type with the
+    // default PAT
+    pub fn mmio_flags() -> PTEntryFlags {
+        Self::data_flags() | PTEntryFlags::NOCACHE | PTEntryFlags::WRITETHRU
+    }
+
+    // Flags of entries pointing to a lower level page-table. Access
+    // restrictions are only applied at the leaf level.
+    fn table_flags() -> PTEntryFlags {
+        PTEntryFlags::PRESENT | PTEntryFlags::WRITABLE | PTEntryFlags::USER | PTEntryFlags::ACCESSED
+    }
+
     fn allocate_page_table() -> Result<*mut PTPage, SvsmError> {
         let ptr = allocate_zeroed_page()?;
         Ok(ptr.as_mut_ptr::<PTPage>())
@@ -301,12 +391,8 @@ impl PageTable {
         };
 
         let paddr = virt_to_phys(VirtAddr::from(page));
-        let flags = PTEntryFlags::PRESENT
-            | PTEntryFlags::WRITABLE
-            | PTEntryFlags::USER
-            | PTEntryFlags::ACCESSED;
         entry.clear();
-        entry.set(set_c_bit(paddr), flags);
+        entry.set(set_c_bit(paddr), PageTable::table_flags());
 
         let idx = PageTable::index::<2>(vaddr);
 
@@ -326,12 +412,8 @@ impl PageTable {
         };
 
         let paddr = virt_to_phys(VirtAddr::from(page));
-        let flags = PTEntryFlags::PRESENT
-            | PTEntryFlags::WRITABLE
-            | PTEntryFlags::USER
-            | PTEntryFlags::ACCESSED;
         entry.clear();
-        entry.set(set_c_bit(paddr), flags);
+        entry.set(set_c_bit(paddr), PageTable::table_flags());
 
         let idx = PageTable::index::<1>(vaddr);
 
@@ -351,12 +433,8 @@ impl PageTable {
         };
 
         let paddr = virt_to_phys(VirtAddr::from(page));
-        let flags = PTEntryFlags::PRESENT
-            | PTEntryFlags::WRITABLE
-            | PTEntryFlags::USER
-            | PTEntryFlags::ACCESSED;
         entry.clear();
-        entry.set(set_c_bit(paddr), flags);
+        entry.set(set_c_bit(paddr), PageTable::table_flags());
 
         let idx = PageTable::index::<0>(vaddr);
 
@@ -404,7 +482,10 @@ impl PageTable {
             }
         }
 
-        entry.set(set_c_bit(virt_to_phys(VirtAddr::from(page))), flags);
+        entry.set(
+            set_c_bit(virt_to_phys(VirtAddr::from(page))),
+            PageTable::table_flags(),
+        );
 
         flush_tlb_global_sync();
 
@@ -442,6 +523,7 @@ impl PageTable {
 
         if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
             PageTable::clear_c_bit(entry);
+            flush_address_sync(vaddr);
             Ok(())
         } else {
             Err(SvsmError::Mem)
@@ -454,12 +536,138 @@ impl PageTable {
 
         if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
             PageTable::set_c_bit(entry);
+            flush_address_sync(vaddr);
             Ok(())
         } else {
             Err(SvsmError::Mem)
         }
     }
 
+    fn huge_entry(&mut self, vaddr: VirtAddr) -> Result<&mut PTEntry, SvsmError> {
+        assert!(vaddr.is_aligned(PAGE_SIZE_2M));
+
+        match self.walk_addr(vaddr) {
+            Mapping::Level1(entry) if entry.flags().contains(PTEntryFlags::HUGE) => Ok(entry),
+            _ => Err(SvsmError::Mem),
+        }
+    }
+
+    /// Clears the C-bit of the 2MB mapping at `vaddr`. Fails if `vaddr` is
+    /// not mapped by a 2MB page.
+    pub fn set_shared_2m(&mut self, vaddr: VirtAddr) -> Result<(), SvsmError> {
+        PageTable::clear_c_bit(self.huge_entry(vaddr)?);
+        flush_range(vaddr, vaddr.offset(PAGE_SIZE_2M));
+        Ok(())
+    }
+
+    /// Sets the C-bit of the 2MB mapping at `vaddr`. Fails if `vaddr` is not
+    /// mapped by a 2MB page.
+    pub fn set_encrypted_2m(&mut self, vaddr: VirtAddr) -> Result<(), SvsmError> {
+        PageTable::set_c_bit(self.huge_entry(vaddr)?);
+        flush_range(vaddr, vaddr.offset(PAGE_SIZE_2M));
+        Ok(())
+    }
+
+    /// Changes the access permissions of all pages in `len` bytes starting
+    /// at `vaddr`. 2MB pages only partially covered by the range are split.
+    /// Fails if any page in the range is not mapped.
+    pub fn set_region_perms(
+        &mut self,
+        vaddr: VirtAddr,
+        len: usize,
+        perms: PagePerms,
+    ) -> Result<(), SvsmError> {
+        assert!(vaddr.is_page_aligned());
+
+        let end = vaddr.offset(len).page_align_up();
+        let mut addr = vaddr;
+        while addr < end {
+            if addr.is_aligned(PAGE_SIZE_2M) && end - addr >= PAGE_SIZE_2M {
+                if let Ok(entry) = self.huge_entry(addr) {
+                    entry.set_perms(perms);
+                    addr = addr.offset(PAGE_SIZE_2M);
+                    continue;
+                }
+            }
+
+            match self.walk_addr(addr) {
+                Mapping::Level1(entry) if entry.present() => {
+                    PageTable::do_split_4k(entry)?;
+                }
+                Mapping::Level0(_) => {}
+                _ => return Err(SvsmError::Mem),
+            }
+
+            match self.walk_addr(addr) {
+                Mapping::Level0(entry) if entry.present() => entry.set_perms(perms),
+                _ => return Err(SvsmError::Mem),
+            }
+            addr = addr.offset(PAGE_SIZE);
+        }
+
+        flush_range(vaddr, end);
+
+        Ok(())
+    }
+
+    /// Translates `vaddr` without modifying the page-table. Returns `None`
+    /// if the address is not mapped.
+    pub fn translate(&self, vaddr: VirtAddr) -> Option<Translation> {
+        let mut page = &self.root;
+
+        for level in (0..4).rev() {
+            let entry = page[vaddr.bits() >> (12 + level * 9) & 0x1ff];
+            let flags = entry.flags();
+            if !flags.contains(PTEntryFlags::PRESENT) {
+                return None;
+            }
+
+            // Bit 7 is the PAT bit at the lowest level
+            let size = 1usize << (12 + level * 9);
+            if level == 0 || (level < 3 && flags.contains(PTEntryFlags::HUGE)) {
+                return Some(Translation {
+                    frame: PhysAddr::from(entry.address().bits() & !(size - 1)),
+                    size,
+                    flags,
+                });
+            }
+
+            page = unsafe { &*phys_to_virt(entry.address()).as_ptr::<PTPage>() };
+        }
+
+        unreachable!()
+    }
+
+    /// Logs the entries the walk for `vaddr` passes, down to the leaf entry
+    /// or the first one which is not present.
+    pub fn dump_walk(&self, vaddr: VirtAddr) {
+        let mut page = &self.root;
+
+        log::info!("Page table walk for {:#018x}:", vaddr);
+        for level in (0..4).rev() {
+            let idx = vaddr.bits() >> (12 + level * 9) & 0x1ff;
+            let entry = page[idx];
+            let flags = entry.flags();
+            log::info!("  L{} [{:3}] {:#018x} {:?}", level, idx, entry.raw(), flags);
+
+            if !flags.contains(PTEntryFlags::PRESENT) {
+                log::info!("  Not mapped");
+                return;
+            }
+            if level == 0 || (level < 3 && flags.contains(PTEntryFlags::HUGE)) {
+                let translation = self.translate(vaddr).unwrap();
+                log::info!(
+                    "  Maps to {:#018x} ({}KiB page)",
+                    translation.phys_addr(vaddr),
+                    translation.size / 1024
+                );
+                return;
+            }
+
+            page = unsafe { &*phys_to_virt(entry.address()).as_ptr::<PTPage>() };
+        }
+    }
+
     pub fn check_mapping(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
         match self.walk_addr(vaddr) {
             Mapping::Level0(entry) => Some(entry.address()),
@@ -516,6 +724,8 @@ impl PageTable {
         }
     }
 
+    /// Removes the 4k mapping at `vaddr`. Flushing the TLB is up to the
+    /// caller, which can batch the flush for several pages.
     pub fn unmap_4k(&mut self, vaddr: VirtAddr) {
         let mapping = self.walk_addr(vaddr);
 
@@ -577,6 +787,7 @@ impl PageTable {
         {
             self.unmap_4k(addr);
         }
+        flush_range(start, end);
     }
 
     pub fn map_region_2m(
@@ -603,6 +814,7 @@ impl PageTable {
         {
             self.unmap_2m(addr);
         }
+        flush_range(start, end);
     }
 
     pub fn map_region(
@@ -654,9 +866,30 @@ impl PageTable {
                 }
             }
         }
+
+        flush_range(start, end);
     }
 }
 
+/// Translates `vaddr` through the page-table currently loaded on this CPU
+pub fn translate_current(vaddr: VirtAddr) -> Option<PhysAddr> {
+    let root = strip_c_bit(read_cr3().page_align());
+    let pgtable = unsafe { &*phys_to_virt(root).as_ptr::<PageTable>() };
+
+    pgtable
+        .translate(vaddr)
+        .map(|translation| translation.phys_addr(vaddr))
+}
+
+/// Logs how `vaddr` is mapped in the page table of the current CPU, see
+/// [`PageTable::dump_walk()`]
+pub fn dump_mapping(vaddr: VirtAddr) {
+    let root = strip_c_bit(read_cr3().page_align());
+    let pgtable = unsafe { &*phys_to_virt(root).as_ptr::<PageTable>() };
+
+    pgtable.dump_walk(vaddr);
+}
+
 static INIT_PGTABLE: SpinLock<PageTableRef> = SpinLock::new(PageTableRef::unset());
 
 pub fn set_init_pgtable(pgtable: PageTableRef) {
@@ -706,3 +939,93 @@ impl DerefMut for PageTableRef {
         unsafe { &mut *self.pgtable_ptr }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[test]
+    fn test_c_bit_valid() {
+        assert!(c_bit_valid(32));
+        assert!(c_bit_valid(47));
+        assert!(c_bit_valid(51));
+        assert!(!c_bit_valid(31));
+        assert!(!c_bit_valid(52));
+        assert!(!c_bit_valid(62));
+        assert!(!c_bit_valid(63));
+    }
+
+    #[test]
+    fn test_page_perms() {
+        let flags = PageTable::data_flags();
+        let rx = PagePerms::ReadExec.apply(flags);
+        assert!(!rx.intersects(PTEntryFlags::WRITABLE | PTEntryFlags::NX));
+        assert!(rx.contains(PTEntryFlags::PRESENT | PTEntryFlags::GLOBAL));
+
+        let ro = PagePerms::ReadOnly.apply(PageTable::exec_flags());
+        assert_eq!(ro, PageTable::data_ro_flags());
+
+        let rw = PagePerms::ReadWrite.apply(rx);
+        assert_eq!(rw, flags);
+    }
+
+    #[repr(C, align(4096))]
+    struct AlignedPage(PTPage);
+
+    fn table() -> &'static mut PTPage {
+        let page = Box::new(AlignedPage(PTPage {
+            entries: [PTEntry(PhysAddr::null()); ENTRY_COUNT],
+        }));
+        &mut Box::leak(page).0
+    }
+
+    fn raw_entry(addr: usize, flags: PTEntryFlags) -> PTEntry {
+        PTEntry(PhysAddr::from(addr | flags.bits() as usize))
+    }
+
+    #[test]
+    fn test_translate() {
+        let table_flags = PTEntryFlags::PRESENT | PTEntryFlags::WRITABLE;
+        let mut pgtable = PageTable {
+            root: PTPage {
+                entries: [PTEntry(PhysAddr::null()); ENTRY_COUNT],
+            },
+        };
+        let l2 = table();
+        let l1 = table();
+        let l0 = table();
+
+        // 0x4020_0000: 2MiB page at 0x80_0000, 0x4000_1000: 4KiB page
+        pgtable.root[0] = raw_entry(l2 as *mut PTPage as usize, table_flags);
+        l2[1] = raw_entry(l1 as *mut PTPage as usize, table_flags);
+        l1[1] = raw_entry(0x80_0000, PTEntryFlags::PRESENT | PTEntryFlags::HUGE);
+        l1[0] = raw_entry(l0 as *mut PTPage as usize, table_flags);
+        l0[1] = raw_entry(0x12_3000, table_flags | PTEntryFlags::NX);
+
+        let t = pgtable.translate(VirtAddr::from(0x4020_1234usize)).unwrap();
+        assert_eq!(t.size, PAGE_SIZE_2M);
+        assert_eq!(t.frame, PhysAddr::from(0x80_0000usize));
+        assert_eq!(
+            t.phys_addr(VirtAddr::from(0x4020_1234usize)),
+            PhysAddr::from(0x80_1234usize)
+        );
+
+        let t = pgtable.translate(VirtAddr::from(0x4000_1008usize)).unwrap();
+        assert_eq!(t.size, PAGE_SIZE);
+        assert!(t.flags.contains(PTEntryFlags::NX));
+        assert_eq!(
+            t.phys_addr(VirtAddr::from(0x4000_1008usize)),
+            PhysAddr::from(0x12_3008usize)
+        );
+
+        assert!(pgtable
+            .translate(VirtAddr::from(0x4000_2000usize))
+            .is_none());
+        assert!(pgtable
+            .translate(VirtAddr::from(0x8000_0000usize))
+            .is_none());
+    }
+}
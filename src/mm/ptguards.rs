@@ -7,7 +7,6 @@
 use super::pagetable::PageTable;
 use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::cpu::percpu::this_cpu_mut;
-use crate::cpu::tlb::flush_address_sync;
 use crate::error::SvsmError;
 use crate::mm::virtualrange::{
     virt_alloc_range_2m, virt_alloc_range_4k, virt_free_range_2m, virt_free_range_4k,
@@ -97,7 +96,6 @@ impl Drop for PerCPUPageMappingGuard {
                 this_cpu_mut().get_pgtable().unmap_region_4k(m.start, m.end);
                 virt_free_range_4k(m.start, size);
             }
-            flush_address_sync(m.start);
         }
     }
 }
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::error::SvsmError;
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Default amount of SVSM heap a single guest context may consume, in bytes
+pub const GUEST_HEAP_QUOTA: usize = 16 * PAGE_SIZE;
+
+/// Usage counter for a single resource with an upper limit. Charges which
+/// would exceed the limit are refused and counted.
+#[derive(Debug)]
+pub struct MemQuota {
+    limit: usize,
+    used: AtomicUsize,
+    denied: AtomicUsize,
+}
+
+impl MemQuota {
+    pub const fn new(limit: usize) -> Self {
+        MemQuota {
+            limit,
+            used: AtomicUsize::new(0),
+            denied: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn charge(&self, amount: usize) -> Result<(), SvsmError> {
+        let res = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(amount).filter(|new| *new <= self.limit)
+            });
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                Err(SvsmError::QuotaExceeded)
+            }
+        }
+    }
+
+    pub fn uncharge(&self, amount: usize) {
+        let prev = self.used.fetch_sub(amount, Ordering::AcqRel);
+        assert!(prev >= amount, "Quota uncharged more than charged");
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn denied(&self) -> usize {
+        self.denied.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits on the SVSM resources a guest context can make the SVSM consume
+/// through protocol requests. Only the heap is capped. Allocations made on
+/// behalf of the guest are charged with a [`QuotaCharge`] by the code
+/// making them, which today is the vTPM command path and the VMSA registry.
+/// New guest-driven allocations have to be charged the same way.
+///
+/// There is no page-table cap. Guest memory is mapped into the SVSM through
+/// the fixed-size per-CPU temporary mapping ranges and released before the
+/// request returns, so guest requests can not accumulate mappings.
+#[derive(Debug)]
+pub struct GuestQuota {
+    /// SVSM heap memory, in bytes
+    pub heap: MemQuota,
+}
+
+impl GuestQuota {
+    pub const fn new() -> Self {
+        GuestQuota {
+            heap: MemQuota::new(GUEST_HEAP_QUOTA),
+        }
+    }
+}
+
+impl Default for GuestQuota {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A charge against a [`MemQuota`] which is returned when dropped, unless
+/// it was made permanent with [`QuotaCharge::keep()`].
+#[derive(Debug)]
+pub struct QuotaCharge<'a> {
+    quota: &'a MemQuota,
+    amount: usize,
+}
+
+impl<'a> QuotaCharge<'a> {
+    pub fn new(quota: &'a MemQuota, amount: usize) -> Result<Self, SvsmError> {
+        quota.charge(amount)?;
+        Ok(QuotaCharge { quota, amount })
+    }
+
+    /// Keeps the charge after the guard is gone. The owner of the resource
+    /// is responsible for calling [`MemQuota::uncharge()`] when releasing
+    /// it.
+    pub fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for QuotaCharge<'_> {
+    fn drop(&mut self) {
+        self.quota.uncharge(self.amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_charge_limit() {
+        let quota = MemQuota::new(8);
+
+        quota.charge(5).unwrap();
+        assert!(quota.charge(4).is_err());
+        assert_eq!(quota.used(), 5);
+        assert_eq!(quota.denied(), 1);
+
+        quota.charge(3).unwrap();
+        assert_eq!(quota.used(), 8);
+
+        quota.uncharge(8);
+        assert_eq!(quota.used(), 0);
+    }
+
+    #[test]
+    fn test_quota_charge_guard() {
+        let quota = MemQuota::new(4);
+
+        {
+            let _charge = QuotaCharge::new(&quota, 4).unwrap();
+            assert_eq!(quota.used(), 4);
+            assert!(QuotaCharge::new(&quota, 1).is_err());
+        }
+        assert_eq!(quota.used(), 0);
+
+        QuotaCharge::new(&quota, 2).unwrap().keep();
+        assert_eq!(quota.used(), 2);
+    }
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::deferred::{defer_work, DeferredWork};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::types::PAGE_SIZE;
+use crate::utils::zero_mem_region;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of pages scrubbed per call to [`scrub_work()`]
+pub const SCRUB_BUDGET: usize = 8;
+
+/// Called with the physical address of the page and its mapping after it
+/// has been cleared, to hand it back to its new owner.
+pub type ScrubDoneFn = fn(PhysAddr, VirtAddr) -> Result<(), SvsmError>;
+
+#[derive(Clone, Copy, Debug)]
+struct ScrubEntry {
+    paddr: PhysAddr,
+    done: ScrubDoneFn,
+}
+
+static SCRUB_QUEUE: SpinLock<VecDeque<ScrubEntry>> = SpinLock::new(VecDeque::new());
+static SCRUB_QUEUED: AtomicUsize = AtomicUsize::new(0);
+static SCRUB_COMPLETED: AtomicUsize = AtomicUsize::new(0);
+static SCRUB_FAILED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScrubStats {
+    /// Pages queued for scrubbing since boot
+    pub queued: usize,
+    /// Pages scrubbed and handed back since boot
+    pub completed: usize,
+    /// Pages which could not be scrubbed or handed back
+    pub failed: usize,
+    /// Pages still waiting in the queue
+    pub pending: usize,
+}
+
+/// Queues a page for scrubbing, which the current CPU starts on before it
+/// returns to the guest. The caller must make sure the page is not
+/// accessible by anyone but the SVSM until `done` is called for it.
+pub fn scrub_page_deferred(paddr: PhysAddr, done: ScrubDoneFn) {
+    assert!(paddr.is_page_aligned());
+    SCRUB_QUEUE.lock().push_back(ScrubEntry { paddr, done });
+    SCRUB_QUEUED.fetch_add(1, Ordering::Relaxed);
+    defer_work(DeferredWork::SCRUB);
+}
+
+fn scrub_one(entry: &ScrubEntry) -> Result<(), SvsmError> {
+    let guard = PerCPUPageMappingGuard::create_4k(entry.paddr)?;
+    let vaddr = guard.virt_addr();
+
+    zero_mem_region(vaddr, vaddr.offset(PAGE_SIZE));
+    (entry.done)(entry.paddr, vaddr)
+}
+
+/// Scrubs up to `budget` pages from the queue and returns the number of
+/// pages processed. Meant to be called from idle points, so that scrubbing
+/// large amounts of memory does not delay request processing.
+pub fn scrub_work(budget: usize) -> usize {
+    let mut processed = 0;
+
+    while processed < budget {
+        // Do not hold the lock while scrubbing
+        let Some(entry) = SCRUB_QUEUE.lock().pop_front() else {
+            break;
+        };
+
+        match scrub_one(&entry) {
+            Ok(()) => SCRUB_COMPLETED.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                log::error!("Failed to scrub page {:#018x}: {:?}", entry.paddr, e);
+                SCRUB_FAILED.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+
+        processed += 1;
+    }
+
+    processed
+}
+
+pub fn scrub_pending() -> bool {
+    !SCRUB_QUEUE.lock().is_empty()
+}
+
+pub fn scrub_stats() -> ScrubStats {
+    ScrubStats {
+        queued: SCRUB_QUEUED.load(Ordering::Relaxed),
+        completed: SCRUB_COMPLETED.load(Ordering::Relaxed),
+        failed: SCRUB_FAILED.load(Ordering::Relaxed),
+        pending: SCRUB_QUEUE.lock().len(),
+    }
+}
@@ -12,7 +12,8 @@ use crate::mm::alloc::{allocate_zeroed_page, free_page};
 use crate::mm::pagetable::{get_init_pgtable_locked, PageTable, PageTableRef};
 use crate::mm::{phys_to_virt, virt_to_phys};
 use crate::mm::{
-    STACK_PAGES, STACK_SIZE, STACK_TOTAL_SIZE, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END,
+    STACK_GUARD_SIZE, STACK_PAGES, STACK_SIZE, STACK_TOTAL_SIZE, SVSM_PERCPU_STACKS_BASE,
+    SVSM_PERCPU_STACKS_END, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END,
 };
 use crate::types::PAGE_SIZE;
 use crate::utils::ffs;
@@ -74,14 +75,29 @@ impl StackRange {
     }
 }
 
+// The first stack starts one guard area into the range, so that it has a
+// guard below it like all other stacks
 static STACK_ALLOC: SpinLock<StackRange> = SpinLock::new(StackRange::new(
-    VirtAddr::new(SVSM_SHARED_STACK_BASE),
+    VirtAddr::new(SVSM_SHARED_STACK_BASE + STACK_GUARD_SIZE),
     VirtAddr::new(SVSM_SHARED_STACK_END),
 ));
 
 pub fn allocate_stack_addr(stack: VirtAddr, pgtable: &mut PageTableRef) -> Result<(), SvsmError> {
+    allocate_stack_pages(stack, STACK_PAGES, pgtable)
+}
+
+/// Maps only the topmost `pages` pages of the stack at `stack`, for CPUs
+/// which get by with a smaller stack. The rest of the stack range acts as
+/// an additional guard.
+pub fn allocate_stack_pages(
+    stack: VirtAddr,
+    pages: usize,
+    pgtable: &mut PageTableRef,
+) -> Result<(), SvsmError> {
+    assert!(pages > 0 && pages <= STACK_PAGES);
+
     let flags = PageTable::data_flags();
-    for i in 0..STACK_PAGES {
+    for i in (STACK_PAGES - pages)..STACK_PAGES {
         let page = allocate_zeroed_page()?;
         let paddr = virt_to_phys(page);
         pgtable.map_4k(stack.offset(i * PAGE_SIZE), paddr, flags)?;
@@ -100,6 +116,21 @@ pub fn stack_base_pointer(stack: VirtAddr) -> VirtAddr {
     VirtAddr::from((stack.bits() & !(STACK_SIZE - 1)) + STACK_SIZE)
 }
 
+/// Whether a fault at `addr` means a stack ran into its guard area. Nothing
+/// but stacks is mapped in the PerCPU stack range, so any fault in there
+/// counts, including the unmapped part of a stack set up with fewer than
+/// `STACK_PAGES` pages. Shared stacks alternate with guard areas of
+/// `STACK_GUARD_SIZE`.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    let addr = addr.bits();
+    if (SVSM_PERCPU_STACKS_BASE..SVSM_PERCPU_STACKS_END).contains(&addr) {
+        return true;
+    }
+
+    (SVSM_SHARED_STACK_BASE..SVSM_SHARED_STACK_END).contains(&addr)
+        && (addr - SVSM_SHARED_STACK_BASE) % STACK_TOTAL_SIZE < STACK_GUARD_SIZE
+}
+
 pub fn free_stack(stack: VirtAddr) {
     let mut pages: [VirtAddr; STACK_PAGES] = [VirtAddr::null(); STACK_PAGES];
 
@@ -124,3 +155,26 @@ pub fn free_stack(stack: VirtAddr) {
 
     STACK_ALLOC.lock().dealloc(stack);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_guard() {
+        let mut range = StackRange::new(
+            VirtAddr::new(SVSM_SHARED_STACK_BASE + STACK_GUARD_SIZE),
+            VirtAddr::new(SVSM_SHARED_STACK_END),
+        );
+        let first = range.alloc().unwrap();
+        let second = range.alloc().unwrap();
+
+        assert!(!is_stack_guard(first));
+        assert!(!is_stack_guard(stack_base_pointer(first) - 8));
+        assert!(is_stack_guard(first - 8));
+        assert!(is_stack_guard(second - 8));
+        assert!(is_stack_guard(stack_base_pointer(first)));
+        assert!(!is_stack_guard(second));
+        assert!(is_stack_guard(VirtAddr::new(SVSM_PERCPU_STACKS_BASE)));
+    }
+}
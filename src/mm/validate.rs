@@ -53,6 +53,17 @@ pub fn validated_phys_addr(paddr: PhysAddr) -> bool {
     vb_ref.is_valid_4k(paddr)
 }
 
+/// Like [`validated_phys_addr()`], but returns `None` instead of waiting if
+/// the valid-bitmap is locked. Returns `None` as well for addresses not
+/// covered by the valid-bitmap.
+pub fn try_validated_phys_addr(paddr: PhysAddr) -> Option<bool> {
+    let vb_ref = VALID_BITMAP.try_lock().ok()?;
+    if !vb_ref.check_addr(paddr) {
+        return None;
+    }
+    Some(vb_ref.is_valid_4k(paddr))
+}
+
 pub fn valid_bitmap_set_valid_4k(paddr: PhysAddr) {
     let mut vb_ref = VALID_BITMAP.lock();
     vb_ref.set_valid_4k(paddr)
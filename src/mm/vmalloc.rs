@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::pagetable::{get_init_pgtable_locked, PTEntryFlags};
+use crate::mm::virtualrange::{VirtualRange, VIRT_ALIGN_4K};
+use crate::types::{PAGE_SHIFT, PAGE_SIZE};
+use crate::utils::align_up;
+use alloc::vec::Vec;
+
+use super::{SVSM_SHARED_VMALLOC_BASE, SVSM_SHARED_VMALLOC_END};
+
+#[derive(Clone, Copy, Debug)]
+struct VMapping {
+    start: VirtAddr,
+    pages: usize,
+}
+
+// Tracks the virtual address ranges handed out from the shared vmalloc
+// area, which is mapped on all CPUs.
+struct VmRegionManager {
+    range: VirtualRange,
+    mappings: Vec<VMapping>,
+    initialized: bool,
+}
+
+impl VmRegionManager {
+    const fn new() -> Self {
+        VmRegionManager {
+            range: VirtualRange::new(),
+            mappings: Vec::new(),
+            initialized: false,
+        }
+    }
+
+    fn alloc(&mut self, pages: usize) -> Result<VirtAddr, SvsmError> {
+        if !self.initialized {
+            let page_count = (SVSM_SHARED_VMALLOC_END - SVSM_SHARED_VMALLOC_BASE) >> PAGE_SHIFT;
+            self.range.init(
+                VirtAddr::from(SVSM_SHARED_VMALLOC_BASE),
+                page_count,
+                PAGE_SHIFT,
+            );
+            self.initialized = true;
+        }
+
+        let start = self.range.alloc(pages, VIRT_ALIGN_4K)?;
+        self.mappings.push(VMapping { start, pages });
+        Ok(start)
+    }
+
+    fn find(&self, start: VirtAddr) -> Option<usize> {
+        self.mappings.iter().position(|m| m.start == start)
+    }
+
+    fn free(&mut self, idx: usize) {
+        let mapping = self.mappings.swap_remove(idx);
+        self.range.free(mapping.start, mapping.pages);
+    }
+}
+
+static VMALLOC: SpinLock<VmRegionManager> = SpinLock::new(VmRegionManager::new());
+
+fn map_pages_common(
+    paddr: PhysAddr,
+    len: usize,
+    flags: PTEntryFlags,
+    shared: bool,
+) -> Result<VirtAddr, SvsmError> {
+    if len == 0 {
+        return Err(SvsmError::Mem);
+    }
+
+    let offset = paddr.page_offset();
+    let pstart = paddr.page_align();
+    let pages = align_up(offset + len, PAGE_SIZE) >> PAGE_SHIFT;
+
+    let vstart = VMALLOC.lock().alloc(pages)?;
+
+    let mut pgtable = get_init_pgtable_locked();
+    for i in 0..pages {
+        let vaddr = vstart.offset(i * PAGE_SIZE);
+        let res = pgtable.map_4k(vaddr, pstart.offset(i * PAGE_SIZE), flags);
+        let res = match (res, shared) {
+            (Ok(()), true) => pgtable.set_shared_4k(vaddr),
+            (res, _) => res,
+        };
+
+        if let Err(e) = res {
+            pgtable.unmap_region_4k(vstart, vstart.offset(i * PAGE_SIZE));
+            drop(pgtable);
+            let mut vm = VMALLOC.lock();
+            let idx = vm.find(vstart).unwrap();
+            vm.free(idx);
+            return Err(e);
+        }
+    }
+
+    Ok(vstart.offset(offset))
+}
+
+/// Maps `len` bytes of physical memory starting at `paddr` into the shared
+/// vmalloc area and returns the virtual address corresponding to `paddr`.
+/// The mapping is visible on all CPUs and stays until [`unmap()`] is called.
+pub fn map_pages(paddr: PhysAddr, len: usize, flags: PTEntryFlags) -> Result<VirtAddr, SvsmError> {
+    map_pages_common(paddr, len, flags, false)
+}
+
+/// Like [`map_pages()`], but maps the memory unencrypted, for pages shared
+/// with the hypervisor. Changing the page state of the memory is up to the
+/// caller.
+pub fn map_pages_shared(
+    paddr: PhysAddr,
+    len: usize,
+    flags: PTEntryFlags,
+) -> Result<VirtAddr, SvsmError> {
+    map_pages_common(paddr, len, flags, true)
+}
+
+/// Removes a mapping created with [`map_pages()`] or [`map_pages_shared()`].
+/// `vaddr` is the address returned when the mapping was created.
+pub fn unmap(vaddr: VirtAddr) {
+    let start = vaddr.page_align();
+    let mut vm = VMALLOC.lock();
+    let idx = vm.find(start).expect("Unmapping unknown vmalloc address");
+    let pages = vm.mappings[idx].pages;
+
+    // Keep the range allocated until no CPU can use the old mapping anymore,
+    // unmap_region_4k() flushes the TLB before returning
+    get_init_pgtable_locked().unmap_region_4k(start, start.offset(pages * PAGE_SIZE));
+    vm.free(idx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_region_alloc_free() {
+        let mut vm = VmRegionManager::new();
+
+        let v1 = vm.alloc(4).unwrap();
+        let v2 = vm.alloc(1).unwrap();
+        assert_eq!(v1, VirtAddr::from(SVSM_SHARED_VMALLOC_BASE));
+        // Ranges are separated by a guard page
+        assert!(v2 > v1.offset(4 * PAGE_SIZE));
+
+        let idx = vm.find(v1).unwrap();
+        vm.free(idx);
+        assert!(vm.find(v1).is_none());
+        assert_eq!(vm.alloc(4).unwrap(), v1);
+    }
+}
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::mm::pagetable::PageTable;
+use crate::mm::vmalloc::{map_pages_shared, unmap};
+use crate::sev::ghcb::{GhcbError, GHCB};
+use core::mem::size_of;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Integer types which can be accessed with a single MMIO operation
+pub trait MmioValue: Copy + private::Sealed {
+    fn from_u64(val: u64) -> Self;
+    fn to_u64(self) -> u64;
+}
+
+macro_rules! impl_mmio_value {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+
+            impl MmioValue for $t {
+                fn from_u64(val: u64) -> Self {
+                    val as $t
+                }
+
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+            }
+        )*
+    };
+}
+
+impl_mmio_value!(u8, u16, u32, u64);
+
+// Device memory is not accessible from the encrypted SVSM context, every
+// access is emulated by the hypervisor through the GHCB of the current
+// CPU.
+fn with_ghcb<R>(f: impl FnOnce(&mut GHCB) -> Result<R, SvsmError>) -> Result<R, SvsmError> {
+    let cpu = this_cpu();
+    if !cpu.has_ghcb() {
+        return Err(GhcbError::Unavailable.into());
+    }
+    f(&mut cpu.ghcb())
+}
+
+/// Reads a `T` from the device register at `paddr`
+pub fn mmio_read<T: MmioValue>(paddr: PhysAddr) -> Result<T, SvsmError> {
+    with_ghcb(|ghcb| ghcb.mmio_read(paddr, size_of::<T>())).map(T::from_u64)
+}
+
+/// Writes `val` to the device register at `paddr`
+pub fn mmio_write<T: MmioValue>(paddr: PhysAddr, val: T) -> Result<(), SvsmError> {
+    with_ghcb(|ghcb| ghcb.mmio_write(paddr, size_of::<T>(), val.to_u64()))
+}
+
+// Checks that a `size` byte register at `offset` is naturally aligned and
+// lies within a region of `len` bytes
+fn check_access(len: usize, offset: usize, size: usize) -> Result<(), SvsmError> {
+    match offset.checked_add(size) {
+        Some(end) if end <= len && offset & (size - 1) == 0 => Ok(()),
+        _ => Err(SvsmError::InvalidAddress),
+    }
+}
+
+/// An uncached, unencrypted mapping of a device MMIO region, created with
+/// [`map_mmio()`] and removed when dropped.
+///
+/// The typed accessors are emulated through the GHCB like [`mmio_read()`]
+/// and [`mmio_write()`], as the #VC handler does not decode MMIO
+/// instructions. They are bounds checked against the mapped region.
+#[derive(Debug)]
+pub struct MmioMapping {
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    len: usize,
+}
+
+impl MmioMapping {
+    /// Virtual address of the start of the region
+    pub fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// Physical address of the start of the region
+    pub fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the `T` register at `offset` into the region
+    pub fn read<T: MmioValue>(&self, offset: usize) -> Result<T, SvsmError> {
+        check_access(self.len, offset, size_of::<T>())?;
+        mmio_read(self.paddr.offset(offset))
+    }
+
+    /// Writes `val` to the `T` register at `offset` into the region
+    pub fn write<T: MmioValue>(&self, offset: usize, val: T) -> Result<(), SvsmError> {
+        check_access(self.len, offset, size_of::<T>())?;
+        mmio_write(self.paddr.offset(offset), val)
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        unmap(self.vaddr);
+    }
+}
+
+/// Maps `len` bytes of device memory at `paddr` uncached and with the C-bit
+/// clear into the shared vmalloc area.
+pub fn map_mmio(paddr: PhysAddr, len: usize) -> Result<MmioMapping, SvsmError> {
+    let vaddr = map_pages_shared(paddr, len, PageTable::mmio_flags())?;
+    Ok(MmioMapping { vaddr, paddr, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmio_access_check() {
+        assert!(check_access(0x1000, 0xffc, 4).is_ok());
+        assert!(check_access(0x1000, 0x1000, 1).is_err());
+        assert!(check_access(0x1000, 0xffc, 8).is_err());
+        assert!(check_access(0x1000, 0x2, 4).is_err());
+        assert!(check_access(0x1000, usize::MAX, 2).is_err());
+    }
+}
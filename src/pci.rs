@@ -0,0 +1,478 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// PCI configuration space access, either through the legacy 0xcf8/0xcfc
+// I/O ports or through a PCI Express ECAM region, whose accesses are
+// emulated by the hypervisor via the GHCB. Devices are enumerated starting
+// from bus 0, following PCI-to-PCI bridges.
+
+extern crate alloc;
+
+use crate::acpi::tables::ACPIMcfgInfo;
+use crate::address::PhysAddr;
+use crate::error::SvsmError;
+use crate::io::IOPort;
+use crate::mmio::{mmio_read, mmio_write};
+use alloc::vec::Vec;
+use core::fmt;
+
+// Configuration mechanism #1 of the PCI host bridge
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+/// Size of the configuration space reachable through the legacy ports
+pub const PCI_CONFIG_SIZE: u16 = 0x100;
+/// Size of the extended configuration space of PCI Express functions
+pub const PCIE_CONFIG_SIZE: u16 = 0x1000;
+
+pub const PCI_MAX_DEVICES: u8 = 32;
+pub const PCI_MAX_FUNCTIONS: u8 = 8;
+
+pub const PCI_REG_ID: u16 = 0x00;
+pub const PCI_REG_COMMAND: u16 = 0x04;
+pub const PCI_REG_CLASS: u16 = 0x08;
+pub const PCI_REG_HEADER_TYPE: u16 = 0x0e;
+pub const PCI_REG_BAR0: u16 = 0x10;
+pub const PCI_REG_SUBSYSTEM: u16 = 0x2c;
+// Secondary bus number of a PCI-to-PCI bridge
+const PCI_REG_SECONDARY_BUS: u16 = 0x19;
+
+pub const PCI_COMMAND_IO: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+
+const PCI_HEADER_TYPE_MASK: u8 = 0x7f;
+const PCI_HEADER_MULTIFUNCTION: u8 = 0x80;
+const PCI_HEADER_TYPE_NORMAL: u8 = 0;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 1;
+
+const PCI_BAR_IO: u32 = 1 << 0;
+const PCI_BAR_MEM_TYPE_MASK: u32 = 0x6;
+const PCI_BAR_MEM_TYPE_64: u32 = 0x4;
+const PCI_BAR_PREFETCHABLE: u32 = 1 << 3;
+const PCI_BAR_IO_MASK: u32 = !0x3;
+const PCI_BAR_MEM_MASK: u32 = !0xf;
+
+/// Number of BARs of a normal function
+pub const PCI_NUM_BARS: usize = 6;
+// Bridges only have the first two
+const PCI_NUM_BARS_BRIDGE: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciError {
+    // Register offset beyond the configuration space or misaligned
+    InvalidRegister(u16),
+    // Bus not covered by the configuration space access method
+    InvalidBus(u8),
+}
+
+impl From<PciError> for SvsmError {
+    fn from(e: PciError) -> Self {
+        Self::Pci(e)
+    }
+}
+
+/// Location of a PCI function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A way to reach the configuration space of PCI functions. Accesses are
+/// 32 bits wide and `reg` must be aligned to 4 bytes.
+pub trait PciConfigAccess {
+    fn read32(&self, addr: PciAddress, reg: u16) -> Result<u32, SvsmError>;
+    fn write32(&self, addr: PciAddress, reg: u16, val: u32) -> Result<(), SvsmError>;
+
+    fn read16(&self, addr: PciAddress, reg: u16) -> Result<u16, SvsmError> {
+        let val = self.read32(addr, reg & !3)?;
+        Ok((val >> ((reg & 2) * 8)) as u16)
+    }
+
+    fn read8(&self, addr: PciAddress, reg: u16) -> Result<u8, SvsmError> {
+        let val = self.read32(addr, reg & !3)?;
+        Ok((val >> ((reg & 3) * 8)) as u8)
+    }
+
+    fn write16(&self, addr: PciAddress, reg: u16, val: u16) -> Result<(), SvsmError> {
+        let shift = (reg & 2) * 8;
+        let old = self.read32(addr, reg & !3)?;
+        let new = (old & !(0xffff << shift)) | (val as u32) << shift;
+        self.write32(addr, reg & !3, new)
+    }
+}
+
+fn check_reg(reg: u16, size: u16) -> Result<(), PciError> {
+    if reg >= size || reg & 3 != 0 {
+        return Err(PciError::InvalidRegister(reg));
+    }
+    Ok(())
+}
+
+/// Configuration space access through configuration mechanism #1
+pub struct PciLegacyConfig<'a> {
+    io: &'a dyn IOPort,
+}
+
+impl<'a> PciLegacyConfig<'a> {
+    pub fn new(io: &'a dyn IOPort) -> Self {
+        PciLegacyConfig { io }
+    }
+
+    fn select(&self, addr: PciAddress, reg: u16) -> Result<(), SvsmError> {
+        let val = PCI_CONFIG_ENABLE
+            | (addr.bus as u32) << 16
+            | (addr.device as u32) << 11
+            | (addr.function as u32) << 8
+            | reg as u32;
+        self.io.try_outl(PCI_CONFIG_ADDRESS, val)
+    }
+}
+
+impl PciConfigAccess for PciLegacyConfig<'_> {
+    fn read32(&self, addr: PciAddress, reg: u16) -> Result<u32, SvsmError> {
+        check_reg(reg, PCI_CONFIG_SIZE)?;
+        self.select(addr, reg)?;
+        self.io.try_inl(PCI_CONFIG_DATA)
+    }
+
+    fn write32(&self, addr: PciAddress, reg: u16, val: u32) -> Result<(), SvsmError> {
+        check_reg(reg, PCI_CONFIG_SIZE)?;
+        self.select(addr, reg)?;
+        self.io.try_outl(PCI_CONFIG_DATA, val)
+    }
+}
+
+/// Configuration space access through a PCI Express ECAM region
+#[derive(Clone, Copy, Debug)]
+pub struct PciEcamConfig {
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl PciEcamConfig {
+    pub fn new(base: PhysAddr, start_bus: u8, end_bus: u8) -> Self {
+        PciEcamConfig {
+            base,
+            start_bus,
+            end_bus,
+        }
+    }
+
+    /// ECAM region of PCI segment 0 described by the MCFG, if there is one
+    pub fn from_mcfg(regions: &[ACPIMcfgInfo]) -> Option<Self> {
+        regions
+            .iter()
+            .find(|r| r.segment == 0)
+            .map(|r| Self::new(PhysAddr::from(r.base), r.start_bus, r.end_bus))
+    }
+
+    fn reg_addr(&self, addr: PciAddress, reg: u16) -> Result<PhysAddr, PciError> {
+        if addr.bus < self.start_bus || addr.bus > self.end_bus {
+            return Err(PciError::InvalidBus(addr.bus));
+        }
+        check_reg(reg, PCIE_CONFIG_SIZE)?;
+
+        let offset = ((addr.bus - self.start_bus) as usize) << 20
+            | (addr.device as usize) << 15
+            | (addr.function as usize) << 12
+            | reg as usize;
+        Ok(self.base + offset)
+    }
+}
+
+impl PciConfigAccess for PciEcamConfig {
+    fn read32(&self, addr: PciAddress, reg: u16) -> Result<u32, SvsmError> {
+        mmio_read::<u32>(self.reg_addr(addr, reg)?)
+    }
+
+    fn write32(&self, addr: PciAddress, reg: u16, val: u32) -> Result<(), SvsmError> {
+        mmio_write::<u32>(self.reg_addr(addr, reg)?, val)
+    }
+}
+
+/// A decoded base address register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciBar {
+    Io {
+        port: u32,
+        size: u32,
+    },
+    Memory {
+        base: u64,
+        size: u64,
+        is_64bit: bool,
+        prefetchable: bool,
+    },
+}
+
+// Decodes a BAR from its value and the value read back after writing all
+// ones. For 64-bit memory BARs, `high` and `high_mask` come from the next
+// BAR, for all others they are zero. Unimplemented BARs decode to None.
+fn decode_bar(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Option<PciBar> {
+    if low & PCI_BAR_IO != 0 {
+        let size = (!(low_mask & PCI_BAR_IO_MASK)).wrapping_add(1) & 0xffff;
+        return (size != 0).then_some(PciBar::Io {
+            port: low & PCI_BAR_IO_MASK,
+            size,
+        });
+    }
+
+    let is_64bit = low & PCI_BAR_MEM_TYPE_MASK == PCI_BAR_MEM_TYPE_64;
+    let base = (high as u64) << 32 | (low & PCI_BAR_MEM_MASK) as u64;
+    let mask = (high_mask as u64) << 32 | (low_mask & PCI_BAR_MEM_MASK) as u64;
+    if mask == 0 {
+        return None;
+    }
+    // The upper half of 32-bit BARs reads as all ones for sizing purposes
+    let mask = if is_64bit {
+        mask
+    } else {
+        mask | 0xffff_ffff_0000_0000
+    };
+    Some(PciBar::Memory {
+        base,
+        size: (!mask).wrapping_add(1),
+        is_64bit,
+        prefetchable: low & PCI_BAR_PREFETCHABLE != 0,
+    })
+}
+
+/// A PCI function found during enumeration
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice {
+    pub addr: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+}
+
+impl PciDevice {
+    fn probe(cfg: &dyn PciConfigAccess, addr: PciAddress) -> Result<Option<Self>, SvsmError> {
+        let id = cfg.read32(addr, PCI_REG_ID)?;
+        let vendor = id as u16;
+        // Reads without a function behind them return all ones
+        if vendor == 0xffff {
+            return Ok(None);
+        }
+
+        let class = cfg.read32(addr, PCI_REG_CLASS)?;
+        Ok(Some(PciDevice {
+            addr,
+            vendor,
+            device: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type: cfg.read8(addr, PCI_REG_HEADER_TYPE)?,
+        }))
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type & PCI_HEADER_TYPE_MASK == PCI_HEADER_TYPE_BRIDGE
+    }
+
+    fn num_bars(&self) -> usize {
+        match self.header_type & PCI_HEADER_TYPE_MASK {
+            PCI_HEADER_TYPE_NORMAL => PCI_NUM_BARS,
+            PCI_HEADER_TYPE_BRIDGE => PCI_NUM_BARS_BRIDGE,
+            _ => 0,
+        }
+    }
+
+    // Writes all ones to a BAR and returns what reads back, restoring the
+    // original value
+    fn bar_mask(&self, cfg: &dyn PciConfigAccess, reg: u16, val: u32) -> Result<u32, SvsmError> {
+        cfg.write32(self.addr, reg, 0xffff_ffff)?;
+        let mask = cfg.read32(self.addr, reg)?;
+        cfg.write32(self.addr, reg, val)?;
+        Ok(mask)
+    }
+
+    /// Decodes the BARs of the function. Sizing a BAR means overwriting it
+    /// for a moment, so decoding is turned off in the command register
+    /// meanwhile. 64-bit BARs take two slots, the upper one is None.
+    pub fn bars(
+        &self,
+        cfg: &dyn PciConfigAccess,
+    ) -> Result<[Option<PciBar>; PCI_NUM_BARS], SvsmError> {
+        let mut bars = [None; PCI_NUM_BARS];
+        let command = cfg.read16(self.addr, PCI_REG_COMMAND)?;
+        cfg.write16(
+            self.addr,
+            PCI_REG_COMMAND,
+            command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY),
+        )?;
+
+        let mut idx = 0;
+        let mut result = Ok(());
+        while idx < self.num_bars() {
+            let reg = PCI_REG_BAR0 + (idx as u16) * 4;
+            let decoded = (|| -> Result<(Option<PciBar>, usize), SvsmError> {
+                let low = cfg.read32(self.addr, reg)?;
+                let low_mask = self.bar_mask(cfg, reg, low)?;
+                let is_64bit = low & PCI_BAR_IO == 0
+                    && low & PCI_BAR_MEM_TYPE_MASK == PCI_BAR_MEM_TYPE_64
+                    && idx + 1 < self.num_bars();
+                if !is_64bit {
+                    return Ok((decode_bar(low, low_mask, 0, 0), 1));
+                }
+                let high = cfg.read32(self.addr, reg + 4)?;
+                let high_mask = self.bar_mask(cfg, reg + 4, high)?;
+                Ok((decode_bar(low, low_mask, high, high_mask), 2))
+            })();
+
+            match decoded {
+                Ok((bar, slots)) => {
+                    bars[idx] = bar;
+                    idx += slots;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        cfg.write16(self.addr, PCI_REG_COMMAND, command)?;
+        result.map(|_| bars)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] class {:02x}{:02x}{:02x}",
+            self.addr, self.vendor, self.device, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+fn scan_bus(
+    cfg: &dyn PciConfigAccess,
+    bus: u8,
+    devices: &mut Vec<PciDevice>,
+    visited: &mut [bool; 256],
+) -> Result<(), SvsmError> {
+    // Misconfigured bridges could otherwise make the scan loop
+    if visited[bus as usize] {
+        return Ok(());
+    }
+    visited[bus as usize] = true;
+
+    for dev in 0..PCI_MAX_DEVICES {
+        let Some(func0) = PciDevice::probe(cfg, PciAddress::new(bus, dev, 0))? else {
+            continue;
+        };
+        let functions = if func0.header_type & PCI_HEADER_MULTIFUNCTION != 0 {
+            PCI_MAX_FUNCTIONS
+        } else {
+            1
+        };
+
+        for func in 0..functions {
+            let found = match func {
+                0 => Some(func0),
+                _ => PciDevice::probe(cfg, PciAddress::new(bus, dev, func))?,
+            };
+            let Some(found) = found else {
+                continue;
+            };
+
+            devices.push(found);
+            if found.is_bridge() {
+                let secondary = cfg.read8(found.addr, PCI_REG_SECONDARY_BUS)?;
+                if secondary != 0 {
+                    scan_bus(cfg, secondary, devices, visited)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Enumerates all functions reachable from bus 0
+pub fn pci_enumerate(cfg: &dyn PciConfigAccess) -> Result<Vec<PciDevice>, SvsmError> {
+    let mut devices = Vec::new();
+    let mut visited = [false; 256];
+    scan_bus(cfg, 0, &mut devices, &mut visited)?;
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_decode_bar() {
+        // 4 KiB non-prefetchable 32-bit memory BAR
+        assert_eq!(
+            decode_bar(0xfebd_1000, 0xffff_f000, 0, 0),
+            Some(PciBar::Memory {
+                base: 0xfebd_1000,
+                size: 0x1000,
+                is_64bit: false,
+                prefetchable: false,
+            })
+        );
+        // 16 KiB prefetchable 64-bit memory BAR above 4 GiB
+        assert_eq!(
+            decode_bar(0x0000_400c, 0xffff_c00c, 0x8, 0xffff_ffff),
+            Some(PciBar::Memory {
+                base: 0x8_0000_4000,
+                size: 0x4000,
+                is_64bit: true,
+                prefetchable: true,
+            })
+        );
+        // 32 byte I/O BAR
+        assert_eq!(
+            decode_bar(0xc041, 0xffff_ffe1, 0, 0),
+            Some(PciBar::Io {
+                port: 0xc040,
+                size: 0x20,
+            })
+        );
+        assert_eq!(decode_bar(0, 0, 0, 0), None);
+    }
+
+    #[test]
+    fn test_ecam_addr() {
+        let ecam = PciEcamConfig::new(PhysAddr::from(0xb000_0000u64), 0, 0xff);
+        let addr = ecam.reg_addr(PciAddress::new(1, 2, 3), 0x104).unwrap();
+        assert_eq!(addr, PhysAddr::from(0xb011_3104u64));
+        assert_eq!(
+            ecam.reg_addr(PciAddress::new(0, 0, 0), 0x1000),
+            Err(PciError::InvalidRegister(0x1000))
+        );
+        assert_eq!(format!("{}", PciAddress::new(0, 0x1f, 2)), "00:1f.2");
+    }
+}
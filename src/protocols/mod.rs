@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+pub mod core;
+pub mod vendor;
+pub mod vtpm;
+
+use self::core::core_protocol_request;
+use self::vendor::vendor_protocol_request;
+use self::vtpm::vtpm_protocol_request;
+use crate::error::SvsmError;
+use crate::sev::vmsa::{GuestVMExit, VMSA};
+use crate::vtpm::vtpm_enabled;
+
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, dead_code, clippy::upper_case_acronyms)]
+pub enum SvsmResultCode {
+    SUCCESS,
+    INCOMPLETE,
+    UNSUPPORTED_PROTOCOL,
+    UNSUPPORTED_CALL,
+    INVALID_ADDRESS,
+    INVALID_FORMAT,
+    INVALID_PARAMETER,
+    INVALID_REQUEST,
+    BUSY,
+    PROTOCOL_BASE(u64),
+}
+
+impl From<SvsmResultCode> for u64 {
+    fn from(res: SvsmResultCode) -> u64 {
+        match res {
+            SvsmResultCode::SUCCESS => 0x0000_0000,
+            SvsmResultCode::INCOMPLETE => 0x8000_0000,
+            SvsmResultCode::UNSUPPORTED_PROTOCOL => 0x8000_0001,
+            SvsmResultCode::UNSUPPORTED_CALL => 0x8000_0002,
+            SvsmResultCode::INVALID_ADDRESS => 0x8000_0003,
+            SvsmResultCode::INVALID_FORMAT => 0x8000_0004,
+            SvsmResultCode::INVALID_PARAMETER => 0x8000_0005,
+            SvsmResultCode::INVALID_REQUEST => 0x8000_0006,
+            SvsmResultCode::BUSY => 0x8000_0007,
+            SvsmResultCode::PROTOCOL_BASE(code) => 0x8000_1000 + code,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SvsmReqError {
+    RequestError(SvsmResultCode),
+    FatalError(SvsmError),
+}
+
+macro_rules! impl_req_err {
+    ($name:ident, $v:ident) => {
+        pub fn $name() -> Self {
+            Self::RequestError(SvsmResultCode::$v)
+        }
+    };
+}
+
+#[allow(dead_code)]
+impl SvsmReqError {
+    impl_req_err!(incomplete, INCOMPLETE);
+    impl_req_err!(unsupported_protocol, UNSUPPORTED_PROTOCOL);
+    impl_req_err!(unsupported_call, UNSUPPORTED_CALL);
+    impl_req_err!(invalid_address, INVALID_ADDRESS);
+    impl_req_err!(invalid_format, INVALID_FORMAT);
+    impl_req_err!(invalid_parameter, INVALID_PARAMETER);
+    impl_req_err!(invalid_request, INVALID_REQUEST);
+    impl_req_err!(busy, BUSY);
+    pub fn protocol(code: u64) -> Self {
+        Self::RequestError(SvsmResultCode::PROTOCOL_BASE(code))
+    }
+}
+
+impl From<SvsmError> for SvsmReqError {
+    fn from(err: SvsmError) -> Self {
+        match err {
+            SvsmError::Mem => Self::FatalError(err),
+            // SEV-SNP errors obtained from PVALIDATE or RMPADJUST are returned
+            // to the guest as protocol-specific errors.
+            SvsmError::SevSnp(e) => Self::protocol(e.ret()),
+            SvsmError::InvalidAddress => Self::invalid_address(),
+            // The guest is asked to retry once it released resources
+            SvsmError::QuotaExceeded => Self::busy(),
+            // Attestation can fail without affecting anything else
+            SvsmError::GuestMsg(_) => Self::invalid_request(),
+            // Use a fatal error for now
+            _ => Self::FatalError(err),
+        }
+    }
+}
+
+// Protocol numbers as passed in RAX[63:32] and to SVSM_REQ_CORE_QUERY_PROTOCOL
+pub const SVSM_CORE_PROTOCOL: u32 = 0;
+pub const SVSM_VTPM_PROTOCOL: u32 = 2;
+// Calls specific to this implementation, numbered far away from the
+// protocols the SVSM specification assigns
+pub const SVSM_VENDOR_PROTOCOL: u32 = 0x8000_0000;
+
+const CORE_PROTOCOL_VERSION_MIN: u32 = 1;
+const CORE_PROTOCOL_VERSION_MAX: u32 = 1;
+const VTPM_PROTOCOL_VERSION_MIN: u32 = 1;
+const VTPM_PROTOCOL_VERSION_MAX: u32 = 1;
+const VENDOR_PROTOCOL_VERSION_MIN: u32 = 1;
+const VENDOR_PROTOCOL_VERSION_MAX: u32 = 1;
+
+/// A protocol served by the SVSM and the range of versions it supports
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolInfo {
+    pub id: u32,
+    pub version_min: u32,
+    pub version_max: u32,
+}
+
+/// All protocols the SVSM exposes to the guest
+pub const SVSM_PROTOCOLS: &[ProtocolInfo] = &[
+    ProtocolInfo {
+        id: SVSM_CORE_PROTOCOL,
+        version_min: CORE_PROTOCOL_VERSION_MIN,
+        version_max: CORE_PROTOCOL_VERSION_MAX,
+    },
+    ProtocolInfo {
+        id: SVSM_VTPM_PROTOCOL,
+        version_min: VTPM_PROTOCOL_VERSION_MIN,
+        version_max: VTPM_PROTOCOL_VERSION_MAX,
+    },
+    ProtocolInfo {
+        id: SVSM_VENDOR_PROTOCOL,
+        version_min: VENDOR_PROTOCOL_VERSION_MIN,
+        version_max: VENDOR_PROTOCOL_VERSION_MAX,
+    },
+];
+
+/// Whether protocol `id` is available to the guest in this boot
+pub fn protocol_enabled(id: u32) -> bool {
+    match id {
+        SVSM_VTPM_PROTOCOL => vtpm_enabled(),
+        _ => true,
+    }
+}
+
+/// Parameters of a guest call. RAX holds the protocol and call number,
+/// arguments and results are passed in RCX, RDX and R8.
+pub struct RequestParams {
+    pub guest_exit_code: GuestVMExit,
+    pub sev_features: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub r8: u64,
+}
+
+impl RequestParams {
+    pub fn from_vmsa(vmsa: &VMSA) -> Self {
+        RequestParams {
+            guest_exit_code: vmsa.guest_exit_code,
+            sev_features: vmsa.sev_features,
+            rcx: vmsa.rcx,
+            rdx: vmsa.rdx,
+            r8: vmsa.r8,
+        }
+    }
+
+    pub fn write_back(&self, vmsa: &mut VMSA) {
+        vmsa.rcx = self.rcx;
+        vmsa.rdx = self.rdx;
+        vmsa.r8 = self.r8;
+    }
+}
+
+/// Dispatches a guest call to the handler of its protocol. Results are
+/// returned to the guest in `params`.
+pub fn protocol_request(
+    protocol: u32,
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    if !protocol_enabled(protocol) {
+        return Err(SvsmReqError::unsupported_protocol());
+    }
+
+    match protocol {
+        SVSM_CORE_PROTOCOL => core_protocol_request(request, params),
+        SVSM_VTPM_PROTOCOL => vtpm_protocol_request(request, params),
+        SVSM_VENDOR_PROTOCOL => vendor_protocol_request(request, params),
+        _ => Err(SvsmReqError::unsupported_protocol()),
+    }
+}
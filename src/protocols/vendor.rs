@@ -0,0 +1,445 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Calls specific to this SVSM implementation. They live in their own
+// protocol, so they never collide with calls the SVSM specification adds to
+// the core protocol.
+
+use super::{RequestParams, SvsmReqError};
+use crate::address::{Address, PhysAddr};
+use crate::cpu::percpu::this_cpu;
+use crate::crypto::sha384::Sha512;
+#[cfg(feature = "lock-stats")]
+use crate::debug::trace::trace_dump_lock_stats;
+use crate::debug::trace::{
+    trace_dump, trace_protocols, trace_reset, trace_set_cpu, trace_set_protocols,
+    trace_set_subsystems, trace_subsystems, TraceSubsystems, TRACE_FILTER_CPUS,
+};
+use crate::deferred::{defer_work, DeferredWork};
+use crate::error::SvsmError;
+use crate::guest_exit::{guest_exit, GuestExitReason};
+#[cfg(feature = "enable-log-export")]
+use crate::log_buffer::LOG_BUFFER;
+use crate::manifest::svsm_manifest_digest;
+use crate::measure::fw_measure_wait;
+use crate::mm::alloc::slab_stats;
+use crate::mm::quota::MemQuota;
+use crate::mm::scrub::scrub_stats;
+use crate::mm::{
+    guest_page_state, valid_phys_address, GuestPageState, GuestPtr, PerCPUPageMappingGuard,
+};
+use crate::sev::guest_msg::{
+    get_attestation_report, get_derived_key, read_certificates, AttestationReport,
+    DERIVED_KEY_SIZE, KEY_FIELD_GUEST_SVN, KEY_FIELD_TCB_VERSION,
+};
+use crate::sev::rmpadjust::RMPFlags;
+use crate::types::{GUEST_VMPL, PAGE_SIZE};
+use crate::vtpm::vtpm_pcr_digest;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SVSM_REQ_VENDOR_QUERY_STATS: u32 = 0;
+const SVSM_REQ_VENDOR_TRACE_CTL: u32 = 1;
+#[cfg(feature = "enable-log-export")]
+const SVSM_REQ_VENDOR_LOG_EXPORT: u32 = 2;
+const SVSM_REQ_VENDOR_QUERY_PAGES: u32 = 3;
+const SVSM_REQ_VENDOR_GET_CERTS: u32 = 4;
+const SVSM_REQ_VENDOR_GUEST_EXIT: u32 = 5;
+const SVSM_REQ_VENDOR_DERIVE_KEY: u32 = 6;
+const SVSM_REQ_VENDOR_GET_REPORT: u32 = 7;
+
+// Resource groups which can be queried with SVSM_REQ_VENDOR_QUERY_STATS
+// Heap quota of the guest context of the calling CPU: bytes used in RCX,
+// the limit in RDX and the number of refused charges in R8. There is no
+// page-table group, guest requests are not capped on page-table memory.
+const SVSM_STATS_HEAP: u64 = 0;
+const SVSM_STATS_SCRUB: u64 = 1;
+// Usage of the slab cache with index RDX
+const SVSM_STATS_SLAB: u64 = 2;
+
+// Operations of SVSM_REQ_VENDOR_TRACE_CTL
+const SVSM_TRACE_DUMP: u64 = 0;
+const SVSM_TRACE_RESET: u64 = 1;
+#[cfg(feature = "lock-stats")]
+const SVSM_TRACE_LOCK_STATS: u64 = 2;
+// Trace subsystems to enable in RDX
+const SVSM_TRACE_SET_SUBSYSTEMS: u64 = 3;
+// Protocols to trace in RDX, bit 63 covers all protocols from 63 upwards
+const SVSM_TRACE_SET_PROTOCOLS: u64 = 4;
+// APIC ID in RDX or SVSM_TRACE_ALL_CPUS, R8 set to 1 enables and 0 disables
+const SVSM_TRACE_SET_CPU: u64 = 5;
+// Returns the enabled subsystems in RCX and protocols in RDX
+const SVSM_TRACE_GET_FILTERS: u64 = 6;
+const SVSM_TRACE_ALL_CPUS: u64 = u64::MAX;
+
+// Page states reported by SVSM_REQ_VENDOR_QUERY_PAGES
+const SVSM_PAGE_NOT_GUEST_MEMORY: u64 = 0;
+const SVSM_PAGE_GUEST: u64 = 1;
+const SVSM_PAGE_VMSA: u64 = 2;
+// Upper bound of pages looked at per SVSM_REQ_VENDOR_QUERY_PAGES call
+const SVSM_QUERY_PAGES_MAX: u64 = 512;
+
+// Root keys SVSM_REQ_VENDOR_DERIVE_KEY can derive from: the VCEK and the
+// VMRK
+const SVSM_KEY_ROOT_MAX: u64 = 1;
+// All guest fields known to MSG_KEY_REQ
+const SVSM_KEY_FIELDS: u64 = (KEY_FIELD_TCB_VERSION << 1) - 1;
+// Guest data the caller puts in front of the report buffer
+const SVSM_REPORT_DATA_SIZE: usize = 64;
+
+// SEV-SNP guest policy bit which allows the host to debug the VM
+const SNP_POLICY_DEBUG: u64 = 1 << 19;
+
+// Whether the guest may read the SVSM log and control tracing
+static DEBUG_CALLS_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Enables log export and trace control if the VM was launched with a
+/// guest policy which allows debugging. The policy is part of every
+/// attestation report, so a verifier can tell whether the guest had access
+/// to SVSM internals.
+pub fn vendor_debug_init() -> Result<(), SvsmError> {
+    let report = get_attestation_report(&[0; 64], 0)?;
+    if { report.policy } & SNP_POLICY_DEBUG != 0 {
+        DEBUG_CALLS_ALLOWED.store(true, Ordering::Relaxed);
+        log::info!("Guest policy allows debugging, enabling log export and trace control");
+    }
+
+    Ok(())
+}
+
+fn debug_calls_allowed() -> Result<(), SvsmReqError> {
+    if !DEBUG_CALLS_ALLOWED.load(Ordering::Relaxed) {
+        return Err(SvsmReqError::unsupported_call());
+    }
+    Ok(())
+}
+
+fn stats_report(params: &mut RequestParams, quota: &MemQuota) {
+    params.rcx = quota.used() as u64;
+    params.rdx = quota.limit() as u64;
+    params.r8 = quota.denied() as u64;
+}
+
+fn vendor_query_stats(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let quota = this_cpu().guest_quota();
+
+    match params.rcx {
+        SVSM_STATS_HEAP => stats_report(params, &quota.heap),
+        SVSM_STATS_SCRUB => {
+            let stats = scrub_stats();
+            params.rcx = stats.pending as u64;
+            params.rdx = stats.completed as u64;
+            params.r8 = stats.failed as u64;
+        }
+        SVSM_STATS_SLAB => {
+            let stats =
+                slab_stats(params.rdx as usize).ok_or_else(SvsmReqError::invalid_parameter)?;
+            params.rcx = stats.item_size as u64;
+            params.rdx = stats.capacity as u64;
+            params.r8 = stats.free as u64;
+        }
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    }
+
+    Ok(())
+}
+
+fn vendor_trace_set_cpu(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let enabled = match params.r8 {
+        0 => false,
+        1 => true,
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    };
+
+    match params.rdx {
+        SVSM_TRACE_ALL_CPUS => trace_set_cpu(None, enabled),
+        id if id < TRACE_FILTER_CPUS as u64 => trace_set_cpu(Some(id as u32), enabled),
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    }
+
+    Ok(())
+}
+
+fn vendor_trace_filter(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    match params.rcx {
+        SVSM_TRACE_SET_SUBSYSTEMS => {
+            let subsystems = TraceSubsystems::from_bits(params.rdx)
+                .ok_or_else(SvsmReqError::invalid_parameter)?;
+            trace_set_subsystems(subsystems);
+        }
+        SVSM_TRACE_SET_PROTOCOLS => trace_set_protocols(params.rdx),
+        SVSM_TRACE_SET_CPU => vendor_trace_set_cpu(params)?,
+        SVSM_TRACE_GET_FILTERS => {
+            params.rcx = trace_subsystems().bits();
+            params.rdx = trace_protocols();
+        }
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    }
+
+    Ok(())
+}
+
+fn vendor_trace_ctl(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    debug_calls_allowed()?;
+
+    match params.rcx {
+        SVSM_TRACE_DUMP => trace_dump(),
+        SVSM_TRACE_RESET => trace_reset(),
+        #[cfg(feature = "lock-stats")]
+        SVSM_TRACE_LOCK_STATS => trace_dump_lock_stats(),
+        _ => return vendor_trace_filter(params),
+    }
+
+    // The dump is complete on the console once the guest sees the result
+    defer_work(DeferredWork::LOG_FLUSH);
+
+    Ok(())
+}
+
+/// Copies SVSM log output into a guest page. RCX holds the guest-physical
+/// address of the buffer, which must not cross a page boundary, R8 its size
+/// and RDX the log position to start at (0 for the oldest available data).
+/// Returns the number of bytes copied in RCX and the position to continue
+/// from in RDX.
+#[cfg(feature = "enable-log-export")]
+fn vendor_log_export(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    const CHUNK_SIZE: usize = 256;
+
+    debug_calls_allowed()?;
+
+    let gpa = PhysAddr::from(params.rcx);
+    let len = params.r8 as usize;
+
+    if !valid_phys_address(gpa) || len > PAGE_SIZE - gpa.page_offset() {
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    let guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let dst = GuestPtr::<u8>::new(guard.virt_addr().offset(gpa.page_offset()));
+
+    let mut pos = params.rdx;
+    let mut copied = 0;
+    let mut chunk = [0u8; CHUNK_SIZE];
+
+    while copied < len {
+        let want = (len - copied).min(CHUNK_SIZE);
+        // Do not hold the log lock while touching guest memory
+        let (n, next) = LOG_BUFFER.lock_irqsave().read(pos, &mut chunk[..want]);
+        if n == 0 {
+            break;
+        }
+
+        for (i, b) in chunk[..n].iter().enumerate() {
+            dst.offset((copied + i) as isize).write(*b)?;
+        }
+
+        copied += n;
+        pos = next;
+    }
+
+    params.rcx = copied as u64;
+    params.rdx = pos;
+
+    Ok(())
+}
+
+fn page_state_report(state: GuestPageState) -> (u64, RMPFlags) {
+    match state {
+        GuestPageState::NotGuestMemory => (SVSM_PAGE_NOT_GUEST_MEMORY, RMPFlags::NONE),
+        GuestPageState::Guest => (SVSM_PAGE_GUEST, RMPFlags::RWX),
+        GuestPageState::Vmsa => (SVSM_PAGE_VMSA, RMPFlags::VMSA),
+    }
+}
+
+/// Reports what the SVSM believes about the guest-physical pages starting at
+/// the page-aligned address in RCX, RDX holds the number of pages. Returns
+/// the state of the first page in RCX, the number of consecutive pages which
+/// share that state in RDX and the RMP permissions the SVSM sets for the
+/// guest VMPL on these pages in R8. Whether guest memory is validated is not
+/// known to the SVSM, the permissions apply once it is.
+fn vendor_query_pages(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let count = params.rdx;
+
+    if !gpa.is_page_aligned() || count == 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let state = guest_page_state(gpa);
+    let mut run = 1;
+    while run < count.min(SVSM_QUERY_PAGES_MAX) {
+        match gpa.checked_offset(run as usize * PAGE_SIZE) {
+            Some(paddr) if guest_page_state(paddr) == state => run += 1,
+            _ => break,
+        }
+    }
+
+    let (code, perms) = page_state_report(state);
+    params.rcx = code;
+    params.rdx = run;
+    params.r8 = perms.bits();
+
+    Ok(())
+}
+
+/// Copies up to R8 bytes of the certificate data the hypervisor provides for
+/// attestation, starting at offset RDX, to the guest-physical address in RCX.
+/// The copy must not cross a page boundary. Returns the number of bytes
+/// copied in RCX and the total size of the data in RDX.
+fn vendor_get_certs(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    const CHUNK_SIZE: usize = 256;
+
+    let gpa = PhysAddr::from(params.rcx);
+    let len = params.r8 as usize;
+
+    if !valid_phys_address(gpa) || len > PAGE_SIZE - gpa.page_offset() {
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    let guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let dst = GuestPtr::<u8>::new(guard.virt_addr().offset(gpa.page_offset()));
+
+    // Fetches the data if this is the first call
+    let (_, total) = read_certificates(0, &mut [])?;
+
+    let mut offset = params.rdx as usize;
+    let mut copied = 0;
+    let mut chunk = [0u8; CHUNK_SIZE];
+
+    while copied < len {
+        let want = (len - copied).min(CHUNK_SIZE);
+        let (n, _) = read_certificates(offset, &mut chunk[..want])?;
+        if n == 0 {
+            break;
+        }
+
+        for (i, b) in chunk[..n].iter().enumerate() {
+            dst.offset((copied + i) as isize).write(*b)?;
+        }
+
+        copied += n;
+        offset += n;
+    }
+
+    params.rcx = copied as u64;
+    params.rdx = total as u64;
+
+    Ok(())
+}
+
+// The guest reports that it is going away, with a GHCB termination reason
+// set in RCX and the reason code in RDX. Does not return on success.
+fn vendor_guest_exit(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let set: u8 = params
+        .rcx
+        .try_into()
+        .map_err(|_| SvsmReqError::invalid_parameter())?;
+    let code: u8 = params
+        .rdx
+        .try_into()
+        .map_err(|_| SvsmReqError::invalid_parameter())?;
+
+    // Reason code sets are 4 bits wide
+    if set > 0xf {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    guest_exit(GuestExitReason::Requested { set, code })
+}
+
+/// Derives a key for the guest VMPL, which is how a guest without a VMPCK
+/// of its own gets keys. R8 selects the root key, RDX the guest fields mixed
+/// into the key, like in the MSG_KEY_REQ message. The key is written to the
+/// guest-physical address in RCX, which must not cross a page boundary.
+fn vendor_derive_key(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let fields = params.rdx;
+    let root_key = params.r8;
+
+    if !valid_phys_address(gpa) || gpa.crosses_page(DERIVED_KEY_SIZE) {
+        return Err(SvsmReqError::invalid_address());
+    }
+    // get_derived_key() asks for SVN and TCB version 0, mixing them in would
+    // not give the guest the key it expects
+    if root_key > SVSM_KEY_ROOT_MAX
+        || fields & !SVSM_KEY_FIELDS != 0
+        || fields & (KEY_FIELD_GUEST_SVN | KEY_FIELD_TCB_VERSION) != 0
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let dst = GuestPtr::<[u8; DERIVED_KEY_SIZE]>::new(guard.virt_addr().offset(gpa.page_offset()));
+
+    let mut key = get_derived_key(root_key as u32, fields, GUEST_VMPL as u32)?;
+    let res = dst.write_ref(&key);
+    key.fill(0);
+    res?;
+
+    Ok(())
+}
+
+// Report data of reports requested for the guest. It binds the SVSM
+// manifest, so verifiers see which protocols and launch options the guest
+// ran with, the firmware the guest was started from and the vTPM PCRs,
+// which stand in for a TPM2_Quote.
+fn guest_report_data(data: &[u8; SVSM_REPORT_DATA_SIZE]) -> [u8; 64] {
+    let mut hash = Sha512::new();
+    hash.update(data);
+    hash.update(&svsm_manifest_digest());
+    // Completed before the guest was launched, so this does not block
+    hash.update(&fw_measure_wait());
+    hash.update(&vtpm_pcr_digest());
+    hash.finalize()
+}
+
+/// Requests an attestation report for the guest VMPL. RCX holds the
+/// guest-physical address of a buffer of R8 bytes, which must not cross a
+/// page boundary. The buffer starts with 64 bytes of guest data. The report
+/// data of the returned report is SHA-512 over the guest data, the SHA-384
+/// digest of the SVSM manifest, the SHA-384 digest of the firmware flash
+/// and the SHA-384 digest over the 24 vTPM PCRs in order. The report
+/// replaces the guest data in the buffer and its size is returned in RCX.
+fn vendor_get_report(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let len = params.r8 as usize;
+
+    if !valid_phys_address(gpa) || len > PAGE_SIZE - gpa.page_offset() {
+        return Err(SvsmReqError::invalid_address());
+    }
+    if len < size_of::<AttestationReport>() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let buf = GuestPtr::<u8>::new(guard.virt_addr().offset(gpa.page_offset()));
+
+    let data = buf.cast::<[u8; SVSM_REPORT_DATA_SIZE]>().read()?;
+    let report = get_attestation_report(&guest_report_data(&data), GUEST_VMPL as u32)?;
+    buf.cast::<AttestationReport>().write_ref(&report)?;
+
+    params.rcx = size_of::<AttestationReport>() as u64;
+
+    Ok(())
+}
+
+pub fn vendor_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_REQ_VENDOR_QUERY_STATS => vendor_query_stats(params),
+        SVSM_REQ_VENDOR_TRACE_CTL => vendor_trace_ctl(params),
+        #[cfg(feature = "enable-log-export")]
+        SVSM_REQ_VENDOR_LOG_EXPORT => vendor_log_export(params),
+        SVSM_REQ_VENDOR_QUERY_PAGES => vendor_query_pages(params),
+        SVSM_REQ_VENDOR_GET_CERTS => vendor_get_certs(params),
+        SVSM_REQ_VENDOR_GUEST_EXIT => vendor_guest_exit(params),
+        SVSM_REQ_VENDOR_DERIVE_KEY => vendor_derive_key(params),
+        SVSM_REQ_VENDOR_GET_REPORT => vendor_get_report(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
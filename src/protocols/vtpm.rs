@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// SVSM vTPM protocol. The guest passes TPM platform commands in a buffer in
+// its own memory, the response replaces the request in the same buffer.
+
+extern crate alloc;
+
+use super::{RequestParams, SvsmReqError};
+use crate::address::{Address, PhysAddr};
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::mm::quota::QuotaCharge;
+use crate::mm::{valid_phys_address, GuestPtr, PerCPUPageMappingGuard};
+use crate::types::PAGE_SIZE;
+use crate::vtpm::{vtpm_send_command, TPM2_MAX_COMMAND_SIZE, TPM2_MAX_LOCALITY};
+use alloc::vec::Vec;
+
+const SVSM_REQ_VTPM_QUERY: u32 = 0;
+const SVSM_REQ_VTPM_CMD: u32 = 1;
+
+// Platform commands, numbered like in the TPM simulator interface
+const TPM_SEND_COMMAND: u32 = 8;
+
+// Bitmap of the platform commands returned by SVSM_REQ_VTPM_QUERY
+const VTPM_PLATFORM_COMMANDS: u64 = 1 << TPM_SEND_COMMAND;
+// No optional vTPM features are supported
+const VTPM_FEATURES: u64 = 0;
+
+// TPM_SEND_COMMAND request: u32 command, u8 locality, u32 size, then the
+// TPM command. All fields are little endian and packed.
+const SEND_COMMAND_REQ_SIZE: usize = 9;
+// TPM_SEND_COMMAND response: u32 size, then the TPM response
+const SEND_COMMAND_RESP_SIZE: usize = 4;
+
+fn vtpm_query(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    params.rcx = VTPM_PLATFORM_COMMANDS;
+    params.rdx = VTPM_FEATURES;
+    Ok(())
+}
+
+fn vtpm_send_command_request(buf: GuestPtr<u8>, len: usize) -> Result<(), SvsmReqError> {
+    if len < SEND_COMMAND_REQ_SIZE {
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    let hdr = buf.cast::<[u8; SEND_COMMAND_REQ_SIZE]>().read()?;
+    let locality = hdr[4];
+    let size = u32::from_le_bytes(hdr[5..9].try_into().unwrap()) as usize;
+
+    if locality > TPM2_MAX_LOCALITY {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if size > TPM2_MAX_COMMAND_SIZE || size > len - SEND_COMMAND_REQ_SIZE {
+        return Err(SvsmReqError::invalid_format());
+    }
+
+    // The command copy and the response are charged to the guest context
+    // until the request is done
+    let _charge = QuotaCharge::new(&this_cpu().guest_quota().heap, size + TPM2_MAX_COMMAND_SIZE)?;
+
+    // Copy the command so the guest can not change it while it is parsed
+    let mut cmd = Vec::new();
+    cmd.try_reserve_exact(size).map_err(|_| SvsmError::Mem)?;
+    for i in 0..size {
+        cmd.push(buf.offset((SEND_COMMAND_REQ_SIZE + i) as isize).read()?);
+    }
+
+    let resp = vtpm_send_command(locality, &cmd);
+    if resp.len() > len - SEND_COMMAND_RESP_SIZE {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    buf.cast::<[u8; SEND_COMMAND_RESP_SIZE]>()
+        .write((resp.len() as u32).to_le_bytes())?;
+    for (i, b) in resp.iter().enumerate() {
+        buf.offset((SEND_COMMAND_RESP_SIZE + i) as isize)
+            .write(*b)?;
+    }
+
+    Ok(())
+}
+
+fn vtpm_cmd(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    if !valid_phys_address(gpa) {
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    // The request and the response must fit into the page of the buffer
+    let guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let len = PAGE_SIZE - gpa.page_offset();
+    let buf = GuestPtr::<u8>::new(guard.virt_addr().offset(gpa.page_offset()));
+
+    if len < 4 {
+        return Err(SvsmReqError::invalid_address());
+    }
+    match buf.cast::<u32>().read()? {
+        TPM_SEND_COMMAND => vtpm_send_command_request(buf, len),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+pub fn vtpm_protocol_request(request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_REQ_VTPM_QUERY => vtpm_query(params),
+        SVSM_REQ_VTPM_CMD => vtpm_cmd(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
@@ -6,13 +6,26 @@
 
 use super::io::{IOPort, DEFAULT_IO_DRIVER};
 use crate::console::ConsoleWriter;
+use crate::cpu::apic::register_irq_handler;
+use crate::cpu::ioapic::route_legacy_irq;
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::fw_cfg::FwCfg;
+use crate::locking::SpinLock;
+use crate::utils::immut_after_init::ImmutAfterInitRef;
+use core::sync::atomic::{AtomicU16, Ordering};
 
 pub const SERIAL_PORT: u16 = 0x3f8;
 const BAUD: u32 = 9600;
+// Input clock of the UART divided by 16, the highest possible baud rate
+const BAUD_MAX: u32 = 115200;
+
+// Legacy ISA ports of COM1-COM4
+const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
 const DLAB: u8 = 0x80;
 
 pub const TXR: u16 = 0; // Transmit register
-pub const _RXR: u16 = 0; // Receive register
+pub const RXR: u16 = 0; // Receive register
 pub const IER: u16 = 1; // Interrupt enable
 pub const _IIR: u16 = 2; // Interrupt ID
 pub const FCR: u16 = 2; // FIFO Control
@@ -20,25 +33,199 @@ pub const LCR: u16 = 3; // Line Control
 pub const MCR: u16 = 4; // Modem Control
 pub const LSR: u16 = 5; // Line Status
 pub const _MSR: u16 = 6; // Modem Status
+pub const SCR: u16 = 7; // Scratch
 pub const DLL: u16 = 0; // Divisor Latch Low
 pub const DLH: u16 = 1; // Divisor Latch High
 
+pub const DR: u8 = 0x01; // Data ready
 pub const XMTRDY: u8 = 0x20;
+pub const TEMT: u8 = 0x40; // Transmitter empty
+
+pub const IER_RDI: u8 = 0x01; // Received data available interrupt
+pub const FCR_ENABLE: u8 = 0x07; // Enable and clear FIFOs, 1 byte trigger
+pub const MCR_OUT2: u8 = 0x08; // Connects the UART interrupt line
+
+// ISA interrupts of COM1/COM3 and COM2/COM4
+const SERIAL_IRQ_COM1: u8 = 4;
+const SERIAL_IRQ_COM2: u8 = 3;
+pub const SERIAL_RX_VECTOR: u8 = 0xe4;
+
+// Present when the SVSM should take input from its console serial port
+const SERIAL_RX_FILE: &str = "opt/svsm/serial-rx";
+
+// Optional console UART chosen by the host, in the format parsed by
+// SerialConfig::parse()
+const SERIAL_CONFIG_FILE: &str = "opt/svsm/serial";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialError {
+    // The port or baud rate of a serial configuration is not valid
+    InvalidConfig,
+    // The port has no known legacy interrupt
+    NoIrq(u16),
+}
+
+impl From<SerialError> for SvsmError {
+    fn from(e: SerialError) -> Self {
+        Self::Serial(e)
+    }
+}
+
+/// Port and baud rate of a UART, chosen by the host or on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub port: u16,
+    /// Programmed into the UART if set, otherwise the settings of the
+    /// firmware are kept
+    pub baud: Option<u32>,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            port: SERIAL_PORT,
+            baud: None,
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Parses `<port>[,<baud>]`. The port is either one of `com1` to `com4`
+    /// or an I/O port number, decimal or 0x-prefixed hexadecimal.
+    pub fn parse(s: &str) -> Result<Self, SerialError> {
+        let (port, baud) = match s.split_once(',') {
+            Some((port, baud)) => (port, Some(baud)),
+            None => (s, None),
+        };
+
+        let port = match port.strip_prefix("com").map(str::parse::<usize>) {
+            Some(Ok(n @ 1..=4)) => COM_PORTS[n - 1],
+            Some(_) => return Err(SerialError::InvalidConfig),
+            None => parse_number(port)
+                .and_then(|p| u16::try_from(p).ok())
+                .ok_or(SerialError::InvalidConfig)?,
+        };
+        // All eight registers must be addressable
+        if port == 0 || port.checked_add(SCR).is_none() {
+            return Err(SerialError::InvalidConfig);
+        }
+
+        let baud = match baud {
+            Some(baud) => Some(
+                parse_number(baud)
+                    .and_then(|b| u32::try_from(b).ok())
+                    .filter(|b| (2..=BAUD_MAX).contains(b))
+                    .ok_or(SerialError::InvalidConfig)?,
+            ),
+            None => None,
+        };
+
+        Ok(SerialConfig { port, baud })
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Returns the console UART configured by the host, or the default one if
+/// there is no configuration.
+pub fn serial_config(fw_cfg: &FwCfg) -> Result<SerialConfig, SvsmError> {
+    let Ok(file) = fw_cfg.file_selector(SERIAL_CONFIG_FILE) else {
+        return Ok(SerialConfig::default());
+    };
+
+    let mut buf = [0u8; 32];
+    let len = fw_cfg.read_file(&file, &mut buf)?;
+    let spec = core::str::from_utf8(&buf[..len])
+        .map_err(|_| SerialError::InvalidConfig)?
+        .trim_end_matches(['\0', '\n']);
+    Ok(SerialConfig::parse(spec)?)
+}
+
+const RX_BUFFER_SIZE: usize = 256;
+
+// Received bytes not yet read. The oldest byte is dropped when the buffer
+// is full.
+#[derive(Debug)]
+struct RxBuffer {
+    data: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl RxBuffer {
+    const fn new() -> Self {
+        RxBuffer {
+            data: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.len == RX_BUFFER_SIZE {
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+            self.len -= 1;
+            self.dropped += 1;
+        }
+        self.data[(self.head + self.len) % RX_BUFFER_SIZE] = b;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.data[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(b)
+    }
+}
 
 pub struct SerialPort<'a> {
     pub driver: &'a dyn IOPort,
-    pub port: u16,
+    // Only changes before the port is in use, see configure()
+    port: AtomicU16,
+    rx: SpinLock<RxBuffer>,
 }
 
 impl<'a> SerialPort<'a> {
-    pub fn new(driver: &'a dyn IOPort, p: u16) -> Self {
-        SerialPort { driver, port: p }
+    pub const fn new(driver: &'a dyn IOPort, p: u16) -> Self {
+        SerialPort {
+            driver,
+            port: AtomicU16::new(p),
+            rx: SpinLock::new(RxBuffer::new()),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.load(Ordering::Relaxed)
+    }
+
+    /// Moves the port to the UART in `config` and sets its baud rate, if
+    /// one is given. Must be called before the port is used.
+    pub fn configure(&self, config: &SerialConfig) {
+        self.port.store(config.port, Ordering::Relaxed);
+        if let Some(baud) = config.baud {
+            self.init_baud(baud);
+        }
     }
 
     pub fn init(&self) {
-        let divisor: u32 = 115200 / BAUD;
+        self.init_baud(BAUD);
+    }
+
+    fn init_baud(&self, baud: u32) {
+        let divisor: u32 = BAUD_MAX / baud;
         let driver = &self.driver;
-        let port = self.port;
+        let port = self.port();
 
         driver.outb(port + LCR, 0x3); // 8n1
         driver.outb(port + IER, 0); // No Interrupt
@@ -51,12 +238,132 @@ impl<'a> SerialPort<'a> {
         driver.outb(port + DLH, ((divisor >> 8) & 0xff) as u8);
         driver.outb(port + LCR, c & !DLAB);
     }
+
+    /// Checks for a UART through its scratch register, reads from a port
+    /// without a device return all ones. Port accesses the hypervisor
+    /// refuses mean there is no device either.
+    pub fn is_present(&self) -> bool {
+        let scratch = self.port() + SCR;
+        let Ok(old) = self.driver.try_inb(scratch) else {
+            return false;
+        };
+
+        let present = [0x5a, 0xa5].iter().all(|&pattern| {
+            self.driver.try_outb(scratch, pattern).is_ok()
+                && self.driver.try_inb(scratch).is_ok_and(|v| v == pattern)
+        });
+
+        let _ = self.driver.try_outb(scratch, old);
+        present
+    }
+
+    // Moves all bytes the UART received into the buffer
+    fn drain_rx(&self, rx: &mut RxBuffer) {
+        while self.driver.inb(self.port() + LSR) & DR != 0 {
+            rx.push(self.driver.inb(self.port() + RXR));
+        }
+    }
+
+    /// Enables the FIFOs and the receive interrupt of the UART. Received
+    /// bytes are picked up by [`Self::handle_rx_irq`] from then on.
+    pub fn enable_rx_irq(&self) {
+        let driver = &self.driver;
+        let port = self.port();
+
+        driver.outb(port + FCR, FCR_ENABLE);
+        let mcr = driver.inb(port + MCR);
+        driver.outb(port + MCR, mcr | MCR_OUT2);
+        driver.outb(port + IER, IER_RDI);
+    }
+
+    pub fn handle_rx_irq(&self) {
+        self.drain_rx(&mut self.rx.lock());
+    }
+
+    /// Returns the next received byte, if there is one. Also polls the
+    /// UART, so this works without the receive interrupt as well.
+    pub fn read_byte(&self) -> Option<u8> {
+        let mut rx = self.rx.lock_irqsave();
+        self.drain_rx(&mut rx);
+        rx.pop()
+    }
+
+    /// Number of received bytes dropped because they were not read in time
+    pub fn rx_dropped(&self) -> usize {
+        self.rx.lock_irqsave().dropped
+    }
+
+    /// Reads a line into `buf` and returns its length, without the line
+    /// terminator. Waits for input, echoes it and handles backspace. Input
+    /// beyond the size of `buf` is discarded.
+    pub fn read_line(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+
+        loop {
+            let Some(b) = self.read_byte() else {
+                core::hint::spin_loop();
+                continue;
+            };
+
+            match b {
+                b'\r' | b'\n' => {
+                    self.put_byte(b'\r');
+                    self.put_byte(b'\n');
+                    return len;
+                }
+                // Backspace and DEL
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        for c in [0x08, b' ', 0x08] {
+                            self.put_byte(c);
+                        }
+                    }
+                }
+                _ if len < buf.len() => {
+                    buf[len] = b;
+                    len += 1;
+                    self.put_byte(b);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+static SERIAL_RX_PORT: ImmutAfterInitRef<SerialPort<'static>> = ImmutAfterInitRef::uninit();
+
+fn serial_rx_irq(_vector: u8) {
+    SERIAL_RX_PORT.handle_rx_irq();
+}
+
+/// Has `port` receive input by interrupt on the current CPU, if the host
+/// asked for it. Needs the I/O APIC to be set up and takes the serial
+/// interrupt away from the guest. Must only be called once.
+pub fn serial_rx_init(fw_cfg: &FwCfg, port: &'static SerialPort<'static>) -> Result<(), SvsmError> {
+    if fw_cfg.file_selector(SERIAL_RX_FILE).is_err() {
+        return Ok(());
+    }
+
+    let irq = match port.port() {
+        0x3f8 | 0x3e8 => SERIAL_IRQ_COM1,
+        0x2f8 | 0x2e8 => SERIAL_IRQ_COM2,
+        p => return Err(SerialError::NoIrq(p).into()),
+    };
+
+    SERIAL_RX_PORT.init_from_ref(port)?;
+    register_irq_handler(SERIAL_RX_VECTOR, serial_rx_irq)?;
+    route_legacy_irq(irq, SERIAL_RX_VECTOR, this_cpu().get_apic_id())?;
+    port.enable_rx_irq();
+    log::info!("Serial input enabled on port {:#x}", port.port());
+
+    Ok(())
 }
 
 impl<'a> ConsoleWriter for SerialPort<'a> {
     fn put_byte(&self, ch: u8) {
         let driver = &self.driver;
-        let port = self.port;
+        let port = self.port();
 
         loop {
             let xmt = driver.inb(port + LSR);
@@ -67,9 +374,56 @@ impl<'a> ConsoleWriter for SerialPort<'a> {
 
         driver.outb(port + TXR, ch)
     }
+
+    fn flush(&self) {
+        while self.driver.inb(self.port() + LSR) & TEMT == 0 {}
+    }
 }
 
-pub static mut DEFAULT_SERIAL_PORT: SerialPort = SerialPort {
-    driver: &DEFAULT_IO_DRIVER,
-    port: SERIAL_PORT,
-};
+pub static DEFAULT_SERIAL_PORT: SerialPort = SerialPort::new(&DEFAULT_IO_DRIVER, SERIAL_PORT);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rx_buffer() {
+        let mut rx = RxBuffer::new();
+        assert_eq!(rx.pop(), None);
+
+        // Overflowing drops the oldest bytes
+        for i in 0..RX_BUFFER_SIZE + 2 {
+            rx.push(i as u8);
+        }
+        assert_eq!(rx.dropped, 2);
+        assert_eq!(rx.pop(), Some(2));
+        for i in 3..RX_BUFFER_SIZE + 2 {
+            assert_eq!(rx.pop(), Some(i as u8));
+        }
+        assert_eq!(rx.pop(), None);
+    }
+    #[test]
+    fn test_serial_config() {
+        let config = SerialConfig::parse("com2").unwrap();
+        assert_eq!(config.port, 0x2f8);
+        assert_eq!(config.baud, None);
+
+        let config = SerialConfig::parse("0x3e8,115200").unwrap();
+        assert_eq!(config.port, 0x3e8);
+        assert_eq!(config.baud, Some(115200));
+        assert_eq!(SerialConfig::parse("1016").unwrap().port, 0x3f8);
+
+        for s in [
+            "",
+            "com5",
+            "0",
+            "0xfffc",
+            "0x10000",
+            "com1,",
+            "com1,0",
+            "com1,230400",
+        ] {
+            assert_eq!(SerialConfig::parse(s), Err(SerialError::InvalidConfig));
+        }
+    }
+}
@@ -5,25 +5,22 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
-use crate::cpu::flush_tlb_global_sync;
-use crate::cpu::msr::{write_msr, SEV_GHCB};
+use crate::cpu::cpuid::CpuidResult;
+use crate::cpu::msr::write_ghcb_msr;
 use crate::error::SvsmError;
 use crate::io::IOPort;
-use crate::mm::pagetable::get_init_pgtable_locked;
-use crate::mm::validate::{
-    valid_bitmap_clear_valid_4k, valid_bitmap_set_valid_4k, valid_bitmap_valid_addr,
-};
 use crate::mm::virt_to_phys;
-use crate::sev::sev_snp_enabled;
 use crate::sev::utils::raw_vmgexit;
 use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
 use core::cell::RefCell;
+use core::ops::{Deref, DerefMut};
 use core::{mem, ptr};
 
+use super::integrity::{SVSM_TERM_GHCB_TAMPERED, SVSM_TERM_SET};
 use super::msr_protocol::{
-    invalidate_page_msr, register_ghcb_gpa_msr, request_termination_msr, validate_page_msr,
+    register_ghcb_gpa_msr, request_termination_msr, request_termination_reason_msr,
 };
-use super::pvalidate;
+use super::shared_page::{make_page_private, make_page_shared};
 
 // TODO: Fix this when Rust gets decent compile time struct offset support
 const OFF_CPL: u16 = 0xcb;
@@ -69,8 +66,14 @@ const PSC_OP_UNSMASH: u64 = 4 << PSC_OP_SHIFT;
 const PSC_FLAG_HUGE_SHIFT: u8 = 56;
 const PSC_FLAG_HUGE: u64 = 1 << PSC_FLAG_HUGE_SHIFT;
 
+// Part of a PSC entry the hypervisor updates while processing it
+const PSC_CUR_PAGE_MASK: u64 = 0xfff;
+
 const GHCB_BUFFER_SIZE: usize = 0x7f0;
 
+// Entries (8 bytes each) fitting into the buffer after the 8 byte header
+const PSC_MAX_ENTRIES: usize = (GHCB_BUFFER_SIZE - 8) / 8;
+
 #[repr(C, packed)]
 pub struct GHCB {
     reserved_1: [u8; 0xcb],
@@ -102,6 +105,47 @@ pub struct GHCB {
     usage: u32,
 }
 
+/// Reference to the GHCB page of a CPU. The lock protecting the GHCB can not
+/// live in the page, which is shared with the hypervisor, so it wraps this
+/// reference instead.
+#[derive(Debug)]
+pub struct GhcbPage(*mut GHCB);
+
+impl GhcbPage {
+    pub const fn null() -> Self {
+        GhcbPage(ptr::null_mut())
+    }
+
+    /// # Safety
+    ///
+    /// `ghcb` must point to a page which is used for nothing else for as long
+    /// as the returned reference exists.
+    pub unsafe fn new(ghcb: *mut GHCB) -> Self {
+        GhcbPage(ghcb)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0.is_null()
+    }
+
+    pub fn as_ptr(&self) -> *const GHCB {
+        self.0
+    }
+}
+
+impl Deref for GhcbPage {
+    type Target = GHCB;
+    fn deref(&self) -> &GHCB {
+        unsafe { self.0.as_ref().unwrap() }
+    }
+}
+
+impl DerefMut for GhcbPage {
+    fn deref_mut(&mut self) -> &mut GHCB {
+        unsafe { self.0.as_mut().unwrap() }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum GhcbError {
     // Attempted to write at an invalid offset in the GHCB
@@ -110,6 +154,12 @@ pub enum GhcbError {
     VmgexitInvalid,
     // A response from the hypervisor included an error code
     VmgexitError(u64, u64),
+    // The current CPU has no GHCB set up
+    Unavailable,
+    // The GHCB is in use by the code an exception handler interrupted
+    Busy,
+    // A request size or alignment the protocol can not express
+    InvalidSize,
 }
 
 impl From<GhcbError> for SvsmError {
@@ -118,46 +168,111 @@ impl From<GhcbError> for SvsmError {
     }
 }
 
+// Fields which identify a VMGEXIT request. They are kept in private memory
+// and only written to the GHCB right before the exit, so the hypervisor can
+// not change them in between. It has no business changing them while
+// handling the request either, so they are compared against the GHCB once
+// it returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GhcbRequest {
+    exit_code: u64,
+    version: u16,
+    usage: u32,
+    sw_scratch: Option<u64>,
+    rcx: Option<u64>,
+}
+
+impl GhcbRequest {
+    const fn new(exit_code: u64) -> Self {
+        GhcbRequest {
+            exit_code,
+            // GHCB is version 2 and follows the standard format
+            version: 2,
+            usage: 0,
+            sw_scratch: None,
+            rcx: None,
+        }
+    }
+
+    const fn with_rcx(self, rcx: u64) -> Self {
+        GhcbRequest {
+            rcx: Some(rcx),
+            ..self
+        }
+    }
+
+    const fn with_sw_scratch(self, sw_scratch: u64) -> Self {
+        GhcbRequest {
+            sw_scratch: Some(sw_scratch),
+            ..self
+        }
+    }
+}
+
 #[non_exhaustive]
 enum GHCBExitCode {}
 
 impl GHCBExitCode {
+    pub const CPUID: u64 = 0x72;
     pub const IOIO: u64 = 0x7b;
+    pub const MSR: u64 = 0x7c;
+    pub const MMIO_READ: u64 = 0x8000_0001;
+    pub const MMIO_WRITE: u64 = 0x8000_0002;
     pub const SNP_PSC: u64 = 0x8000_0010;
+    pub const SNP_GUEST_REQUEST: u64 = 0x8000_0011;
+    pub const SNP_EXT_GUEST_REQUEST: u64 = 0x8000_0012;
     pub const AP_CREATE: u64 = 0x80000013;
+    pub const HV_DOORBELL_PAGE: u64 = 0x80000014;
     pub const RUN_VMPL: u64 = 0x80000018;
 }
 
+// Whether the hypervisor returns a result in RCX for an exit, so RCX is not
+// part of the request it must leave alone
+fn exit_returns_rcx(exit_code: u64) -> bool {
+    // ECX of the CPUID result
+    exit_code == GHCBExitCode::CPUID
+}
+
+// Sub-functions of the HV_DOORBELL_PAGE exit
+const HV_DOORBELL_SET: u64 = 1;
+
+#[derive(Clone, Copy, Debug)]
 pub enum GHCBIOSize {
     Size8,
     Size16,
     Size32,
 }
 
-impl GHCB {
-    pub fn init(&mut self) -> Result<(), SvsmError> {
-        let vaddr = VirtAddr::from(self as *const GHCB);
-        let paddr = virt_to_phys(vaddr);
-
-        if sev_snp_enabled() {
-            // Make page invalid
-            pvalidate(vaddr, false, false)?;
-
-            // Let the Hypervisor take the page back
-            invalidate_page_msr(paddr)?;
-
-            // Needs guarding for Stage2 GHCB
-            if valid_bitmap_valid_addr(paddr) {
-                valid_bitmap_clear_valid_4k(paddr);
-            }
+impl GHCBIOSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            GHCBIOSize::Size8 => 1,
+            GHCBIOSize::Size16 => 2,
+            GHCBIOSize::Size32 => 4,
         }
+    }
+}
 
-        // Map page unencrypted
-        get_init_pgtable_locked().set_shared_4k(vaddr)?;
-
-        flush_tlb_global_sync();
+// Bits of the IOIO exit information
+const IOIO_TYPE_IN: u64 = 1 << 0;
+const IOIO_STR: u64 = 1 << 2;
+const IOIO_REP: u64 = 1 << 3;
+const IOIO_ADDR_64: u64 = 1 << 9;
+const IOIO_SEG_ES: u64 = 0 << 10;
+const IOIO_SEG_DS: u64 = 3 << 10;
+
+fn ioio_info(port: u16, size: GHCBIOSize) -> u64 {
+    let size = match size {
+        GHCBIOSize::Size8 => 1 << 4,
+        GHCBIOSize::Size16 => 1 << 5,
+        GHCBIOSize::Size32 => 1 << 6,
+    };
+    (port as u64) << 16 | size
+}
 
-        Ok(())
+impl GHCB {
+    pub fn init(&mut self) -> Result<(), SvsmError> {
+        make_page_shared(VirtAddr::from(self as *const GHCB))
     }
 
     pub fn register(&self) -> Result<(), SvsmError> {
@@ -169,27 +284,11 @@ impl GHCB {
     }
 
     pub fn shutdown(&mut self) -> Result<(), SvsmError> {
-        let vaddr = VirtAddr::from(self as *const GHCB);
-        let paddr = virt_to_phys(vaddr);
-
-        // Re-encrypt page
-        get_init_pgtable_locked().set_encrypted_4k(vaddr)?;
-
         // Unregister GHCB PA
         register_ghcb_gpa_msr(PhysAddr::null())?;
 
-        // Make page guest-invalid
-        validate_page_msr(paddr)?;
-
-        // Make page guest-valid
-        pvalidate(vaddr, false, true)?;
-
-        // Needs guarding for Stage2 GHCB
-        if valid_bitmap_valid_addr(paddr) {
-            valid_bitmap_set_valid_4k(paddr);
-        }
-
-        Ok(())
+        // Page state changes fall back to the MSR protocol from here on
+        make_page_private(VirtAddr::from(self as *const GHCB))
     }
 
     pub fn clear(&mut self) {
@@ -218,43 +317,88 @@ impl GHCB {
         (self.valid_bitmap[index] & mask) == mask
     }
 
+    // Copies the private request into the GHCB
+    fn write_request(&mut self, request: &GhcbRequest) {
+        self.version = request.version;
+        self.set_valid(OFF_VERSION);
+
+        self.usage = request.usage;
+        self.set_valid(OFF_USAGE);
+
+        self.sw_exit_code = request.exit_code;
+        self.set_valid(OFF_SW_EXIT_CODE);
+
+        if let Some(sw_scratch) = request.sw_scratch {
+            self.set_sw_scratch(sw_scratch);
+        }
+        if let Some(rcx) = request.rcx {
+            self.set_rcx(rcx);
+        }
+    }
+
+    // Only values are compared, the hypervisor rewrites the valid bitmap for
+    // its response
+    fn request_tampered(&self, request: &GhcbRequest) -> bool {
+        let rcx = request.rcx.filter(|_| !exit_returns_rcx(request.exit_code));
+        let expected = GhcbRequest { rcx, ..*request };
+        let current = GhcbRequest {
+            exit_code: self.sw_exit_code,
+            version: self.version,
+            usage: self.usage,
+            sw_scratch: request.sw_scratch.map(|_| self.sw_scratch),
+            rcx: rcx.map(|_| self.rcx),
+        };
+
+        current != expected
+    }
+
+    // Terminates the guest if the hypervisor changed the request while
+    // handling it. There is no way to recover, and a log message would be
+    // dropped while this GHCB is in use.
+    fn verify_request(&self, request: &GhcbRequest) {
+        if self.request_tampered(request) {
+            request_termination_reason_msr(SVSM_TERM_SET, SVSM_TERM_GHCB_TAMPERED);
+        }
+    }
+
     fn vmgexit(
         &mut self,
         exit_code: u64,
         exit_info_1: u64,
         exit_info_2: u64,
     ) -> Result<(), GhcbError> {
-        // GHCB is version 2
-        self.version = 2;
-        self.set_valid(OFF_VERSION);
-
-        // GHCB Follows standard format
-        self.usage = 0;
-        self.set_valid(OFF_USAGE);
-
-        self.sw_exit_code = exit_code;
-        self.set_valid(OFF_SW_EXIT_CODE);
+        self.vmgexit_request(GhcbRequest::new(exit_code), exit_info_1, exit_info_2)
+    }
 
+    fn vmgexit_request(
+        &mut self,
+        request: GhcbRequest,
+        exit_info_1: u64,
+        exit_info_2: u64,
+    ) -> Result<(), GhcbError> {
         self.sw_exit_info_1 = exit_info_1;
         self.set_valid(OFF_SW_EXIT_INFO_1);
 
         self.sw_exit_info_2 = exit_info_2;
         self.set_valid(OFF_SW_EXIT_INFO_2);
 
+        self.write_request(&request);
+
         let ghcb_address = VirtAddr::from(self as *const GHCB);
         let ghcb_pa = u64::from(virt_to_phys(ghcb_address));
-        write_msr(SEV_GHCB, ghcb_pa);
+        write_ghcb_msr(ghcb_pa);
         raw_vmgexit();
 
+        self.verify_request(&request);
+
         if !self.is_valid(OFF_SW_EXIT_INFO_1) {
             return Err(GhcbError::VmgexitInvalid);
         }
 
-        if self.sw_exit_info_1 != 0 {
-            return Err(GhcbError::VmgexitError(
-                self.sw_exit_info_1,
-                self.sw_exit_info_2,
-            ));
+        // Read the response only once, the hypervisor can change it anytime
+        let (info_1, info_2) = (self.sw_exit_info_1, self.sw_exit_info_2);
+        if info_1 != 0 {
+            return Err(GhcbError::VmgexitError(info_1, info_2));
         }
 
         Ok(())
@@ -313,16 +457,7 @@ impl GHCB {
     pub fn ioio_in(&mut self, port: u16, size: GHCBIOSize) -> Result<u64, SvsmError> {
         self.clear();
 
-        let mut info: u64 = 1; // IN instruction
-
-        info |= (port as u64) << 16;
-
-        match size {
-            GHCBIOSize::Size8 => info |= 1 << 4,
-            GHCBIOSize::Size16 => info |= 1 << 5,
-            GHCBIOSize::Size32 => info |= 1 << 6,
-        }
-
+        let info = ioio_info(port, size) | IOIO_TYPE_IN;
         self.vmgexit(GHCBExitCode::IOIO, info, 0)?;
         if !self.is_valid(OFF_RAX) {
             return Err(GhcbError::VmgexitInvalid.into());
@@ -333,18 +468,149 @@ impl GHCB {
     pub fn ioio_out(&mut self, port: u16, size: GHCBIOSize, value: u64) -> Result<(), SvsmError> {
         self.clear();
 
-        let mut info: u64 = 0; // OUT instruction
+        let info = ioio_info(port, size);
+        self.set_rax(value);
+        self.vmgexit(GHCBExitCode::IOIO, info, 0)?;
+        Ok(())
+    }
 
-        info |= (port as u64) << 16;
+    // Runs one string I/O request for `count` elements, the data is passed
+    // in the shared buffer
+    fn ioio_string(&mut self, info: u64, count: usize) -> Result<(), SvsmError> {
+        let request = GhcbRequest::new(GHCBExitCode::IOIO).with_sw_scratch(self.buffer_pa());
+        let info = info | IOIO_STR | IOIO_REP | IOIO_ADDR_64;
+        self.vmgexit_request(request, info, count as u64)?;
+        Ok(())
+    }
 
-        match size {
-            GHCBIOSize::Size8 => info |= 1 << 4,
-            GHCBIOSize::Size16 => info |= 1 << 5,
-            GHCBIOSize::Size32 => info |= 1 << 6,
+    /// Fills `buf` with elements of `size` read from `port`, like `rep ins`.
+    /// Needs one exit per GHCB buffer full of data instead of one per
+    /// element. The length of `buf` must be a multiple of the element size.
+    pub fn ioio_ins(
+        &mut self,
+        port: u16,
+        size: GHCBIOSize,
+        buf: &mut [u8],
+    ) -> Result<(), SvsmError> {
+        let elem = size.bytes();
+        if !buf.len().is_multiple_of(elem) {
+            return Err(GhcbError::InvalidSize.into());
         }
 
-        self.set_rax(value);
-        self.vmgexit(GHCBExitCode::IOIO, info, 0)?;
+        for chunk in buf.chunks_mut(GHCB_BUFFER_SIZE / elem * elem) {
+            self.clear();
+            self.ioio_string(
+                ioio_info(port, size) | IOIO_TYPE_IN | IOIO_SEG_ES,
+                chunk.len() / elem,
+            )?;
+            chunk.copy_from_slice(&self.buffer[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the elements of `size` in `buf` to `port`, like `rep outs`.
+    /// The length of `buf` must be a multiple of the element size.
+    pub fn ioio_outs(&mut self, port: u16, size: GHCBIOSize, buf: &[u8]) -> Result<(), SvsmError> {
+        let elem = size.bytes();
+        if !buf.len().is_multiple_of(elem) {
+            return Err(GhcbError::InvalidSize.into());
+        }
+
+        for chunk in buf.chunks(GHCB_BUFFER_SIZE / elem * elem) {
+            self.clear();
+            self.buffer[..chunk.len()].copy_from_slice(chunk);
+            self.ioio_string(ioio_info(port, size) | IOIO_SEG_DS, chunk.len() / elem)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn rdmsr(&mut self, msr: u32) -> Result<u64, SvsmError> {
+        self.clear();
+
+        let request = GhcbRequest::new(GHCBExitCode::MSR).with_rcx(msr as u64);
+        self.vmgexit_request(request, 0, 0)?;
+        if !self.is_valid(OFF_RAX) || !self.is_valid(OFF_RDX) {
+            return Err(GhcbError::VmgexitInvalid.into());
+        }
+
+        Ok((self.rdx & 0xffff_ffff) << 32 | (self.rax & 0xffff_ffff))
+    }
+
+    /// Asks the hypervisor to execute CPUID `leaf`/`subleaf` with `xcr0` in
+    /// place. The result is not validated by the firmware.
+    pub fn cpuid(&mut self, leaf: u32, subleaf: u32, xcr0: u64) -> Result<CpuidResult, SvsmError> {
+        self.clear();
+
+        self.set_rax(leaf as u64);
+        self.set_sw_xcr0(xcr0);
+        let request = GhcbRequest::new(GHCBExitCode::CPUID).with_rcx(subleaf as u64);
+        self.vmgexit_request(request, 0, 0)?;
+        if !self.is_valid(OFF_RAX)
+            || !self.is_valid(OFF_RBX)
+            || !self.is_valid(OFF_RCX)
+            || !self.is_valid(OFF_RDX)
+        {
+            return Err(GhcbError::VmgexitInvalid.into());
+        }
+
+        Ok(CpuidResult {
+            eax: self.rax as u32,
+            ebx: self.rbx as u32,
+            ecx: self.rcx as u32,
+            edx: self.rdx as u32,
+        })
+    }
+
+    pub fn wrmsr(&mut self, msr: u32, value: u64) -> Result<(), SvsmError> {
+        self.clear();
+
+        self.set_rax(value & 0xffff_ffff);
+        self.set_rdx(value >> 32);
+        let request = GhcbRequest::new(GHCBExitCode::MSR).with_rcx(msr as u64);
+        self.vmgexit_request(request, 1, 0)?;
+        Ok(())
+    }
+
+    fn buffer_pa(&self) -> u64 {
+        let buffer_va = VirtAddr::from(self.buffer.as_ptr());
+        u64::from(virt_to_phys(buffer_va))
+    }
+
+    // Returns the request for an MMIO access with the data in the buffer
+    fn mmio_prepare(&mut self, exit_code: u64, size: usize) -> Result<GhcbRequest, GhcbError> {
+        if !matches!(size, 1 | 2 | 4 | 8) {
+            return Err(GhcbError::InvalidSize);
+        }
+        self.clear();
+
+        Ok(GhcbRequest::new(exit_code).with_sw_scratch(self.buffer_pa()))
+    }
+
+    /// Reads `size` bytes (1, 2, 4 or 8) from the emulated MMIO location at
+    /// `paddr`.
+    pub fn mmio_read(&mut self, paddr: PhysAddr, size: usize) -> Result<u64, SvsmError> {
+        let request = self.mmio_prepare(GHCBExitCode::MMIO_READ, size)?;
+        self.write_buffer(&0u64, 0)?;
+        self.vmgexit_request(request, paddr.bits() as u64, size as u64)?;
+
+        let value: u64 = self.read_buffer(0)?;
+        let mask = u64::MAX >> (64 - size * 8);
+        Ok(value & mask)
+    }
+
+    /// Writes the low `size` bytes (1, 2, 4 or 8) of `value` to the emulated
+    /// MMIO location at `paddr`.
+    pub fn mmio_write(
+        &mut self,
+        paddr: PhysAddr,
+        size: usize,
+        value: u64,
+    ) -> Result<(), SvsmError> {
+        let request = self.mmio_prepare(GHCBExitCode::MMIO_WRITE, size)?;
+        self.write_buffer(&value, 0)?;
+        self.vmgexit_request(request, paddr.bits() as u64, size as u64)?;
         Ok(())
     }
 
@@ -373,8 +639,16 @@ impl GHCB {
         Ok(())
     }
 
-    pub fn psc_entry(&self, paddr: PhysAddr, op_mask: u64, current_page: u64, huge: bool) -> u64 {
-        assert!(!huge || paddr.is_aligned(PAGE_SIZE_2M));
+    pub fn psc_entry(
+        &self,
+        paddr: PhysAddr,
+        op_mask: u64,
+        current_page: u64,
+        huge: bool,
+    ) -> Result<u64, GhcbError> {
+        if huge && !paddr.is_aligned(PAGE_SIZE_2M) {
+            return Err(GhcbError::InvalidSize);
+        }
 
         let mut entry: u64 =
             ((paddr.bits() as u64) & PSC_GFN_MASK) | op_mask | (current_page & 0xfffu64);
@@ -382,9 +656,101 @@ impl GHCB {
             entry |= PSC_FLAG_HUGE;
         }
 
-        entry
+        Ok(entry)
+    }
+
+    fn read_buffer<T>(&self, offset: isize) -> Result<T, GhcbError>
+    where
+        T: Sized,
+    {
+        let size: isize = mem::size_of::<T>() as isize;
+
+        if offset < 0 || offset + size > (GHCB_BUFFER_SIZE as isize) {
+            return Err(GhcbError::InvalidOffset);
+        }
+
+        unsafe {
+            let src = self.buffer.as_ptr().cast::<u8>().offset(offset).cast::<T>();
+
+            Ok(ptr::read_unaligned(src))
+        }
+    }
+
+    // Whether the hypervisor changed more than the current page of the PSC
+    // entries it was asked to process, or the reserved header field
+    fn psc_tampered(&self, entries: &[u64]) -> Result<bool, GhcbError> {
+        let header: PageStateChangeHeader = self.read_buffer(0)?;
+        if { header.reserved } != 0 {
+            return Ok(true);
+        }
+
+        for (i, entry) in entries.iter().enumerate() {
+            let current: u64 = self.read_buffer(8 + i as isize * 8)?;
+            if (current ^ entry) & !PSC_CUR_PAGE_MASK != 0 {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Submits the PSC `entries`, which are kept in private memory. The
+    // hypervisor may return before all entries are processed, in which case
+    // the request is re-issued until cur_entry moves past end_entry.
+    fn psc_submit(&mut self, entries: &[u64]) -> Result<(), SvsmError> {
+        let end_entry = (entries.len() - 1) as u16;
+        let header = PageStateChangeHeader {
+            cur_entry: 0,
+            end_entry,
+            reserved: 0,
+        };
+
+        self.clear();
+        self.write_buffer(&header, 0)?;
+        for (i, entry) in entries.iter().enumerate() {
+            self.write_buffer(entry, 8 + i as isize * 8)?;
+        }
+
+        let request = GhcbRequest::new(GHCBExitCode::SNP_PSC).with_sw_scratch(self.buffer_pa());
+        let mut last_entry = 0u16;
+
+        loop {
+            if let Err(mut e) = self.vmgexit_request(request, 0, 0) {
+                // The error carries SW_EXITINFO2 with the error code for the
+                // caller to report, console output is dropped while this
+                // GHCB is held.
+                if !self.is_valid(OFF_SW_EXIT_INFO_2) {
+                    e = GhcbError::VmgexitInvalid;
+                }
+                return Err(e.into());
+            }
+
+            // The entries are re-submitted as the hypervisor left them, they
+            // must still describe the requested change
+            if self.psc_tampered(entries)? {
+                request_termination_reason_msr(SVSM_TERM_SET, SVSM_TERM_GHCB_TAMPERED);
+            }
+
+            let header: PageStateChangeHeader = self.read_buffer(0)?;
+            let (cur_entry, end_entry) = (header.cur_entry, header.end_entry);
+            if cur_entry > end_entry {
+                break;
+            }
+
+            // Do not loop forever if the hypervisor does not make progress
+            // or messes with the header.
+            if end_entry != entries.len() as u16 - 1 || cur_entry <= last_entry {
+                return Err(GhcbError::VmgexitInvalid.into());
+            }
+            last_entry = cur_entry;
+        }
+
+        Ok(())
     }
 
+    /// Changes the page state of the range [start, end) with as few exits as
+    /// possible, submitting up to 253 entries per VMGEXIT. 2M entries are
+    /// used for aligned parts of the range if `huge` is set.
     pub fn page_state_change(
         &mut self,
         start: PhysAddr,
@@ -392,9 +758,8 @@ impl GHCB {
         huge: bool,
         op: PageStateChangeOp,
     ) -> Result<(), SvsmError> {
-        // Maximum entries (8 bytes each_ minus 8 bytes for header
-        let max_entries: u16 = ((GHCB_BUFFER_SIZE - 8) / 8).try_into().unwrap();
-        let mut entries: u16 = 0;
+        let mut entries = [0u64; PSC_MAX_ENTRIES];
+        let mut count = 0;
         let mut paddr = start;
         let op_mask: u64 = match op {
             PageStateChangeOp::PscPrivate => PSC_OP_PRIVATE,
@@ -403,53 +768,26 @@ impl GHCB {
             PageStateChangeOp::PscUnsmash => PSC_OP_UNSMASH,
         };
 
-        self.clear();
-
         while paddr < end {
             let huge = huge && paddr.is_aligned(PAGE_SIZE_2M) && paddr.offset(PAGE_SIZE_2M) <= end;
             let pgsize: usize = match huge {
                 true => PAGE_SIZE_2M,
                 false => PAGE_SIZE,
             };
-            let entry = self.psc_entry(paddr, op_mask, 0, huge);
-            let offset: isize = (entries as isize) * 8 + 8;
-            self.write_buffer(&entry, offset)?;
-            entries += 1;
+            entries[count] = self.psc_entry(paddr, op_mask, 0, huge)?;
+            count += 1;
             paddr = paddr.offset(pgsize);
 
-            if entries == max_entries {
-                let header = PageStateChangeHeader {
-                    cur_entry: 0,
-                    end_entry: entries - 1,
-                    reserved: 0,
-                };
-                self.write_buffer(&header, 0)?;
-
-                let buffer_va = VirtAddr::from(self.buffer.as_ptr());
-                let buffer_pa = u64::from(virt_to_phys(buffer_va));
-                self.set_sw_scratch(buffer_pa);
-
-                if let Err(mut e) = self.vmgexit(GHCBExitCode::SNP_PSC, 0, 0) {
-                    if !self.is_valid(OFF_SW_EXIT_INFO_2) {
-                        e = GhcbError::VmgexitInvalid;
-                    }
-
-                    if let GhcbError::VmgexitError(_, info2) = e {
-                        let info_high: u32 = (info2 >> 32) as u32;
-                        let info_low: u32 = (info2 & 0xffff_ffffu64) as u32;
-                        log::error!(
-                            "GHCB SnpPageStateChange failed err_high: {:#x} err_low: {:#x}",
-                            info_high,
-                            info_low
-                        );
-                    }
-                    return Err(e.into());
-                }
-
-                entries = 0;
+            if count == PSC_MAX_ENTRIES {
+                self.psc_submit(&entries)?;
+                count = 0;
             }
         }
 
+        if count > 0 {
+            self.psc_submit(&entries[..count])?;
+        }
+
         Ok(())
     }
 
@@ -468,6 +806,80 @@ impl GHCB {
         Ok(())
     }
 
+    // Errors of the firmware or the hypervisor are returned in the second
+    // value of GhcbError::VmgexitError
+    fn guest_request_result(&self) -> Result<(), SvsmError> {
+        if !self.is_valid(OFF_SW_EXIT_INFO_2) {
+            return Err(GhcbError::VmgexitInvalid.into());
+        }
+
+        let info_2 = self.sw_exit_info_2;
+        if info_2 != 0 {
+            return Err(GhcbError::VmgexitError(0, info_2).into());
+        }
+
+        Ok(())
+    }
+
+    /// Passes the guest message in the shared page at `req_gpa` to the PSP,
+    /// which writes its response to the shared page at `resp_gpa`. Errors
+    /// of the firmware or the hypervisor are returned in the second value
+    /// of [`GhcbError::VmgexitError`].
+    pub fn guest_request(
+        &mut self,
+        req_gpa: PhysAddr,
+        resp_gpa: PhysAddr,
+    ) -> Result<(), SvsmError> {
+        self.clear();
+        self.vmgexit(
+            GHCBExitCode::SNP_GUEST_REQUEST,
+            req_gpa.into(),
+            resp_gpa.into(),
+        )?;
+
+        self.guest_request_result()
+    }
+
+    /// Like [`Self::guest_request`], but the hypervisor also places its
+    /// certificate data into the `npages` shared pages at `data_gpa`. If
+    /// they are too small, `npages` is updated to the number of pages the
+    /// hypervisor needs.
+    pub fn ext_guest_request(
+        &mut self,
+        req_gpa: PhysAddr,
+        resp_gpa: PhysAddr,
+        data_gpa: PhysAddr,
+        npages: &mut u64,
+    ) -> Result<(), SvsmError> {
+        self.clear();
+        self.set_rax(data_gpa.into());
+        self.set_rbx(*npages);
+        let result = self.vmgexit(
+            GHCBExitCode::SNP_EXT_GUEST_REQUEST,
+            req_gpa.into(),
+            resp_gpa.into(),
+        );
+
+        if self.is_valid(OFF_RBX) {
+            *npages = self.rbx;
+        }
+
+        result?;
+        self.guest_request_result()
+    }
+
+    /// Registers the #HV doorbell page at `vaddr`, which must be shared
+    pub fn register_hv_doorbell(&mut self, vaddr: VirtAddr) -> Result<(), SvsmError> {
+        let paddr = virt_to_phys(vaddr);
+        self.clear();
+        self.vmgexit(
+            GHCBExitCode::HV_DOORBELL_PAGE,
+            HV_DOORBELL_SET,
+            u64::from(paddr),
+        )?;
+        Ok(())
+    }
+
     pub fn run_vmpl(&mut self, vmpl: u64) -> Result<(), SvsmError> {
         self.clear();
         self.vmgexit(GHCBExitCode::RUN_VMPL, vmpl, 0)?;
@@ -520,4 +932,98 @@ impl<'a> IOPort for GHCBIOPort<'a> {
             Err(_e) => request_termination_msr(),
         }
     }
+
+    fn outl(&self, port: u16, value: u32) {
+        let mut g = self.ghcb.borrow_mut();
+        let ret = g.ioio_out(port, GHCBIOSize::Size32, value as u64);
+        if ret.is_err() {
+            request_termination_msr();
+        }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        let mut g = self.ghcb.borrow_mut();
+        let ret = g.ioio_in(port, GHCBIOSize::Size32);
+        match ret {
+            Ok(v) => (v & 0xffff_ffff) as u32,
+            Err(_e) => request_termination_msr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[test]
+    fn test_request_tampered() {
+        let mut ghcb: Box<GHCB> = Box::new(unsafe { mem::zeroed() });
+
+        // CPUID returns ECX in RCX
+        let request = GhcbRequest::new(GHCBExitCode::CPUID).with_rcx(1);
+        ghcb.clear();
+        ghcb.write_request(&request);
+        ghcb.rcx = 0x1234;
+        assert!(!ghcb.request_tampered(&request));
+        ghcb.sw_exit_code = GHCBExitCode::MSR;
+        assert!(ghcb.request_tampered(&request));
+
+        // The MSR index in RCX must not change
+        let request = GhcbRequest::new(GHCBExitCode::MSR).with_rcx(0xc001_0130);
+        ghcb.clear();
+        ghcb.write_request(&request);
+        ghcb.rax = 0x5678;
+        assert!(!ghcb.request_tampered(&request));
+        ghcb.rcx = 0xc001_0131;
+        assert!(ghcb.request_tampered(&request));
+
+        // Neither must the buffer address
+        let request = GhcbRequest::new(GHCBExitCode::SNP_PSC).with_sw_scratch(0x1000);
+        ghcb.clear();
+        ghcb.write_request(&request);
+        assert!(!ghcb.request_tampered(&request));
+        ghcb.sw_scratch = 0x2000;
+        assert!(ghcb.request_tampered(&request));
+    }
+
+    #[test]
+    fn test_psc_tampered() {
+        let mut ghcb: Box<GHCB> = Box::new(unsafe { mem::zeroed() });
+        let entries = [
+            ghcb.psc_entry(PhysAddr::from(0x20_0000u64), PSC_OP_SHARED, 0, true)
+                .unwrap(),
+            ghcb.psc_entry(PhysAddr::from(0x40_0000u64), PSC_OP_SHARED, 0, false)
+                .unwrap(),
+        ];
+        let header = PageStateChangeHeader {
+            cur_entry: 0,
+            end_entry: 1,
+            reserved: 0,
+        };
+
+        ghcb.write_buffer(&header, 0).unwrap();
+        ghcb.write_buffer(&entries, 8).unwrap();
+        assert!(!ghcb.psc_tampered(&entries).unwrap());
+
+        // Progress on the current page is reported in the entry itself
+        let partial = entries[0] | 0x10;
+        ghcb.write_buffer(&partial, 8).unwrap();
+        assert!(!ghcb.psc_tampered(&entries).unwrap());
+
+        // Anything else must stay as submitted
+        let other_op = (entries[1] & !PSC_OP_SHARED) | PSC_OP_PRIVATE;
+        ghcb.write_buffer(&other_op, 16).unwrap();
+        assert!(ghcb.psc_tampered(&entries).unwrap());
+
+        ghcb.write_buffer(&entries, 8).unwrap();
+        let header = PageStateChangeHeader {
+            reserved: 1,
+            ..header
+        };
+        ghcb.write_buffer(&header, 0).unwrap();
+        assert!(ghcb.psc_tampered(&entries).unwrap());
+    }
 }
@@ -0,0 +1,739 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::cpu::percpu::this_cpu;
+use crate::crypto::gcm::{Aes256Gcm, GCM_IV_SIZE, GCM_TAG_SIZE};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::sev::ghcb::GhcbError;
+use crate::sev::secrets_page::SecretsPage;
+use crate::sev::shared_page::SharedPages;
+use crate::types::PAGE_SIZE;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
+
+// Message header values for VMPCK encrypted messages
+const MSG_AEAD_AES_256_GCM: u8 = 1;
+const MSG_HDR_VERSION: u8 = 1;
+const MSG_HDR_SIZE: usize = 0x60;
+const MSG_PAYLOAD_SIZE: usize = PAGE_SIZE - MSG_HDR_SIZE;
+// The AAD covers the header from the algorithm field to its end
+const MSG_AAD_OFFSET: usize = 0x30;
+
+// Message types, a response always has the type of its request plus one
+const MSG_KEY_REQ: u8 = 1;
+const MSG_KEY_RSP: u8 = 2;
+const MSG_KEY_VERSION: u8 = 1;
+const MSG_REPORT_REQ: u8 = 5;
+const MSG_REPORT_RSP: u8 = 6;
+const MSG_REPORT_VERSION: u8 = 1;
+
+// The SVSM is the only user of VMPCK0
+const SVSM_VMPCK: u8 = 0;
+
+// The firmware keeps a 32 bit sequence number per VMPCK
+const MSG_SEQNO_MAX: u64 = u32::MAX as u64;
+
+// VMM errors in the upper half of SW_EXITINFO2
+const SNP_GUEST_VMM_ERR_INVALID_LEN: u64 = 1 << 32;
+const SNP_GUEST_VMM_ERR_BUSY: u64 = 2 << 32;
+const GUEST_REQUEST_BUSY_RETRIES: usize = 100;
+
+// Size of the buffer for the certificate data of extended requests. It
+// grows on demand, the VCEK, ASK and ARK usually fit into two pages.
+const CERT_BUFFER_INITIAL_PAGES: usize = 2;
+const CERT_BUFFER_MAX_PAGES: usize = 16;
+
+// Entry of the table at the start of the certificate data, the table ends
+// with an all-zero entry
+const CERT_TABLE_ENTRY_SIZE: usize = 24;
+const CERT_TABLE_GUID_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestMsgError {
+    // Guest messages were not set up
+    NotInitialized,
+    // VMPCK0 was disabled after a failed request
+    KeyDisabled,
+    // The message sequence numbers are used up
+    SeqnoOverflow,
+    // The payload does not fit into a message
+    PayloadTooLarge,
+    // The firmware rejected the request
+    FirmwareError(u32),
+    // The hypervisor failed to pass the request on
+    VmmError(u32),
+    // Response header does not match the request
+    InvalidResponse,
+    // The firmware could not produce a report
+    ReportStatus(u32),
+    // The firmware could not derive a key
+    KeyStatus(u32),
+    // The hypervisor needs a larger certificate buffer than allowed
+    CertBufferTooLarge(u64),
+    // The certificate table from the hypervisor is malformed
+    InvalidCertTable,
+}
+
+impl From<GuestMsgError> for SvsmError {
+    fn from(e: GuestMsgError) -> Self {
+        Self::GuestMsg(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct SnpGuestMsgHdr {
+    authtag: [u8; 32],
+    msg_seqno: u64,
+    rsvd1: [u8; 8],
+    algo: u8,
+    hdr_version: u8,
+    hdr_sz: u16,
+    msg_type: u8,
+    msg_version: u8,
+    msg_sz: u16,
+    rsvd2: u32,
+    msg_vmpck: u8,
+    rsvd3: [u8; 35],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SnpGuestMsg {
+    hdr: SnpGuestMsgHdr,
+    payload: [u8; MSG_PAYLOAD_SIZE],
+}
+
+impl SnpGuestMsg {
+    const fn new() -> Self {
+        SnpGuestMsg {
+            hdr: SnpGuestMsgHdr {
+                authtag: [0; 32],
+                msg_seqno: 0,
+                rsvd1: [0; 8],
+                algo: 0,
+                hdr_version: 0,
+                hdr_sz: 0,
+                msg_type: 0,
+                msg_version: 0,
+                msg_sz: 0,
+                rsvd2: 0,
+                msg_vmpck: 0,
+                rsvd3: [0; 35],
+            },
+            payload: [0; MSG_PAYLOAD_SIZE],
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>()) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut((self as *mut Self).cast::<u8>(), size_of::<Self>()) }
+    }
+
+    fn iv(&self) -> [u8; GCM_IV_SIZE] {
+        let mut iv = [0u8; GCM_IV_SIZE];
+        iv[..8].copy_from_slice(&{ self.hdr.msg_seqno }.to_le_bytes());
+        iv
+    }
+
+    fn aad(&self) -> [u8; MSG_HDR_SIZE - MSG_AAD_OFFSET] {
+        self.as_bytes()[MSG_AAD_OFFSET..MSG_HDR_SIZE]
+            .try_into()
+            .unwrap()
+    }
+
+    /// Builds and encrypts a message with the first `len` bytes of the
+    /// payload.
+    fn seal(
+        &mut self,
+        gcm: &Aes256Gcm,
+        seqno: u64,
+        msg_type: u8,
+        msg_version: u8,
+        len: usize,
+    ) -> Result<(), GuestMsgError> {
+        if len > MSG_PAYLOAD_SIZE {
+            return Err(GuestMsgError::PayloadTooLarge);
+        }
+
+        self.hdr = SnpGuestMsgHdr {
+            msg_seqno: seqno,
+            algo: MSG_AEAD_AES_256_GCM,
+            hdr_version: MSG_HDR_VERSION,
+            hdr_sz: MSG_HDR_SIZE as u16,
+            msg_type,
+            msg_version,
+            msg_sz: len as u16,
+            msg_vmpck: SVSM_VMPCK,
+            ..SnpGuestMsg::new().hdr
+        };
+
+        let iv = self.iv();
+        let aad = self.aad();
+        let tag = gcm.encrypt(&iv, &aad, &mut self.payload[..len]);
+        self.hdr.authtag[..GCM_TAG_SIZE].copy_from_slice(&tag);
+
+        Ok(())
+    }
+
+    /// Checks the header of a response and decrypts its payload. Returns
+    /// the payload size.
+    fn open(
+        &mut self,
+        gcm: &Aes256Gcm,
+        seqno: u64,
+        msg_type: u8,
+        msg_version: u8,
+    ) -> Result<usize, SvsmError> {
+        let hdr = self.hdr;
+        let len = hdr.msg_sz as usize;
+
+        if hdr.msg_seqno != seqno
+            || hdr.algo != MSG_AEAD_AES_256_GCM
+            || hdr.hdr_version != MSG_HDR_VERSION
+            || hdr.hdr_sz as usize != MSG_HDR_SIZE
+            || hdr.msg_type != msg_type
+            || hdr.msg_version != msg_version
+            || hdr.msg_vmpck != SVSM_VMPCK
+            || len > MSG_PAYLOAD_SIZE
+        {
+            return Err(GuestMsgError::InvalidResponse.into());
+        }
+
+        let iv = self.iv();
+        let aad = self.aad();
+        let tag: [u8; GCM_TAG_SIZE] = hdr.authtag[..GCM_TAG_SIZE].try_into().unwrap();
+        gcm.decrypt(&iv, &aad, &mut self.payload[..len], &tag)?;
+
+        Ok(len)
+    }
+}
+
+/// Attestation report as returned by the firmware in MSG_REPORT_RSP
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct AttestationReport {
+    pub version: u32,
+    pub guest_svn: u32,
+    pub policy: u64,
+    pub family_id: [u8; 16],
+    pub image_id: [u8; 16],
+    pub vmpl: u32,
+    pub signature_algo: u32,
+    pub current_tcb: u64,
+    pub platform_info: u64,
+    pub flags: u32,
+    rsvd1: u32,
+    pub report_data: [u8; 64],
+    pub measurement: [u8; 48],
+    pub host_data: [u8; 32],
+    pub id_key_digest: [u8; 48],
+    pub author_key_digest: [u8; 48],
+    pub report_id: [u8; 32],
+    pub report_id_ma: [u8; 32],
+    pub reported_tcb: u64,
+    rsvd2: [u8; 24],
+    pub chip_id: [u8; 64],
+    pub committed_tcb: u64,
+    pub current_build: u8,
+    pub current_minor: u8,
+    pub current_major: u8,
+    rsvd3: u8,
+    pub committed_build: u8,
+    pub committed_minor: u8,
+    pub committed_major: u8,
+    rsvd4: u8,
+    pub launch_tcb: u64,
+    rsvd5: [u8; 168],
+    pub signature: [u8; 512],
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct SnpReportRequest {
+    user_data: [u8; 64],
+    vmpl: u32,
+    rsvd: [u8; 28],
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct SnpReportResponse {
+    status: u32,
+    report_size: u32,
+    rsvd: [u8; 24],
+    report: AttestationReport,
+}
+
+/// Size of keys derived with MSG_KEY_REQ
+pub const DERIVED_KEY_SIZE: usize = 32;
+
+// Root key to derive from: the chip-unique VCEK, or the VMRK shared with a
+// migration agent
+pub const KEY_ROOT_VCEK: u32 = 0;
+
+// Guest fields mixed into a derived key
+pub const KEY_FIELD_POLICY: u64 = 1 << 0;
+pub const KEY_FIELD_IMAGE_ID: u64 = 1 << 1;
+pub const KEY_FIELD_FAMILY_ID: u64 = 1 << 2;
+pub const KEY_FIELD_MEASUREMENT: u64 = 1 << 3;
+pub const KEY_FIELD_GUEST_SVN: u64 = 1 << 4;
+pub const KEY_FIELD_TCB_VERSION: u64 = 1 << 5;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct SnpKeyRequest {
+    root_key_select: u32,
+    rsvd: u32,
+    guest_field_select: u64,
+    vmpl: u32,
+    guest_svn: u32,
+    tcb_version: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct SnpKeyResponse {
+    status: u32,
+    rsvd: [u8; 28],
+    key: [u8; DERIVED_KEY_SIZE],
+}
+
+// Messages to the PSP are serialized, every request takes the next pair of
+// sequence numbers.
+struct GuestMessenger {
+    // Dropped when the sequence number can no longer be trusted, reusing it
+    // would reuse an IV
+    gcm: Option<Aes256Gcm>,
+    // Sequence number of the next request
+    seqno: u64,
+    request: Option<SharedPages>,
+    response: Option<SharedPages>,
+    // Pages to allocate for the certificate data of the next extended
+    // request
+    cert_pages: usize,
+    // Private copy of the message, the shared pages are only copied to and
+    // from
+    msg: SnpGuestMsg,
+}
+
+static GUEST_MESSENGER: SpinLock<GuestMessenger> = SpinLock::new(GuestMessenger::new());
+
+impl GuestMessenger {
+    const fn new() -> Self {
+        GuestMessenger {
+            gcm: None,
+            seqno: 0,
+            request: None,
+            response: None,
+            cert_pages: CERT_BUFFER_INITIAL_PAGES,
+            msg: SnpGuestMsg::new(),
+        }
+    }
+
+    fn disable_key(&mut self) {
+        if self.gcm.take().is_some() {
+            log::error!("Disabling VMPCK0 after failed guest request");
+        }
+    }
+
+    // Passes the sealed message to the PSP, retrying as long as the
+    // hypervisor did not forward it. With `certs`, the certificate data of
+    // the hypervisor is returned in a buffer that is private again.
+    fn submit(
+        request: &SharedPages,
+        response: &SharedPages,
+        certs: Option<&mut Vec<u8>>,
+        cert_pages: &mut usize,
+    ) -> Result<(), SvsmError> {
+        let mut data = match certs {
+            Some(_) => Some(SharedPages::new(*cert_pages)?),
+            None => None,
+        };
+
+        let mut retries = 0;
+        loop {
+            let mut npages = data.as_ref().map_or(0, |d| d.npages() as u64);
+            let result = {
+                let mut ghcb = this_cpu().ghcb();
+                match &data {
+                    Some(d) => ghcb.ext_guest_request(
+                        request.paddr(),
+                        response.paddr(),
+                        d.paddr(),
+                        &mut npages,
+                    ),
+                    None => ghcb.guest_request(request.paddr(), response.paddr()),
+                }
+            };
+
+            // Throttled requests and those with too little room for the
+            // certificates never reached the PSP. They are sent again
+            // unchanged, which keeps the IV safe.
+            match result {
+                Err(SvsmError::Ghcb(GhcbError::VmgexitError(_, SNP_GUEST_VMM_ERR_BUSY)))
+                    if retries < GUEST_REQUEST_BUSY_RETRIES =>
+                {
+                    retries += 1;
+                }
+                Err(SvsmError::Ghcb(GhcbError::VmgexitError(_, SNP_GUEST_VMM_ERR_INVALID_LEN)))
+                    if data.is_some() =>
+                {
+                    let current = data.as_ref().map_or(0, |d| d.npages() as u64);
+                    if npages <= current || npages > CERT_BUFFER_MAX_PAGES as u64 {
+                        return Err(GuestMsgError::CertBufferTooLarge(npages).into());
+                    }
+                    // Free the old buffer first, it is shared
+                    drop(data.take());
+                    data = Some(SharedPages::new(npages as usize)?);
+                    *cert_pages = npages as usize;
+                }
+                Err(SvsmError::Ghcb(GhcbError::VmgexitError(_, info_2))) if info_2 >> 32 != 0 => {
+                    return Err(GuestMsgError::VmmError((info_2 >> 32) as u32).into());
+                }
+                Err(SvsmError::Ghcb(GhcbError::VmgexitError(_, info_2))) => {
+                    return Err(GuestMsgError::FirmwareError(info_2 as u32).into());
+                }
+                Err(e) => return Err(e),
+                Ok(()) => break,
+            }
+        }
+
+        if let (Some(certs), Some(d)) = (certs, data) {
+            let mut blob = vec![0u8; d.size()];
+            d.read(&mut blob);
+            *certs = blob;
+        }
+
+        Ok(())
+    }
+
+    // Sends the first `len` bytes of the payload as a message of type
+    // `req_type` and leaves the decrypted response of type `resp_type` in
+    // the message buffer. Returns the size of the response payload.
+    fn send(
+        &mut self,
+        req_type: u8,
+        resp_type: u8,
+        msg_version: u8,
+        len: usize,
+        certs: Option<&mut Vec<u8>>,
+    ) -> Result<usize, SvsmError> {
+        let (Some(request), Some(response)) = (&self.request, &self.response) else {
+            return Err(GuestMsgError::NotInitialized.into());
+        };
+        let gcm = self.gcm.as_ref().ok_or(GuestMsgError::KeyDisabled)?;
+
+        let seqno = self.seqno;
+        if seqno >= MSG_SEQNO_MAX {
+            return Err(GuestMsgError::SeqnoOverflow.into());
+        }
+
+        self.msg.seal(gcm, seqno, req_type, msg_version, len)?;
+        request.write(self.msg.as_bytes());
+        response.clear();
+
+        // From here on it is unknown whether the firmware advanced its
+        // sequence number, so any failure retires the key
+        let result = Self::submit(request, response, certs, &mut self.cert_pages).and_then(|_| {
+            response.read(self.msg.as_bytes_mut());
+            self.msg.open(gcm, seqno + 1, resp_type, msg_version)
+        });
+
+        match result {
+            Ok(len) => {
+                self.seqno += 2;
+                Ok(len)
+            }
+            Err(e) => {
+                self.disable_key();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Sets up encrypted messages to the PSP with VMPCK0 from `secrets`. Needs
+/// the GHCB and page allocator of the current CPU.
+pub fn guest_msg_init(secrets: &SecretsPage) -> Result<(), SvsmError> {
+    let key = secrets
+        .vmpck(SVSM_VMPCK as usize)
+        .ok_or(GuestMsgError::KeyDisabled)?;
+
+    let request = SharedPages::new(1)?;
+    let response = SharedPages::new(1)?;
+
+    let mut messenger = GUEST_MESSENGER.lock();
+    messenger.gcm = Some(Aes256Gcm::new(key));
+    // The firmware expects the first message to carry sequence number 1
+    messenger.seqno = 1;
+    messenger.request = Some(request);
+    messenger.response = Some(response);
+
+    Ok(())
+}
+
+// Returns the size of the certificate data in `blob`, which starts with a
+// table of GUID, offset and length entries. Nothing past the last
+// certificate is kept.
+fn cert_data_len(blob: &[u8]) -> Result<usize, GuestMsgError> {
+    let mut end = 0;
+    let mut min_offset = usize::MAX;
+
+    for (index, entry) in blob.chunks_exact(CERT_TABLE_ENTRY_SIZE).enumerate() {
+        if entry.iter().all(|&b| b == 0) {
+            // No certificates if the table is empty
+            if index == 0 {
+                return Ok(0);
+            }
+            // Certificates must not overlap the table
+            if min_offset < (index + 1) * CERT_TABLE_ENTRY_SIZE {
+                return Err(GuestMsgError::InvalidCertTable);
+            }
+            return Ok(end);
+        }
+
+        let field = |pos: usize| {
+            let start = CERT_TABLE_GUID_SIZE + pos * 4;
+            u32::from_le_bytes(entry[start..start + 4].try_into().unwrap()) as usize
+        };
+        let (offset, len) = (field(0), field(1));
+        let cert_end = offset
+            .checked_add(len)
+            .filter(|&e| e <= blob.len())
+            .ok_or(GuestMsgError::InvalidCertTable)?;
+
+        min_offset = min_offset.min(offset);
+        end = end.max(cert_end);
+    }
+
+    // The table has no terminating entry
+    Err(GuestMsgError::InvalidCertTable)
+}
+
+fn request_report(
+    user_data: &[u8; 64],
+    vmpl: u32,
+    certs: Option<&mut Vec<u8>>,
+) -> Result<AttestationReport, SvsmError> {
+    let mut messenger = GUEST_MESSENGER.lock();
+
+    let request = SnpReportRequest {
+        user_data: *user_data,
+        vmpl,
+        rsvd: [0; 28],
+    };
+    let req_len = size_of::<SnpReportRequest>();
+    let req_bytes = unsafe {
+        slice::from_raw_parts((&request as *const SnpReportRequest).cast::<u8>(), req_len)
+    };
+    messenger.msg.payload[..req_len].copy_from_slice(req_bytes);
+
+    let len = messenger.send(
+        MSG_REPORT_REQ,
+        MSG_REPORT_RSP,
+        MSG_REPORT_VERSION,
+        req_len,
+        certs,
+    )?;
+    if len < size_of::<SnpReportResponse>() {
+        return Err(GuestMsgError::InvalidResponse.into());
+    }
+
+    let response = unsafe {
+        messenger
+            .msg
+            .payload
+            .as_ptr()
+            .cast::<SnpReportResponse>()
+            .read_unaligned()
+    };
+    if response.status != 0 {
+        return Err(GuestMsgError::ReportStatus(response.status).into());
+    }
+    if response.report_size as usize != size_of::<AttestationReport>() {
+        return Err(GuestMsgError::InvalidResponse.into());
+    }
+
+    Ok(response.report)
+}
+
+/// Requests an attestation report from the PSP which carries `user_data`
+/// and is issued for `vmpl`, which must not be more privileged than the
+/// SVSM.
+pub fn get_attestation_report(
+    user_data: &[u8; 64],
+    vmpl: u32,
+) -> Result<AttestationReport, SvsmError> {
+    request_report(user_data, vmpl, None)
+}
+
+/// Asks the PSP for a key derived from `root_key` and the guest fields in
+/// `fields`, for `vmpl`. The key stays the same across launches as long as
+/// the selected fields do, which makes it suitable for sealing data.
+pub fn get_derived_key(
+    root_key: u32,
+    fields: u64,
+    vmpl: u32,
+) -> Result<[u8; DERIVED_KEY_SIZE], SvsmError> {
+    let mut messenger = GUEST_MESSENGER.lock();
+
+    let request = SnpKeyRequest {
+        root_key_select: root_key,
+        rsvd: 0,
+        guest_field_select: fields,
+        vmpl,
+        guest_svn: 0,
+        tcb_version: 0,
+    };
+    let req_len = size_of::<SnpKeyRequest>();
+    let req_bytes =
+        unsafe { slice::from_raw_parts((&request as *const SnpKeyRequest).cast::<u8>(), req_len) };
+    messenger.msg.payload[..req_len].copy_from_slice(req_bytes);
+
+    let len = messenger.send(MSG_KEY_REQ, MSG_KEY_RSP, MSG_KEY_VERSION, req_len, None)?;
+    if len < size_of::<SnpKeyResponse>() {
+        return Err(GuestMsgError::InvalidResponse.into());
+    }
+
+    let response = unsafe {
+        messenger
+            .msg
+            .payload
+            .as_ptr()
+            .cast::<SnpKeyResponse>()
+            .read_unaligned()
+    };
+    // The plaintext of the response is a key, don't leave it around
+    messenger.msg.payload[..len].fill(0);
+    if response.status != 0 {
+        return Err(GuestMsgError::KeyStatus(response.status).into());
+    }
+
+    Ok(response.key)
+}
+
+// Certificate data of the last extended request, None until one succeeded
+static CERTIFICATES: SpinLock<Option<Vec<u8>>> = SpinLock::new(None);
+
+/// Like [`get_attestation_report`], but also returns the certificate data
+/// the hypervisor keeps for the platform, usually the VCEK, ASK and ARK.
+/// The data is untrusted, it is only useful for verifying the report.
+pub fn get_extended_report(
+    user_data: &[u8; 64],
+    vmpl: u32,
+) -> Result<(AttestationReport, Vec<u8>), SvsmError> {
+    let mut certs = Vec::new();
+    let report = request_report(user_data, vmpl, Some(&mut certs))?;
+
+    let len = cert_data_len(&certs)?;
+    certs.truncate(len);
+    *CERTIFICATES.lock() = Some(certs.clone());
+
+    Ok((report, certs))
+}
+
+/// Copies the certificate data from `offset` on into `buf`, fetching it
+/// with an extended request on first use. Returns the number of bytes
+/// copied and the total size of the data.
+pub fn read_certificates(offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SvsmError> {
+    if CERTIFICATES.lock().is_none() {
+        get_extended_report(&[0; 64], 0)?;
+    }
+
+    let certs = CERTIFICATES.lock();
+    let data = certs.as_deref().unwrap_or_default();
+    let src = data.get(offset..).unwrap_or_default();
+    let len = src.len().min(buf.len());
+    buf[..len].copy_from_slice(&src[..len]);
+
+    Ok((len, data.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn test_guest_msg_layout() {
+        assert_eq!(size_of::<SnpGuestMsgHdr>(), MSG_HDR_SIZE);
+        assert_eq!(size_of::<SnpGuestMsg>(), PAGE_SIZE);
+        assert_eq!(offset_of!(SnpGuestMsgHdr, algo), MSG_AAD_OFFSET);
+        assert_eq!(offset_of!(SnpGuestMsgHdr, msg_vmpck), 0x3c);
+        assert_eq!(size_of::<SnpReportRequest>(), 96);
+        assert_eq!(size_of::<SnpKeyRequest>(), 32);
+        assert_eq!(size_of::<SnpKeyResponse>(), 64);
+        assert_eq!(size_of::<AttestationReport>(), 0x4a0);
+        assert_eq!(offset_of!(AttestationReport, report_data), 0x50);
+        assert_eq!(offset_of!(AttestationReport, chip_id), 0x1a0);
+        assert_eq!(offset_of!(AttestationReport, signature), 0x2a0);
+    }
+
+    #[test]
+    fn test_cert_data_len() {
+        let mut blob = [0u8; 256];
+        assert_eq!(cert_data_len(&blob), Ok(0));
+
+        // Two certificates behind a table with two entries
+        for (index, (offset, len)) in [(72u32, 100u32), (172, 50)].iter().enumerate() {
+            let entry = &mut blob[index * CERT_TABLE_ENTRY_SIZE..];
+            entry[..CERT_TABLE_GUID_SIZE].fill(index as u8 + 1);
+            entry[16..20].copy_from_slice(&offset.to_le_bytes());
+            entry[20..24].copy_from_slice(&len.to_le_bytes());
+        }
+        assert_eq!(cert_data_len(&blob), Ok(222));
+
+        // Overlaps the table
+        blob[16..20].copy_from_slice(&40u32.to_le_bytes());
+        assert_eq!(cert_data_len(&blob), Err(GuestMsgError::InvalidCertTable));
+
+        // Ends past the buffer
+        blob[16..20].copy_from_slice(&200u32.to_le_bytes());
+        assert_eq!(cert_data_len(&blob), Err(GuestMsgError::InvalidCertTable));
+
+        // No terminating entry
+        assert_eq!(
+            cert_data_len(&[1u8; 48]),
+            Err(GuestMsgError::InvalidCertTable)
+        );
+    }
+
+    #[test]
+    fn test_guest_msg_seal_open() {
+        let gcm = Aes256Gcm::new(&[0x5a; 32]);
+        let mut msg = SnpGuestMsg::new();
+        msg.payload[..4].copy_from_slice(b"ping");
+
+        // Pretend to be the firmware answering with the next seqno
+        msg.seal(&gcm, 8, MSG_REPORT_RSP, 1, 4).unwrap();
+        assert_ne!(&msg.payload[..4], b"ping");
+        let sealed = msg;
+
+        assert_eq!(msg.open(&gcm, 8, MSG_REPORT_RSP, 1).unwrap(), 4);
+        assert_eq!(&msg.payload[..4], b"ping");
+
+        let mut msg = sealed;
+        assert!(msg.open(&gcm, 6, MSG_REPORT_RSP, 1).is_err());
+
+        let mut msg = sealed;
+        msg.hdr.msg_vmpck = 1;
+        assert!(msg.open(&gcm, 8, MSG_REPORT_RSP, 1).is_err());
+
+        // The header is authenticated as well
+        let mut msg = sealed;
+        msg.hdr.rsvd3[0] = 1;
+        assert!(msg.open(&gcm, 8, MSG_REPORT_RSP, 1).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// With Restricted Injection the hypervisor can only inject #HV. Events for
+// the CPU are posted in a per-CPU #HV doorbell page shared with the
+// hypervisor, and the #HV handler delivers them.
+
+use super::shared_page::{make_page_private, make_page_shared};
+use super::status::{sev_features, SEVStatusFlags};
+use crate::cpu::apic::dispatch_irq;
+use crate::cpu::idt::X86Regs;
+use crate::cpu::irq::{irqs_disable, irqs_enable};
+use crate::cpu::percpu::this_cpu;
+use crate::debug::softlockup::handle_softlockup_nmi;
+use crate::error::SvsmError;
+use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::sev::ghcb::GHCB;
+use crate::sev::integrity::handle_machine_check;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+
+const HVDB_VECTOR_MASK: u16 = 0xff;
+const HVDB_NMI_PENDING: u16 = 1 << 8;
+const HVDB_MC_PENDING: u16 = 1 << 9;
+const HVDB_NO_FURTHER_SIGNAL: u16 = 1 << 15;
+
+const EFLAGS_IF: usize = 1 << 9;
+
+/// Layout of the #HV doorbell page as defined by the GHCB specification.
+/// The hypervisor writes to it at any time.
+#[repr(C)]
+#[derive(Debug)]
+pub struct HVDoorbell {
+    events: AtomicU16,
+    no_eoi_required: AtomicU8,
+}
+
+impl HVDoorbell {
+    /// Returns whether the hypervisor posted events not delivered yet
+    pub fn events_pending(&self) -> bool {
+        self.events.load(Ordering::Relaxed) & !HVDB_NO_FURTHER_SIGNAL != 0
+    }
+
+    /// Delivers all pending events. Must be called with interrupts
+    /// disabled.
+    pub fn process_events(&self, regs: &X86Regs) {
+        loop {
+            // Taking the events allows the hypervisor to signal again
+            let events = self.events.swap(0, Ordering::AcqRel);
+            if events & !HVDB_NO_FURTHER_SIGNAL == 0 {
+                break;
+            }
+
+            if events & HVDB_MC_PENDING != 0 {
+                handle_machine_check(regs);
+            }
+            if events & HVDB_NMI_PENDING != 0 && !handle_softlockup_nmi(regs) {
+                log::warn!("Unexpected NMI posted in #HV doorbell");
+            }
+
+            let vector = (events & HVDB_VECTOR_MASK) as u8;
+            if vector != 0 {
+                // The hypervisor may have retired the EOI already
+                let eoi = self.no_eoi_required.swap(0, Ordering::AcqRel) & 1 == 0;
+                dispatch_irq(vector, eoi);
+            }
+        }
+    }
+}
+
+// Set once the first doorbell page is registered, so that code shared with
+// stage2 can check for pending events without touching per-CPU data
+static HV_DOORBELL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the SVSM runs with Restricted Injection
+pub fn restricted_injection() -> bool {
+    sev_features().contains(SEVStatusFlags::REST_INJ)
+}
+
+/// Allocates a #HV doorbell page for the current CPU and registers it with
+/// the hypervisor through its GHCB.
+pub fn hv_doorbell_init(ghcb: &mut GHCB) -> Result<&'static HVDoorbell, SvsmError> {
+    let vaddr = allocate_zeroed_page()?;
+    if let Err(e) = make_page_shared(vaddr) {
+        free_page(vaddr);
+        return Err(e);
+    }
+
+    if let Err(e) = ghcb.register_hv_doorbell(vaddr) {
+        // Only free the page if it is ours again
+        if make_page_private(vaddr).is_ok() {
+            free_page(vaddr);
+        }
+        return Err(e);
+    }
+
+    HV_DOORBELL_ENABLED.store(true, Ordering::Relaxed);
+    Ok(unsafe { &*vaddr.as_ptr::<HVDoorbell>() })
+}
+
+/// Handler for #HV exceptions. Events arriving while interrupts are
+/// disabled stay in the doorbell page until they are enabled again.
+pub fn handle_hv_exception(regs: &X86Regs) {
+    if regs.flags & EFLAGS_IF == 0 {
+        return;
+    }
+
+    if let Some(doorbell) = this_cpu().hv_doorbell() {
+        doorbell.process_events(regs);
+    }
+}
+
+/// Delivers events which were posted while interrupts were disabled. Must
+/// be called with interrupts disabled.
+pub fn hv_doorbell_process_pending() {
+    if !HV_DOORBELL_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(doorbell) = this_cpu().hv_doorbell() {
+        if doorbell.events_pending() {
+            doorbell.process_events(&X86Regs::capture());
+        }
+    }
+}
+
+/// Like [`hv_doorbell_process_pending()`], but called right after
+/// interrupts were enabled again
+pub fn hv_doorbell_poll() {
+    if HV_DOORBELL_ENABLED.load(Ordering::Relaxed) {
+        irqs_disable();
+        hv_doorbell_process_pending();
+        irqs_enable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hv_doorbell_events() {
+        let db = HVDoorbell {
+            events: AtomicU16::new(HVDB_NO_FURTHER_SIGNAL),
+            no_eoi_required: AtomicU8::new(0),
+        };
+        assert!(!db.events_pending());
+        db.events
+            .store(HVDB_NO_FURTHER_SIGNAL | 0x30, Ordering::Relaxed);
+        assert!(db.events_pending());
+    }
+}
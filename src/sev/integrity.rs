@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::cpu::control_regs::read_cr2;
+use crate::cpu::idt::{dump_regs, X86Regs};
+use crate::cpu::percpu::this_cpu;
+use crate::mm::validate::try_validated_phys_addr;
+use crate::sev::msr_protocol::request_termination_reason_msr;
+
+/// #PF error code bit set when the fault was caused by an RMP check
+pub const PF_ERROR_RMP: usize = 1 << 31;
+
+/// Termination reason code set used for SVSM-detected integrity failures
+pub const SVSM_TERM_SET: u8 = 3;
+/// An access by the SVSM failed an RMP check
+pub const SVSM_TERM_RMP_VIOLATION: u8 = 1;
+/// The CPU reported a machine check
+pub const SVSM_TERM_MACHINE_CHECK: u8 = 2;
+/// The hypervisor changed GHCB request fields it must leave alone
+pub const SVSM_TERM_GHCB_TAMPERED: u8 = 3;
+/// The guest asked for termination
+pub const SVSM_TERM_GUEST_REQUEST: u8 = 4;
+/// The guest crashed
+pub const SVSM_TERM_GUEST_CRASH: u8 = 5;
+/// The SVSM panicked
+pub const SVSM_TERM_PANIC: u8 = 6;
+
+pub fn is_rmp_fault(error_code: usize) -> bool {
+    error_code & PF_ERROR_RMP != 0
+}
+
+// Looks up the physical page backing `vaddr` without blocking on locks the
+// interrupted code might hold
+fn fault_gpa(vaddr: VirtAddr) -> Option<PhysAddr> {
+    let mut pgtable = this_cpu().try_get_pgtable()?;
+    pgtable
+        .check_mapping(vaddr.page_align())
+        .map(|paddr| paddr.offset(vaddr.page_offset()))
+}
+
+/// Reports an RMP violation the SVSM could not recover from and terminates
+/// the guest. An RMP violation on SVSM-owned memory means the host changed
+/// the RMP state of a page behind the SVSM's back.
+pub fn handle_rmp_fault(regs: &X86Regs) -> ! {
+    let vaddr = VirtAddr::from(read_cr2());
+    let err = regs.error_code;
+
+    log::error!(
+        "RMP violation at RIP {:#018x} address {:#018x} error code: {:#018x}",
+        { regs.rip },
+        vaddr,
+        err
+    );
+
+    match fault_gpa(vaddr) {
+        Some(gpa) => {
+            let expected = match try_validated_phys_addr(gpa) {
+                Some(true) => "validated",
+                Some(false) => "not validated",
+                None => "unknown",
+            };
+            log::error!(
+                "Faulting GPA {:#018x}, expected state: {} by the SVSM",
+                gpa,
+                expected
+            );
+        }
+        None => log::error!("Faulting GPA unknown"),
+    }
+    dump_regs(regs);
+
+    request_termination_reason_msr(SVSM_TERM_SET, SVSM_TERM_RMP_VIOLATION);
+}
+
+/// Machine checks can be caused by integrity errors on encrypted memory.
+/// There is no way to recover, so report and terminate.
+pub fn handle_machine_check(regs: &X86Regs) -> ! {
+    log::error!("Machine check at RIP {:#018x}", { regs.rip });
+    dump_regs(regs);
+
+    request_termination_reason_msr(SVSM_TERM_SET, SVSM_TERM_MACHINE_CHECK);
+}
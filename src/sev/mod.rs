@@ -5,15 +5,23 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 pub mod ghcb;
+pub mod guest_msg;
+pub mod hv_doorbell;
+pub mod integrity;
 pub mod msr_protocol;
+pub mod rmpadjust;
 pub mod secrets_page;
+pub mod shared_page;
 pub mod status;
+pub mod transaction;
 pub mod vmsa;
 
 pub mod utils;
 
+pub use msr_protocol::{hv_features, sev_init, HvFeatures};
+pub use rmpadjust::{rmp_adjust, RMPFlags};
 pub use status::sev_status_init;
 pub use status::sev_status_verify;
-pub use status::{sev_es_enabled, sev_snp_enabled};
+pub use status::{sev_es_enabled, sev_features, sev_snp_enabled, SEVStatusFlags};
+pub use transaction::RmpTransaction;
 pub use utils::{pvalidate, pvalidate_range, SevSnpError};
-pub use utils::{rmp_adjust, RMPFlags};
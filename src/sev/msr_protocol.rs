@@ -5,10 +5,13 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr};
-use crate::cpu::msr::{read_msr, write_msr, SEV_GHCB};
+use crate::cpu::msr::{read_ghcb_msr, write_ghcb_msr};
 use crate::error::SvsmError;
+use crate::types::PAGE_SIZE;
 use crate::utils::halt;
+use crate::utils::immut_after_init::ImmutAfterInitCell;
 
+use super::status::sev_status_init;
 use super::utils::raw_vmgexit;
 
 #[derive(Clone, Copy, Debug)]
@@ -32,83 +35,244 @@ pub enum GHCBMsr {}
 impl GHCBMsr {
     pub const SEV_INFO_REQ: u64 = 0x02;
     pub const SEV_INFO_RESP: u64 = 0x01;
+    pub const CPUID_REQ: u64 = 0x04;
+    pub const CPUID_RESP: u64 = 0x05;
+    pub const PREFERRED_GHCB_GPA_REQ: u64 = 0x10;
+    pub const PREFERRED_GHCB_GPA_RESP: u64 = 0x11;
     pub const SNP_REG_GHCB_GPA_REQ: u64 = 0x12;
     pub const SNP_REG_GHCB_GPA_RESP: u64 = 0x13;
     pub const SNP_STATE_CHANGE_REQ: u64 = 0x14;
     pub const SNP_STATE_CHANGE_RESP: u64 = 0x15;
+    pub const HV_FEATURES_REQ: u64 = 0x80;
+    pub const HV_FEATURES_RESP: u64 = 0x81;
     pub const TERM_REQ: u64 = 0x100;
 }
 
-/// Check that we support the hypervisor's advertised GHCB versions.
-pub fn verify_ghcb_version() {
-    // Request SEV information.
-    write_msr(SEV_GHCB, GHCBMsr::SEV_INFO_REQ);
-    raw_vmgexit();
-    let sev_info = read_msr(SEV_GHCB);
+// GHCB protocol version the SVSM implements
+const GHCB_PROTOCOL_VERSION: u16 = 2;
 
-    // Parse the results.
+// Bits of the GHCB MSR holding the request or response code
+const GHCB_MSR_INFO_MASK: u64 = 0xfff;
+// Bits of the GHCB MSR holding a guest frame number
+const GHCB_MSR_GFN_MASK: u64 = 0x000f_ffff_ffff_f000;
 
-    let response_ty = sev_info & 0xfff;
-    assert_eq!(
-        response_ty,
-        GHCBMsr::SEV_INFO_RESP,
-        "unexpected response type: {response_ty:#05x}"
-    );
+// Reason codes of the general termination reason code set
+const GHCB_TERM_SET_GENERAL: u8 = 0;
+const GHCB_TERM_UNSUPPORTED_PROTOCOL: u8 = 1;
+const GHCB_TERM_SNP_UNSUPPORTED: u8 = 2;
 
-    // Compare announced supported GHCB MSR protocol version range
-    // for compatibility.
-    let min_version = (sev_info >> 32) & 0xffff;
-    let max_version = (sev_info >> 48) & 0xffff;
-    assert!(
-        (min_version..=max_version).contains(&2),
-        "the hypervisor doesn't support GHCB version 2 (min: {min_version}, max: {max_version})"
-    );
+bitflags::bitflags! {
+    /// Features the hypervisor supports, as reported by the HV features
+    /// request
+    pub struct HvFeatures: u64 {
+        const SEV_SNP                     = 1 << 0;
+        const AP_CREATION                 = 1 << 1;
+        const RESTRICTED_INJECTION        = 1 << 2;
+        const RESTRICTED_INJECTION_TIMER  = 1 << 3;
+        const APIC_ID_LIST                = 1 << 4;
+        const MULTI_VMPL                  = 1 << 5;
+    }
 }
 
-pub fn register_ghcb_gpa_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
-    let mut info = addr.bits() as u64;
+/// Register selected by a CPUID request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidReg {
+    Eax = 0,
+    Ebx = 1,
+    Ecx = 2,
+    Edx = 3,
+}
 
-    info |= GHCBMsr::SNP_REG_GHCB_GPA_REQ;
-    write_msr(SEV_GHCB, info);
-    raw_vmgexit();
-    info = read_msr(SEV_GHCB);
+/// Response to the SEV information request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SevInfo {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub c_bit: u8,
+}
 
-    if (info & 0xfff) != GHCBMsr::SNP_REG_GHCB_GPA_RESP {
-        return Err(GhcbMsrError::InfoMismatch);
+impl SevInfo {
+    pub fn supports(&self, version: u16) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
     }
+}
 
-    if (info & !0xfff) != (addr.bits() as u64) {
-        return Err(GhcbMsrError::DataMismatch);
-    }
+/// Request sent through the GHCB MSR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GhcbMsrRequest {
+    SevInfo,
+    Cpuid { function: u32, reg: CpuidReg },
+    PreferredGhcbGpa,
+    RegisterGhcbGpa(PhysAddr),
+    PageStateChange { addr: PhysAddr, valid: bool },
+    HvFeatures,
+}
 
-    Ok(())
+/// Decoded response to a [`GhcbMsrRequest`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GhcbMsrResponse {
+    SevInfo(SevInfo),
+    Cpuid(u32),
+    // None when the hypervisor has no preference
+    PreferredGhcbGpa(Option<PhysAddr>),
+    RegisterGhcbGpa(PhysAddr),
+    // Error code of the page state change, 0 on success
+    PageStateChange(u32),
+    HvFeatures(HvFeatures),
 }
 
-fn set_page_valid_status_msr(addr: PhysAddr, valid: bool) -> Result<(), GhcbMsrError> {
-    let mut info: u64 = (addr.bits() as u64) & 0x000f_ffff_ffff_f000;
+impl GhcbMsrRequest {
+    pub fn encode(&self) -> u64 {
+        match *self {
+            Self::SevInfo => GHCBMsr::SEV_INFO_REQ,
+            Self::Cpuid { function, reg } => {
+                GHCBMsr::CPUID_REQ | (reg as u64) << 30 | (function as u64) << 32
+            }
+            Self::PreferredGhcbGpa => GHCBMsr::PREFERRED_GHCB_GPA_REQ,
+            Self::RegisterGhcbGpa(addr) => {
+                GHCBMsr::SNP_REG_GHCB_GPA_REQ | (addr.bits() as u64 & GHCB_MSR_GFN_MASK)
+            }
+            Self::PageStateChange { addr, valid } => {
+                let op: u64 = if valid { 1 } else { 2 };
+                GHCBMsr::SNP_STATE_CHANGE_REQ | (addr.bits() as u64 & GHCB_MSR_GFN_MASK) | op << 52
+            }
+            Self::HvFeatures => GHCBMsr::HV_FEATURES_REQ,
+        }
+    }
+
+    fn response_code(&self) -> u64 {
+        match self {
+            Self::SevInfo => GHCBMsr::SEV_INFO_RESP,
+            Self::Cpuid { .. } => GHCBMsr::CPUID_RESP,
+            Self::PreferredGhcbGpa => GHCBMsr::PREFERRED_GHCB_GPA_RESP,
+            Self::RegisterGhcbGpa(_) => GHCBMsr::SNP_REG_GHCB_GPA_RESP,
+            Self::PageStateChange { .. } => GHCBMsr::SNP_STATE_CHANGE_RESP,
+            Self::HvFeatures => GHCBMsr::HV_FEATURES_RESP,
+        }
+    }
 
-    if valid {
-        info |= 1u64 << 52;
-    } else {
-        info |= 2u64 << 52;
+    /// Parses the hypervisor's answer to this request
+    pub fn decode(&self, val: u64) -> Result<GhcbMsrResponse, GhcbMsrError> {
+        if val & GHCB_MSR_INFO_MASK != self.response_code() {
+            return Err(GhcbMsrError::InfoMismatch);
+        }
+
+        let data = val & !GHCB_MSR_INFO_MASK;
+        match *self {
+            Self::SevInfo => Ok(GhcbMsrResponse::SevInfo(SevInfo {
+                min_version: (val >> 32) as u16,
+                max_version: (val >> 48) as u16,
+                c_bit: (val >> 24) as u8,
+            })),
+            Self::Cpuid { reg, .. } => {
+                // The response repeats the register
+                if (val >> 30) & 3 != reg as u64 {
+                    return Err(GhcbMsrError::DataMismatch);
+                }
+                Ok(GhcbMsrResponse::Cpuid((val >> 32) as u32))
+            }
+            Self::PreferredGhcbGpa => {
+                let gpa = (data != GHCB_MSR_GFN_MASK).then(|| PhysAddr::from(data));
+                Ok(GhcbMsrResponse::PreferredGhcbGpa(gpa))
+            }
+            Self::RegisterGhcbGpa(addr) => {
+                if data != (addr.bits() as u64 & GHCB_MSR_GFN_MASK) {
+                    return Err(GhcbMsrError::DataMismatch);
+                }
+                Ok(GhcbMsrResponse::RegisterGhcbGpa(addr))
+            }
+            Self::PageStateChange { .. } => {
+                // Only the error code in the upper half may be set
+                if data & 0xffff_ffff != 0 {
+                    return Err(GhcbMsrError::DataMismatch);
+                }
+                Ok(GhcbMsrResponse::PageStateChange((val >> 32) as u32))
+            }
+            Self::HvFeatures => Ok(GhcbMsrResponse::HvFeatures(HvFeatures::from_bits_truncate(
+                val >> 12,
+            ))),
+        }
     }
+}
 
-    info |= GHCBMsr::SNP_STATE_CHANGE_REQ;
-    write_msr(SEV_GHCB, info);
+/// Sends `request` through the GHCB MSR and returns the decoded response.
+/// Must not be used while a GHCB is registered and in use on this CPU.
+pub fn ghcb_msr_request(request: GhcbMsrRequest) -> Result<GhcbMsrResponse, GhcbMsrError> {
+    write_ghcb_msr(request.encode());
     raw_vmgexit();
-    let response = read_msr(SEV_GHCB);
+    request.decode(read_ghcb_msr())
+}
+
+pub fn sev_info_msr() -> Result<SevInfo, GhcbMsrError> {
+    match ghcb_msr_request(GhcbMsrRequest::SevInfo)? {
+        GhcbMsrResponse::SevInfo(info) => Ok(info),
+        _ => unreachable!(),
+    }
+}
+
+/// Reads one register of a CPUID leaf from the hypervisor. The result is
+/// not validated by the firmware, unlike the values in the CPUID page.
+pub fn cpuid_msr(function: u32, reg: CpuidReg) -> Result<u32, GhcbMsrError> {
+    match ghcb_msr_request(GhcbMsrRequest::Cpuid { function, reg })? {
+        GhcbMsrResponse::Cpuid(val) => Ok(val),
+        _ => unreachable!(),
+    }
+}
 
-    if (response & 0xfff) != GHCBMsr::SNP_STATE_CHANGE_RESP {
-        return Err(GhcbMsrError::InfoMismatch);
+/// Returns the GHCB address the hypervisor would like the guest to use, if
+/// it has a preference
+pub fn preferred_ghcb_gpa_msr() -> Result<Option<PhysAddr>, GhcbMsrError> {
+    match ghcb_msr_request(GhcbMsrRequest::PreferredGhcbGpa)? {
+        GhcbMsrResponse::PreferredGhcbGpa(gpa) => Ok(gpa),
+        _ => unreachable!(),
     }
+}
+
+pub fn hv_features_msr() -> Result<HvFeatures, GhcbMsrError> {
+    match ghcb_msr_request(GhcbMsrRequest::HvFeatures)? {
+        GhcbMsrResponse::HvFeatures(features) => Ok(features),
+        _ => unreachable!(),
+    }
+}
+
+static HV_FEATURES: ImmutAfterInitCell<HvFeatures> = ImmutAfterInitCell::uninit();
 
-    if (response & !0xfff) != 0 {
-        return Err(GhcbMsrError::DataMismatch);
+/// Features the hypervisor supports, valid after [`sev_init`]
+pub fn hv_features() -> HvFeatures {
+    *HV_FEATURES
+}
+
+/// Negotiates the GHCB protocol with the hypervisor and reads the SEV
+/// status. Terminates the guest when the hypervisor lacks what the GHCB
+/// specification requires for SEV-SNP, at a point where nothing can be
+/// logged yet.
+pub fn sev_init() {
+    match sev_info_msr() {
+        Ok(info) if info.supports(GHCB_PROTOCOL_VERSION) => {}
+        _ => request_termination_reason_msr(GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_PROTOCOL),
     }
 
+    let features = match hv_features_msr() {
+        Ok(features) if features.contains(HvFeatures::SEV_SNP) => features,
+        _ => request_termination_reason_msr(GHCB_TERM_SET_GENERAL, GHCB_TERM_SNP_UNSUPPORTED),
+    };
+    HV_FEATURES.init(&features).expect("SEV initialized twice");
+
+    sev_status_init();
+}
+
+pub fn register_ghcb_gpa_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
+    ghcb_msr_request(GhcbMsrRequest::RegisterGhcbGpa(addr))?;
     Ok(())
 }
 
+fn set_page_valid_status_msr(addr: PhysAddr, valid: bool) -> Result<(), GhcbMsrError> {
+    match ghcb_msr_request(GhcbMsrRequest::PageStateChange { addr, valid })? {
+        GhcbMsrResponse::PageStateChange(0) => Ok(()),
+        _ => Err(GhcbMsrError::DataMismatch),
+    }
+}
+
 pub fn validate_page_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
     set_page_valid_status_msr(addr, true)
 }
@@ -117,12 +281,98 @@ pub fn invalidate_page_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
     set_page_valid_status_msr(addr, false)
 }
 
+/// Changes the page state of [start, end) one 4k page at a time. Only meant
+/// as a fallback for when no GHCB is available, as every page needs its own
+/// exit to the hypervisor.
+pub fn page_state_change_range_msr(
+    start: PhysAddr,
+    end: PhysAddr,
+    valid: bool,
+) -> Result<(), GhcbMsrError> {
+    let mut paddr = start.page_align();
+
+    while paddr < end {
+        set_page_valid_status_msr(paddr, valid)?;
+        paddr = paddr.offset(PAGE_SIZE);
+    }
+
+    Ok(())
+}
+
 pub fn request_termination_msr() -> ! {
-    let info: u64 = GHCBMsr::TERM_REQ;
+    request_termination_reason_msr(0, 0)
+}
+
+/// Asks the hypervisor to terminate the guest and reports `code` from the
+/// reason code set `set` as the cause.
+pub fn request_termination_reason_msr(set: u8, code: u8) -> ! {
+    let info: u64 = GHCBMsr::TERM_REQ | ((set as u64) & 0xf) << 12 | (code as u64) << 16;
 
-    write_msr(SEV_GHCB, info);
+    write_ghcb_msr(info);
     raw_vmgexit();
     loop {
         halt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let cpuid = GhcbMsrRequest::Cpuid {
+            function: 0x8000_001f,
+            reg: CpuidReg::Ebx,
+        };
+        assert_eq!(cpuid.encode(), 0x8000_001f_4000_0004);
+
+        let psc = GhcbMsrRequest::PageStateChange {
+            addr: PhysAddr::from(0x1234_5678usize),
+            valid: false,
+        };
+        assert_eq!(psc.encode(), 0x0020_0000_1234_5014);
+    }
+
+    #[test]
+    fn test_decode() {
+        let info = GhcbMsrRequest::SevInfo
+            .decode(0x0002_0001_3300_0001)
+            .unwrap();
+        assert_eq!(
+            info,
+            GhcbMsrResponse::SevInfo(SevInfo {
+                min_version: 1,
+                max_version: 2,
+                c_bit: 0x33,
+            })
+        );
+
+        let features = GhcbMsrRequest::HvFeatures.decode(0x3081).unwrap();
+        assert_eq!(
+            features,
+            GhcbMsrResponse::HvFeatures(HvFeatures::SEV_SNP | HvFeatures::AP_CREATION)
+        );
+
+        // No preference is reported with all bits of the GFN set
+        let gpa = GhcbMsrRequest::PreferredGhcbGpa;
+        assert_eq!(
+            gpa.decode(0x000f_ffff_ffff_f011).unwrap(),
+            GhcbMsrResponse::PreferredGhcbGpa(None)
+        );
+        assert_eq!(
+            gpa.decode(0x0000_0000_0010_0011).unwrap(),
+            GhcbMsrResponse::PreferredGhcbGpa(Some(PhysAddr::from(0x10_0000usize)))
+        );
+
+        let reg = GhcbMsrRequest::RegisterGhcbGpa(PhysAddr::from(0x10_0000usize));
+        assert!(matches!(
+            reg.decode(0x0000_0000_0020_0013),
+            Err(GhcbMsrError::DataMismatch)
+        ));
+        assert!(matches!(
+            reg.decode(0x0000_0000_0010_0011),
+            Err(GhcbMsrError::InfoMismatch)
+        ));
+    }
+}
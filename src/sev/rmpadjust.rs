@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use super::utils::SevSnpError;
+use crate::address::{Address, VirtAddr};
+use crate::error::SvsmError;
+use crate::types::GUEST_VMPL;
+use core::arch::asm;
+
+bitflags::bitflags! {
+    pub struct RMPFlags: u64 {
+        const VMPL0 = 0;
+        const VMPL1 = 1;
+        const VMPL2 = 2;
+        const VMPL3 = 3;
+        const GUEST_VMPL = GUEST_VMPL as u64;
+        const READ = 1u64 << 8;
+        const WRITE = 1u64 << 9;
+        const X_USER = 1u64 << 10;
+        const X_SUPER = 1u64 << 11;
+        const BIT_VMSA = 1u64 << 16;
+        const NONE = 0;
+        const RWX = Self::READ.bits | Self::WRITE.bits | Self::X_USER.bits | Self::X_SUPER.bits;
+        const VMSA = Self::READ.bits | Self::BIT_VMSA.bits;
+    }
+}
+
+// Bits of RMPFlags selecting the target VMPL
+const RMP_VMPL_MASK: u64 = 0xff;
+
+// Return codes of RMPADJUST
+const RMPADJUST_SUCCESS: u64 = 0;
+const RMPADJUST_FAIL_INPUT: u64 = 1;
+const RMPADJUST_FAIL_PERMISSION: u64 = 2;
+const RMPADJUST_FAIL_SIZEMISMATCH: u64 = 6;
+
+fn rmpadjust_result(ret: u64) -> Result<(), SevSnpError> {
+    match ret {
+        RMPADJUST_SUCCESS => Ok(()),
+        RMPADJUST_FAIL_INPUT => Err(SevSnpError::FAIL_INPUT(ret)),
+        RMPADJUST_FAIL_PERMISSION => Err(SevSnpError::FAIL_PERMISSION(ret)),
+        RMPADJUST_FAIL_SIZEMISMATCH => Err(SevSnpError::FAIL_SIZEMISMATCH(ret)),
+        _ => {
+            log::error!("RMPADJUST: Unexpected return value: {:#x}", ret);
+            Err(SevSnpError::FAIL_INPUT(ret))
+        }
+    }
+}
+
+/// Sets the RMP permissions of the VMPL encoded in `flags` for the page at
+/// `addr`, replacing the ones it had. Only VMPLs less privileged than the
+/// SVSM can be changed.
+pub fn rmp_adjust(addr: VirtAddr, flags: RMPFlags, huge: bool) -> Result<(), SvsmError> {
+    let rcx: usize = if huge { 1 } else { 0 };
+    let rax: u64 = addr.bits() as u64;
+    let rdx: u64 = flags.bits();
+    let mut ret: u64;
+    let mut ex: u64;
+
+    unsafe {
+        asm!("1: .byte 0xf3, 0x0f, 0x01, 0xfe
+                 xorq %rcx, %rcx
+              2:
+              .pushsection \"__exception_table\",\"a\"
+              .balign 16
+              .quad (1b)
+              .quad (2b)
+              .popsection",
+                inout("rax") rax => ret,
+                inout("rcx") rcx => ex,
+                in("rdx") rdx,
+                options(att_syntax));
+    }
+
+    if ex != 0 {
+        // Report exceptions just as FAIL_INPUT
+        return Err(SevSnpError::FAIL_INPUT(1).into());
+    }
+
+    Ok(rmpadjust_result(ret)?)
+}
+
+/// Gives `vmpl` the access rights in `perms` to a page. `vmpl` must be one
+/// of the VMPL flags and `perms` may only hold access rights.
+pub fn rmp_set_permissions(
+    vaddr: VirtAddr,
+    vmpl: RMPFlags,
+    perms: RMPFlags,
+    huge: bool,
+) -> Result<(), SvsmError> {
+    assert!(vmpl.bits() & !RMP_VMPL_MASK == 0);
+    assert!(RMPFlags::RWX.contains(perms));
+
+    rmp_adjust(vaddr, vmpl | perms, huge)
+}
+
+/// Turns a 4k page into a VMSA which `vmpl` can be launched with. The page
+/// can not be written by `vmpl` while it is a VMSA.
+pub fn rmp_set_vmsa(vaddr: VirtAddr, vmpl: RMPFlags) -> Result<(), SvsmError> {
+    assert!(vmpl.bits() & !RMP_VMPL_MASK == 0);
+
+    rmp_adjust(vaddr, vmpl | RMPFlags::VMSA, false)
+}
+
+/// Turns a VMSA page of `vmpl` back into a normal page, with the access
+/// rights in `perms` for `vmpl`
+pub fn rmp_clear_vmsa(vaddr: VirtAddr, vmpl: RMPFlags, perms: RMPFlags) -> Result<(), SvsmError> {
+    rmp_set_permissions(vaddr, vmpl, perms, false)
+}
+
+pub fn rmp_revoke_guest_access(vaddr: VirtAddr, huge: bool) -> Result<(), SvsmError> {
+    for vmpl in RMPFlags::GUEST_VMPL.bits()..=RMPFlags::VMPL3.bits() {
+        let vmpl = RMPFlags::from_bits_truncate(vmpl);
+        rmp_set_permissions(vaddr, vmpl, RMPFlags::NONE, huge)?;
+    }
+    Ok(())
+}
+
+pub fn rmp_grant_guest_access(vaddr: VirtAddr, huge: bool) -> Result<(), SvsmError> {
+    rmp_set_permissions(vaddr, RMPFlags::GUEST_VMPL, RMPFlags::RWX, huge)
+}
+
+pub fn rmp_set_guest_vmsa(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    rmp_revoke_guest_access(vaddr, false)?;
+    rmp_set_vmsa(vaddr, RMPFlags::GUEST_VMPL)
+}
+
+pub fn rmp_clear_guest_vmsa(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    rmp_revoke_guest_access(vaddr, false)?;
+    rmp_grant_guest_access(vaddr, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rmpadjust_result() {
+        assert_eq!(rmpadjust_result(0), Ok(()));
+        assert_eq!(rmpadjust_result(1), Err(SevSnpError::FAIL_INPUT(1)));
+        assert_eq!(rmpadjust_result(2), Err(SevSnpError::FAIL_PERMISSION(2)));
+        assert_eq!(rmpadjust_result(6), Err(SevSnpError::FAIL_SIZEMISMATCH(6)));
+        // Unknown codes fail instead of stopping the SVSM
+        assert_eq!(rmpadjust_result(3), Err(SevSnpError::FAIL_INPUT(3)));
+    }
+}
@@ -5,8 +5,31 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::VirtAddr;
+use crate::cmdline::cmdline;
+use crate::error::SvsmError;
 use crate::sev::vmsa::VMPL_MAX;
 
+// Secrets page layout versions with the fields below
+const SECRETS_PAGE_VERSION_MIN: u32 = 2;
+const SECRETS_PAGE_VERSION_MAX: u32 = 3;
+
+/// Size of the area reserved for use by the guest OS
+pub const SECRETS_OS_AREA_SIZE: usize = 96;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SecretsPageError {
+    // Layout version is not known
+    UnsupportedVersion(u32),
+    // The key the SVSM needs to talk to the PSP is missing
+    MissingVmpck0,
+}
+
+impl From<SecretsPageError> for SvsmError {
+    fn from(e: SecretsPageError) -> Self {
+        Self::SecretsPage(e)
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct SecretsPage {
@@ -16,7 +39,7 @@ pub struct SecretsPage {
     reserved_00c: u32,
     pub gosvw: [u8; 16],
     pub vmpck: [[u8; 32]; VMPL_MAX],
-    reserved_0a0: [u8; 96],
+    os_area: [u8; SECRETS_OS_AREA_SIZE],
     pub vmsa_tweak_bmp: [u64; 8],
     pub svsm_base: u64,
     pub svsm_size: u64,
@@ -28,6 +51,75 @@ pub struct SecretsPage {
     reserved_164: [u8; 3740],
 }
 
+impl SecretsPage {
+    /// Checks that the page has a layout this code understands and carries
+    /// the keys the SVSM relies on.
+    pub fn validate(&self) -> Result<(), SvsmError> {
+        let version = self.version;
+        if !(SECRETS_PAGE_VERSION_MIN..=SECRETS_PAGE_VERSION_MAX).contains(&version) {
+            return Err(SecretsPageError::UnsupportedVersion(version).into());
+        }
+        if self.is_vmpck_clear(0) {
+            return Err(SecretsPageError::MissingVmpck0.into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the VMPCK for `vmpl`, unless it was cleared or `vmpl` is out
+    /// of range.
+    pub fn vmpck(&self, vmpl: usize) -> Option<&[u8; 32]> {
+        let key = self.vmpck.get(vmpl)?;
+        (!self.is_vmpck_clear(vmpl)).then_some(key)
+    }
+
+    /// Area the firmware leaves for the guest OS, e.g. for message sequence
+    /// numbers
+    pub fn os_area(&self) -> &[u8; SECRETS_OS_AREA_SIZE] {
+        &self.os_area
+    }
+
+    pub fn os_area_mut(&mut self) -> &mut [u8; SECRETS_OS_AREA_SIZE] {
+        &mut self.os_area
+    }
+
+    /// Returns true if the VMPCK for `vmpl` has been cleared, which is how
+    /// the firmware and the SVSM signal that a key must no longer be used.
+    pub fn is_vmpck_clear(&self, vmpl: usize) -> bool {
+        self.vmpck[vmpl].iter().all(|&b| b == 0)
+    }
+
+    /// Clears the VMPCK for `vmpl` so that it can no longer be used to
+    /// communicate with the PSP from this copy of the secrets page.
+    pub fn clear_vmpck(&mut self, vmpl: usize) {
+        self.vmpck[vmpl].fill(0);
+    }
+
+    /// Clears the VMPCKs of all VMPLs more privileged than `vmpl`.
+    pub fn restrict_to_vmpl(&mut self, vmpl: usize) {
+        for idx in 0..vmpl {
+            self.clear_vmpck(idx);
+        }
+    }
+
+    /// Clears the VMPCKs of `vmpl` and all less privileged VMPLs, which then
+    /// have to go through the SVSM to talk to the PSP.
+    pub fn retire_from_vmpl(&mut self, vmpl: usize) {
+        for idx in vmpl..VMPL_MAX {
+            self.clear_vmpck(idx);
+        }
+    }
+}
+
+/// Whether the guest copy of the secrets page goes without VMPCKs, so that
+/// the guest can only request keys and attestation reports through the
+/// SVSM. The host asks for it with "retire-vmpck" on the SVSM command line.
+/// The setting is part of the SVSM manifest, which the SVSM binds into the
+/// attestation reports it requests for the guest.
+pub fn vmpck_retired() -> bool {
+    cmdline().get_bool("retire-vmpck") == Some(true)
+}
+
 pub fn copy_secrets_page(target: &mut SecretsPage, source: VirtAddr) {
     let table = source.as_ptr::<SecretsPage>();
 
@@ -35,3 +127,59 @@ pub fn copy_secrets_page(target: &mut SecretsPage, source: VirtAddr) {
         *target = *table;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PAGE_SIZE;
+    use core::mem::size_of;
+    use core::ptr::addr_of;
+
+    fn test_page() -> SecretsPage {
+        let mut page: SecretsPage = unsafe { core::mem::zeroed() };
+        page.version = 3;
+        page.vmpck[0] = [0xaa; 32];
+        page.vmpck[1] = [0xbb; 32];
+        page
+    }
+
+    #[test]
+    fn test_secrets_page_layout() {
+        let page = test_page();
+        let base = addr_of!(page) as usize;
+
+        assert_eq!(size_of::<SecretsPage>(), PAGE_SIZE);
+        assert_eq!(addr_of!(page.vmpck) as usize - base, 0x20);
+        assert_eq!(addr_of!(page.os_area) as usize - base, 0xa0);
+        assert_eq!(addr_of!(page.svsm_base) as usize - base, 0x140);
+        assert_eq!(addr_of!(page.tsc_factor) as usize - base, 0x160);
+    }
+
+    #[test]
+    fn test_secrets_page_validate() {
+        let mut page = test_page();
+        assert!(page.validate().is_ok());
+        assert_eq!(page.vmpck(1), Some(&[0xbb; 32]));
+        assert_eq!(page.vmpck(VMPL_MAX), None);
+
+        page.restrict_to_vmpl(1);
+        assert_eq!(page.vmpck(0), None);
+        assert!(page.validate().is_err());
+
+        let mut page = test_page();
+        page.version = 1;
+        assert!(page.validate().is_err());
+    }
+
+    #[test]
+    fn test_secrets_page_retire() {
+        let mut page = test_page();
+        page.vmpck[2] = [0xcc; 32];
+
+        page.retire_from_vmpl(1);
+        assert_eq!(page.vmpck(0), Some(&[0xaa; 32]));
+        assert_eq!(page.vmpck(1), None);
+        assert_eq!(page.vmpck(2), None);
+        assert!(page.validate().is_ok());
+    }
+}
@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::address::{PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::mm::alloc::{allocate_pages, free_pages};
+use crate::mm::pagetable::get_init_pgtable_locked;
+use crate::mm::validate::{
+    valid_bitmap_clear_valid_4k, valid_bitmap_set_valid_4k, valid_bitmap_valid_addr,
+};
+use crate::mm::virt_to_phys;
+use crate::sev::sev_snp_enabled;
+use crate::types::PAGE_SIZE;
+use crate::utils::zero_mem_region;
+use core::slice;
+
+use super::RmpTransaction;
+
+/// Hands the 4k page at `vaddr` over to the hypervisor and maps it
+/// unencrypted. Nothing is changed if the conversion fails.
+pub fn make_page_shared(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    let paddr = virt_to_phys(vaddr);
+
+    // Roll back the page state changes if the conversion fails halfway
+    let mut txn = RmpTransaction::new();
+
+    if sev_snp_enabled() {
+        // Make page invalid
+        txn.pvalidate(vaddr, false, false)?;
+
+        // Let the Hypervisor take the page back
+        txn.page_state(paddr, false)?;
+    }
+
+    // Map page unencrypted
+    get_init_pgtable_locked().set_shared_4k(vaddr)?;
+
+    txn.commit();
+
+    // Needs guarding for Stage2 GHCB
+    if sev_snp_enabled() && valid_bitmap_valid_addr(paddr) {
+        valid_bitmap_clear_valid_4k(paddr);
+    }
+
+    Ok(())
+}
+
+/// Reverses [`make_page_shared`]. The contents of the page are undefined
+/// afterwards.
+pub fn make_page_private(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    let paddr = virt_to_phys(vaddr);
+
+    // Re-encrypt page
+    get_init_pgtable_locked().set_encrypted_4k(vaddr)?;
+
+    if sev_snp_enabled() {
+        let mut txn = RmpTransaction::new();
+
+        // Take the page back from the hypervisor and validate it again
+        txn.page_state(paddr, true)?;
+        txn.pvalidate(vaddr, false, true)?;
+        txn.commit();
+
+        if valid_bitmap_valid_addr(paddr) {
+            valid_bitmap_set_valid_4k(paddr);
+        }
+    }
+
+    Ok(())
+}
+
+/// Pages allocated from the SVSM heap and shared with the hypervisor for
+/// as long as they live. Everything in them must be treated as untrusted.
+#[derive(Debug)]
+pub struct SharedPages {
+    vaddr: VirtAddr,
+    order: usize,
+}
+
+impl SharedPages {
+    /// Allocates at least `npages` pages, rounded up to a power of two
+    pub fn new(npages: usize) -> Result<Self, SvsmError> {
+        let order = npages.max(1).next_power_of_two().trailing_zeros() as usize;
+        let vaddr = allocate_pages(order)?;
+        let size = PAGE_SIZE << order;
+        zero_mem_region(vaddr, vaddr + size);
+
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            if let Err(e) = make_page_shared(vaddr + offset) {
+                // Only pages which are private again may be freed
+                for done in (0..offset).step_by(PAGE_SIZE) {
+                    make_page_private(vaddr + done)?;
+                }
+                free_pages(vaddr, order);
+                return Err(e);
+            }
+        }
+
+        let pages = SharedPages { vaddr, order };
+        // The zeroes written before the conversion are gone now
+        pages.clear();
+        Ok(pages)
+    }
+
+    pub fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        virt_to_phys(self.vaddr)
+    }
+
+    pub fn npages(&self) -> usize {
+        1 << self.order
+    }
+
+    pub fn size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    pub fn clear(&self) {
+        zero_mem_region(self.vaddr, self.vaddr + self.size());
+    }
+
+    /// Copies `data` to the start of the pages
+    pub fn write(&self, data: &[u8]) {
+        self.write_at(0, data);
+    }
+
+    /// Copies `data` to the pages, starting `offset` bytes in
+    pub fn write_at(&self, offset: usize, data: &[u8]) {
+        assert!(offset <= self.size() && data.len() <= self.size() - offset);
+        let dst = unsafe {
+            slice::from_raw_parts_mut(self.vaddr.as_mut_ptr::<u8>().add(offset), data.len())
+        };
+        dst.copy_from_slice(data);
+    }
+
+    /// Copies the start of the pages to `data`. The hypervisor can change
+    /// them at any time, so callers must only look at the copy.
+    pub fn read(&self, data: &mut [u8]) {
+        self.read_at(0, data);
+    }
+
+    /// Like [`SharedPages::read()`], but starting `offset` bytes in
+    pub fn read_at(&self, offset: usize, data: &mut [u8]) {
+        assert!(offset <= self.size() && data.len() <= self.size() - offset);
+        let src =
+            unsafe { slice::from_raw_parts(self.vaddr.as_ptr::<u8>().add(offset), data.len()) };
+        data.copy_from_slice(src);
+    }
+}
+
+impl Drop for SharedPages {
+    fn drop(&mut self) {
+        for offset in (0..self.size()).step_by(PAGE_SIZE) {
+            if let Err(e) = make_page_private(self.vaddr + offset) {
+                // Leak the pages, the allocator must never hand out shared
+                // memory
+                log::error!(
+                    "Failed to make shared page {:#018x} private: {:?}",
+                    self.vaddr + offset,
+                    e
+                );
+                return;
+            }
+        }
+
+        free_pages(self.vaddr, self.order);
+    }
+}
@@ -4,7 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::cpu::msr::{read_msr, SEV_STATUS};
+use crate::cpu::msr::read_sev_status;
 use crate::utils::immut_after_init::ImmutAfterInitCell;
 use bitflags::bitflags;
 use core::fmt::{self, Write};
@@ -27,155 +27,157 @@ bitflags! {
     }
 }
 
+// Names of the features, in bit order
+const SEV_FEATURE_NAMES: &[(SEVStatusFlags, &str)] = &[
+    (SEVStatusFlags::SEV, "SEV"),
+    (SEVStatusFlags::SEV_ES, "SEV-ES"),
+    (SEVStatusFlags::SEV_SNP, "SEV-SNP"),
+    (SEVStatusFlags::VTOM, "VTOM"),
+    (SEVStatusFlags::REFLECT_VC, "REFLECT_VC"),
+    (SEVStatusFlags::REST_INJ, "RESTRICTED_INJECTION"),
+    (SEVStatusFlags::ALT_INJ, "ALTERNATE_INJECTION"),
+    (SEVStatusFlags::DBGSWP, "DEBUG_SWAP"),
+    (SEVStatusFlags::PREV_HOST_IBS, "PREVENT_HOST_IBS"),
+    (SEVStatusFlags::BTB_ISOLATION, "SNP_BTB_ISOLATION"),
+    (SEVStatusFlags::SECURE_TSC, "SECURE_TSC"),
+    (SEVStatusFlags::VMSA_REG_PROT, "VMSA_REG_PROT"),
+];
+
 impl fmt::Display for SEVStatusFlags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut first = true;
 
-        if self.contains(SEVStatusFlags::SEV) {
-            f.write_str("SEV")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::SEV_ES) {
-            if !first {
-                f.write_char(' ')?;
+        for (flag, name) in SEV_FEATURE_NAMES {
+            if !self.contains(*flag) {
+                continue;
             }
-            f.write_str("SEV-ES")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::SEV_SNP) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("SEV-SNP")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::VTOM) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("VTOM")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::REFLECT_VC) {
             if !first {
                 f.write_char(' ')?;
             }
-            f.write_str("REFLECT_VC")?;
+            f.write_str(name)?;
             first = false;
         }
 
-        if self.contains(SEVStatusFlags::REST_INJ) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("RESTRICTED_INJECTION")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::ALT_INJ) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("ALTERNATE_INJECTION")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::DBGSWP) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("DEBUG_SWAP")?;
-            first = false;
-        }
-
-        if self.contains(SEVStatusFlags::PREV_HOST_IBS) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("PREVENT_HOST_IBS")?;
-            first = false;
-        }
+        Ok(())
+    }
+}
 
-        if self.contains(SEVStatusFlags::BTB_ISOLATION) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("SNP_BTB_ISOLATION")?;
-            first = false;
-        }
+/// Features the SVSM cannot run without
+pub const SEV_FEATURES_REQUIRED: SEVStatusFlags = SEVStatusFlags::SEV
+    .union(SEVStatusFlags::SEV_ES)
+    .union(SEVStatusFlags::SEV_SNP);
+
+/// Features the SVSM cannot run with, as it does not implement the guest
+/// side of them
+pub const SEV_FEATURES_UNSUPPORTED: SEVStatusFlags = SEVStatusFlags::VTOM
+    .union(SEVStatusFlags::REFLECT_VC)
+    .union(SEVStatusFlags::ALT_INJ)
+    .union(SEVStatusFlags::DBGSWP)
+    .union(SEVStatusFlags::PREV_HOST_IBS)
+    .union(SEVStatusFlags::BTB_ISOLATION)
+    .union(SEVStatusFlags::SECURE_TSC)
+    .union(SEVStatusFlags::VMSA_REG_PROT);
+
+/// Reason the SEV features of the VM are unusable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SevFeaturesError {
+    /// Required features which are not enabled
+    Missing(SEVStatusFlags),
+    /// Enabled features the SVSM does not support
+    Unsupported(SEVStatusFlags),
+}
 
-        if self.contains(SEVStatusFlags::SECURE_TSC) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("SECURE_TSC")?;
-            first = false;
+impl fmt::Display for SevFeaturesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing(flags) => write!(f, "required SEV features not enabled: {}", flags),
+            Self::Unsupported(flags) => write!(f, "unsupported SEV features enabled: {}", flags),
         }
+    }
+}
 
-        if self.contains(SEVStatusFlags::VMSA_REG_PROT) {
-            if !first {
-                f.write_char(' ')?;
-            }
-            f.write_str("VMSA_REG_PROT")?;
-        }
+/// Checks whether the SVSM can run with the features in `status`
+pub fn check_sev_features(status: SEVStatusFlags) -> Result<(), SevFeaturesError> {
+    let missing = SEV_FEATURES_REQUIRED - status;
+    if !missing.is_empty() {
+        return Err(SevFeaturesError::Missing(missing));
+    }
 
-        Ok(())
+    let unsupported = status & SEV_FEATURES_UNSUPPORTED;
+    if !unsupported.is_empty() {
+        return Err(SevFeaturesError::Unsupported(unsupported));
     }
+
+    Ok(())
 }
 
+// Raw SEV_STATUS value, which keeps bits unknown to SEVStatusFlags
+static SEV_STATUS_RAW: ImmutAfterInitCell<u64> = ImmutAfterInitCell::uninit();
 static SEV_FLAGS: ImmutAfterInitCell<SEVStatusFlags> = ImmutAfterInitCell::uninit();
 
-fn read_sev_status() -> SEVStatusFlags {
-    SEVStatusFlags::from_bits_truncate(read_msr(SEV_STATUS))
+pub fn sev_status_init() {
+    let raw = read_sev_status();
+    SEV_STATUS_RAW
+        .init(&raw)
+        .expect("SEV status initialized twice");
+    SEV_FLAGS
+        .init(&SEVStatusFlags::from_bits_truncate(raw))
+        .expect("SEV status initialized twice");
 }
 
-fn sev_flags() -> SEVStatusFlags {
+/// SEV features enabled for this VM, as reported by the SEV_STATUS MSR.
+/// Only valid after [`sev_status_init()`].
+pub fn sev_features() -> SEVStatusFlags {
     *SEV_FLAGS
 }
 
-pub fn sev_status_init() {
-    let status: SEVStatusFlags = read_sev_status();
-    unsafe { SEV_FLAGS.init(&status) };
-}
-
 pub fn sev_es_enabled() -> bool {
-    sev_flags().contains(SEVStatusFlags::SEV_ES)
+    sev_features().contains(SEVStatusFlags::SEV_ES)
 }
 
 pub fn sev_snp_enabled() -> bool {
-    sev_flags().contains(SEVStatusFlags::SEV_SNP)
+    sev_features().contains(SEVStatusFlags::SEV_SNP)
 }
 
+/// Stops the SVSM if the enabled SEV features are not the ones it was
+/// written for. Needs a console to report the reason.
 pub fn sev_status_verify() {
-    let required = SEVStatusFlags::SEV | SEVStatusFlags::SEV_ES | SEVStatusFlags::SEV_SNP;
-    let not_supported = SEVStatusFlags::VTOM
-        | SEVStatusFlags::REFLECT_VC
-        | SEVStatusFlags::REST_INJ
-        | SEVStatusFlags::ALT_INJ
-        | SEVStatusFlags::DBGSWP
-        | SEVStatusFlags::PREV_HOST_IBS
-        | SEVStatusFlags::BTB_ISOLATION
-        | SEVStatusFlags::SECURE_TSC
-        | SEVStatusFlags::VMSA_REG_PROT;
-
-    let status = sev_flags();
-    let required_check = status & required;
-    let supported_check = status & not_supported;
-
-    if required_check != required {
-        log::error!(
-            "Required features not available: {}",
-            required & !required_check
-        );
-        panic!("Required SEV features not available");
+    let raw = *SEV_STATUS_RAW;
+    let status = sev_features();
+    log::info!("SEV features: {} ({:#x})", status, raw);
+
+    let unknown = raw & !SEVStatusFlags::all().bits();
+    if unknown != 0 {
+        log::warn!("Unknown SEV_STATUS bits set: {:#x}", unknown);
     }
 
-    if !supported_check.is_empty() {
-        log::error!("Unsupported features enabled: {}", supported_check);
-        panic!("Unsupported SEV features enabled");
+    if let Err(e) = check_sev_features(status) {
+        panic!("Unusable SEV configuration, {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_check_sev_features() {
+        assert!(check_sev_features(SEV_FEATURES_REQUIRED).is_ok());
+        assert_eq!(
+            check_sev_features(SEVStatusFlags::SEV | SEVStatusFlags::SEV_ES),
+            Err(SevFeaturesError::Missing(SEVStatusFlags::SEV_SNP))
+        );
+
+        let err = check_sev_features(
+            SEV_FEATURES_REQUIRED | SEVStatusFlags::REST_INJ | SEVStatusFlags::SECURE_TSC,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "unsupported SEV features enabled: SECURE_TSC"
+        );
     }
 }
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+use crate::address::{PhysAddr, VirtAddr};
+use crate::cpu::flush_tlb_global_sync;
+use crate::error::SvsmError;
+use crate::sev::msr_protocol::{invalidate_page_msr, validate_page_msr};
+use crate::sev::rmpadjust::{
+    rmp_clear_guest_vmsa, rmp_grant_guest_access, rmp_revoke_guest_access, rmp_set_guest_vmsa,
+};
+use crate::sev::utils::pvalidate;
+
+// Maximum number of steps a single transaction can record
+const RMP_TXN_MAX_STEPS: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+enum RmpStep {
+    // PVALIDATE of a page, undone by PVALIDATE with the inverse state
+    Pvalidate(VirtAddr, bool, bool),
+    // Page state change via the GHCB MSR protocol, undone by the inverse
+    // state change
+    PageState(PhysAddr, bool),
+    // Revocation of guest access, undone by granting access again
+    RevokeGuestAccess(VirtAddr, bool),
+    // Granting of guest access, undone by revoking access again
+    GrantGuestAccess(VirtAddr, bool),
+    // Turning a page into a guest VMSA, undone by turning it back into a
+    // normal guest page
+    SetGuestVmsa(VirtAddr),
+}
+
+impl RmpStep {
+    fn undo(&self) -> Result<(), SvsmError> {
+        match *self {
+            Self::Pvalidate(vaddr, huge, valid) => pvalidate(vaddr, huge, !valid),
+            Self::PageState(paddr, true) => Ok(invalidate_page_msr(paddr)?),
+            Self::PageState(paddr, false) => Ok(validate_page_msr(paddr)?),
+            Self::RevokeGuestAccess(vaddr, huge) => rmp_grant_guest_access(vaddr, huge),
+            Self::GrantGuestAccess(vaddr, huge) => rmp_revoke_guest_access(vaddr, huge),
+            Self::SetGuestVmsa(vaddr) => rmp_clear_guest_vmsa(vaddr),
+        }
+    }
+}
+
+/// Records the steps of a multi-step RMP state change so that a failure
+/// halfway through does not leave pages in an inconsistent state.
+///
+/// Every successfully completed step is recorded. If the transaction is
+/// dropped without having been committed, the recorded steps are undone in
+/// reverse order.
+///
+/// ```ignore
+/// let mut txn = RmpTransaction::new();
+/// txn.revoke_guest_access(vaddr, false)?;
+/// txn.pvalidate(vaddr, false, false)?;
+/// txn.commit();
+/// ```
+#[derive(Debug)]
+pub struct RmpTransaction {
+    steps: [Option<RmpStep>; RMP_TXN_MAX_STEPS],
+    count: usize,
+    committed: bool,
+}
+
+impl RmpTransaction {
+    pub const fn new() -> Self {
+        RmpTransaction {
+            steps: [None; RMP_TXN_MAX_STEPS],
+            count: 0,
+            committed: false,
+        }
+    }
+
+    fn record(&mut self, step: RmpStep) {
+        assert!(self.count < RMP_TXN_MAX_STEPS, "RMP transaction too long");
+        self.steps[self.count] = Some(step);
+        self.count += 1;
+    }
+
+    fn run(
+        &mut self,
+        step: RmpStep,
+        op: impl FnOnce() -> Result<(), SvsmError>,
+    ) -> Result<(), SvsmError> {
+        op()?;
+        self.record(step);
+        Ok(())
+    }
+
+    pub fn pvalidate(&mut self, vaddr: VirtAddr, huge: bool, valid: bool) -> Result<(), SvsmError> {
+        self.run(RmpStep::Pvalidate(vaddr, huge, valid), || {
+            pvalidate(vaddr, huge, valid)
+        })
+    }
+
+    pub fn page_state(&mut self, paddr: PhysAddr, valid: bool) -> Result<(), SvsmError> {
+        self.run(RmpStep::PageState(paddr, valid), || {
+            if valid {
+                Ok(validate_page_msr(paddr)?)
+            } else {
+                Ok(invalidate_page_msr(paddr)?)
+            }
+        })
+    }
+
+    pub fn revoke_guest_access(&mut self, vaddr: VirtAddr, huge: bool) -> Result<(), SvsmError> {
+        self.run(RmpStep::RevokeGuestAccess(vaddr, huge), || {
+            rmp_revoke_guest_access(vaddr, huge)
+        })
+    }
+
+    pub fn grant_guest_access(&mut self, vaddr: VirtAddr, huge: bool) -> Result<(), SvsmError> {
+        self.run(RmpStep::GrantGuestAccess(vaddr, huge), || {
+            rmp_grant_guest_access(vaddr, huge)
+        })
+    }
+
+    pub fn set_guest_vmsa(&mut self, vaddr: VirtAddr) -> Result<(), SvsmError> {
+        self.run(RmpStep::SetGuestVmsa(vaddr), || rmp_set_guest_vmsa(vaddr))
+    }
+
+    /// Makes all recorded steps permanent.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+
+    /// Undoes all recorded steps in reverse order and flushes the TLBs of
+    /// all CPUs afterwards. Failures to undo a step are logged, but do not
+    /// stop the remaining steps from being undone.
+    pub fn rollback(mut self) {
+        self.undo_all();
+    }
+
+    fn undo_all(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+
+        while self.count > 0 {
+            self.count -= 1;
+            if let Some(step) = self.steps[self.count].take() {
+                if let Err(e) = step.undo() {
+                    log::error!("Failed to roll back RMP step {:?}: {:?}", step, e);
+                }
+            }
+        }
+
+        // Like after the forward steps, the changed permissions and page
+        // states only take effect once no CPU has stale TLB entries
+        flush_tlb_global_sync();
+    }
+}
+
+impl Default for RmpTransaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for RmpTransaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.undo_all();
+        }
+    }
+}
@@ -4,7 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::utils::{rmp_adjust, RMPFlags};
+use super::rmpadjust::{rmp_clear_vmsa, rmp_set_vmsa, RMPFlags};
 use crate::address::{Address, VirtAddr};
 use crate::error::SvsmError;
 use crate::mm::alloc::{allocate_pages, free_page};
@@ -201,7 +201,7 @@ pub fn allocate_new_vmsa(vmpl: RMPFlags) -> Result<VirtAddr, SvsmError> {
 
     zero_mem_region(vmsa_page, vmsa_page.offset(PAGE_SIZE));
 
-    if let Err(e) = rmp_adjust(vmsa_page, RMPFlags::VMSA | vmpl, false) {
+    if let Err(e) = rmp_set_vmsa(vmsa_page, vmpl) {
         free_page(vmsa_page);
         return Err(e);
     }
@@ -209,6 +209,6 @@ pub fn allocate_new_vmsa(vmpl: RMPFlags) -> Result<VirtAddr, SvsmError> {
 }
 
 pub fn free_vmsa(vaddr: VirtAddr) {
-    rmp_adjust(vaddr, RMPFlags::RWX | RMPFlags::VMPL0, false).expect("Failed to free VMSA page");
+    rmp_clear_vmsa(vaddr, RMPFlags::VMPL0, RMPFlags::RWX).expect("Failed to free VMSA page");
     free_page(vaddr);
 }
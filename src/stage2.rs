@@ -10,106 +10,302 @@
 
 pub mod boot_stage2;
 
-use core::arch::asm;
+use core::arch::global_asm;
+use core::cmp::{max, min};
+use core::fmt::Debug;
 use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
 use core::slice;
 use log;
 use svsm::address::{Address, PhysAddr, VirtAddr};
-use svsm::console::{init_console, install_console_logger, WRITER};
-use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table, SnpCpuidTable};
+use svsm::cmdline::CMDLINE_MAX_LEN;
+#[cfg(not(feature = "stage2-silent"))]
+use svsm::console::{console_retarget, init_console, install_console_logger};
+use svsm::cpu::control_regs::cr0_init;
+#[cfg(not(feature = "stage2-silent"))]
+use svsm::cpu::cpuid::dump_cpuid_table;
+use svsm::cpu::cpuid::{register_cpuid_table, SnpCpuidTable};
+use svsm::cpu::efer::efer_init;
+use svsm::cpu::gdt::load_gdt;
+use svsm::cpu::idt::early_idt_init;
+#[cfg(not(feature = "stage2-silent"))]
+use svsm::cpu::idt::{dump_control_regs, dump_regs, X86Regs};
 use svsm::cpu::percpu::{this_cpu_mut, PerCpu};
+use svsm::crypto::ed25519::{ed25519_verify, ED25519_PUBLIC_KEY_SIZE, ED25519_SIGNATURE_SIZE};
+use svsm::crypto::sha384::{sha384, SHA384_DIGEST_SIZE};
 use svsm::elf;
+use svsm::error::Context;
 use svsm::fw_cfg::FwCfg;
-use svsm::kernel_launch::KernelLaunchInfo;
+use svsm::kernel_launch::{KernelLaunchInfo, KernelNotes};
 use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
-use svsm::mm::init_kernel_mapping_info;
+use svsm::mm::bootmem::BootMemoryMap;
 use svsm::mm::pagetable::{
-    get_init_pgtable_locked, paging_init_early, set_init_pgtable, PTEntryFlags, PageTable,
-    PageTableRef,
+    get_init_pgtable_locked, paging_init, paging_init_early, set_init_pgtable, PTEntryFlags,
+    PagePerms, PageTable, PageTableRef,
 };
 use svsm::mm::validate::{
     init_valid_bitmap_alloc, valid_bitmap_addr, valid_bitmap_set_valid_range,
 };
+use svsm::mm::{init_kernel_mapping_info, virt_to_phys};
+use svsm::serial::{serial_config, SerialConfig};
+#[cfg(not(feature = "stage2-silent"))]
 use svsm::serial::{SerialPort, SERIAL_PORT};
 use svsm::sev::ghcb::PageStateChangeOp;
-use svsm::sev::msr_protocol::verify_ghcb_version;
-use svsm::sev::{pvalidate_range, sev_status_init, sev_status_verify};
+use svsm::sev::msr_protocol::page_state_change_range_msr;
+#[cfg(any(feature = "stage2-silent", feature = "panic-terminate"))]
+use svsm::sev::msr_protocol::request_termination_reason_msr;
+use svsm::sev::{pvalidate_range, sev_init, sev_status_verify};
 use svsm::svsm_console::SVSMIOPort;
-use svsm::types::PAGE_SIZE;
+use svsm::types::{MemoryRegion, PAGE_SIZE};
+#[cfg(not(any(feature = "stage2-silent", feature = "panic-terminate")))]
 use svsm::utils::halt;
 
+// Termination reason code set used by stage2. With the stage2-silent feature
+// these codes are the only way stage2 reports why it failed.
+#[cfg(any(feature = "stage2-silent", feature = "panic-terminate"))]
+const STAGE2_TERM_SET: u8 = 4;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+enum Stage2Failure {
+    #[cfg_attr(
+        not(any(feature = "stage2-silent", feature = "panic-terminate")),
+        allow(dead_code)
+    )]
+    Panic = 1,
+    Setup = 2,
+    KernelRegion = 3,
+    KernelElf = 4,
+    KernelNotes = 5,
+    Relocation = 6,
+    Mapping = 7,
+    Payload = 8,
+    Signature = 9,
+}
+
+#[cfg(feature = "stage2-silent")]
+#[inline(always)]
+fn fail(reason: Stage2Failure, _msg: &str) -> ! {
+    request_termination_reason_msr(STAGE2_TERM_SET, reason as u8)
+}
+
+#[cfg(not(feature = "stage2-silent"))]
+fn fail(_reason: Stage2Failure, msg: &str) -> ! {
+    panic!("{}", msg);
+}
+
+trait OrFail<T> {
+    /// Unwraps the result or fails stage2 with `reason`. The error is only
+    /// formatted when stage2 has a console.
+    fn or_fail(self, reason: Stage2Failure, msg: &str) -> T;
+}
+
+impl<T, E: Debug> OrFail<T> for Result<T, E> {
+    #[cfg(feature = "stage2-silent")]
+    #[inline(always)]
+    fn or_fail(self, reason: Stage2Failure, _msg: &str) -> T {
+        match self {
+            Ok(v) => v,
+            Err(_) => fail(reason, ""),
+        }
+    }
+
+    #[cfg(not(feature = "stage2-silent"))]
+    fn or_fail(self, _reason: Stage2Failure, msg: &str) -> T {
+        match self {
+            Ok(v) => v,
+            Err(e) => panic!("{}: {:?}", msg, e),
+        }
+    }
+}
+
 extern "C" {
+    pub static startup_32: u8;
     pub static heap_start: u8;
     pub static heap_end: u8;
-    pub static mut pgtable: PageTable;
-    pub static CPUID_PAGE: SnpCpuidTable;
+    static mut pgtable: PageTable;
 }
 
-fn setup_stage2_allocator() {
+// Returns a reference to the page table stage1 set up, the only place
+// which touches the `static mut` itself.
+fn stage2_pgtable() -> PageTableRef {
+    PageTableRef::new(unsafe { &mut *addr_of_mut!(pgtable) })
+}
+
+// Pages the firmware sets up at fixed addresses, as described by the SVSM
+// metadata in utils/gen_meta.c. Unlike stage2 itself they never move.
+const SECRETS_PAGE: usize = 632 * 1024;
+const CPUID_PAGE: usize = 636 * 1024;
+// Low memory validated by the firmware according to the metadata
+const STAGE2_LOW_MEM_END: usize = 640 * 1024;
+
+// Returns the heap of stage2. It is linked to end right below the fixed
+// pages, but moves along when stage2 is loaded at another address.
+fn stage2_heap() -> (VirtAddr, VirtAddr) {
     let vstart = unsafe { VirtAddr::from(&heap_start as *const u8).page_align_up() };
-    let vend = unsafe { VirtAddr::from(&heap_end as *const u8).page_align() };
+    let mut vend = unsafe { VirtAddr::from(&heap_end as *const u8).page_align() };
+
+    let fixed_start = VirtAddr::from(SECRETS_PAGE);
+    let fixed_end = VirtAddr::from(CPUID_PAGE + PAGE_SIZE);
+    if vstart < fixed_end && vend > fixed_start {
+        vend = min(vend, fixed_start);
+    }
+    if vend <= vstart {
+        fail(Stage2Failure::Setup, "No room for the stage2 heap");
+    }
+
+    (vstart, vend)
+}
+
+fn setup_stage2_allocator() {
+    let (vstart, vend) = stage2_heap();
     let pstart = PhysAddr::from(vstart.bits()); // Identity mapping
     let nr_pages = (vend - vstart) / PAGE_SIZE;
 
     root_mem_init(pstart, vstart, nr_pages);
 }
 
-pub static mut PERCPU: PerCpu = PerCpu::new();
-
 fn init_percpu() {
-    unsafe {
-        let bsp_percpu = PerCpu::alloc(0)
-            .expect("Failed to allocate BSP per-cpu data")
+    let bsp_percpu = unsafe {
+        PerCpu::alloc(0)
+            .or_fail(Stage2Failure::Setup, "Failed to allocate BSP per-cpu data")
             .as_mut()
-            .unwrap();
+            .unwrap()
+    };
 
-        bsp_percpu.set_pgtable(PageTableRef::new(&mut pgtable));
-        bsp_percpu.map_self().expect("Failed to map per-cpu area");
-        bsp_percpu.setup_ghcb().expect("Failed to setup BSP GHCB");
-        bsp_percpu.register_ghcb().expect("Failed to register GHCB");
-    }
+    bsp_percpu.set_pgtable(stage2_pgtable());
+    bsp_percpu
+        .map_self()
+        .or_fail(Stage2Failure::Setup, "Failed to map per-cpu area");
+    bsp_percpu
+        .setup_ghcb()
+        .or_fail(Stage2Failure::Setup, "Failed to setup BSP GHCB");
+    bsp_percpu
+        .register_ghcb()
+        .or_fail(Stage2Failure::Setup, "Failed to register GHCB");
 }
 
 fn shutdown_percpu() {
-    unsafe {
-        PERCPU
-            .shutdown()
-            .expect("Failed to shut down percpu data (including GHCB)");
-    }
+    this_cpu_mut().shutdown().or_fail(
+        Stage2Failure::Setup,
+        "Failed to shut down percpu data (including GHCB)",
+    );
 }
 
 static CONSOLE_IO: SVSMIOPort = SVSMIOPort::new();
-static mut CONSOLE_SERIAL: SerialPort = SerialPort {
-    driver: &CONSOLE_IO,
-    port: SERIAL_PORT,
-};
+#[cfg(not(feature = "stage2-silent"))]
+static CONSOLE_SERIAL: SerialPort = SerialPort::new(&CONSOLE_IO, SERIAL_PORT);
 
-fn setup_env() {
+// Returns the console UART the host asked for, which the kernel uses too
+fn setup_env() -> SerialConfig {
+    #[cfg(not(feature = "stage2-silent"))]
     install_console_logger("Stage2");
+    load_gdt();
+    early_idt_init();
+    let (_, heap_end_addr) = stage2_heap();
     init_kernel_mapping_info(
         VirtAddr::null(),
-        VirtAddr::from(640 * 1024 as usize),
+        max(VirtAddr::from(STAGE2_LOW_MEM_END), heap_end_addr),
         PhysAddr::null(),
     );
-    register_cpuid_table(unsafe { &CPUID_PAGE });
+    register_cpuid_table(unsafe { &*(CPUID_PAGE as *const SnpCpuidTable) })
+        .or_fail(Stage2Failure::Setup, "Invalid CPUID page");
     paging_init_early();
 
     // Bring up the GCHB for use from the SVSMIOPort console.
-    verify_ghcb_version();
-    sev_status_init();
-    set_init_pgtable(PageTableRef::new(unsafe { &mut pgtable }));
+    sev_init();
+    set_init_pgtable(stage2_pgtable());
     setup_stage2_allocator();
     init_percpu();
 
-    unsafe {
-        WRITER.lock().set(&mut CONSOLE_SERIAL);
-    }
-    init_console();
+    // Fall back to the default port, the console is needed to report
+    // anything
+    let config = serial_config(&FwCfg::new(&CONSOLE_IO));
+    let serial = config.unwrap_or_default();
 
-    // Console is fully working now and any unsupported configuration can be
-    // properly reported.
-    dump_cpuid_table();
+    #[cfg(not(feature = "stage2-silent"))]
+    {
+        CONSOLE_SERIAL.configure(&serial);
+        console_retarget(&CONSOLE_SERIAL, false);
+        init_console();
+        if let Err(e) = config {
+            log::warn!("Invalid serial configuration from host: {:?}", e);
+        }
+
+        // Console is fully working now and any unsupported configuration can
+        // be properly reported.
+        dump_cpuid_table();
+    }
     sev_status_verify();
+
+    serial
+}
+
+// Maps the kernel's text RX and all other segments NX, read-only unless they
+// are writable. Write protection and NX are enabled first, so that the
+// kernel runs with these permissions right from its entry point.
+fn protect_kernel_segments(kernel_elf: &elf::Elf64File, vaddr_base: u64) {
+    cr0_init();
+    efer_init();
+    paging_init();
+
+    let mut pgtbl = get_init_pgtable_locked();
+    for segment in kernel_elf.image_load_segment_iter(vaddr_base) {
+        let vaddr_start = VirtAddr::from(segment.vaddr_range.vaddr_begin);
+        let len = segment.vaddr_range.len() as usize;
+        let perms = if segment.flags.contains(elf::Elf64PhdrFlags::EXECUTE) {
+            PagePerms::ReadExec
+        } else if segment.flags.contains(elf::Elf64PhdrFlags::WRITE) {
+            PagePerms::ReadWrite
+        } else {
+            PagePerms::ReadOnly
+        };
+
+        pgtbl.set_region_perms(vaddr_start, len, perms).or_fail(
+            Stage2Failure::Mapping,
+            "Failed to set kernel segment permissions",
+        );
+    }
+}
+
+// Takes `len` bytes from the kernel region, right after the memory used so
+// far, which `next` points to. Fails instead of running past the end of the
+// region into memory which may belong to stage2 or the firmware.
+fn take_kernel_pages(next: &mut PhysAddr, region_end: PhysAddr, len: usize) -> PhysAddr {
+    let start = *next;
+    if region_end - start < len {
+        fail(
+            Stage2Failure::KernelRegion,
+            "Kernel image does not fit into kernel region",
+        );
+    }
+    *next = start.offset(len);
+    start
+}
+
+// Stack the kernel is entered on, placed in the kernel region right after
+// the launch info page. The kernel switches to its own BSP stack at once.
+const LAUNCH_STACK_SIZE: usize = 2 * PAGE_SIZE;
+const HANDOVER_SIZE: usize = PAGE_SIZE + LAUNCH_STACK_SIZE;
+
+// Jumps to the kernel entry point with the launch info in %r8 and the valid
+// bitmap in %r9, on a stack which is not part of stage2 memory.
+global_asm!(
+    r#"
+        .text
+        .globl  launch_kernel
+    launch_kernel:
+        movq    %rsi, %rsp
+        movq    %rdx, %r8
+        movq    %rcx, %r9
+        xorl    %ebp, %ebp
+        jmp     *%rdi
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn launch_kernel(entry: u64, stack: u64, launch_info: u64, valid_bitmap: u64) -> !;
 }
 
 fn map_and_validate(vaddr: VirtAddr, paddr: PhysAddr, len: usize) {
@@ -121,21 +317,81 @@ fn map_and_validate(vaddr: VirtAddr, paddr: PhysAddr, len: usize) {
     let mut pgtbl = get_init_pgtable_locked();
     pgtbl
         .map_region(vaddr, vaddr.offset(len), paddr, flags)
-        .expect("Error mapping kernel region");
+        .or_fail(Stage2Failure::Mapping, "Error mapping kernel region");
 
-    this_cpu_mut()
-        .ghcb()
-        .page_state_change(
+    // Batch the page state changes through the GHCB if there is one, going
+    // page by page through the MSR protocol is considerably slower.
+    if this_cpu_mut().has_ghcb() {
+        let result = this_cpu_mut().ghcb().page_state_change(
             paddr,
             paddr.offset(len),
             true,
             PageStateChangeOp::PscPrivate,
-        )
-        .expect("GHCB::PAGE_STATE_CHANGE call failed for kernel region");
-    pvalidate_range(vaddr, vaddr.offset(len), true).expect("PVALIDATE kernel region failed");
+        );
+        result.or_fail(
+            Stage2Failure::Mapping,
+            "GHCB::PAGE_STATE_CHANGE call failed for kernel region",
+        );
+    } else {
+        page_state_change_range_msr(paddr, paddr.offset(len), true).or_fail(
+            Stage2Failure::Mapping,
+            "MSR page state change failed for kernel region",
+        );
+    }
+    pvalidate_range(vaddr, vaddr.offset(len), true)
+        .or_fail(Stage2Failure::Mapping, "PVALIDATE kernel region failed");
     valid_bitmap_set_valid_range(paddr, paddr.offset(len));
 }
 
+const fn hex_digit(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => panic!("invalid hex digit in kernel signing key"),
+    }
+}
+
+const fn parse_signing_key(hex: &str) -> [u8; ED25519_PUBLIC_KEY_SIZE] {
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 2 * ED25519_PUBLIC_KEY_SIZE);
+
+    let mut key = [0u8; ED25519_PUBLIC_KEY_SIZE];
+    let mut i = 0;
+    while i < ED25519_PUBLIC_KEY_SIZE {
+        key[i] = hex_digit(hex[2 * i]) << 4 | hex_digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+// Ed25519 public key the kernel ELF has to be signed with, passed as hex
+// string at build time. Without it stage2 launches any kernel.
+const KERNEL_SIGNING_KEY: Option<[u8; ED25519_PUBLIC_KEY_SIZE]> =
+    match option_env!("SVSM_KERNEL_SIGNING_KEY") {
+        Some(hex) => Some(parse_signing_key(hex)),
+        None => None,
+    };
+
+/// Checks the signature stage1 passed along with the kernel. What is
+/// signed is the SHA-384 digest of the kernel ELF, which is also what ends
+/// up in the measurement log.
+fn verify_kernel_signature(digest: &[u8; SHA384_DIGEST_SIZE], sig: &[u8]) {
+    let Some(key) = KERNEL_SIGNING_KEY else {
+        log::warn!("No kernel signing key built in, kernel signature not checked");
+        return;
+    };
+
+    let sig: &[u8; ED25519_SIGNATURE_SIZE] = sig
+        .try_into()
+        .or_fail(Stage2Failure::Signature, "Kernel signature missing");
+    ed25519_verify(&key, digest, sig).or_fail(
+        Stage2Failure::Signature,
+        "Kernel signature verification failed",
+    );
+    log::info!("Kernel signature verified");
+}
+
 // Launch info from stage1, usually at the bottom of the stack
 // The layout has to match the order in which the parts are pushed to the stack
 // in stage1/stage1.S
@@ -145,39 +401,125 @@ pub struct Stage1LaunchInfo {
     kernel_elf_end: u32,
     kernel_fs_start: u32,
     kernel_fs_end: u32,
+    kernel_sig_start: u32,
+    kernel_sig_end: u32,
+}
+
+// Returns the memory map of the loader with all ranges stage2 knows to be in
+// use. The GHCB and all other stage2 allocations come from its heap.
+fn boot_memory_map(fw_cfg: &FwCfg, launch_info: &Stage1LaunchInfo) -> BootMemoryMap {
+    let ram = fw_cfg
+        .get_memory_regions()
+        .or_fail(Stage2Failure::Setup, "Failed to read E820 map");
+    let mut memmap = BootMemoryMap::new(ram);
+
+    let stage2_start = unsafe { &startup_32 as *const u8 as u64 };
+    let (heap_start_addr, heap_end_addr) = stage2_heap();
+    let used = [
+        // stage1 leaves the stack and the launch info right below stage2
+        (0, stage2_start, "stage2 stack"),
+        (stage2_start, u64::from(heap_start_addr), "stage2 image"),
+        (
+            u64::from(heap_start_addr),
+            u64::from(heap_end_addr),
+            "stage2 heap",
+        ),
+        (
+            SECRETS_PAGE as u64,
+            (SECRETS_PAGE + PAGE_SIZE) as u64,
+            "secrets page",
+        ),
+        (
+            CPUID_PAGE as u64,
+            (CPUID_PAGE + PAGE_SIZE) as u64,
+            "CPUID page",
+        ),
+        (
+            launch_info.kernel_elf_start.into(),
+            launch_info.kernel_elf_end.into(),
+            "kernel ELF",
+        ),
+        (
+            launch_info.kernel_fs_start.into(),
+            launch_info.kernel_fs_end.into(),
+            "kernel file system",
+        ),
+        (
+            launch_info.kernel_sig_start.into(),
+            launch_info.kernel_sig_end.into(),
+            "kernel signature",
+        ),
+    ];
+    for (start, end, name) in used {
+        memmap
+            .reserve(MemoryRegion::new(start, end), name)
+            .or_fail(Stage2Failure::Setup, "Stage2 memory ranges overlap");
+    }
+
+    memmap
 }
 
 #[no_mangle]
 pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
-    setup_env();
+    let serial = setup_env();
 
     let kernel_elf_start: PhysAddr = PhysAddr::from(launch_info.kernel_elf_start as u64);
     let kernel_elf_end: PhysAddr = PhysAddr::from(launch_info.kernel_elf_end as u64);
 
     let fw_cfg = FwCfg::new(&CONSOLE_IO);
+    let mut memmap = boot_memory_map(&fw_cfg, launch_info);
     let r = fw_cfg
-        .find_kernel_region()
-        .expect("Failed to find memory region for SVSM kernel");
+        .find_kernel_region(&mut memmap)
+        .context("finding kernel region")
+        .or_fail(
+            Stage2Failure::KernelRegion,
+            "Failed to find memory region for SVSM kernel",
+        );
+    for (region, name) in memmap.iter_used() {
+        log::debug!("  {:#018x}-{:#018x} {}", region.start, region.end, name);
+    }
 
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM) Stage 2 Loader");
 
-    let kernel_region_phys_start = PhysAddr::from(r.start);
-    let kernel_region_phys_end = PhysAddr::from(r.end);
+    let kernel_region_phys_start = r.start_phys();
+    let kernel_region_phys_end = r.end_phys();
     init_valid_bitmap_alloc(kernel_region_phys_start, kernel_region_phys_end)
-        .expect("Failed to allocate valid-bitmap");
+        .or_fail(Stage2Failure::Setup, "Failed to allocate valid-bitmap");
 
     // Read the SVSM kernel's ELF file metadata.
     let kernel_elf_len = kernel_elf_end - kernel_elf_start;
     let kernel_elf_buf =
         unsafe { slice::from_raw_parts(kernel_elf_start.bits() as *const u8, kernel_elf_len) };
-    let kernel_elf = match elf::Elf64File::read(kernel_elf_buf) {
-        Ok(kernel_elf) => kernel_elf,
-        Err(e) => panic!("error reading kernel ELF: {}", e),
-    };
+    // Measure the kernel before anything of it is interpreted
+    let kernel_elf_digest = sha384(kernel_elf_buf);
+    log::info!(
+        "Kernel ELF measurement (SHA-384): {:02x?}",
+        kernel_elf_digest
+    );
+
+    let kernel_sig_start = launch_info.kernel_sig_start as usize;
+    let kernel_sig_len = launch_info.kernel_sig_end as usize - kernel_sig_start;
+    let kernel_sig =
+        unsafe { slice::from_raw_parts(kernel_sig_start as *const u8, kernel_sig_len) };
+    verify_kernel_signature(&kernel_elf_digest, kernel_sig);
+
+    let kernel_elf = elf::Elf64File::read(kernel_elf_buf)
+        .or_fail(Stage2Failure::KernelElf, "error reading kernel ELF");
 
     let kernel_vaddr_alloc_info = kernel_elf.image_load_vaddr_alloc_info();
     let kernel_vaddr_alloc_base = kernel_vaddr_alloc_info.range.vaddr_begin;
 
+    // Refuse to launch a kernel which expects more than this stage2 provides.
+    match KernelNotes::read(&kernel_elf)
+        .or_fail(Stage2Failure::KernelNotes, "error reading kernel ELF notes")
+    {
+        Some(notes) => notes.check(kernel_vaddr_alloc_base).or_fail(
+            Stage2Failure::KernelNotes,
+            "kernel ELF incompatible with stage2",
+        ),
+        None => log::warn!("kernel ELF has no loader notes, skipping compatibility check"),
+    }
+
     // Map, validate and populate the SVSM kernel ELF's PT_LOAD segments. The
     // segments' virtual address range might not necessarily be contiguous,
     // track their total extent along the way. Physical memory is successively
@@ -194,7 +536,10 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         // with bounds specified as in the ELF file, are non-overlapping.
         let vaddr_start = VirtAddr::from(segment.vaddr_range.vaddr_begin);
         if !vaddr_start.is_page_aligned() {
-            panic!("kernel ELF segment not aligned to page boundary");
+            fail(
+                Stage2Failure::KernelElf,
+                "kernel ELF segment not aligned to page boundary",
+            );
         }
 
         // Remember the mapping range's lower bound to pass it on the kernel
@@ -209,8 +554,11 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         loaded_kernel_virt_end = aligned_vaddr_end;
 
         let segment_len = aligned_vaddr_end - vaddr_start;
-        let paddr_start = loaded_kernel_phys_end;
-        loaded_kernel_phys_end = loaded_kernel_phys_end.offset(segment_len);
+        let paddr_start = take_kernel_pages(
+            &mut loaded_kernel_phys_end,
+            kernel_region_phys_end,
+            segment_len,
+        );
 
         map_and_validate(vaddr_start, paddr_start, segment_len);
 
@@ -219,33 +567,27 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         let segment_contents = segment.file_contents;
         let contents_len = segment_contents.len();
         segment_buf[..contents_len].copy_from_slice(segment_contents);
+        // Zeroes the BSS
         segment_buf[contents_len..].fill(0);
     }
 
     let loaded_kernel_virt_start = match loaded_kernel_virt_start {
         Some(loaded_kernel_virt_start) => loaded_kernel_virt_start,
-        None => {
-            panic!("no loadable segment found in kernel ELF");
-        }
+        None => fail(
+            Stage2Failure::KernelElf,
+            "no loadable segment found in kernel ELF",
+        ),
     };
 
     // Apply relocations, if any.
-    let dyn_relocs = match kernel_elf
+    let dyn_relocs = kernel_elf
         .apply_dyn_relas(elf::Elf64X86RelocProcessor::new(), kernel_vaddr_alloc_base)
-    {
-        Ok(dyn_relocs) => dyn_relocs,
-        Err(e) => {
-            panic!("failed to read ELF relocations : {}", e);
-        }
-    };
+        .or_fail(Stage2Failure::Relocation, "failed to read ELF relocations");
     if let Some(dyn_relocs) = dyn_relocs {
         for reloc in dyn_relocs {
-            let reloc = match reloc {
-                Ok(Some(reloc)) => reloc,
-                Ok(None) => continue,
-                Err(e) => {
-                    panic!("ELF relocation error: {}", e);
-                }
+            let Some(reloc) = reloc.or_fail(Stage2Failure::Relocation, "ELF relocation error")
+            else {
+                continue;
             };
             let dst = unsafe { slice::from_raw_parts_mut(reloc.dst as *mut u8, reloc.value_len) };
             let src = &reloc.value[..reloc.value_len];
@@ -253,6 +595,79 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         }
     }
 
+    // Put the kernel command line, if the host passed one, into a page of
+    // its own after the kernel image. The kernel copies it early on.
+    let (cmdline_start, cmdline_len) = match fw_cfg.cmdline_file() {
+        Ok(file) if file.size() as usize <= CMDLINE_MAX_LEN => {
+            let cmdline_virt = loaded_kernel_virt_end;
+            let cmdline_phys = take_kernel_pages(
+                &mut loaded_kernel_phys_end,
+                kernel_region_phys_end,
+                PAGE_SIZE,
+            );
+            map_and_validate(cmdline_virt, cmdline_phys, PAGE_SIZE);
+            loaded_kernel_virt_end = loaded_kernel_virt_end.offset(PAGE_SIZE);
+
+            let buf =
+                unsafe { slice::from_raw_parts_mut(cmdline_virt.as_mut_ptr::<u8>(), PAGE_SIZE) };
+            buf.fill(0);
+            let len = fw_cfg
+                .read_file(&file, buf)
+                .or_fail(Stage2Failure::Setup, "Failed to read kernel command line");
+            (u64::from(cmdline_virt), len as u64)
+        }
+        Ok(file) => {
+            log::warn!(
+                "Kernel command line too long ({} bytes), ignoring it",
+                file.size()
+            );
+            (0, 0)
+        }
+        Err(_) => (0, 0),
+    };
+
+    // Load the payload, if the host passed one, into validated pages of the
+    // kernel region. Its size is only known now, so the heap shrinks by
+    // whatever it needs.
+    let (payload_phys_start, payload_len) = match fw_cfg.payload_file() {
+        Ok(file) => {
+            let len = file.size() as usize;
+            let aligned_len = len.next_multiple_of(PAGE_SIZE);
+            if kernel_region_phys_end - loaded_kernel_phys_end <= aligned_len + HANDOVER_SIZE {
+                fail(
+                    Stage2Failure::Payload,
+                    "Payload does not fit into kernel region",
+                );
+            }
+
+            let payload_virt = loaded_kernel_virt_end;
+            let payload_phys = loaded_kernel_phys_end;
+            map_and_validate(payload_virt, payload_phys, aligned_len);
+            loaded_kernel_virt_end = loaded_kernel_virt_end.offset(aligned_len);
+            loaded_kernel_phys_end = loaded_kernel_phys_end.offset(aligned_len);
+
+            let buf =
+                unsafe { slice::from_raw_parts_mut(payload_virt.as_mut_ptr::<u8>(), aligned_len) };
+            let len = fw_cfg
+                .read_file(&file, buf)
+                .or_fail(Stage2Failure::Payload, "Failed to read payload");
+            buf[len..].fill(0);
+            (u64::from(payload_phys), len as u64)
+        }
+        Err(_) => (0, 0),
+    };
+
+    // The launch info and the stack the kernel is entered on. Stage2 memory
+    // is reclaimed by the kernel, so nothing handed over may stay there.
+    let handover_virt = loaded_kernel_virt_end;
+    let handover_phys = take_kernel_pages(
+        &mut loaded_kernel_phys_end,
+        kernel_region_phys_end,
+        HANDOVER_SIZE,
+    );
+    map_and_validate(handover_virt, handover_phys, HANDOVER_SIZE);
+    loaded_kernel_virt_end = loaded_kernel_virt_end.offset(HANDOVER_SIZE);
+
     // Map the rest of the memory region to right after the kernel image.
     let heap_area_phys_start = loaded_kernel_phys_end;
     let heap_area_virt_start = loaded_kernel_virt_end;
@@ -261,7 +676,14 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
 
     // Build the handover information describing the memory layout and hand
     // control to the SVSM kernel.
-    let launch_info = KernelLaunchInfo {
+    let stage2_ghcb = if this_cpu_mut().has_ghcb() {
+        let ghcb = VirtAddr::from(this_cpu_mut().ghcb().as_ptr());
+        u64::from(virt_to_phys(ghcb))
+    } else {
+        0
+    };
+    let stage2_heap = stage2_heap();
+    let mut launch_info = KernelLaunchInfo {
         kernel_region_phys_start: u64::from(kernel_region_phys_start),
         kernel_region_phys_end: u64::from(kernel_region_phys_end),
         heap_area_phys_start: u64::from(heap_area_phys_start),
@@ -271,9 +693,30 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         kernel_elf_stage2_virt_end: u64::from(kernel_elf_end),
         kernel_fs_start: u64::from(launch_info.kernel_fs_start),
         kernel_fs_end: u64::from(launch_info.kernel_fs_end),
-        cpuid_page: 0x9f000u64,
-        secrets_page: 0x9e000u64,
+        cpuid_page: CPUID_PAGE as u64,
+        secrets_page: SECRETS_PAGE as u64,
+        cmdline_start,
+        cmdline_len,
+        stage2_heap_start: u64::from(stage2_heap.0),
+        stage2_heap_end: u64::from(stage2_heap.1),
+        stage2_phys_start: unsafe { &startup_32 as *const u8 as u64 },
+        stage2_phys_end: u64::from(stage2_heap.1),
+        stage2_ghcb,
+        console_io_port: serial.port,
+        kernel_elf_digest,
+        payload_phys_start,
+        payload_len,
+        ..KernelLaunchInfo::new()
     };
+    let memory_regions = fw_cfg
+        .get_memory_regions()
+        .or_fail(Stage2Failure::Setup, "Failed to read E820 map");
+    for region in memory_regions.iter() {
+        launch_info
+            .add_memory_region(region.start, region.end)
+            .or_fail(Stage2Failure::Setup, "Too many memory regions for kernel");
+    }
+    launch_info.seal();
 
     let mem_info = memory_info();
     print_memory_info(&mem_info);
@@ -291,26 +734,45 @@ pub extern "C" fn stage2_main(launch_info: &Stage1LaunchInfo) {
         loaded_kernel_virt_start
     );
 
+    protect_kernel_segments(&kernel_elf, kernel_vaddr_alloc_base);
+
     let kernel_entry = kernel_elf.get_entry(kernel_vaddr_alloc_base);
     let valid_bitmap = valid_bitmap_addr();
 
+    let launch_info_ptr = handover_virt.as_mut_ptr::<KernelLaunchInfo>();
+    unsafe { launch_info_ptr.write(launch_info) };
+    let launch_stack = handover_virt.offset(HANDOVER_SIZE);
+
     // Shut down the GHCB
     shutdown_percpu();
 
     unsafe {
-        asm!("jmp *%rax",
-             in("rax") kernel_entry,
-             in("r8") &launch_info,
-             in("r9") valid_bitmap.bits(),
-             options(att_syntax))
-    };
+        launch_kernel(
+            kernel_entry,
+            u64::from(launch_stack),
+            launch_info_ptr as u64,
+            valid_bitmap.bits() as u64,
+        )
+    }
+}
 
-    panic!("Road ends here!");
+#[cfg(feature = "stage2-silent")]
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    request_termination_reason_msr(STAGE2_TERM_SET, Stage2Failure::Panic as u8)
 }
 
+#[cfg(not(feature = "stage2-silent"))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log::error!("Panic: {}", info);
+    dump_regs(&X86Regs::capture());
+    dump_control_regs();
+
+    #[cfg(feature = "panic-terminate")]
+    request_termination_reason_msr(STAGE2_TERM_SET, Stage2Failure::Panic as u8);
+
+    #[cfg(not(feature = "panic-terminate"))]
     loop {
         halt();
     }
@@ -0,0 +1,443 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Persistent SVSM state kept on a host-provided disk. No component stores
+// records yet: the vTPM has no NV storage and there are no monotonic
+// counters, the record IDs below are reserved for them. The state is a set of records identified by number,
+// sealed with AES-256-GCM under a key the PSP derives from the launch
+// measurement, so only the same SVSM build can open it.
+//
+// The disk holds two slots, each with a complete copy of the state and a
+// generation number. Commits overwrite the older slot, so a torn write
+// leaves the previous state intact. The host can still hand out an old
+// disk image; to detect that, the generation of the loaded state is bound
+// into the attestation data the SVSM reports, for a verifier to compare
+// against the last generation it saw.
+
+extern crate alloc;
+
+use crate::crypto::aes::AES256_KEY_SIZE;
+use crate::crypto::gcm::{Aes256Gcm, GCM_IV_SIZE, GCM_TAG_SIZE};
+use crate::crypto::rng::rng_fill;
+use crate::error::SvsmError;
+use crate::io::IOPort;
+use crate::locking::SpinLock;
+use crate::pci::PciLegacyConfig;
+use crate::sev::guest_msg::{
+    get_derived_key, KEY_FIELD_MEASUREMENT, KEY_FIELD_POLICY, KEY_ROOT_VCEK,
+};
+use crate::virtio::{virtio_blk_probe, VirtioBlk, VIRTIO_BLK_SECTOR_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Record reserved for the vTPM NV storage
+pub const STATE_ID_VTPM_NV: u32 = 1;
+/// Record reserved for monotonic counters
+pub const STATE_ID_COUNTERS: u32 = 2;
+
+const STATE_MAGIC: [u8; 8] = *b"SVSMSTAT";
+const STATE_VERSION: u32 = 1;
+
+// Slot header, everything before the tag is authenticated:
+//
+// magic        [u8; 8]
+// version      u32
+// payload len  u32
+// generation   u64
+// iv           [u8; 12]
+// reserved     [u8; 12]
+// tag          [u8; 16]
+const HEADER_SIZE: usize = 64;
+const HEADER_TAG_OFFSET: usize = HEADER_SIZE - GCM_TAG_SIZE;
+
+// Largest slot, half of the disk is used if it is smaller
+const STATE_SLOT_MAX: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    // No state store was set up
+    NotInitialized,
+    // The disk is too small for two slots
+    BackendTooSmall,
+    // The state does not fit into a slot
+    TooLarge,
+    // A slot carries state which could not be authenticated
+    AuthFailed,
+    // An authenticated slot has an invalid record layout
+    Corrupted,
+}
+
+impl From<StateError> for SvsmError {
+    fn from(e: StateError) -> Self {
+        Self::State(e)
+    }
+}
+
+/// Storage the state is kept in. Offsets and lengths are multiples of the
+/// block size.
+pub trait StateBackend {
+    fn block_size(&self) -> usize;
+    fn size(&self) -> u64;
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), SvsmError>;
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), SvsmError>;
+    fn flush(&mut self) -> Result<(), SvsmError>;
+}
+
+impl StateBackend for VirtioBlk {
+    fn block_size(&self) -> usize {
+        VIRTIO_BLK_SECTOR_SIZE
+    }
+
+    fn size(&self) -> u64 {
+        self.capacity() * VIRTIO_BLK_SECTOR_SIZE as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), SvsmError> {
+        self.read_sectors(offset / VIRTIO_BLK_SECTOR_SIZE as u64, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), SvsmError> {
+        self.write_sectors(offset / VIRTIO_BLK_SECTOR_SIZE as u64, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SvsmError> {
+        VirtioBlk::flush(self)
+    }
+}
+
+// Serializes the records as a count followed by (id, length, data) tuples,
+// all integers little endian
+fn serialize_records(records: &BTreeMap<u32, Vec<u8>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    for (id, data) in records {
+        buf.extend_from_slice(&id.to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+    }
+    buf
+}
+
+fn deserialize_records(buf: &[u8]) -> Result<BTreeMap<u32, Vec<u8>>, StateError> {
+    let mut records = BTreeMap::new();
+    let mut rest = buf;
+    let mut take = |len: usize| -> Result<&[u8], StateError> {
+        if rest.len() < len {
+            return Err(StateError::Corrupted);
+        }
+        let (head, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(head)
+    };
+    let u32_at = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+    let count = u32_at(take(4)?);
+    for _ in 0..count {
+        let id = u32_at(take(4)?);
+        let len = u32_at(take(4)?) as usize;
+        records.insert(id, take(len)?.to_vec());
+    }
+
+    Ok(records)
+}
+
+/// State loaded from one slot
+#[derive(Debug)]
+struct SlotState {
+    generation: u64,
+    records: BTreeMap<u32, Vec<u8>>,
+}
+
+// Opens the sealed slot in `buf`. Returns None for a slot which was never
+// written.
+fn open_slot(gcm: &Aes256Gcm, buf: &mut [u8]) -> Result<Option<SlotState>, StateError> {
+    let (header, body) = buf.split_at_mut(HEADER_SIZE);
+    if header[..8] != STATE_MAGIC {
+        return Ok(None);
+    }
+
+    let field = |off: usize, len: usize| &header[off..off + len];
+    let version = u32::from_le_bytes(field(8, 4).try_into().unwrap());
+    let len = u32::from_le_bytes(field(12, 4).try_into().unwrap()) as usize;
+    let generation = u64::from_le_bytes(field(16, 8).try_into().unwrap());
+    let iv: [u8; GCM_IV_SIZE] = field(24, GCM_IV_SIZE).try_into().unwrap();
+    let tag: [u8; GCM_TAG_SIZE] = field(HEADER_TAG_OFFSET, GCM_TAG_SIZE).try_into().unwrap();
+    if version != STATE_VERSION || len > body.len() {
+        return Err(StateError::AuthFailed);
+    }
+
+    let payload = &mut body[..len];
+    gcm.decrypt(&iv, &header[..HEADER_TAG_OFFSET], payload, &tag)
+        .map_err(|_| StateError::AuthFailed)?;
+
+    Ok(Some(SlotState {
+        generation,
+        records: deserialize_records(payload)?,
+    }))
+}
+
+// Seals `payload` into a slot image of `slot_size` bytes
+fn seal_slot(
+    gcm: &Aes256Gcm,
+    generation: u64,
+    iv: &[u8; GCM_IV_SIZE],
+    payload: &[u8],
+    slot_size: usize,
+) -> Result<Vec<u8>, StateError> {
+    if payload.len() > slot_size - HEADER_SIZE {
+        return Err(StateError::TooLarge);
+    }
+
+    let mut buf = vec![0u8; slot_size];
+    let (header, body) = buf.split_at_mut(HEADER_SIZE);
+    header[..8].copy_from_slice(&STATE_MAGIC);
+    header[8..12].copy_from_slice(&STATE_VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&generation.to_le_bytes());
+    header[24..24 + GCM_IV_SIZE].copy_from_slice(iv);
+
+    let sealed = &mut body[..payload.len()];
+    sealed.copy_from_slice(payload);
+    let tag = gcm.encrypt(iv, &header[..HEADER_TAG_OFFSET], sealed);
+    header[HEADER_TAG_OFFSET..].copy_from_slice(&tag);
+
+    Ok(buf)
+}
+
+/// Sealed, rollback-detecting record store on top of a [`StateBackend`]
+pub struct StateStore<B: StateBackend> {
+    backend: B,
+    gcm: Aes256Gcm,
+    slot_size: usize,
+    // Slot holding the current generation
+    slot: usize,
+    generation: u64,
+    records: BTreeMap<u32, Vec<u8>>,
+}
+
+impl<B: StateBackend> StateStore<B> {
+    /// Loads the newest state from `backend` which authenticates with
+    /// `key`. An empty backend yields an empty store at generation 0.
+    /// Fails if there is state, but none of it can be opened, to avoid
+    /// overwriting state sealed for another SVSM build.
+    pub fn open(mut backend: B, key: &[u8; AES256_KEY_SIZE]) -> Result<Self, SvsmError> {
+        let block = backend.block_size() as u64;
+        let slot_size = ((backend.size() / 2).min(STATE_SLOT_MAX) / block * block) as usize;
+        if slot_size < HEADER_SIZE + block as usize {
+            return Err(StateError::BackendTooSmall.into());
+        }
+
+        let gcm = Aes256Gcm::new(key);
+        let mut buf = vec![0u8; slot_size];
+        let mut newest: Option<(usize, SlotState)> = None;
+        let mut failed = false;
+
+        for slot in 0..2 {
+            backend.read((slot * slot_size) as u64, &mut buf)?;
+            match open_slot(&gcm, &mut buf) {
+                Ok(Some(state)) => {
+                    if newest
+                        .as_ref()
+                        .is_none_or(|(_, n)| state.generation > n.generation)
+                    {
+                        newest = Some((slot, state));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("Persistent state slot {} is unusable: {:?}", slot, e);
+                    failed = true;
+                }
+            }
+        }
+
+        let (slot, state) = match newest {
+            Some(newest) => newest,
+            None if failed => return Err(StateError::AuthFailed.into()),
+            // Nothing written yet, the first commit goes to slot 0
+            None => (
+                1,
+                SlotState {
+                    generation: 0,
+                    records: BTreeMap::new(),
+                },
+            ),
+        };
+
+        Ok(StateStore {
+            backend,
+            gcm,
+            slot_size,
+            slot,
+            generation: state.generation,
+            records: state.records,
+        })
+    }
+
+    /// Generation of the current state, incremented by every commit
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get(&self, id: u32) -> Option<&[u8]> {
+        self.records.get(&id).map(Vec::as_slice)
+    }
+
+    fn commit_with_iv(
+        &mut self,
+        records: BTreeMap<u32, Vec<u8>>,
+        iv: &[u8; GCM_IV_SIZE],
+    ) -> Result<(), SvsmError> {
+        let generation = self.generation + 1;
+        let slot = 1 - self.slot;
+        let image = seal_slot(
+            &self.gcm,
+            generation,
+            iv,
+            &serialize_records(&records),
+            self.slot_size,
+        )?;
+
+        self.backend.write((slot * self.slot_size) as u64, &image)?;
+        self.backend.flush()?;
+
+        self.slot = slot;
+        self.generation = generation;
+        self.records = records;
+        Ok(())
+    }
+
+    /// Stores `data` as record `id` and commits the state. Nothing changes
+    /// if the commit fails.
+    pub fn set(&mut self, id: u32, data: &[u8]) -> Result<(), SvsmError> {
+        let mut records = self.records.clone();
+        records.insert(id, data.to_vec());
+
+        // A fresh IV for every commit, generations may repeat after the
+        // host rolled the disk back
+        let mut iv = [0u8; GCM_IV_SIZE];
+        rng_fill(&mut iv)?;
+        self.commit_with_iv(records, &iv)
+    }
+}
+
+static STATE_STORE: SpinLock<Option<StateStore<VirtioBlk>>> = SpinLock::new(None);
+
+/// Opens the persistent state on the first virtio block device. The key is
+/// bound to the launch measurement and guest policy. Needs guest messages
+/// and the random number generator to be set up.
+pub fn state_store_init(io: &dyn IOPort) -> Result<(), SvsmError> {
+    let blk = virtio_blk_probe(&PciLegacyConfig::new(io))?;
+    let key = get_derived_key(KEY_ROOT_VCEK, KEY_FIELD_MEASUREMENT | KEY_FIELD_POLICY, 0)?;
+    let store = StateStore::open(blk, &key)?;
+
+    log::info!("Persistent state generation {}", store.generation());
+    *STATE_STORE.lock() = Some(store);
+    Ok(())
+}
+
+/// Generation of the loaded state, None without a state store
+pub fn state_store_generation() -> Option<u64> {
+    STATE_STORE.lock().as_ref().map(StateStore::generation)
+}
+
+/// Returns a copy of record `id`
+pub fn state_read(id: u32) -> Result<Option<Vec<u8>>, SvsmError> {
+    let store = STATE_STORE.lock();
+    let store = store.as_ref().ok_or(StateError::NotInitialized)?;
+    Ok(store.get(id).map(<[u8]>::to_vec))
+}
+
+/// Replaces record `id` with `data` and commits it to disk
+pub fn state_write(id: u32, data: &[u8]) -> Result<(), SvsmError> {
+    let mut store = STATE_STORE.lock();
+    store
+        .as_mut()
+        .ok_or(StateError::NotInitialized)?
+        .set(id, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemBackend(Vec<u8>);
+
+    impl StateBackend for &mut MemBackend {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), SvsmError> {
+            let start = offset as usize;
+            buf.copy_from_slice(&self.0[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), SvsmError> {
+            let start = offset as usize;
+            self.0[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), SvsmError> {
+            Ok(())
+        }
+    }
+
+    fn commit(store: &mut StateStore<&mut MemBackend>, id: u32, data: &[u8], iv: u8) {
+        let mut records = store.records.clone();
+        records.insert(id, data.to_vec());
+        store.commit_with_iv(records, &[iv; GCM_IV_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn test_state_store() {
+        let key = [0x42; AES256_KEY_SIZE];
+        let mut disk = MemBackend(vec![0; 8192]);
+
+        let mut store = StateStore::open(&mut disk, &key).unwrap();
+        assert_eq!(store.generation(), 0);
+        commit(&mut store, STATE_ID_VTPM_NV, b"nvram", 1);
+        commit(&mut store, STATE_ID_COUNTERS, b"counters", 2);
+        drop(store);
+
+        let store = StateStore::open(&mut disk, &key).unwrap();
+        assert_eq!(store.generation(), 2);
+        assert_eq!(store.get(STATE_ID_VTPM_NV), Some(&b"nvram"[..]));
+        assert_eq!(store.get(STATE_ID_COUNTERS), Some(&b"counters"[..]));
+        drop(store);
+
+        // A torn write of the newer slot falls back to the older one
+        disk.0[4096 + HEADER_SIZE] ^= 1;
+        let store = StateStore::open(&mut disk, &key).unwrap();
+        assert_eq!(store.generation(), 1);
+        assert_eq!(store.get(STATE_ID_COUNTERS), None);
+        drop(store);
+
+        // State sealed for another build is left alone
+        assert!(StateStore::open(&mut disk, &[0x43; AES256_KEY_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_state_records() {
+        let mut records = BTreeMap::new();
+        records.insert(7, b"abc".to_vec());
+        records.insert(1, Vec::new());
+        let buf = serialize_records(&records);
+        assert_eq!(buf.len(), 4 + 8 + 8 + 3);
+        assert_eq!(deserialize_records(&buf).unwrap(), records);
+        assert_eq!(
+            deserialize_records(&buf[..buf.len() - 1]),
+            Err(StateError::Corrupted)
+        );
+    }
+}
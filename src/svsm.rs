@@ -19,35 +19,63 @@ use core::panic::PanicInfo;
 use core::slice;
 use svsm::acpi::tables::load_acpi_cpu_info;
 use svsm::address::{Address, PhysAddr, VirtAddr};
-use svsm::console::{init_console, install_console_logger, WRITER};
+use svsm::cmdline::{cmdline, cmdline_init};
+use svsm::console::{
+    console_add_sink, console_flush, console_retarget, init_console, install_console_logger,
+    DebugConsole, DEBUG_CONSOLE_PORT,
+};
+use svsm::console_ring::{console_backend, init_console_ring, ConsoleBackend};
+use svsm::cpu::apic::{apic_init, apic_owned};
 use svsm::cpu::control_regs::{cr0_init, cr4_init};
 use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table, SnpCpuidTable};
 use svsm::cpu::efer::efer_init;
 use svsm::cpu::gdt::load_gdt;
-use svsm::cpu::idt::{early_idt_init, idt_init};
+use svsm::cpu::idt::{dump_control_regs, dump_regs, early_idt_init, idt_init, X86Regs};
+use svsm::cpu::ioapic::ioapic_init;
 use svsm::cpu::percpu::PerCpu;
 use svsm::cpu::percpu::{this_cpu, this_cpu_mut};
 use svsm::cpu::smp::start_secondary_cpus;
+use svsm::crypto::rng::{rng_init, rng_policy_digest};
+#[cfg(feature = "enable-gdb")]
+use svsm::debug::gdbstub::{gdbstub_start, GDB_SERIAL_PORT};
+use svsm::debug::softlockup::softlockup_init;
 use svsm::debug::stacktrace::print_stack;
+use svsm::device_manifest::{probe_devices, DEVICE_MANIFEST};
 use svsm::elf;
 use svsm::error::SvsmError;
 use svsm::fs::{initialize_fs, populate_ram_fs};
 use svsm::fw_cfg::FwCfg;
-use svsm::kernel_launch::KernelLaunchInfo;
-use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
+use svsm::guest_exit::guest_exit_init;
+use svsm::kernel_launch::{
+    KernelLaunchInfo, SvsmNote, KERNEL_LAUNCH_INFO_VERSION, STAGE2_FEATURES, SVSM_NOTE_KASLR,
+    SVSM_NOTE_LAUNCH_INFO_VERSION, SVSM_NOTE_STAGE2_FEATURES, SVSM_NOTE_VIRT_BASE,
+};
+use svsm::log_filter::{log_filter_init, log_set_filter};
+use svsm::manifest::{svsm_manifest_digest, SVSM_BUILD_ID};
+use svsm::measure::{
+    fw_measure_start, fw_measure_wait, kernel_measurement, kernel_measurement_init,
+};
+use svsm::mm::alloc::{memory_info, print_memory_info, print_slab_info, root_mem_init};
 use svsm::mm::memory::init_memory_map;
 use svsm::mm::pagetable::paging_init;
 use svsm::mm::virtualrange::virt_log_usage;
 use svsm::mm::{init_kernel_mapping_info, PerCPUPageMappingGuard};
+use svsm::protocols::vendor::vendor_debug_init;
 use svsm::requests::{request_loop, update_mappings};
 use svsm::serial::SerialPort;
-use svsm::serial::SERIAL_PORT;
-use svsm::sev::secrets_page::{copy_secrets_page, SecretsPage};
-use svsm::sev::sev_status_init;
-use svsm::sev::utils::{rmp_adjust, RMPFlags};
+use svsm::serial::{serial_rx_init, SerialConfig, SerialError, SERIAL_PORT};
+use svsm::sev::guest_msg::guest_msg_init;
+use svsm::sev::integrity::{SVSM_TERM_PANIC, SVSM_TERM_SET};
+use svsm::sev::msr_protocol::request_termination_reason_msr;
+use svsm::sev::rmpadjust::{rmp_adjust, RMPFlags};
+use svsm::sev::secrets_page::{copy_secrets_page, vmpck_retired, SecretsPage};
+use svsm::sev::{sev_init, sev_status_verify};
+use svsm::state_store::state_store_init;
 use svsm::svsm_console::SVSMIOPort;
-use svsm::types::{GUEST_VMPL, PAGE_SIZE};
+use svsm::time::tsc_calibrate;
+use svsm::types::{MemoryRegion, GUEST_VMPL, PAGE_SIZE};
 use svsm::utils::{halt, immut_after_init::ImmutAfterInitCell, zero_mem_region};
+use svsm::vtpm::vtpm_disable;
 use svsm_paging::{init_page_table, invalidate_stage2};
 
 use svsm::mm::validate::{init_valid_bitmap_ptr, migrate_valid_bitmap};
@@ -101,11 +129,44 @@ global_asm!(
     options(att_syntax)
 );
 
+// Loader contract checked by stage2 before jumping to the kernel
+#[used]
+#[link_section = ".note.svsm"]
+static NOTE_STAGE2_FEATURES: SvsmNote<8> =
+    SvsmNote::new(SVSM_NOTE_STAGE2_FEATURES, STAGE2_FEATURES.to_le_bytes());
+#[used]
+#[link_section = ".note.svsm"]
+static NOTE_LAUNCH_INFO_VERSION: SvsmNote<4> = SvsmNote::new(
+    SVSM_NOTE_LAUNCH_INFO_VERSION,
+    KERNEL_LAUNCH_INFO_VERSION.to_le_bytes(),
+);
+#[used]
+#[link_section = ".note.svsm"]
+static NOTE_KASLR: SvsmNote<4> = SvsmNote::new(SVSM_NOTE_KASLR, 0u32.to_le_bytes());
+
+// The virtual base comes from svsm.lds, so the note is emitted in assembly
+// where the linker can fill it in. The layout is the one of SvsmNote.
+global_asm!(
+    r#"
+        .pushsection .note.svsm, "a"
+        .balign 4
+        .long 5
+        .long 16
+        .long {n_type}
+        .asciz "SVSM"
+        .balign 4
+        .quad svsm_virt_base
+        .quad {align}
+        .popsection
+        "#,
+    n_type = const SVSM_NOTE_VIRT_BASE,
+    align = const PAGE_SIZE,
+    options(att_syntax)
+);
+
 static CPUID_PAGE: ImmutAfterInitCell<SnpCpuidTable> = ImmutAfterInitCell::uninit();
 static LAUNCH_INFO: ImmutAfterInitCell<KernelLaunchInfo> = ImmutAfterInitCell::uninit();
 
-pub static mut PERCPU: PerCpu = PerCpu::new();
-
 fn copy_cpuid_table_to_fw(fw_addr: PhysAddr) -> Result<(), SvsmError> {
     let guard = PerCPUPageMappingGuard::create_4k(fw_addr)?;
     let start = guard.virt_addr();
@@ -143,11 +204,14 @@ fn copy_secrets_page_to_fw(fw_addr: PhysAddr, caa_addr: PhysAddr) -> Result<(),
         *dst = SECRETS_PAGE;
 
         // Copy Table
-        let mut fw_sp = target.as_mut();
+        let fw_sp = target.as_mut();
 
         // Zero VMCK key for VMPLs with more privileges than the guest
-        for vmpck in fw_sp.vmpck.iter_mut().take(GUEST_VMPL) {
-            vmpck.fill(0);
+        fw_sp.restrict_to_vmpl(GUEST_VMPL);
+
+        // The guest gets keys and reports through the vendor protocol
+        if vmpck_retired() {
+            fw_sp.retire_from_vmpl(GUEST_VMPL);
         }
 
         let &li = &*LAUNCH_INFO;
@@ -222,30 +286,35 @@ fn launch_fw() -> Result<(), SvsmError> {
     Ok(())
 }
 
-fn validate_flash() -> Result<(), SvsmError> {
-    let mut fw_cfg = FwCfg::new(&CONSOLE_IO);
+fn read_flash_regions() -> Vec<MemoryRegion> {
+    let fw_cfg = FwCfg::new(&CONSOLE_IO);
 
-    let flash_regions = fw_cfg.iter_flash_regions().collect::<Vec<_>>();
+    let flash_regions = fw_cfg
+        .flash_regions()
+        .expect("Failed to read flash regions");
 
     // Sanity-check flash regions.
     for region in flash_regions.iter() {
         // Make sure that the regions are between 3GiB and 4GiB.
-        if !region.overlaps(3 * 1024 * 1024 * 1024, 4 * 1024 * 1024 * 1024) {
+        if !region.overlaps(&MemoryRegion::new(
+            3 * 1024 * 1024 * 1024,
+            4 * 1024 * 1024 * 1024,
+        )) {
             panic!("flash region in unexpected region");
         }
 
         // Make sure that no regions overlap with the kernel.
-        if region.overlaps(
+        if region.overlaps(&MemoryRegion::new(
             LAUNCH_INFO.kernel_region_phys_start,
             LAUNCH_INFO.kernel_region_phys_end,
-        ) {
+        )) {
             panic!("flash region overlaps with kernel");
         }
     }
     // Make sure that regions don't overlap.
     for (i, outer) in flash_regions.iter().enumerate() {
         for inner in flash_regions[..i].iter() {
-            if outer.overlaps(inner.start, inner.end) {
+            if outer.overlaps(inner) {
                 panic!("flash regions overlap");
             }
         }
@@ -256,14 +325,18 @@ fn validate_flash() -> Result<(), SvsmError> {
         .any(|region| region.end == 4 * 1024 * 1024 * 1024);
     assert!(one_region_ends_at_4gib);
 
-    for (i, region) in flash_regions.into_iter().enumerate() {
-        let pstart = PhysAddr::from(region.start);
-        let pend = PhysAddr::from(region.end);
+    flash_regions
+}
+
+fn validate_flash(flash_regions: &[MemoryRegion]) -> Result<(), SvsmError> {
+    for (i, region) in flash_regions.iter().enumerate() {
+        let pstart = region.start_phys();
+        let pend = region.end_phys();
         log::info!(
             "Flash region {} at {:#018x} size {:018x}",
             i,
             pstart,
-            pend - pstart
+            region.len()
         );
 
         for paddr in (pstart.bits()..pend.bits())
@@ -291,10 +364,12 @@ pub fn memory_init(launch_info: &KernelLaunchInfo) {
 }
 
 static CONSOLE_IO: SVSMIOPort = SVSMIOPort::new();
-static mut CONSOLE_SERIAL: SerialPort = SerialPort {
-    driver: &CONSOLE_IO,
-    port: SERIAL_PORT,
-};
+static CONSOLE_SERIAL: SerialPort = SerialPort::new(&CONSOLE_IO, SERIAL_PORT);
+static CONSOLE_DEBUG: DebugConsole = DebugConsole::new(&CONSOLE_IO, DEBUG_CONSOLE_PORT);
+// Port set by serial_mirror_init(), if the command line asks for it
+static MIRROR_SERIAL: SerialPort = SerialPort::new(&CONSOLE_IO, 0);
+#[cfg(feature = "enable-gdb")]
+static GDB_SERIAL: SerialPort = SerialPort::new(&CONSOLE_IO, GDB_SERIAL_PORT);
 
 pub fn boot_stack_info() {
     unsafe {
@@ -313,6 +388,7 @@ fn mapping_info_init(launch_info: &KernelLaunchInfo) {
 
 #[no_mangle]
 pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
+    li.check().expect("Invalid KernelLaunchInfo from stage2");
     let launch_info: KernelLaunchInfo = *li;
     let vb_ptr = vb_addr.as_mut_ptr::<u64>();
 
@@ -327,25 +403,35 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
     load_gdt();
     early_idt_init();
 
+    LAUNCH_INFO.init(li).expect("Launch info initialized twice");
     unsafe {
-        LAUNCH_INFO.init(li);
+        cmdline_init(
+            VirtAddr::from(launch_info.cmdline_start),
+            launch_info.cmdline_len as usize,
+        );
+        kernel_measurement_init(&launch_info.kernel_elf_digest);
     }
 
     let cpuid_table_virt = VirtAddr::from(launch_info.cpuid_page);
-    unsafe { CPUID_PAGE.init(&*(cpuid_table_virt.as_ptr::<SnpCpuidTable>())) };
-    register_cpuid_table(&CPUID_PAGE);
+    CPUID_PAGE
+        .init(unsafe { &*(cpuid_table_virt.as_ptr::<SnpCpuidTable>()) })
+        .expect("CPUID page initialized twice");
+    register_cpuid_table(&CPUID_PAGE).expect("Invalid CPUID page");
     dump_cpuid_table();
 
     unsafe {
         let secrets_page_virt = VirtAddr::from(launch_info.secrets_page);
         copy_secrets_page(&mut SECRETS_PAGE, secrets_page_virt);
         zero_mem_region(secrets_page_virt, secrets_page_virt + PAGE_SIZE);
+        SECRETS_PAGE
+            .validate()
+            .expect("Invalid SEV-SNP secrets page");
     }
 
     cr0_init();
     cr4_init();
     efer_init();
-    sev_status_init();
+    sev_init();
 
     memory_init(&launch_info);
     migrate_valid_bitmap().expect("Failed to migrate valid-bitmap");
@@ -378,16 +464,54 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
     }
     idt_init();
 
-    unsafe {
-        WRITER.lock().set(&mut CONSOLE_SERIAL);
-    }
+    // Stage2 passes on the UART the host chose, the command line overrides
+    // it
+    let stage2_port = match LAUNCH_INFO.console_io_port {
+        0 => SERIAL_PORT,
+        port => port,
+    };
+    let serial = cmdline().get("serial").map(SerialConfig::parse);
+    CONSOLE_SERIAL.configure(&match serial {
+        Some(Ok(config)) => config,
+        _ => SerialConfig {
+            port: stage2_port,
+            baud: None,
+        },
+    });
+    console_retarget(&CONSOLE_SERIAL, false);
     init_console();
     install_console_logger("SVSM");
 
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM)");
+    log::info!("Command line: {}", cmdline().as_str());
+    sev_status_verify();
+    if let Some(Err(e)) = serial {
+        log::warn!("Invalid serial port on command line: {:?}", e);
+    }
+    if CONSOLE_SERIAL.port() != stage2_port {
+        log::info!(
+            "Stage2 console on port {:#x}, kernel console on port {:#x}",
+            stage2_port,
+            CONSOLE_SERIAL.port()
+        );
+    }
+    if let Some(spec) = cmdline().get("serial_mirror") {
+        if let Err(e) = serial_mirror_init(spec) {
+            log::warn!("Failed to mirror console to serial port: {:?}", e);
+        }
+    }
+
+    if let Some(spec) = cmdline().get("log") {
+        if let Err(e) = log_set_filter(spec) {
+            log::warn!("Invalid log filter on command line: {:?}", e);
+        }
+    }
+
+    tsc_calibrate();
 
     let mem_info = memory_info();
     print_memory_info(&mem_info);
+    print_slab_info();
 
     boot_stack_info();
 
@@ -404,20 +528,133 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
     }
 }
 
+// Has a second UART receive a copy of all console output
+fn serial_mirror_init(spec: &str) -> Result<(), SvsmError> {
+    let config = SerialConfig::parse(spec)?;
+    if config.port == CONSOLE_SERIAL.port() {
+        return Err(SerialError::InvalidConfig.into());
+    }
+
+    MIRROR_SERIAL.configure(&config);
+    if !MIRROR_SERIAL.is_present() {
+        return Err(SvsmError::MissingDevice("console mirror serial port"));
+    }
+    console_add_sink(&MIRROR_SERIAL)?;
+    log::info!("Mirroring console to serial port {:#x}", config.port);
+
+    Ok(())
+}
+
 #[no_mangle]
 pub extern "C" fn svsm_main() {
-    invalidate_stage2().expect("Failed to invalidate Stage2 memory");
+    invalidate_stage2(&LAUNCH_INFO).expect("Failed to invalidate Stage2 memory");
+
+    #[cfg(feature = "enable-gdb")]
+    if [CONSOLE_SERIAL.port(), MIRROR_SERIAL.port()].contains(&GDB_SERIAL.port()) {
+        log::warn!(
+            "Not starting GDB stub, port {:#x} is used by the console",
+            GDB_SERIAL.port()
+        );
+    } else if let Err(e) = gdbstub_start(&GDB_SERIAL) {
+        log::warn!("Failed to start GDB stub: {:?}", e);
+    }
+
+    if let Err(e) = guest_msg_init(unsafe { &SECRETS_PAGE }) {
+        log::warn!("Failed to set up SNP guest messages: {:?}", e);
+    }
 
     let fw_cfg = FwCfg::new(&CONSOLE_IO);
 
-    init_memory_map(&fw_cfg, &LAUNCH_INFO).expect("Failed to init guest memory map");
+    if let Err(e) = log_filter_init(&fw_cfg) {
+        log::warn!("Failed to apply log filter: {:?}", e);
+    }
+
+    probe_devices(DEVICE_MANIFEST, &CONSOLE_IO).expect("Required host device is missing");
+
+    init_memory_map(&LAUNCH_INFO);
+
+    match console_backend(&fw_cfg) {
+        ConsoleBackend::Serial => {}
+        ConsoleBackend::Ring => match init_console_ring(&fw_cfg) {
+            Ok(ring) => {
+                log::info!("Switching console to shared ring");
+                console_retarget(ring, false);
+            }
+            Err(e) => log::warn!("Failed to set up console ring, keeping serial: {:?}", e),
+        },
+        ConsoleBackend::Debug => {
+            log::info!("Switching console to hypervisor debug port");
+            console_retarget(&CONSOLE_DEBUG, false);
+        }
+        ConsoleBackend::SerialDebug => {
+            log::info!("Mirroring console to hypervisor debug port");
+            console_retarget(&CONSOLE_DEBUG, true);
+        }
+    }
 
     initialize_fs();
 
     populate_ram_fs(LAUNCH_INFO.kernel_fs_start, LAUNCH_INFO.kernel_fs_end)
         .expect("Failed to unpack FS archive");
 
+    // A payload from the host may add files, if it is an FS archive
+    if LAUNCH_INFO.payload_len != 0 {
+        let start = LAUNCH_INFO.payload_phys_start;
+        if let Err(e) = populate_ram_fs(start, start + LAUNCH_INFO.payload_len) {
+            log::warn!("Failed to unpack payload as FS archive: {:?}", e);
+        }
+    }
+
+    if let Err(e) = rng_init(&fw_cfg) {
+        log::warn!("Failed to seed RNG: {:?}", e);
+    }
+
     let cpus = load_acpi_cpu_info(&fw_cfg).expect("Failed to load ACPI tables");
+
+    // Without a local APIC of its own the SVSM polls for serial input
+    let apic_ready = if !apic_owned() {
+        log::info!("Local APIC belongs to the guest, interrupts unavailable");
+        false
+    } else if let Err(e) = apic_init() {
+        log::warn!("Failed to enable x2APIC, interrupts unavailable: {:?}", e);
+        false
+    } else {
+        true
+    };
+
+    if let Err(e) = ioapic_init(&fw_cfg) {
+        log::warn!(
+            "Failed to discover IOAPICs, legacy IRQs unavailable: {:?}",
+            e
+        );
+    } else if apic_ready {
+        if let Err(e) = serial_rx_init(&fw_cfg, &CONSOLE_SERIAL) {
+            log::warn!("Failed to set up serial input: {:?}", e);
+        }
+    }
+
+    if apic_ready {
+        if let Err(e) = softlockup_init(&fw_cfg) {
+            log::warn!("Failed to set up soft lockup detector: {:?}", e);
+        }
+    }
+
+    if cmdline().get_bool("vtpm") == Some(false) {
+        log::info!("vTPM disabled on command line");
+        vtpm_disable();
+    }
+
+    if let Err(e) = guest_exit_init(&fw_cfg) {
+        log::warn!("Failed to read guest exit policy: {:?}", e);
+    }
+
+    // The disk is overwritten, so the host must ask for it explicitly
+    if cmdline().get("state") == Some("virtio-blk") {
+        if let Err(e) = state_store_init(&CONSOLE_IO) {
+            log::warn!("Failed to open persistent state: {:?}", e);
+        }
+    }
+
     let mut nr_cpus = 0;
 
     for cpu in cpus.iter() {
@@ -430,6 +667,11 @@ pub extern "C" fn svsm_main() {
 
     start_secondary_cpus(&cpus);
 
+    // Measure the firmware while the remaining boot work runs, idle APs
+    // help with it. The guest must not run before the measurement is done.
+    let flash_regions = read_flash_regions();
+    fw_measure_start(flash_regions.clone());
+
     let fw_meta = parse_fw_meta_data()
         .unwrap_or_else(|e| panic!("Failed to parse FW SEV meta-data: {:#?}", e));
 
@@ -443,7 +685,7 @@ pub extern "C" fn svsm_main() {
         panic!("Failed to copy firmware tables: {:#?}", e);
     }
 
-    if let Err(e) = validate_flash() {
+    if let Err(e) = validate_flash(&flash_regions) {
         panic!("Failed to validate flash memory: {:#?}", e);
     }
 
@@ -451,6 +693,27 @@ pub extern "C" fn svsm_main() {
 
     virt_log_usage();
 
+    let fw_digest = fw_measure_wait();
+    log::info!("Firmware measurement (SHA-384): {:02x?}", fw_digest);
+    log::info!(
+        "Kernel measurement (SHA-384): {:02x?}",
+        kernel_measurement()
+    );
+    log::info!(
+        "SVSM build {} manifest (SHA-384): {:02x?}",
+        SVSM_BUILD_ID,
+        svsm_manifest_digest()
+    );
+    if let Some(policy) = rng_policy_digest() {
+        log::info!("RNG seed policy (SHA-384): {:02x?}", policy);
+    }
+
+    // Like every other attestation report, the one this reads the guest
+    // policy from is requested after the firmware measurement completed
+    if let Err(e) = vendor_debug_init() {
+        log::warn!("Failed to read guest policy, debug calls disabled: {:?}", e);
+    }
+
     if let Err(e) = launch_fw() {
         panic!("Failed to launch FW: {:#?}", e);
     }
@@ -460,12 +723,29 @@ pub extern "C" fn svsm_main() {
     panic!("Road ends here!");
 }
 
+// Whether a panic terminates the VM instead of halting the CPU. The
+// "panic" command line option overrides the build default.
+fn panic_terminates() -> bool {
+    match cmdline().get("panic") {
+        Some("terminate") => true,
+        Some("halt") => false,
+        _ => cfg!(feature = "panic-terminate"),
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log::error!("Panic: CPU[{}] {}", this_cpu().get_apic_id(), info);
 
+    dump_regs(&X86Regs::capture());
+    dump_control_regs();
     print_stack(3);
 
+    if panic_terminates() {
+        console_flush();
+        request_termination_reason_msr(SVSM_TERM_SET, SVSM_TERM_PANIC);
+    }
+
     loop {
         halt();
     }
@@ -4,9 +4,10 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::cpu::percpu::this_cpu_mut;
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
 use crate::io::IOPort;
-use crate::sev::ghcb::GHCBIOSize;
+use crate::sev::ghcb::{GHCBIOSize, GhcbError, GHCB};
 use crate::sev::msr_protocol::request_termination_msr;
 
 pub struct SVSMIOPort {}
@@ -17,38 +18,93 @@ impl SVSMIOPort {
     }
 }
 
+// The console runs on top of these ports, so it must not wait for the GHCB
+// of this CPU. Code holding the GHCB might be logging or panicking.
+fn with_ghcb<R>(f: impl FnOnce(&mut GHCB) -> Result<R, SvsmError>) -> Result<R, SvsmError> {
+    let mut ghcb = this_cpu().try_ghcb().ok_or(GhcbError::Busy)?;
+    f(&mut ghcb)
+}
+
+// The infallible accessors terminate the VM when the hypervisor does not
+// complete the request, as there is no value to return. While the GHCB is
+// busy, output is dropped and input reads as `busy`, all ones like from a
+// port without a device.
+fn or_terminate<T>(ret: Result<T, SvsmError>, busy: T) -> T {
+    match ret {
+        Ok(v) => v,
+        Err(SvsmError::Ghcb(GhcbError::Busy)) => busy,
+        Err(_) => request_termination_msr(),
+    }
+}
+
 impl IOPort for SVSMIOPort {
     fn outb(&self, port: u16, value: u8) {
-        let ret = this_cpu_mut()
-            .ghcb()
-            .ioio_out(port, GHCBIOSize::Size8, value as u64);
-        if ret.is_err() {
-            request_termination_msr();
-        }
+        or_terminate(self.try_outb(port, value), ())
     }
 
     fn inb(&self, port: u16) -> u8 {
-        let ret = this_cpu_mut().ghcb().ioio_in(port, GHCBIOSize::Size8);
-        match ret {
-            Ok(v) => (v & 0xff) as u8,
-            Err(_e) => request_termination_msr(),
-        }
+        or_terminate(self.try_inb(port), u8::MAX)
     }
 
     fn outw(&self, port: u16, value: u16) {
-        let ret = this_cpu_mut()
-            .ghcb()
-            .ioio_out(port, GHCBIOSize::Size16, value as u64);
-        if ret.is_err() {
-            request_termination_msr();
-        }
+        or_terminate(self.try_outw(port, value), ())
     }
 
     fn inw(&self, port: u16) -> u16 {
-        let ret = this_cpu_mut().ghcb().ioio_in(port, GHCBIOSize::Size16);
-        match ret {
-            Ok(v) => (v & 0xffff) as u16,
-            Err(_e) => request_termination_msr(),
+        or_terminate(self.try_inw(port), u16::MAX)
+    }
+
+    fn outl(&self, port: u16, value: u32) {
+        or_terminate(self.try_outl(port, value), ())
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        or_terminate(self.try_inl(port), u32::MAX)
+    }
+
+    fn insb(&self, port: u16, buf: &mut [u8]) {
+        match self.try_insb(port, buf) {
+            Err(SvsmError::Ghcb(GhcbError::Busy)) => buf.fill(u8::MAX),
+            ret => or_terminate(ret, ()),
         }
     }
+
+    fn outsb(&self, port: u16, buf: &[u8]) {
+        or_terminate(self.try_outsb(port, buf), ())
+    }
+
+    fn try_outb(&self, port: u16, value: u8) -> Result<(), SvsmError> {
+        with_ghcb(|ghcb| ghcb.ioio_out(port, GHCBIOSize::Size8, value as u64))
+    }
+
+    fn try_inb(&self, port: u16) -> Result<u8, SvsmError> {
+        let v = with_ghcb(|ghcb| ghcb.ioio_in(port, GHCBIOSize::Size8))?;
+        Ok((v & 0xff) as u8)
+    }
+
+    fn try_outw(&self, port: u16, value: u16) -> Result<(), SvsmError> {
+        with_ghcb(|ghcb| ghcb.ioio_out(port, GHCBIOSize::Size16, value as u64))
+    }
+
+    fn try_inw(&self, port: u16) -> Result<u16, SvsmError> {
+        let v = with_ghcb(|ghcb| ghcb.ioio_in(port, GHCBIOSize::Size16))?;
+        Ok((v & 0xffff) as u16)
+    }
+
+    fn try_outl(&self, port: u16, value: u32) -> Result<(), SvsmError> {
+        with_ghcb(|ghcb| ghcb.ioio_out(port, GHCBIOSize::Size32, value as u64))
+    }
+
+    fn try_inl(&self, port: u16) -> Result<u32, SvsmError> {
+        let v = with_ghcb(|ghcb| ghcb.ioio_in(port, GHCBIOSize::Size32))?;
+        Ok((v & 0xffff_ffff) as u32)
+    }
+
+    fn try_insb(&self, port: u16, buf: &mut [u8]) -> Result<(), SvsmError> {
+        with_ghcb(|ghcb| ghcb.ioio_ins(port, GHCBIOSize::Size8, buf))
+    }
+
+    fn try_outsb(&self, port: u16, buf: &[u8]) -> Result<(), SvsmError> {
+        with_ghcb(|ghcb| ghcb.ioio_outs(port, GHCBIOSize::Size8, buf))
+    }
 }
@@ -4,6 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use core::cmp::max;
 use svsm::address::{Address, PhysAddr, VirtAddr};
 use svsm::cpu::percpu::this_cpu_mut;
 use svsm::elf;
@@ -59,14 +60,9 @@ pub fn init_page_table(launch_info: &KernelLaunchInfo, kernel_elf: &elf::Elf64Fi
     set_init_pgtable(pgtable);
 }
 
-pub fn invalidate_stage2() -> Result<(), SvsmError> {
-    let pstart = PhysAddr::null();
-    let pend = pstart.offset(640 * 1024);
+fn invalidate_range(pstart: PhysAddr, pend: PhysAddr) -> Result<(), SvsmError> {
     let mut paddr = pstart;
 
-    // Stage2 memory must be invalidated when already on the SVSM page-table,
-    // because before that the stage2 page-table is still active, which is in
-    // stage2 memory, causing invalidation of page-table pages.
     while paddr < pend {
         let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
         let vaddr = guard.virt_addr();
@@ -76,10 +72,29 @@ pub fn invalidate_stage2() -> Result<(), SvsmError> {
         paddr = paddr.offset(PAGE_SIZE);
     }
 
-    this_cpu_mut()
-        .ghcb()
-        .page_state_change(paddr, pend, false, PageStateChangeOp::PscShared)
-        .expect("Failed to invalidate Stage2 memory");
+    let result =
+        this_cpu_mut()
+            .ghcb()
+            .page_state_change(paddr, pend, false, PageStateChangeOp::PscShared);
+    result.expect("Failed to invalidate Stage2 memory");
+
+    Ok(())
+}
+
+pub fn invalidate_stage2(launch_info: &KernelLaunchInfo) -> Result<(), SvsmError> {
+    let low_end = PhysAddr::from(640 * 1024usize);
+
+    // Stage2 memory must be invalidated when already on the SVSM page-table,
+    // because before that the stage2 page-table is still active, which is in
+    // stage2 memory, causing invalidation of page-table pages.
+    invalidate_range(PhysAddr::null(), low_end)?;
+
+    // A relocated stage2 may have used memory beyond the low 640K
+    let stage2_start = max(PhysAddr::from(launch_info.stage2_phys_start), low_end);
+    let stage2_end = PhysAddr::from(launch_info.stage2_phys_end);
+    if stage2_start < stage2_end {
+        invalidate_range(stage2_start, stage2_end)?;
+    }
 
     Ok(())
 }
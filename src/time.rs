@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Time keeping based on the TSC. The TSC frequency is taken from CPUID,
+// either from the architectural leaves 0x15/0x16 or from the hypervisor
+// timing leaf 0x40000010.
+
+use crate::cpu::cpuid::{cpuid, CpuidResult};
+use crate::cpu::msr::rdtsc;
+use crate::utils::immut_after_init::ImmutAfterInitCell;
+use core::hint::spin_loop;
+use log;
+
+/// TSC frequency assumed when CPUID does not report one. It is on the high
+/// side, so delays last at least as long as requested on real hardware.
+pub const TSC_FREQ_DEFAULT_KHZ: u64 = 4_000_000;
+
+const NSEC_PER_MSEC: u64 = 1_000_000;
+
+static TSC_FREQ_KHZ: ImmutAfterInitCell<u64> = ImmutAfterInitCell::new(TSC_FREQ_DEFAULT_KHZ);
+
+// Derives the TSC frequency from the CPUID leaves which may report it:
+// 0x15 (TSC/crystal ratio and crystal frequency), 0x16 (base frequency in
+// MHz) and the hypervisor leaf 0x40000010 (TSC frequency in kHz).
+fn tsc_freq_from_cpuid(
+    leaf_15: Option<CpuidResult>,
+    leaf_16: Option<CpuidResult>,
+    leaf_hv: Option<CpuidResult>,
+) -> Option<u64> {
+    if let Some(r) = leaf_15 {
+        if r.eax != 0 && r.ebx != 0 && r.ecx != 0 {
+            return Some(r.ecx as u64 * r.ebx as u64 / r.eax as u64 / 1000);
+        }
+    }
+
+    if let Some(r) = leaf_16 {
+        if r.eax & 0xffff != 0 {
+            return Some((r.eax & 0xffff) as u64 * 1000);
+        }
+    }
+
+    leaf_hv.map(|r| r.eax as u64).filter(|&khz| khz != 0)
+}
+
+/// Determines the TSC frequency. Must run once on the BSP, before any
+/// other CPU uses the time functions.
+pub fn tsc_calibrate() {
+    let hv_max = cpuid(0x4000_0000, 0).map_or(0, |r| r.eax);
+    let leaf_hv = if hv_max >= 0x4000_0010 {
+        cpuid(0x4000_0010, 0)
+    } else {
+        None
+    };
+
+    let khz = match tsc_freq_from_cpuid(cpuid(0x15, 0), cpuid(0x16, 0), leaf_hv) {
+        Some(khz) => khz,
+        None => {
+            log::warn!(
+                "TSC frequency unknown, assuming {} MHz",
+                TSC_FREQ_DEFAULT_KHZ / 1000
+            );
+            TSC_FREQ_DEFAULT_KHZ
+        }
+    };
+
+    unsafe { TSC_FREQ_KHZ.reinit(&khz) };
+    log::info!("TSC frequency: {}.{:03} MHz", khz / 1000, khz % 1000);
+}
+
+/// The TSC frequency in kHz
+pub fn tsc_freq_khz() -> u64 {
+    *TSC_FREQ_KHZ
+}
+
+fn tsc_to_ns(tsc: u64, khz: u64) -> u64 {
+    (tsc as u128 * NSEC_PER_MSEC as u128 / khz as u128) as u64
+}
+
+fn ns_to_tsc(ns: u64, khz: u64) -> u64 {
+    (ns as u128 * khz as u128 / NSEC_PER_MSEC as u128) as u64
+}
+
+/// Nanoseconds since the TSC was reset, usually at platform reset
+pub fn current_time_ns() -> u64 {
+    tsc_to_ns(rdtsc(), tsc_freq_khz())
+}
+
+/// Busy-waits for at least `ns` nanoseconds
+pub fn ndelay(ns: u64) {
+    let start = rdtsc();
+    let ticks = ns_to_tsc(ns, tsc_freq_khz());
+    while rdtsc().wrapping_sub(start) < ticks {
+        spin_loop();
+    }
+}
+
+/// Busy-waits for at least `us` microseconds
+pub fn udelay(us: u64) {
+    ndelay(us.saturating_mul(1000));
+}
+
+/// Busy-waits for at least `ms` milliseconds
+pub fn mdelay(ms: u64) {
+    ndelay(ms.saturating_mul(NSEC_PER_MSEC));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn res(eax: u32, ebx: u32, ecx: u32) -> Option<CpuidResult> {
+        Some(CpuidResult {
+            eax,
+            ebx,
+            ecx,
+            edx: 0,
+        })
+    }
+
+    #[test]
+    fn test_tsc_freq() {
+        // 25 MHz crystal with a 2/184 ratio: 2.3 GHz
+        let khz = tsc_freq_from_cpuid(res(2, 184, 25_000_000), res(2000, 0, 0), None);
+        assert_eq!(khz, Some(2_300_000));
+        // Ratio without crystal frequency, use the base frequency
+        let khz = tsc_freq_from_cpuid(res(2, 184, 0), res(2000, 0, 0), res(1, 0, 0));
+        assert_eq!(khz, Some(2_000_000));
+        let khz = tsc_freq_from_cpuid(None, None, res(2_994_374, 0, 0));
+        assert_eq!(khz, Some(2_994_374));
+        assert_eq!(tsc_freq_from_cpuid(None, res(0, 0, 0), None), None);
+
+        assert_eq!(tsc_to_ns(3_000_000_000, 3_000_000), 1_000_000_000);
+        assert_eq!(ns_to_tsc(1_000, 2_500_000), 2_500);
+    }
+}
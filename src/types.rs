@@ -4,7 +4,11 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+extern crate alloc;
+
+use crate::address::PhysAddr;
 use crate::sev::vmsa::VMPL_MAX;
+use alloc::vec::Vec;
 
 pub const PAGE_SHIFT: usize = 12;
 pub const PAGE_SHIFT_2M: usize = 21;
@@ -28,3 +32,236 @@ pub const GUEST_VMPL: usize = 1;
 const _: () = assert!(GUEST_VMPL > 0 && GUEST_VMPL < VMPL_MAX);
 
 pub const MAX_CPUS: usize = 512;
+
+/// A range of guest-physical memory from `start` (inclusive) to `end`
+/// (exclusive).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MemoryRegion {
+    pub const fn new(start: u64, end: u64) -> Self {
+        MemoryRegion { start, end }
+    }
+
+    pub fn start_phys(&self) -> PhysAddr {
+        PhysAddr::from(self.start)
+    }
+
+    pub fn end_phys(&self) -> PhysAddr {
+        PhysAddr::from(self.end)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Returns `true` if `other` lies completely within this region.
+    pub fn contains_region(&self, other: &MemoryRegion) -> bool {
+        other.is_empty() || (self.start <= other.start && other.end <= self.end)
+    }
+
+    /// Returns `true` if the two regions have at least one byte in common.
+    pub fn overlaps(&self, other: &MemoryRegion) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn intersect(&self, other: &MemoryRegion) -> Option<MemoryRegion> {
+        let r = MemoryRegion::new(self.start.max(other.start), self.end.min(other.end));
+        (!r.is_empty()).then_some(r)
+    }
+
+    /// Removes `other` from this region and returns what is left below and
+    /// above it.
+    pub fn subtract(&self, other: &MemoryRegion) -> (Option<MemoryRegion>, Option<MemoryRegion>) {
+        if !self.overlaps(other) {
+            return (Some(*self).filter(|r| !r.is_empty()), None);
+        }
+
+        let below = MemoryRegion::new(self.start, other.start);
+        let above = MemoryRegion::new(other.end, self.end);
+        (
+            (!below.is_empty()).then_some(below),
+            (!above.is_empty()).then_some(above),
+        )
+    }
+
+    /// Combines two overlapping or adjacent regions into one.
+    pub fn merge(&self, other: &MemoryRegion) -> Option<MemoryRegion> {
+        if self.start > other.end || other.start > self.end {
+            return None;
+        }
+        Some(MemoryRegion::new(
+            self.start.min(other.start),
+            self.end.max(other.end),
+        ))
+    }
+
+    /// Returns `true` if start and end are multiples of `align`, which must
+    /// be a power of two.
+    pub fn is_aligned(&self, align: u64) -> bool {
+        (self.start | self.end) & (align - 1) == 0
+    }
+
+    /// Returns the largest `align`-aligned region within this region, if
+    /// there is one.
+    pub fn align_inward(&self, align: u64) -> Option<MemoryRegion> {
+        let start = self.start.checked_add(align - 1)? & !(align - 1);
+        let r = MemoryRegion::new(start, self.end & !(align - 1));
+        (!r.is_empty()).then_some(r)
+    }
+}
+
+/// Set of non-overlapping memory regions, kept sorted by start address.
+/// Overlapping and adjacent regions are merged on insertion.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryRegionSet {
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryRegionSet {
+    pub const fn new() -> Self {
+        MemoryRegionSet {
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+
+        let mut merged = region;
+        self.regions.retain(|r| match merged.merge(r) {
+            Some(m) => {
+                merged = m;
+                false
+            }
+            None => true,
+        });
+
+        let idx = self.regions.partition_point(|r| r.start < merged.start);
+        self.regions.insert(idx, merged);
+    }
+
+    /// Removes all addresses covered by `region` from the set.
+    pub fn remove(&mut self, region: &MemoryRegion) {
+        let mut regions = Vec::with_capacity(self.regions.len() + 1);
+        for r in self.regions.iter() {
+            let (below, above) = r.subtract(region);
+            regions.extend(below);
+            regions.extend(above);
+        }
+        self.regions = regions;
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.find(addr).is_some()
+    }
+
+    /// Returns the region containing `addr`.
+    pub fn find(&self, addr: u64) -> Option<&MemoryRegion> {
+        let idx = self.regions.partition_point(|r| r.end <= addr);
+        self.regions.get(idx).filter(|r| r.contains(addr))
+    }
+
+    /// Returns `true` if any region of the set overlaps with `region`.
+    pub fn overlaps(&self, region: &MemoryRegion) -> bool {
+        self.regions.iter().any(|r| r.overlaps(region))
+    }
+
+    /// Returns the region with the highest addresses.
+    pub fn last(&self) -> Option<&MemoryRegion> {
+        self.regions.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &MemoryRegion> {
+        self.regions.iter()
+    }
+}
+
+impl FromIterator<MemoryRegion> for MemoryRegionSet {
+    fn from_iter<I: IntoIterator<Item = MemoryRegion>>(iter: I) -> Self {
+        let mut set = MemoryRegionSet::new();
+        for region in iter {
+            set.insert(region);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_region_ops() {
+        let a = MemoryRegion::new(0x1000, 0x5000);
+        let b = MemoryRegion::new(0x3000, 0x8000);
+
+        assert_eq!(a.intersect(&b), Some(MemoryRegion::new(0x3000, 0x5000)));
+        assert_eq!(a.merge(&b), Some(MemoryRegion::new(0x1000, 0x8000)));
+        assert_eq!(
+            a.subtract(&b),
+            (Some(MemoryRegion::new(0x1000, 0x3000)), None)
+        );
+        assert_eq!(
+            b.subtract(&MemoryRegion::new(0x4000, 0x5000)),
+            (
+                Some(MemoryRegion::new(0x3000, 0x4000)),
+                Some(MemoryRegion::new(0x5000, 0x8000))
+            )
+        );
+        assert!(a.intersect(&MemoryRegion::new(0x5000, 0x6000)).is_none());
+        assert!(a.merge(&MemoryRegion::new(0x6000, 0x7000)).is_none());
+        assert_eq!(
+            MemoryRegion::new(0x1800, 0x6800).align_inward(0x1000),
+            Some(MemoryRegion::new(0x2000, 0x6000))
+        );
+    }
+
+    #[test]
+    fn test_memory_region_set() {
+        let mut set: MemoryRegionSet = [
+            MemoryRegion::new(0x8000, 0x9000),
+            MemoryRegion::new(0x1000, 0x2000),
+            MemoryRegion::new(0x2000, 0x3000),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.iter().next(), Some(&MemoryRegion::new(0x1000, 0x3000)));
+        assert!(set.contains(0x2fff));
+        assert!(!set.contains(0x3000));
+
+        set.remove(&MemoryRegion::new(0x1800, 0x8800));
+        let regions: Vec<_> = set.iter().copied().collect();
+        assert_eq!(
+            regions,
+            [
+                MemoryRegion::new(0x1000, 0x1800),
+                MemoryRegion::new(0x8800, 0x9000)
+            ]
+        );
+        assert_eq!(set.last(), Some(&MemoryRegion::new(0x8800, 0x9000)));
+    }
+}
@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+use crate::error::SvsmError;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const WORD_INIT: AtomicU64 = AtomicU64::new(0);
+
+// Mask of bits `lo..hi` of a word, with `lo < hi <= 64`
+fn word_mask(lo: usize, hi: usize) -> u64 {
+    (u64::MAX >> (BITS_PER_WORD - (hi - lo))) << lo
+}
+
+/// Bitmap with atomic operations on single bits and ranges, all bits start
+/// out clear. Range operations are atomic per 64-bit word only. Indexes
+/// beyond the end of the bitmap are a bug and panic.
+#[derive(Debug)]
+pub struct Bitmap<S> {
+    words: S,
+    nbits: usize,
+}
+
+/// Bitmap of `N * 64` bits which can live in a static
+pub type FixedBitmap<const N: usize> = Bitmap<[AtomicU64; N]>;
+
+/// Bitmap sized at runtime and allocated from the heap
+pub type DynBitmap = Bitmap<Box<[AtomicU64]>>;
+
+impl<const N: usize> Bitmap<[AtomicU64; N]> {
+    pub const fn new() -> Self {
+        Bitmap {
+            words: [WORD_INIT; N],
+            nbits: N * BITS_PER_WORD,
+        }
+    }
+}
+
+impl<const N: usize> Default for Bitmap<[AtomicU64; N]> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bitmap<Box<[AtomicU64]>> {
+    pub fn new_dyn(nbits: usize) -> Result<Self, SvsmError> {
+        let nwords = nbits.div_ceil(BITS_PER_WORD);
+        let mut words = Vec::new();
+        words
+            .try_reserve_exact(nwords)
+            .map_err(|_| SvsmError::Mem)?;
+        words.resize_with(nwords, || AtomicU64::new(0));
+
+        Ok(Bitmap {
+            words: words.into_boxed_slice(),
+            nbits,
+        })
+    }
+}
+
+impl<S: AsRef<[AtomicU64]>> Bitmap<S> {
+    /// Number of bits in the bitmap
+    pub fn len(&self) -> usize {
+        self.nbits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nbits == 0
+    }
+
+    fn word(&self, bit: usize) -> (&AtomicU64, u64) {
+        assert!(bit < self.nbits);
+        (
+            &self.words.as_ref()[bit / BITS_PER_WORD],
+            1 << (bit % BITS_PER_WORD),
+        )
+    }
+
+    pub fn test(&self, bit: usize) -> bool {
+        let (word, mask) = self.word(bit);
+        word.load(Ordering::Acquire) & mask != 0
+    }
+
+    /// Sets `bit` and returns its previous value
+    pub fn set(&self, bit: usize) -> bool {
+        let (word, mask) = self.word(bit);
+        word.fetch_or(mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Clears `bit` and returns its previous value
+    pub fn clear(&self, bit: usize) -> bool {
+        let (word, mask) = self.word(bit);
+        word.fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+
+    fn update_range(&self, start: usize, len: usize, value: bool) {
+        let end = start.checked_add(len).expect("Bitmap range overflows");
+        assert!(end <= self.nbits);
+
+        let mut bit = start;
+        while bit < end {
+            let word = &self.words.as_ref()[bit / BITS_PER_WORD];
+            let lo = bit % BITS_PER_WORD;
+            let hi = BITS_PER_WORD.min(lo + (end - bit));
+            let mask = word_mask(lo, hi);
+
+            if value {
+                word.fetch_or(mask, Ordering::AcqRel);
+            } else {
+                word.fetch_and(!mask, Ordering::AcqRel);
+            }
+            bit += hi - lo;
+        }
+    }
+
+    /// Sets the `len` bits starting at `start`
+    pub fn set_range(&self, start: usize, len: usize) {
+        self.update_range(start, len, true);
+    }
+
+    /// Clears the `len` bits starting at `start`
+    pub fn clear_range(&self, start: usize, len: usize) {
+        self.update_range(start, len, false);
+    }
+
+    /// Returns the first clear bit at or after `start`. The result might be
+    /// outdated by the time it is used, allocators must claim the bit with
+    /// [`Self::set`] and retry when it was set already.
+    pub fn find_next_zero(&self, start: usize) -> Option<usize> {
+        let words = self.words.as_ref();
+        let mut bit = start;
+
+        while bit < self.nbits {
+            let index = bit / BITS_PER_WORD;
+            // Treat the bits below `bit` as set
+            let word = words[index].load(Ordering::Acquire) | ((1 << (bit % BITS_PER_WORD)) - 1);
+            if word != u64::MAX {
+                let found = index * BITS_PER_WORD + word.trailing_ones() as usize;
+                return (found < self.nbits).then_some(found);
+            }
+            bit = (index + 1) * BITS_PER_WORD;
+        }
+
+        None
+    }
+
+    pub fn find_first_zero(&self) -> Option<usize> {
+        self.find_next_zero(0)
+    }
+
+    /// Number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .as_ref()
+            .iter()
+            .map(|word| word.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_bits() {
+        let b = FixedBitmap::<2>::new();
+        assert_eq!(b.len(), 128);
+        assert!(!b.test(0));
+
+        assert!(!b.set(0));
+        assert!(b.set(0));
+        assert!(!b.set(63));
+        assert!(!b.set(64));
+        assert!(!b.set(127));
+        assert!(b.test(63) && b.test(64) && b.test(127));
+        assert!(!b.test(1) && !b.test(62) && !b.test(65));
+        assert_eq!(b.count_ones(), 4);
+
+        assert!(b.clear(64));
+        assert!(!b.clear(64));
+        assert!(!b.test(64));
+        assert_eq!(b.count_ones(), 3);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let b = FixedBitmap::<4>::new();
+
+        // Crosses two word boundaries
+        b.set_range(60, 72);
+        assert!(!b.test(59));
+        assert!((60..132).all(|bit| b.test(bit)));
+        assert!(!b.test(132));
+        assert_eq!(b.count_ones(), 72);
+
+        b.clear_range(64, 64);
+        assert!((60..64).all(|bit| b.test(bit)));
+        assert!((64..128).all(|bit| !b.test(bit)));
+        assert!((128..132).all(|bit| b.test(bit)));
+
+        // Whole bitmap and empty ranges
+        b.set_range(0, 256);
+        assert_eq!(b.count_ones(), 256);
+        b.clear_range(17, 0);
+        assert_eq!(b.count_ones(), 256);
+        b.clear_range(0, 256);
+        assert_eq!(b.count_ones(), 0);
+    }
+
+    #[test]
+    fn test_find_zero() {
+        let b = FixedBitmap::<2>::new();
+        assert_eq!(b.find_first_zero(), Some(0));
+
+        b.set_range(0, 70);
+        assert_eq!(b.find_first_zero(), Some(70));
+        assert_eq!(b.find_next_zero(71), Some(71));
+        b.clear(5);
+        assert_eq!(b.find_first_zero(), Some(5));
+        assert_eq!(b.find_next_zero(6), Some(70));
+        assert_eq!(b.find_next_zero(64), Some(70));
+
+        b.set_range(0, 128);
+        assert_eq!(b.find_first_zero(), None);
+        assert_eq!(b.find_next_zero(128), None);
+    }
+
+    #[test]
+    fn test_dyn_bitmap() {
+        let b = DynBitmap::new_dyn(100).unwrap();
+        assert_eq!(b.len(), 100);
+
+        // The unused bits of the last word are never found
+        b.set_range(0, 100);
+        assert_eq!(b.find_first_zero(), None);
+        b.clear(99);
+        assert_eq!(b.find_first_zero(), Some(99));
+
+        let empty = DynBitmap::new_dyn(0).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.find_first_zero(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_out_of_range() {
+        let b = DynBitmap::new_dyn(100).unwrap();
+        b.set(100);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_range_out_of_range() {
+        let b = FixedBitmap::<1>::new();
+        b.set_range(60, 5);
+    }
+}
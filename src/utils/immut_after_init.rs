@@ -4,11 +4,34 @@
 //
 // Author: Nicolai Stange <nstange@suse.de>
 
+use crate::error::SvsmError;
 use core::cell::UnsafeCell;
 use core::marker::Copy;
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImmutAfterInitError {
+    // The value has been initialized before
+    AlreadyInit,
+    // The value is used before being initialized
+    Uninitialized,
+}
+
+impl From<ImmutAfterInitError> for SvsmError {
+    fn from(err: ImmutAfterInitError) -> Self {
+        Self::ImmutAfterInit(err)
+    }
+}
+
+pub type ImmutAfterInitResult<T> = Result<T, ImmutAfterInitError>;
+
+// Initialization states of an ImmutAfterInitCell
+const IMMUT_UNINIT: u8 = 0;
+const IMMUT_INITIALIZING: u8 = 1;
+const IMMUT_INIT: u8 = 2;
 
 /// A memory location which is effectively immutable after initalization code
 /// has run.
@@ -23,19 +46,22 @@ use core::ops::Deref;
 ///
 /// Using `ImmutAfterInitCell` as an alternative makes the intended usage
 /// pattern more verbatim and limits the `unsafe{}` regions to the
-/// initialization code.  The main purpose is to facilitate code review: it must
-/// get verified that the value gets initialized only once and before first
-/// potential use.
+/// reinitialization code. The cell tracks whether it has been initialized:
+/// initializing it a second time fails with
+/// [`ImmutAfterInitError::AlreadyInit`] and dereferencing it before it has
+/// been initialized panics.
 ///
 /// # Examples
 /// A `ImmutAfterInitCell` may start out in unitialized state and can get
 /// initialized at runtime:
 /// ```
-/// # use svsm::utils::immut_after_init::ImmutAfterInitCell;
+/// # use svsm::utils::immut_after_init::{ImmutAfterInitCell, ImmutAfterInitError};
 /// static X : ImmutAfterInitCell<i32> = ImmutAfterInitCell::uninit();
 /// pub fn main() {
-///     unsafe { X.init(&123) };
+///     assert_eq!(X.try_get(), Err(ImmutAfterInitError::Uninitialized));
+///     X.init(&123).unwrap();
 ///     assert_eq!(*X, 123);
+///     assert_eq!(X.init(&456), Err(ImmutAfterInitError::AlreadyInit));
 /// }
 /// ```
 ///
@@ -52,10 +78,11 @@ use core::ops::Deref;
 /// }
 /// ```
 ///
-#[repr(transparent)]
 pub struct ImmutAfterInitCell<T: Copy> {
     #[doc(hidden)]
     data: UnsafeCell<MaybeUninit<T>>,
+    #[doc(hidden)]
+    state: AtomicU8,
 }
 
 impl<T: Copy> ImmutAfterInitCell<T> {
@@ -64,16 +91,29 @@ impl<T: Copy> ImmutAfterInitCell<T> {
     pub const fn uninit() -> Self {
         ImmutAfterInitCell {
             data: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(IMMUT_UNINIT),
         }
     }
 
     /// Initialize an uninitialized `ImmutAfterInitCell` instance from a value.
-    ///
-    /// Must **not** get called on an already initialized instance!
+    /// Fails with [`ImmutAfterInitError::AlreadyInit`] if the instance has
+    /// been initialized before, which includes instances created by
+    /// [`Self::new()`].
     ///
     /// * `v` - Initialization value.
-    pub unsafe fn init(&self, v: &T) {
-        core::ptr::copy_nonoverlapping(v as *const T, (*self.data.get()).as_mut_ptr(), 1);
+    pub fn init(&self, v: &T) -> ImmutAfterInitResult<()> {
+        self.state
+            .compare_exchange(
+                IMMUT_UNINIT,
+                IMMUT_INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .map_err(|_| ImmutAfterInitError::AlreadyInit)?;
+        // No one else can access the value until the state says it is
+        // initialized.
+        unsafe { self.write(v) };
+        Ok(())
     }
 
     /// Reinitialize an initialized `ImmutAfterInitCell` instance from a value.
@@ -83,7 +123,12 @@ impl<T: Copy> ImmutAfterInitCell<T> {
     ///
     /// * `v` - Initialization value.
     pub unsafe fn reinit(&self, v: &T) {
-        self.init(v);
+        self.write(v);
+    }
+
+    unsafe fn write(&self, v: &T) {
+        core::ptr::copy_nonoverlapping(v as *const T, (*self.data.get()).as_mut_ptr(), 1);
+        self.state.store(IMMUT_INIT, Ordering::Release);
     }
 
     /// Create an initialized `ImmutAfterInitCell` instance from a value.
@@ -92,17 +137,28 @@ impl<T: Copy> ImmutAfterInitCell<T> {
     pub const fn new(v: T) -> Self {
         ImmutAfterInitCell {
             data: UnsafeCell::new(MaybeUninit::new(v)),
+            state: AtomicU8::new(IMMUT_INIT),
+        }
+    }
+
+    /// Returns the wrapped value, or [`ImmutAfterInitError::Uninitialized`]
+    /// if the instance has not been initialized yet.
+    pub fn try_get(&self) -> ImmutAfterInitResult<&T> {
+        if self.state.load(Ordering::Acquire) != IMMUT_INIT {
+            return Err(ImmutAfterInitError::Uninitialized);
         }
+        Ok(unsafe { (*self.data.get()).assume_init_ref() })
     }
 }
 
 impl<T: Copy> Deref for ImmutAfterInitCell<T> {
     type Target = T;
 
-    /// Dereference the wrapped value. Must **only ever** get called on an
-    /// initialized instance!
+    /// Dereference the wrapped value. Panics if the instance has not been
+    /// initialized yet.
     fn deref(&self) -> &T {
-        unsafe { (&*self.data.get()).assume_init_ref() }
+        self.try_get()
+            .expect("ImmutAfterInitCell used before initialization")
     }
 }
 
@@ -125,8 +181,8 @@ unsafe impl<T: Copy> Sync for ImmutAfterInitCell<T> {}
 /// static X : ImmutAfterInitCell<i32> = ImmutAfterInitCell::uninit();
 /// static RX : ImmutAfterInitRef<'_, i32> = ImmutAfterInitRef::uninit();
 /// fn main() {
-///     unsafe { X.init(&123) };
-///     unsafe { RX.init_from_cell(&X) };
+///     X.init(&123).unwrap();
+///     RX.init_from_cell(&X).unwrap();
 ///     assert_eq!(*RX, 123);
 /// }
 /// ```
@@ -136,7 +192,7 @@ unsafe impl<T: Copy> Sync for ImmutAfterInitCell<T> {}
 /// static X : i32 = 123;
 /// static RX : ImmutAfterInitRef<'_, i32> = ImmutAfterInitRef::uninit();
 /// fn main() {
-///     unsafe { RX.init_from_ref(&X) };
+///     RX.init_from_ref(&X).unwrap();
 ///     assert_eq!(*RX, 123);
 /// }
 /// ```
@@ -149,13 +205,13 @@ unsafe impl<T: Copy> Sync for ImmutAfterInitCell<T> {}
 /// static RX : ImmutAfterInitRef::<'static, i32> = ImmutAfterInitRef::uninit();
 ///
 /// fn init_rx(r : ImmutAfterInitRef<'static, i32>) {
-///     unsafe { RX.init_from_ref(r.get()) };
+///     RX.init_from_ref(r.get()).unwrap();
 /// }
 ///
 /// static X : ImmutAfterInitCell<i32> = ImmutAfterInitCell::uninit();
 ///
 /// fn main() {
-///     unsafe { X.init(&123) };
+///     X.init(&123).unwrap();
 ///
 ///     init_rx(ImmutAfterInitRef::new_from_cell(&X));
 ///     assert_eq!(*RX, 123);
@@ -167,7 +223,7 @@ unsafe impl<T: Copy> Sync for ImmutAfterInitCell<T> {}
 /// static RX : ImmutAfterInitRef::<'static, i32> = ImmutAfterInitRef::uninit();
 //
 /// fn init_rx(r : ImmutAfterInitRef<'static, i32>) {
-///     unsafe { RX.init_from_ref(r.get()) };
+///     RX.init_from_ref(r.get()).unwrap();
 /// }
 ///
 /// static X : i32 = 123;
@@ -198,19 +254,18 @@ impl<'a, T> ImmutAfterInitRef<'a, T> {
     }
 
     /// Initialize an uninitialized `ImmutAfterInitRef` instance to point to value
-    /// specified by a regular reference.
-    ///
-    /// Must **not** get called on an already initialized `ImmutAfterInitRef`
-    /// instance!
+    /// specified by a regular reference. Fails with
+    /// [`ImmutAfterInitError::AlreadyInit`] on an already initialized
+    /// `ImmutAfterInitRef` instance.
     ///
     /// * `r` - Reference to the value to make the `ImmutAfterInitRef` to refer
     ///         to. By convention, the referenced value must have been
     ///         initialized already.
-    pub unsafe fn init_from_ref<'b>(&self, r: &'b T)
+    pub fn init_from_ref<'b>(&self, r: &'b T) -> ImmutAfterInitResult<()>
     where
         'b: 'a,
     {
-        self.ptr.init(&(r as *const T));
+        self.ptr.init(&(r as *const T))
     }
 
     /// Create an initialized `ImmutAfterInitRef` instance pointing to a value
@@ -226,9 +281,8 @@ impl<'a, T> ImmutAfterInitRef<'a, T> {
         }
     }
 
-    /// Dereference the referenced value with lifetime propagation. Must **only
-    /// ever** get called on an initialized `ImmutAfterInitRef` instance! Moreover,
-    /// the value referenced must have been initialized as well.
+    /// Dereference the referenced value with lifetime propagation. Panics if
+    /// the `ImmutAfterInitRef` instance has not been initialized yet.
     pub fn get(&self) -> &'a T {
         unsafe { &**self.ptr }
     }
@@ -236,18 +290,18 @@ impl<'a, T> ImmutAfterInitRef<'a, T> {
 
 impl<'a, T: Copy> ImmutAfterInitRef<'a, T> {
     /// Initialize an uninitialized `ImmutAfterInitRef` instance to point to
-    /// value wrapped in a [`ImmutAfterInitCell`].
-    ///
-    /// Must **not** get called on an already initialized `ImmutAfterInitRef` instance!
+    /// value wrapped in a [`ImmutAfterInitCell`]. Fails with
+    /// [`ImmutAfterInitError::AlreadyInit`] on an already initialized
+    /// `ImmutAfterInitRef` instance.
     ///
-    /// * `cell` - The value to make the `ImmutAfterInitRef` to refer to. By
-    ///            convention, the referenced value must have been initialized
-    ///            already.
-    pub unsafe fn init_from_cell<'b>(&self, cell: &'b ImmutAfterInitCell<T>)
+    /// * `cell` - The value to make the `ImmutAfterInitRef` to refer to. It
+    ///            must have been initialized already, or this fails with
+    ///            [`ImmutAfterInitError::Uninitialized`].
+    pub fn init_from_cell<'b>(&self, cell: &'b ImmutAfterInitCell<T>) -> ImmutAfterInitResult<()>
     where
         'b: 'a,
     {
-        self.ptr.init(&(*cell.data.get()).as_ptr());
+        self.ptr.init(&(cell.try_get()? as *const T))
     }
 
     /// Create an initialized `ImmutAfterInitRef` instance pointing to a value
@@ -267,9 +321,8 @@ impl<'a, T: Copy> ImmutAfterInitRef<'a, T> {
 impl<'a, T> Deref for ImmutAfterInitRef<'a, T> {
     type Target = T;
 
-    /// Dereference the referenced value *without* lifetime propagation. Must
-    /// **only ever** get called on an initialized `ImmutAfterInitRef` instance!
-    /// Moreover, the value referenced must have been initialized as well. If
+    /// Dereference the referenced value *without* lifetime propagation. Panics
+    /// if the `ImmutAfterInitRef` instance has not been initialized yet. If
     /// lifetime propagation is needed, use [`ImmutAfterInitRef::get()`].
     fn deref(&self) -> &T {
         self.get()
@@ -4,8 +4,10 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod bitmap;
 pub mod bitmap_allocator;
 pub mod immut_after_init;
 pub mod util;
 
+pub use bitmap::{Bitmap, DynBitmap, FixedBitmap};
 pub use util::{align_up, ffs, halt, overlap, page_align_up, page_offset, zero_mem_region};
@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Synchronous virtio block driver. Requests go through a bounce buffer in
+// shared memory, one at a time, so the device never sees private memory.
+
+use super::queue::{VirtqBuffer, Virtqueue};
+use super::*;
+use crate::error::SvsmError;
+use crate::pci::{pci_enumerate, PciConfigAccess, PciDevice};
+use crate::sev::shared_page::SharedPages;
+use crate::types::PAGE_SIZE;
+
+pub const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// Capacity in sectors, at the start of the device configuration
+const VIRTIO_BLK_CFG_CAPACITY: usize = 0;
+
+// Layout of the bounce buffer: request header and status byte in the first
+// page, data in the second
+const REQ_HEADER_OFFSET: usize = 0;
+const REQ_HEADER_SIZE: usize = 16;
+const REQ_STATUS_OFFSET: usize = REQ_HEADER_SIZE;
+const REQ_DATA_OFFSET: usize = PAGE_SIZE;
+const REQ_DATA_SIZE: usize = PAGE_SIZE;
+
+const VIRTIO_BLK_QUEUE: u16 = 0;
+const VIRTIO_BLK_TIMEOUT_NS: u64 = 5_000_000_000;
+
+fn req_header(req_type: u32, sector: u64) -> [u8; REQ_HEADER_SIZE] {
+    let mut header = [0u8; REQ_HEADER_SIZE];
+    header[0..4].copy_from_slice(&req_type.to_le_bytes());
+    header[8..16].copy_from_slice(&sector.to_le_bytes());
+    header
+}
+
+/// A virtio block device. Not synchronized, callers sharing a device must
+/// put it behind a lock.
+#[derive(Debug)]
+pub struct VirtioBlk {
+    transport: VirtioPciTransport,
+    queue: Virtqueue,
+    bounce: SharedPages,
+    features: u64,
+    capacity: u64,
+}
+
+impl VirtioBlk {
+    pub fn new(cfg: &dyn PciConfigAccess, dev: &PciDevice) -> Result<Self, SvsmError> {
+        let transport = VirtioPciTransport::new(cfg, dev)?;
+        let features = transport.init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+
+        let queue = Virtqueue::new()?;
+        transport.setup_queue(VIRTIO_BLK_QUEUE, &queue)?;
+        let bounce = SharedPages::new(2)?;
+        transport.driver_ok()?;
+
+        let capacity = transport.read_config_u64(VIRTIO_BLK_CFG_CAPACITY)?;
+        log::info!(
+            "virtio-blk {}: {} sectors{}",
+            dev.addr,
+            capacity,
+            if features & VIRTIO_BLK_F_RO != 0 {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            bounce,
+            features,
+            capacity,
+        })
+    }
+
+    /// Size of the device in sectors
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+
+    // Submits a request with `len` bytes of data in the bounce buffer and
+    // waits for its completion
+    fn request(&mut self, req_type: u32, sector: u64, len: usize) -> Result<(), SvsmError> {
+        self.bounce
+            .write_at(REQ_HEADER_OFFSET, &req_header(req_type, sector));
+        self.bounce.write_at(REQ_STATUS_OFFSET, &[0xff]);
+
+        let paddr = self.bounce.paddr();
+        let header = VirtqBuffer {
+            paddr: paddr + REQ_HEADER_OFFSET,
+            len: REQ_HEADER_SIZE as u32,
+            device_writes: false,
+        };
+        let data = VirtqBuffer {
+            paddr: paddr + REQ_DATA_OFFSET,
+            len: len as u32,
+            device_writes: req_type == VIRTIO_BLK_T_IN,
+        };
+        let status = VirtqBuffer {
+            paddr: paddr + REQ_STATUS_OFFSET,
+            len: 1,
+            device_writes: true,
+        };
+
+        if len > 0 {
+            self.queue.submit(&[header, data, status])?;
+        } else {
+            self.queue.submit(&[header, status])?;
+        }
+        self.transport.notify(VIRTIO_BLK_QUEUE)?;
+        if let Err(e) = self.queue.wait_used(VIRTIO_BLK_TIMEOUT_NS) {
+            // The queue is broken now, all later requests fail. The device
+            // might still complete this one, so a later request must never
+            // pick up its completion.
+            let _ = self.transport.fail();
+            return Err(e);
+        }
+
+        let mut status = [0u8];
+        self.bounce.read_at(REQ_STATUS_OFFSET, &mut status);
+        match status[0] {
+            VIRTIO_BLK_S_OK => Ok(()),
+            s => Err(VirtioError::Request(s).into()),
+        }
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), SvsmError> {
+        if !len.is_multiple_of(VIRTIO_BLK_SECTOR_SIZE) {
+            return Err(VirtioError::InvalidArgument.into());
+        }
+        let sectors = (len / VIRTIO_BLK_SECTOR_SIZE) as u64;
+        match sector.checked_add(sectors) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(VirtioError::InvalidArgument.into()),
+        }
+    }
+
+    /// Reads `buf.len()` bytes starting at `sector`. The length must be a
+    /// multiple of the sector size.
+    pub fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SvsmError> {
+        self.check_range(sector, buf.len())?;
+
+        let mut sector = sector;
+        for chunk in buf.chunks_mut(REQ_DATA_SIZE) {
+            self.request(VIRTIO_BLK_T_IN, sector, chunk.len())?;
+            self.bounce.read_at(REQ_DATA_OFFSET, chunk);
+            sector += (chunk.len() / VIRTIO_BLK_SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` starting at `sector`. The length must be a multiple of
+    /// the sector size.
+    pub fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SvsmError> {
+        if self.is_read_only() {
+            return Err(VirtioError::ReadOnly.into());
+        }
+        self.check_range(sector, buf.len())?;
+
+        let mut sector = sector;
+        for chunk in buf.chunks(REQ_DATA_SIZE) {
+            self.bounce.write_at(REQ_DATA_OFFSET, chunk);
+            self.request(VIRTIO_BLK_T_OUT, sector, chunk.len())?;
+            sector += (chunk.len() / VIRTIO_BLK_SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Makes completed writes persistent. Devices without a volatile write
+    /// cache don't offer flushing and need nothing to be done.
+    pub fn flush(&mut self) -> Result<(), SvsmError> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(VIRTIO_BLK_T_FLUSH, 0, 0)
+    }
+}
+
+/// Sets up the first virtio block device found on the PCI bus
+pub fn virtio_blk_probe(cfg: &dyn PciConfigAccess) -> Result<VirtioBlk, SvsmError> {
+    let devices = pci_enumerate(cfg)?;
+    let dev = virtio_pci_find(cfg, &devices, VIRTIO_ID_BLOCK)?.ok_or(VirtioError::NoDevice)?;
+    VirtioBlk::new(cfg, &dev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blk_req_header() {
+        let header = req_header(VIRTIO_BLK_T_OUT, 0x1234_5678_9abc);
+        assert_eq!(
+            header,
+            [1, 0, 0, 0, 0, 0, 0, 0, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12, 0, 0]
+        );
+    }
+}
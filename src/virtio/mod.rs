@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+mod blk;
+mod pci;
+mod queue;
+
+pub use blk::*;
+pub use pci::{virtio_pci_find, VirtioPciTransport};
+
+use crate::error::SvsmError;
+
+pub const VIRTIO_PCI_VENDOR: u16 = 0x1af4;
+// Transitional devices carry the virtio ID in the subsystem ID, modern ones
+// in the PCI device ID
+pub const VIRTIO_PCI_TRANSITIONAL_FIRST: u16 = 0x1000;
+pub const VIRTIO_PCI_TRANSITIONAL_LAST: u16 = 0x103f;
+pub const VIRTIO_PCI_MODERN_BASE: u16 = 0x1040;
+
+pub const VIRTIO_ID_BLOCK: u16 = 2;
+
+// Device status bits
+const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
+const VIRTIO_STATUS_DRIVER: u8 = 2;
+const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
+const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
+const VIRTIO_STATUS_FAILED: u8 = 0x80;
+
+// Device-independent feature bits
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtioError {
+    // No device of the requested type was found
+    NoDevice,
+    // A required virtio PCI capability is missing or points outside its BAR
+    InvalidCapability,
+    // The device did not accept the negotiated features
+    FeaturesRejected,
+    // The queue does not exist, is too small or already in use
+    QueueUnavailable,
+    // The device did not complete a request in time
+    Timeout,
+    // The device completed a request it was never given
+    InvalidCompletion,
+    // The device reported an error status for a request
+    Request(u8),
+    // Invalid arguments for a request, e.g. not a multiple of the block size
+    InvalidArgument,
+    // Writing to a read-only device
+    ReadOnly,
+    // A request failed to complete before, the queue is not used anymore
+    Broken,
+}
+
+impl From<VirtioError> for SvsmError {
+    fn from(e: VirtioError) -> Self {
+        Self::Virtio(e)
+    }
+}
@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Modern (virtio 1.x) PCI transport. The device exposes its register
+// blocks through vendor-specific PCI capabilities pointing into its BARs.
+
+use super::queue::{Virtqueue, QUEUE_SIZE};
+use super::*;
+use crate::error::SvsmError;
+use crate::mmio::{map_mmio, MmioMapping, MmioValue};
+use crate::pci::{
+    PciAddress, PciBar, PciConfigAccess, PciDevice, PCI_COMMAND_MEMORY, PCI_REG_COMMAND,
+};
+
+const PCI_REG_STATUS: u16 = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_REG_CAP_PTR: u16 = 0x34;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+// Guards against capability lists looping back on themselves
+const PCI_MAX_CAPS: usize = 48;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Layout of the common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+const COMMON_CFG_SIZE: u32 = 0x38;
+
+/// Location of a register block described by a virtio PCI capability
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct VirtioPciCap {
+    bar: u8,
+    offset: u32,
+    length: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct VirtioPciCaps {
+    common: Option<VirtioPciCap>,
+    notify: Option<VirtioPciCap>,
+    notify_off_multiplier: u32,
+    device: Option<VirtioPciCap>,
+}
+
+// Walks the capability list of the function at `addr`, taking the first
+// capability of each type as recommended by the specification
+fn find_caps(cfg: &dyn PciConfigAccess, addr: PciAddress) -> Result<VirtioPciCaps, SvsmError> {
+    let mut caps = VirtioPciCaps::default();
+    if cfg.read16(addr, PCI_REG_STATUS)? & PCI_STATUS_CAP_LIST == 0 {
+        return Ok(caps);
+    }
+
+    let mut ptr = cfg.read8(addr, PCI_REG_CAP_PTR)? & !3;
+    for _ in 0..PCI_MAX_CAPS {
+        if ptr == 0 {
+            break;
+        }
+        let reg = ptr as u16;
+        let header = cfg.read32(addr, reg)?;
+        let next = (header >> 8) as u8 & !3;
+
+        if header as u8 == PCI_CAP_ID_VENDOR {
+            let cfg_type = (header >> 24) as u8;
+            let cap = VirtioPciCap {
+                bar: cfg.read8(addr, reg + 4)?,
+                offset: cfg.read32(addr, reg + 8)?,
+                length: cfg.read32(addr, reg + 12)?,
+            };
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG if caps.common.is_none() => caps.common = Some(cap),
+                VIRTIO_PCI_CAP_NOTIFY_CFG if caps.notify.is_none() => {
+                    caps.notify = Some(cap);
+                    caps.notify_off_multiplier = cfg.read32(addr, reg + 16)?;
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG if caps.device.is_none() => caps.device = Some(cap),
+                _ => {}
+            }
+        }
+        ptr = next;
+    }
+
+    Ok(caps)
+}
+
+fn map_cap(
+    bars: &[Option<PciBar>],
+    cap: Option<VirtioPciCap>,
+    min_len: u32,
+) -> Result<MmioMapping, SvsmError> {
+    let cap = cap.ok_or(VirtioError::InvalidCapability)?;
+    let Some(Some(PciBar::Memory { base, size, .. })) = bars.get(cap.bar as usize) else {
+        return Err(VirtioError::InvalidCapability.into());
+    };
+    let end = cap.offset as u64 + cap.length as u64;
+    if cap.length < min_len || end > *size {
+        return Err(VirtioError::InvalidCapability.into());
+    }
+    map_mmio((base + cap.offset as u64).into(), cap.length as usize)
+}
+
+/// Register access to a virtio device through the modern PCI transport
+#[derive(Debug)]
+pub struct VirtioPciTransport {
+    addr: PciAddress,
+    common: MmioMapping,
+    notify: MmioMapping,
+    notify_off_multiplier: u32,
+    device: MmioMapping,
+}
+
+impl VirtioPciTransport {
+    /// Maps the register blocks of `dev` and enables it as a bus master
+    pub fn new(cfg: &dyn PciConfigAccess, dev: &PciDevice) -> Result<Self, SvsmError> {
+        let caps = find_caps(cfg, dev.addr)?;
+        let bars = dev.bars(cfg)?;
+
+        let transport = VirtioPciTransport {
+            addr: dev.addr,
+            common: map_cap(&bars, caps.common, COMMON_CFG_SIZE)?,
+            notify: map_cap(&bars, caps.notify, 2)?,
+            notify_off_multiplier: caps.notify_off_multiplier,
+            device: map_cap(&bars, caps.device, 0)?,
+        };
+
+        let command = cfg.read16(dev.addr, PCI_REG_COMMAND)?;
+        cfg.write16(
+            dev.addr,
+            PCI_REG_COMMAND,
+            command | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER,
+        )?;
+
+        Ok(transport)
+    }
+
+    pub fn pci_addr(&self) -> PciAddress {
+        self.addr
+    }
+
+    fn write_u64(&self, offset: usize, val: u64) -> Result<(), SvsmError> {
+        self.common.write::<u32>(offset, val as u32)?;
+        self.common.write::<u32>(offset + 4, (val >> 32) as u32)
+    }
+
+    pub fn status(&self) -> Result<u8, SvsmError> {
+        self.common.read::<u8>(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) -> Result<(), SvsmError> {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, status)
+    }
+
+    fn add_status(&self, bits: u8) -> Result<(), SvsmError> {
+        self.set_status(self.status()? | bits)
+    }
+
+    /// Resets the device and waits for the reset to complete
+    pub fn reset(&self) -> Result<(), SvsmError> {
+        self.set_status(0)?;
+        while self.status()? != 0 {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn device_features(&self) -> Result<u64, SvsmError> {
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0)?;
+        let low = self.common.read::<u32>(COMMON_DEVICE_FEATURE)?;
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1)?;
+        let high = self.common.read::<u32>(COMMON_DEVICE_FEATURE)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+
+    fn set_driver_features(&self, features: u64) -> Result<(), SvsmError> {
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0)?;
+        self.common
+            .write::<u32>(COMMON_DRIVER_FEATURE, features as u32)?;
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1)?;
+        self.common
+            .write::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32)
+    }
+
+    /// Resets the device and negotiates the subset of `wanted` device
+    /// features it offers, plus the transport features the SVSM needs.
+    /// Returns the negotiated feature set.
+    pub fn init(&self, wanted: u64) -> Result<u64, SvsmError> {
+        self.reset()?;
+        self.add_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER)?;
+
+        let offered = self.device_features()?;
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+            self.add_status(VIRTIO_STATUS_FAILED)?;
+            return Err(VirtioError::FeaturesRejected.into());
+        }
+        // All buffers are in shared memory, so platform restrictions on DMA
+        // are fine
+        let features = offered & (wanted | VIRTIO_F_VERSION_1 | VIRTIO_F_ACCESS_PLATFORM);
+        self.set_driver_features(features)?;
+
+        self.add_status(VIRTIO_STATUS_FEATURES_OK)?;
+        if self.status()? & VIRTIO_STATUS_FEATURES_OK == 0 {
+            self.add_status(VIRTIO_STATUS_FAILED)?;
+            return Err(VirtioError::FeaturesRejected.into());
+        }
+
+        Ok(features)
+    }
+
+    /// Tells the device that the driver is set up
+    pub fn driver_ok(&self) -> Result<(), SvsmError> {
+        self.add_status(VIRTIO_STATUS_DRIVER_OK)
+    }
+
+    /// Tells the device that the driver gave up on it
+    pub fn fail(&self) -> Result<(), SvsmError> {
+        self.add_status(VIRTIO_STATUS_FAILED)
+    }
+
+    /// Configures queue `index` to use `queue`
+    pub fn setup_queue(&self, index: u16, queue: &Virtqueue) -> Result<(), SvsmError> {
+        if index >= self.common.read::<u16>(COMMON_NUM_QUEUES)? {
+            return Err(VirtioError::QueueUnavailable.into());
+        }
+        self.common.write::<u16>(COMMON_QUEUE_SELECT, index)?;
+        let max_size = self.common.read::<u16>(COMMON_QUEUE_SIZE)?;
+        if max_size < QUEUE_SIZE || self.common.read::<u16>(COMMON_QUEUE_ENABLE)? != 0 {
+            return Err(VirtioError::QueueUnavailable.into());
+        }
+
+        self.common.write::<u16>(COMMON_QUEUE_SIZE, QUEUE_SIZE)?;
+        self.write_u64(COMMON_QUEUE_DESC, queue.desc_paddr().into())?;
+        self.write_u64(COMMON_QUEUE_DRIVER, queue.avail_paddr().into())?;
+        self.write_u64(COMMON_QUEUE_DEVICE, queue.used_paddr().into())?;
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1)
+    }
+
+    /// Notifies the device of new buffers in queue `index`
+    pub fn notify(&self, index: u16) -> Result<(), SvsmError> {
+        self.common.write::<u16>(COMMON_QUEUE_SELECT, index)?;
+        let off = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF)?;
+        let offset = (off as usize)
+            .checked_mul(self.notify_off_multiplier as usize)
+            .ok_or(VirtioError::InvalidCapability)?;
+        self.notify.write::<u16>(offset, index)
+    }
+
+    /// Reads a field of the device-specific configuration, retrying until
+    /// the device does not change the configuration during the read
+    pub fn read_config<T: MmioValue>(&self, offset: usize) -> Result<T, SvsmError> {
+        loop {
+            let gen = self.common.read::<u8>(COMMON_CONFIG_GENERATION)?;
+            let val = self.device.read::<T>(offset)?;
+            if self.common.read::<u8>(COMMON_CONFIG_GENERATION)? == gen {
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Reads a 64-bit field of the device-specific configuration with two
+    /// 32-bit accesses
+    pub fn read_config_u64(&self, offset: usize) -> Result<u64, SvsmError> {
+        loop {
+            let gen = self.common.read::<u8>(COMMON_CONFIG_GENERATION)?;
+            let low = self.device.read::<u32>(offset)?;
+            let high = self.device.read::<u32>(offset + 4)?;
+            if self.common.read::<u8>(COMMON_CONFIG_GENERATION)? == gen {
+                return Ok((high as u64) << 32 | low as u64);
+            }
+        }
+    }
+}
+
+impl Drop for VirtioPciTransport {
+    fn drop(&mut self) {
+        // Stop the device from using queue memory which is about to be freed
+        if let Err(e) = self.reset() {
+            log::error!("Failed to reset virtio device {}: {:?}", self.addr, e);
+        }
+    }
+}
+
+/// Finds the first virtio function with device ID `virtio_id`
+pub fn virtio_pci_find(
+    cfg: &dyn PciConfigAccess,
+    devices: &[PciDevice],
+    virtio_id: u16,
+) -> Result<Option<PciDevice>, SvsmError> {
+    for dev in devices.iter().filter(|d| d.vendor == VIRTIO_PCI_VENDOR) {
+        let id = match dev.device {
+            VIRTIO_PCI_TRANSITIONAL_FIRST..=VIRTIO_PCI_TRANSITIONAL_LAST => {
+                cfg.read16(dev.addr, crate::pci::PCI_REG_SUBSYSTEM + 2)?
+            }
+            d => match d.checked_sub(VIRTIO_PCI_MODERN_BASE) {
+                Some(id) => id,
+                None => continue,
+            },
+        };
+        if id == virtio_id {
+            return Ok(Some(*dev));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Configuration space of a single function, as a modern virtio-blk
+    // device in QEMU lays it out
+    struct FakeConfig([u8; 256]);
+
+    impl PciConfigAccess for FakeConfig {
+        fn read32(&self, _addr: PciAddress, reg: u16) -> Result<u32, SvsmError> {
+            let r = reg as usize;
+            Ok(u32::from_le_bytes(self.0[r..r + 4].try_into().unwrap()))
+        }
+
+        fn write32(&self, _addr: PciAddress, _reg: u16, _val: u32) -> Result<(), SvsmError> {
+            Ok(())
+        }
+    }
+
+    fn put_cap(space: &mut [u8; 256], at: usize, next: u8, cfg_type: u8, off: u32) {
+        space[at] = PCI_CAP_ID_VENDOR;
+        space[at + 1] = next;
+        space[at + 2] = 20;
+        space[at + 3] = cfg_type;
+        space[at + 4] = 4;
+        space[at + 8..at + 12].copy_from_slice(&off.to_le_bytes());
+        space[at + 12..at + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_find_caps() {
+        let mut space = [0u8; 256];
+        space[PCI_REG_STATUS as usize] = PCI_STATUS_CAP_LIST as u8;
+        space[PCI_REG_CAP_PTR as usize] = 0x84;
+        // MSI-X capability pointing back to the start of the list
+        space[0x98] = 0x11;
+        space[0x99] = 0x84;
+        put_cap(&mut space, 0x84, 0x70, VIRTIO_PCI_CAP_COMMON_CFG, 0);
+        put_cap(&mut space, 0x70, 0x54, VIRTIO_PCI_CAP_DEVICE_CFG, 0x2000);
+        put_cap(&mut space, 0x54, 0x40, VIRTIO_PCI_CAP_NOTIFY_CFG, 0x3000);
+        space[0x54 + 16..0x54 + 20].copy_from_slice(&4u32.to_le_bytes());
+        // Second common configuration, ignored
+        put_cap(&mut space, 0x40, 0x98, VIRTIO_PCI_CAP_COMMON_CFG, 0x4000);
+
+        let caps = find_caps(&FakeConfig(space), PciAddress::new(0, 3, 0)).unwrap();
+        let cap = |offset| {
+            Some(VirtioPciCap {
+                bar: 4,
+                offset,
+                length: 0x1000,
+            })
+        };
+        assert_eq!(caps.common, cap(0));
+        assert_eq!(caps.device, cap(0x2000));
+        assert_eq!(caps.notify, cap(0x3000));
+        assert_eq!(caps.notify_off_multiplier, 4);
+    }
+}
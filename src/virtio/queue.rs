@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Split virtqueue living in a single page shared with the hypervisor. Only
+// one descriptor chain is in flight at a time, which is all the synchronous
+// drivers in the SVSM need.
+
+use super::VirtioError;
+use crate::address::{PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::sev::shared_page::SharedPages;
+use crate::time::current_time_ns;
+use crate::types::PAGE_SIZE;
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+/// Number of descriptors of the queues set up by the SVSM
+pub const QUEUE_SIZE: u16 = 16;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+// Offsets of the three parts of the queue within the page. The avail ring
+// consists of flags, idx, ring[QUEUE_SIZE] and used_event, the used ring
+// of flags, idx, ring[QUEUE_SIZE] and avail_event.
+const DESC_OFFSET: usize = 0;
+const AVAIL_OFFSET: usize = DESC_OFFSET + size_of::<VirtqDesc>() * QUEUE_SIZE as usize;
+const AVAIL_SIZE: usize = 2 * (3 + QUEUE_SIZE as usize);
+const USED_OFFSET: usize = (AVAIL_OFFSET + AVAIL_SIZE + 3) & !3;
+const USED_SIZE: usize = 6 + size_of::<VirtqUsedElem>() * QUEUE_SIZE as usize;
+
+const _: () = assert!(USED_OFFSET + USED_SIZE <= PAGE_SIZE);
+
+/// One buffer of a descriptor chain
+#[derive(Clone, Copy, Debug)]
+pub struct VirtqBuffer {
+    pub paddr: PhysAddr,
+    pub len: u32,
+    /// Buffer is written by the device
+    pub device_writes: bool,
+}
+
+#[derive(Debug)]
+pub struct Virtqueue {
+    page: SharedPages,
+    avail_idx: u16,
+    used_idx: u16,
+    // Set when the device did not properly complete a chain. The chain might
+    // still be in flight, so its descriptors and buffers must not be reused.
+    broken: bool,
+}
+
+impl Virtqueue {
+    pub fn new() -> Result<Self, SvsmError> {
+        Ok(Virtqueue {
+            page: SharedPages::new(1)?,
+            avail_idx: 0,
+            used_idx: 0,
+            broken: false,
+        })
+    }
+
+    fn vaddr(&self, offset: usize) -> VirtAddr {
+        self.page.vaddr() + offset
+    }
+
+    pub fn desc_paddr(&self) -> PhysAddr {
+        self.page.paddr() + DESC_OFFSET
+    }
+
+    pub fn avail_paddr(&self) -> PhysAddr {
+        self.page.paddr() + AVAIL_OFFSET
+    }
+
+    pub fn used_paddr(&self) -> PhysAddr {
+        self.page.paddr() + USED_OFFSET
+    }
+
+    /// Places a descriptor chain made of `bufs` in the avail ring. The
+    /// device must be notified afterwards.
+    pub fn submit(&mut self, bufs: &[VirtqBuffer]) -> Result<(), SvsmError> {
+        if self.broken {
+            return Err(VirtioError::Broken.into());
+        }
+        if bufs.is_empty() || bufs.len() > QUEUE_SIZE as usize {
+            return Err(VirtioError::InvalidArgument.into());
+        }
+
+        for (i, buf) in bufs.iter().enumerate() {
+            let mut flags = 0;
+            if buf.device_writes {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if i + 1 < bufs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = VirtqDesc {
+                addr: u64::from(buf.paddr),
+                len: buf.len,
+                flags,
+                next: i as u16 + 1,
+            };
+            let ptr = self.vaddr(DESC_OFFSET + i * size_of::<VirtqDesc>());
+            unsafe { ptr::write_volatile(ptr.as_mut_ptr::<VirtqDesc>(), desc) };
+        }
+
+        // The chain always starts at descriptor 0
+        let slot = (self.avail_idx % QUEUE_SIZE) as usize;
+        let ring = self.vaddr(AVAIL_OFFSET + 4 + slot * 2);
+        unsafe { ptr::write_volatile(ring.as_mut_ptr::<u16>(), 0) };
+
+        // Descriptors must be visible before the index update publishes them
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        let idx = self.vaddr(AVAIL_OFFSET + 2);
+        unsafe { ptr::write_volatile(idx.as_mut_ptr::<u16>(), self.avail_idx) };
+        fence(Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Waits until the device returns the chain submitted last and returns
+    /// the number of bytes it claims to have written. The value comes from
+    /// the hypervisor and must not be trusted. After a timeout or a bogus
+    /// completion the queue is broken and takes no more chains.
+    pub fn wait_used(&mut self, timeout_ns: u64) -> Result<u32, SvsmError> {
+        let ret = self.wait_used_elem(timeout_ns);
+        if ret.is_err() {
+            self.broken = true;
+        }
+        ret
+    }
+
+    fn wait_used_elem(&mut self, timeout_ns: u64) -> Result<u32, SvsmError> {
+        let start = current_time_ns();
+        let idx_ptr = self.vaddr(USED_OFFSET + 2);
+
+        loop {
+            let idx = unsafe { ptr::read_volatile(idx_ptr.as_ptr::<u16>()) };
+            if idx != self.used_idx {
+                break;
+            }
+            if current_time_ns().wrapping_sub(start) > timeout_ns {
+                return Err(VirtioError::Timeout.into());
+            }
+            spin_loop();
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = (self.used_idx % QUEUE_SIZE) as usize;
+        let elem_ptr = self.vaddr(USED_OFFSET + 4 + slot * size_of::<VirtqUsedElem>());
+        let elem = unsafe { ptr::read_volatile(elem_ptr.as_ptr::<VirtqUsedElem>()) };
+        self.used_idx = self.used_idx.wrapping_add(1);
+
+        if elem.id != 0 {
+            return Err(VirtioError::InvalidCompletion.into());
+        }
+        Ok(elem.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtq_layout() {
+        assert_eq!(size_of::<VirtqDesc>(), 16);
+        assert_eq!(AVAIL_OFFSET, 256);
+        assert_eq!(USED_OFFSET, 296);
+        assert_eq!(USED_OFFSET % 4, 0);
+    }
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+extern crate alloc;
+
+pub mod tpm2;
+
+use crate::crypto::sha384::SHA384_DIGEST_SIZE;
+use crate::locking::SpinLock;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use tpm2::Tpm2;
+
+pub use tpm2::{TPM2_MAX_COMMAND_SIZE, TPM2_MAX_LOCALITY};
+
+static VTPM: SpinLock<Tpm2> = SpinLock::new(Tpm2::new());
+static VTPM_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Whether the vTPM protocol is offered to the guest
+pub fn vtpm_enabled() -> bool {
+    VTPM_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Hides the vTPM from the guest. Must be called before the guest runs.
+pub fn vtpm_disable() {
+    VTPM_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Runs a TPM 2.0 command for the guest and returns the response. Commands
+/// of all CPUs are serialized.
+pub fn vtpm_send_command(locality: u8, cmd: &[u8]) -> Vec<u8> {
+    VTPM.lock().execute(locality, cmd)
+}
+
+/// Returns the SHA-384 digest over the current PCR values without stopping
+/// the vTPM
+pub fn vtpm_pcr_digest() -> [u8; SHA384_DIGEST_SIZE] {
+    VTPM.lock().pcr_digest()
+}
+
+/// Stops the vTPM for good and returns the SHA-384 digest over its PCRs
+pub fn vtpm_finalize() -> [u8; SHA384_DIGEST_SIZE] {
+    VTPM.lock().finalize()
+}
@@ -0,0 +1,679 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+// Interpreter for the subset of TPM 2.0 commands needed for measured boot:
+// startup, self test, random numbers, capabilities and a SHA-384 PCR bank
+// which can be extended and read. There are no keys, NV storage or
+// sessions other than the empty password session, so the TPM can not sign
+// quotes. All state lives in SVSM memory and is lost on reset.
+
+extern crate alloc;
+
+use crate::crypto::rng::rng_fill;
+use crate::crypto::sha384::{Sha384, SHA384_DIGEST_SIZE};
+use alloc::vec::Vec;
+
+/// Largest command and response the TPM handles
+pub const TPM2_MAX_COMMAND_SIZE: usize = 4096;
+
+pub const TPM2_NUM_PCRS: usize = 24;
+// Bytes of the PCR bitmap in a TPMS_PCR_SELECTION
+const PCR_SELECT_SIZE: usize = TPM2_NUM_PCRS / 8;
+// Digests returned by a single TPM2_PCR_Read
+const PCR_READ_MAX: usize = 8;
+
+pub const TPM2_MAX_LOCALITY: u8 = 4;
+
+const TPM_HEADER_SIZE: usize = 10;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+
+const TPM_CC_SELF_TEST: u32 = 0x143;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+const TPM_CC_GET_CAPABILITY: u32 = 0x17a;
+const TPM_CC_GET_RANDOM: u32 = 0x17b;
+const TPM_CC_PCR_READ: u32 = 0x17e;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+
+// Implemented commands in ascending order with the number of handles they
+// take, for TPM_CAP_COMMANDS
+const TPM_COMMANDS: &[(u32, u32)] = &[
+    (TPM_CC_SELF_TEST, 0),
+    (TPM_CC_STARTUP, 0),
+    (TPM_CC_SHUTDOWN, 0),
+    (TPM_CC_GET_CAPABILITY, 0),
+    (TPM_CC_GET_RANDOM, 0),
+    (TPM_CC_PCR_READ, 0),
+    (TPM_CC_PCR_EXTEND, 1),
+];
+// Shift of the handle count in a TPMA_CC
+const TPMA_CC_CHANDLES_SHIFT: u32 = 25;
+
+pub const TPM_RC_SUCCESS: u32 = 0x000;
+pub const TPM_RC_BAD_TAG: u32 = 0x01e;
+pub const TPM_RC_INITIALIZE: u32 = 0x100;
+pub const TPM_RC_FAILURE: u32 = 0x101;
+pub const TPM_RC_AUTH_MISSING: u32 = 0x125;
+pub const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+pub const TPM_RC_COMMAND_CODE: u32 = 0x143;
+pub const TPM_RC_AUTH_CONTEXT: u32 = 0x145;
+pub const TPM_RC_HASH: u32 = 0x083;
+pub const TPM_RC_VALUE: u32 = 0x084;
+pub const TPM_RC_HANDLE: u32 = 0x08b;
+pub const TPM_RC_AUTH_FAIL: u32 = 0x08e;
+pub const TPM_RC_SIZE: u32 = 0x095;
+pub const TPM_RC_INSUFFICIENT: u32 = 0x09a;
+pub const TPM_RC_LOCALITY: u32 = 0x907;
+
+const TPM_ALG_SHA384: u16 = 0x000c;
+
+const TPM_SU_CLEAR: u16 = 0;
+const TPM_SU_STATE: u16 = 1;
+
+const TPM_RS_PW: u32 = 0x4000_0009;
+// Session attribute which keeps the session open, echoed in the response
+const TPMA_SESSION_CONTINUE: u8 = 1;
+
+const TPM_CAP_COMMANDS: u32 = 2;
+const TPM_CAP_PCRS: u32 = 5;
+const TPM_CAP_TPM_PROPERTIES: u32 = 6;
+
+const TPM_PT_FAMILY_INDICATOR: u32 = 0x100;
+const TPM_PT_LEVEL: u32 = 0x101;
+const TPM_PT_REVISION: u32 = 0x102;
+const TPM_PT_MANUFACTURER: u32 = 0x105;
+const TPM_PT_PCR_COUNT: u32 = 0x112;
+const TPM_PT_MAX_COMMAND_SIZE: u32 = 0x11e;
+const TPM_PT_MAX_RESPONSE_SIZE: u32 = 0x11f;
+const TPM_PT_MAX_DIGEST: u32 = 0x120;
+const TPM_PT_TOTAL_COMMANDS: u32 = 0x129;
+
+// Fixed properties in ascending order, for TPM_CAP_TPM_PROPERTIES
+const TPM_PROPERTIES: &[(u32, u32)] = &[
+    // "2.0"
+    (TPM_PT_FAMILY_INDICATOR, 0x322e_3000),
+    (TPM_PT_LEVEL, 0),
+    (TPM_PT_REVISION, 138),
+    // "SVSM"
+    (TPM_PT_MANUFACTURER, 0x5356_534d),
+    (TPM_PT_PCR_COUNT, TPM2_NUM_PCRS as u32),
+    (TPM_PT_MAX_COMMAND_SIZE, TPM2_MAX_COMMAND_SIZE as u32),
+    (TPM_PT_MAX_RESPONSE_SIZE, TPM2_MAX_COMMAND_SIZE as u32),
+    (TPM_PT_MAX_DIGEST, SHA384_DIGEST_SIZE as u32),
+    (TPM_PT_TOTAL_COMMANDS, TPM_COMMANDS.len() as u32),
+];
+
+type TpmResult<T> = Result<T, u32>;
+
+// Bitmask of the localities which may extend a PCR, after the PC Client
+// Platform TPM Profile. The PCRs of the dynamic root of trust are reserved
+// for the higher localities.
+fn pcr_extend_localities(pcr: usize) -> u8 {
+    match pcr {
+        17..=19 => 0b11100,
+        20 => 0b01110,
+        21 | 22 => 0b00100,
+        _ => 0b11111,
+    }
+}
+
+// PCRs of the dynamic root of trust start out with all bits set, so that
+// they can not be mistaken for PCRs reset by a dynamic launch
+fn pcr_reset_value(pcr: usize) -> [u8; SHA384_DIGEST_SIZE] {
+    match pcr {
+        17..=22 => [0xff; SHA384_DIGEST_SIZE],
+        _ => [0; SHA384_DIGEST_SIZE],
+    }
+}
+
+// Parser for the big-endian TPM wire format
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> TpmResult<&'a [u8]> {
+        if n > self.buf.len() {
+            return Err(TPM_RC_INSUFFICIENT);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> TpmResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> TpmResult<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> TpmResult<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn tpm2b(&mut self) -> TpmResult<&'a [u8]> {
+        let size = self.u16()?;
+        self.bytes(size.into())
+    }
+
+    // All parameters must have been consumed
+    fn finish(&self) -> TpmResult<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(TPM_RC_SIZE)
+        }
+    }
+}
+
+// Builder for the parameters of a response
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn tpm2b(&mut self, data: &[u8]) {
+        self.u16(data.len() as u16);
+        self.buf.extend_from_slice(data);
+    }
+}
+
+/// Parsed command, with the handles and sessions split off
+struct Command<'a> {
+    sessions: bool,
+    params: Reader<'a>,
+}
+
+/// TPM 2.0 device state
+#[derive(Debug)]
+pub struct Tpm2 {
+    started: bool,
+    // Set once the guest exited, all commands fail from then on
+    finalized: bool,
+    pcrs: [[u8; SHA384_DIGEST_SIZE]; TPM2_NUM_PCRS],
+    pcr_update_counter: u32,
+}
+
+impl Tpm2 {
+    pub const fn new() -> Self {
+        Tpm2 {
+            started: false,
+            finalized: false,
+            pcrs: [[0; SHA384_DIGEST_SIZE]; TPM2_NUM_PCRS],
+            pcr_update_counter: 0,
+        }
+    }
+
+    /// Executes the TPM command in `cmd` on behalf of `locality` and returns
+    /// the response. Errors are reported in the response code, so there
+    /// always is a response.
+    pub fn execute(&mut self, locality: u8, cmd: &[u8]) -> Vec<u8> {
+        let mut out = Writer::default();
+        let (tag, rc) = match self.dispatch(locality, cmd, &mut out) {
+            Ok(sessions) => (sessions, TPM_RC_SUCCESS),
+            Err(rc) => {
+                out.buf.clear();
+                (false, rc)
+            }
+        };
+
+        let tag = if tag {
+            TPM_ST_SESSIONS
+        } else {
+            TPM_ST_NO_SESSIONS
+        };
+        let size = TPM_HEADER_SIZE + out.buf.len();
+        let mut resp = Vec::with_capacity(size);
+        resp.extend_from_slice(&tag.to_be_bytes());
+        resp.extend_from_slice(&(size as u32).to_be_bytes());
+        resp.extend_from_slice(&rc.to_be_bytes());
+        resp.extend_from_slice(&out.buf);
+        resp
+    }
+
+    /// Returns the SHA-384 digest over all PCRs, in order
+    pub fn pcr_digest(&self) -> [u8; SHA384_DIGEST_SIZE] {
+        let mut ctx = Sha384::new();
+        for pcr in &self.pcrs {
+            ctx.update(pcr);
+        }
+        ctx.finalize()
+    }
+
+    /// Stops the TPM and returns the SHA-384 digest over all PCRs, in order
+    pub fn finalize(&mut self) -> [u8; SHA384_DIGEST_SIZE] {
+        self.finalized = true;
+        self.pcr_digest()
+    }
+
+    // Returns whether the response carries sessions
+    fn dispatch(&mut self, locality: u8, cmd: &[u8], out: &mut Writer) -> TpmResult<bool> {
+        if self.finalized {
+            return Err(TPM_RC_FAILURE);
+        }
+        if locality > TPM2_MAX_LOCALITY {
+            return Err(TPM_RC_LOCALITY);
+        }
+        if cmd.len() < TPM_HEADER_SIZE || cmd.len() > TPM2_MAX_COMMAND_SIZE {
+            return Err(TPM_RC_COMMAND_SIZE);
+        }
+
+        let mut header = Reader { buf: cmd };
+        let tag = header.u16()?;
+        let size = header.u32()?;
+        let code = header.u32()?;
+
+        let sessions = match tag {
+            TPM_ST_NO_SESSIONS => false,
+            TPM_ST_SESSIONS => true,
+            _ => return Err(TPM_RC_BAD_TAG),
+        };
+        if size as usize != cmd.len() {
+            return Err(TPM_RC_COMMAND_SIZE);
+        }
+        if !self.started && code != TPM_CC_STARTUP {
+            return Err(TPM_RC_INITIALIZE);
+        }
+
+        let mut cmd = Command {
+            sessions,
+            params: header,
+        };
+        match code {
+            TPM_CC_STARTUP => self.startup(&mut cmd)?,
+            TPM_CC_SHUTDOWN => self.shutdown(&mut cmd)?,
+            TPM_CC_SELF_TEST => self.self_test(&mut cmd)?,
+            TPM_CC_GET_RANDOM => self.get_random(&mut cmd, out)?,
+            TPM_CC_GET_CAPABILITY => self.get_capability(&mut cmd, out)?,
+            TPM_CC_PCR_READ => self.pcr_read(&mut cmd, out)?,
+            TPM_CC_PCR_EXTEND => return self.pcr_extend(locality, &mut cmd, out),
+            _ => return Err(TPM_RC_COMMAND_CODE),
+        }
+
+        Ok(false)
+    }
+
+    // Commands without authorized handles take no sessions, as audit and
+    // encryption sessions are not supported
+    fn no_sessions(cmd: &Command<'_>) -> TpmResult<()> {
+        if cmd.sessions {
+            Err(TPM_RC_AUTH_CONTEXT)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn startup(&mut self, cmd: &mut Command<'_>) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+        let startup_type = cmd.params.u16()?;
+        cmd.params.finish()?;
+
+        if self.started {
+            return Err(TPM_RC_INITIALIZE);
+        }
+
+        match startup_type {
+            TPM_SU_CLEAR => {}
+            // There is no saved state to resume from
+            TPM_SU_STATE => return Err(TPM_RC_VALUE),
+            _ => return Err(TPM_RC_VALUE),
+        }
+
+        for (pcr, value) in self.pcrs.iter_mut().enumerate() {
+            *value = pcr_reset_value(pcr);
+        }
+        self.pcr_update_counter = 0;
+        self.started = true;
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, cmd: &mut Command<'_>) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+        let shutdown_type = cmd.params.u16()?;
+        cmd.params.finish()?;
+
+        match shutdown_type {
+            TPM_SU_CLEAR | TPM_SU_STATE => Ok(()),
+            _ => Err(TPM_RC_VALUE),
+        }
+    }
+
+    fn self_test(&mut self, cmd: &mut Command<'_>) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+        // fullTest, there is nothing to test
+        cmd.params.u8()?;
+        cmd.params.finish()
+    }
+
+    fn get_random(&mut self, cmd: &mut Command<'_>, out: &mut Writer) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+        let requested = cmd.params.u16()?;
+        cmd.params.finish()?;
+
+        // The response is a TPM2B_DIGEST, which limits the size
+        let mut buf = [0u8; SHA384_DIGEST_SIZE];
+        let buf = &mut buf[..usize::from(requested).min(SHA384_DIGEST_SIZE)];
+        rng_fill(buf).map_err(|_| TPM_RC_FAILURE)?;
+        out.tpm2b(buf);
+
+        Ok(())
+    }
+
+    fn get_capability(&mut self, cmd: &mut Command<'_>, out: &mut Writer) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+        let capability = cmd.params.u32()?;
+        let property = cmd.params.u32()?;
+        let count = cmd.params.u32()? as usize;
+        cmd.params.finish()?;
+
+        let mut data = Writer::default();
+        data.u32(capability);
+        let more = match capability {
+            TPM_CAP_COMMANDS => {
+                let cmds: Vec<_> = TPM_COMMANDS
+                    .iter()
+                    .filter(|(code, _)| *code >= property)
+                    .collect();
+                let n = cmds.len().min(count);
+                data.u32(n as u32);
+                for (code, handles) in &cmds[..n] {
+                    data.u32(code | (handles << TPMA_CC_CHANDLES_SHIFT));
+                }
+                n < cmds.len()
+            }
+            TPM_CAP_PCRS => {
+                data.u32(1);
+                data.u16(TPM_ALG_SHA384);
+                data.u8(PCR_SELECT_SIZE as u8);
+                data.buf.extend_from_slice(&[0xff; PCR_SELECT_SIZE]);
+                false
+            }
+            TPM_CAP_TPM_PROPERTIES => {
+                let props: Vec<_> = TPM_PROPERTIES
+                    .iter()
+                    .filter(|(prop, _)| *prop >= property)
+                    .collect();
+                let n = props.len().min(count);
+                data.u32(n as u32);
+                for (prop, value) in &props[..n] {
+                    data.u32(*prop);
+                    data.u32(*value);
+                }
+                n < props.len()
+            }
+            _ => return Err(TPM_RC_VALUE),
+        };
+
+        out.u8(more.into());
+        out.buf.extend_from_slice(&data.buf);
+
+        Ok(())
+    }
+
+    fn pcr_read(&mut self, cmd: &mut Command<'_>, out: &mut Writer) -> TpmResult<()> {
+        Self::no_sessions(cmd)?;
+
+        let count = cmd.params.u32()?;
+        if count > PCR_READ_MAX as u32 {
+            return Err(TPM_RC_SIZE);
+        }
+
+        // The selection is returned with the bits of the PCRs which were not
+        // read cleared
+        let mut selection = Writer::default();
+        let mut digests: Vec<usize> = Vec::new();
+        selection.u32(count);
+        for _ in 0..count {
+            let alg = cmd.params.u16()?;
+            let size = cmd.params.u8()? as usize;
+            if size > PCR_SELECT_SIZE + 1 {
+                return Err(TPM_RC_VALUE);
+            }
+            let mut select = [0u8; PCR_SELECT_SIZE + 1];
+            select[..size].copy_from_slice(cmd.params.bytes(size)?);
+
+            for pcr in 0..size * 8 {
+                let (byte, bit) = (pcr / 8, 1 << (pcr % 8));
+                if select[byte] & bit == 0 {
+                    continue;
+                }
+                if alg == TPM_ALG_SHA384 && pcr < TPM2_NUM_PCRS && digests.len() < PCR_READ_MAX {
+                    digests.push(pcr);
+                } else {
+                    select[byte] &= !bit;
+                }
+            }
+
+            selection.u16(alg);
+            selection.u8(size as u8);
+            selection.buf.extend_from_slice(&select[..size]);
+        }
+        cmd.params.finish()?;
+
+        out.u32(self.pcr_update_counter);
+        out.buf.extend_from_slice(&selection.buf);
+        out.u32(digests.len() as u32);
+        for pcr in digests {
+            out.tpm2b(&self.pcrs[pcr]);
+        }
+
+        Ok(())
+    }
+
+    fn pcr_extend(
+        &mut self,
+        locality: u8,
+        cmd: &mut Command<'_>,
+        out: &mut Writer,
+    ) -> TpmResult<bool> {
+        let handle = cmd.params.u32()?;
+        let attrs = Self::password_session(cmd)?;
+
+        let pcr = handle as usize;
+        if pcr >= TPM2_NUM_PCRS {
+            return Err(TPM_RC_HANDLE);
+        }
+        if pcr_extend_localities(pcr) & (1 << locality) == 0 {
+            return Err(TPM_RC_LOCALITY);
+        }
+
+        let count = cmd.params.u32()?;
+        let mut extend = Vec::new();
+        for _ in 0..count {
+            if cmd.params.u16()? != TPM_ALG_SHA384 {
+                return Err(TPM_RC_HASH);
+            }
+            extend.push(cmd.params.bytes(SHA384_DIGEST_SIZE)?);
+        }
+        cmd.params.finish()?;
+
+        for digest in extend {
+            let mut ctx = Sha384::new();
+            ctx.update(&self.pcrs[pcr]);
+            ctx.update(digest);
+            self.pcrs[pcr] = ctx.finalize();
+            self.pcr_update_counter = self.pcr_update_counter.wrapping_add(1);
+        }
+
+        // No response parameters, followed by the password session
+        out.u32(0);
+        out.tpm2b(&[]);
+        out.u8(attrs & TPMA_SESSION_CONTINUE);
+        out.tpm2b(&[]);
+
+        Ok(true)
+    }
+
+    // Parses the authorization area of a command with one authorized
+    // handle. The only authorization supported is the empty password all
+    // PCRs have. Returns the session attributes.
+    fn password_session(cmd: &mut Command<'_>) -> TpmResult<u8> {
+        if !cmd.sessions {
+            return Err(TPM_RC_AUTH_MISSING);
+        }
+
+        let size = cmd.params.u32()? as usize;
+        let mut area = Reader {
+            buf: cmd.params.bytes(size)?,
+        };
+        if area.u32()? != TPM_RS_PW {
+            return Err(TPM_RC_HANDLE);
+        }
+        let nonce = area.tpm2b()?;
+        let attrs = area.u8()?;
+        let hmac = area.tpm2b()?;
+        area.finish()?;
+
+        if !nonce.is_empty() {
+            return Err(TPM_RC_SIZE);
+        }
+        if !hmac.is_empty() {
+            return Err(TPM_RC_AUTH_FAIL);
+        }
+
+        Ok(attrs)
+    }
+}
+
+impl Default for Tpm2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(tag: u16, code: u32, body: &[u8]) -> Vec<u8> {
+        let mut cmd = Vec::new();
+        cmd.extend_from_slice(&tag.to_be_bytes());
+        cmd.extend_from_slice(&((TPM_HEADER_SIZE + body.len()) as u32).to_be_bytes());
+        cmd.extend_from_slice(&code.to_be_bytes());
+        cmd.extend_from_slice(body);
+        cmd
+    }
+
+    fn response_code(resp: &[u8]) -> u32 {
+        assert_eq!(
+            u32::from_be_bytes(resp[2..6].try_into().unwrap()) as usize,
+            resp.len()
+        );
+        u32::from_be_bytes(resp[6..10].try_into().unwrap())
+    }
+
+    fn startup(tpm: &mut Tpm2) -> u32 {
+        let cmd = command(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP, &[0, 0]);
+        response_code(&tpm.execute(0, &cmd))
+    }
+
+    fn extend(tpm: &mut Tpm2, locality: u8, pcr: u32, digest: &[u8]) -> u32 {
+        let mut body = Vec::new();
+        body.extend_from_slice(&pcr.to_be_bytes());
+        // Password session with empty nonce and hmac
+        body.extend_from_slice(&9u32.to_be_bytes());
+        body.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+        body.extend_from_slice(&[0, 0, TPMA_SESSION_CONTINUE, 0, 0]);
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&TPM_ALG_SHA384.to_be_bytes());
+        body.extend_from_slice(digest);
+        let cmd = command(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND, &body);
+        response_code(&tpm.execute(locality, &cmd))
+    }
+
+    fn read(tpm: &mut Tpm2, pcr: usize) -> Vec<u8> {
+        let mut select = [0u8; PCR_SELECT_SIZE];
+        select[pcr / 8] = 1 << (pcr % 8);
+        let mut body = Vec::new();
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&TPM_ALG_SHA384.to_be_bytes());
+        body.push(PCR_SELECT_SIZE as u8);
+        body.extend_from_slice(&select);
+        let cmd = command(TPM_ST_NO_SESSIONS, TPM_CC_PCR_READ, &body);
+
+        let resp = tpm.execute(0, &cmd);
+        assert_eq!(response_code(&resp), TPM_RC_SUCCESS);
+        // Update counter, selection, digest count and digest size
+        let digest = TPM_HEADER_SIZE + 4 + 4 + 3 + PCR_SELECT_SIZE + 4 + 2;
+        resp[digest..].to_vec()
+    }
+
+    #[test]
+    fn test_startup() {
+        let mut tpm = Tpm2::new();
+        let cmd = command(TPM_ST_NO_SESSIONS, TPM_CC_SELF_TEST, &[1]);
+        assert_eq!(response_code(&tpm.execute(0, &cmd)), TPM_RC_INITIALIZE);
+
+        assert_eq!(startup(&mut tpm), TPM_RC_SUCCESS);
+        assert_eq!(startup(&mut tpm), TPM_RC_INITIALIZE);
+        assert_eq!(response_code(&tpm.execute(0, &cmd)), TPM_RC_SUCCESS);
+
+        // Malformed headers
+        assert_eq!(
+            response_code(&tpm.execute(0, &cmd[..8])),
+            TPM_RC_COMMAND_SIZE
+        );
+        let mut bad = cmd.clone();
+        bad[0] = 0;
+        assert_eq!(response_code(&tpm.execute(0, &bad)), TPM_RC_BAD_TAG);
+        let cmd = command(TPM_ST_NO_SESSIONS, 0x1ff, &[]);
+        assert_eq!(response_code(&tpm.execute(0, &cmd)), TPM_RC_COMMAND_CODE);
+    }
+
+    #[test]
+    fn test_pcr_extend() {
+        let mut tpm = Tpm2::new();
+        startup(&mut tpm);
+        assert_eq!(read(&mut tpm, 0), [0; SHA384_DIGEST_SIZE]);
+        assert_eq!(read(&mut tpm, 17), [0xff; SHA384_DIGEST_SIZE]);
+
+        let digest = [0x5a; SHA384_DIGEST_SIZE];
+        assert_eq!(extend(&mut tpm, 0, 0, &digest), TPM_RC_SUCCESS);
+        let mut expected = [0u8; 2 * SHA384_DIGEST_SIZE];
+        expected[SHA384_DIGEST_SIZE..].copy_from_slice(&digest);
+        assert_eq!(read(&mut tpm, 0), crate::crypto::sha384::sha384(&expected));
+
+        // Dynamic launch PCRs are reserved for higher localities
+        assert_eq!(extend(&mut tpm, 0, 17, &digest), TPM_RC_LOCALITY);
+        assert_eq!(extend(&mut tpm, 4, 17, &digest), TPM_RC_SUCCESS);
+        assert_eq!(extend(&mut tpm, 0, 24, &digest), TPM_RC_HANDLE);
+        assert_eq!(extend(&mut tpm, 0, 0, &digest[..32]), TPM_RC_INSUFFICIENT);
+    }
+
+    #[test]
+    fn test_pcr_digest() {
+        let mut tpm = Tpm2::new();
+        startup(&mut tpm);
+        let before = tpm.pcr_digest();
+        let digest = [0x5a; SHA384_DIGEST_SIZE];
+        assert_eq!(extend(&mut tpm, 0, 3, &digest), TPM_RC_SUCCESS);
+        let after = tpm.pcr_digest();
+        assert_ne!(before, after);
+
+        // Finalizing reports the same digest and stops the TPM
+        assert_eq!(tpm.finalize(), after);
+        let cmd = command(TPM_ST_NO_SESSIONS, TPM_CC_SELF_TEST, &[1]);
+        assert_eq!(response_code(&tpm.execute(0, &cmd)), TPM_RC_FAILURE);
+    }
+}
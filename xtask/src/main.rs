@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2022-2023 SUSE LLC
+//
+// Author: Joerg Roedel <jroedel@suse.de>
+
+//! Build orchestration for the SVSM, invoked as `cargo xtask <command>`.
+//! It does the same steps as the Makefile, but can also launch the result.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+const USAGE: &str = "\
+Usage: cargo xtask <command> [options]
+
+Commands:
+  build             Build stage1, stage2 and the kernel into svsm.bin
+  run               Build, then launch svsm.bin in an SEV-SNP guest
+  clean             Remove all build artifacts
+
+Build options:
+  --release         Build the release profile
+  --features LIST   Extra cargo features for stage2 and the kernel
+  --fs FILE         Embed FILE as the SVSM file-system image
+  --silent-stage2   Build stage2 without console output
+  --sign-key FILE   Sign the kernel with the Ed25519 key in FILE (PEM) and
+                    make stage2 launch only kernels signed with it
+
+Run options:
+  --qemu PATH       QEMU binary with SVSM support (default: qemu-system-x86_64)
+  --ovmf-code PATH  OVMF code image
+  --ovmf-vars PATH  OVMF variable store image
+  --image PATH      Guest disk image in qcow2 format
+  --mem SIZE        Guest memory size (default: 8G)
+  --smp N           Number of guest CPUs (default: 4)
+  -- ARGS           Pass the remaining arguments to QEMU
+";
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Default)]
+struct BuildOpts {
+    release: bool,
+    features: Option<String>,
+    fs_file: Option<PathBuf>,
+    silent_stage2: bool,
+    sign_key: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct RunOpts {
+    qemu: PathBuf,
+    ovmf_code: Option<PathBuf>,
+    ovmf_vars: Option<PathBuf>,
+    image: Option<PathBuf>,
+    mem: String,
+    smp: u32,
+    extra: Vec<OsString>,
+}
+
+impl Default for RunOpts {
+    fn default() -> Self {
+        RunOpts {
+            qemu: PathBuf::from("qemu-system-x86_64"),
+            ovmf_code: None,
+            ovmf_vars: None,
+            image: None,
+            mem: String::from("8G"),
+            smp: 4,
+            extra: Vec::new(),
+        }
+    }
+}
+
+fn root_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn run_cmd(cmd: &mut Command) -> Result<()> {
+    eprintln!("+ {:?}", cmd);
+    let status = cmd
+        .current_dir(root_dir())
+        .status()
+        .map_err(|e| format!("failed to run {:?}: {}", cmd.get_program(), e))?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("{:?} failed: {}", cmd.get_program(), status)),
+    }
+}
+
+fn cargo_build(
+    opts: &BuildOpts,
+    bin: &str,
+    extra_features: &[&str],
+    envs: &[(&str, String)],
+) -> Result<PathBuf> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    let mut cmd = Command::new(cargo);
+    cmd.args(["build", "--bin", bin]);
+    cmd.envs(envs.iter().cloned());
+    if opts.release {
+        cmd.arg("--release");
+    }
+    let features: Vec<&str> = opts
+        .features
+        .iter()
+        .map(String::as_str)
+        .chain(extra_features.iter().copied())
+        .collect();
+    if !features.is_empty() {
+        cmd.args(["--features", &features.join(",")]);
+    }
+    run_cmd(&mut cmd)?;
+
+    let profile = if opts.release { "release" } else { "debug" };
+    Ok(root_dir()
+        .join("target/svsm-target")
+        .join(profile)
+        .join(bin))
+}
+
+fn objcopy(args: &[&str], input: &Path, output: &str) -> Result<()> {
+    run_cmd(Command::new("objcopy").args(args).arg(input).arg(output))
+}
+
+// Returns the raw Ed25519 public key of a PEM private key as hex string
+fn signing_pubkey(key: &Path) -> Result<String> {
+    let out = Command::new("openssl")
+        .args(["pkey", "-pubout", "-outform", "DER", "-in"])
+        .arg(key)
+        .output()
+        .map_err(|e| format!("failed to run openssl: {}", e))?;
+    // The DER encoding ends with the 32 byte key
+    if !out.status.success() || out.stdout.len() < 32 {
+        return Err(format!("failed to read public key from {}", key.display()));
+    }
+
+    Ok(out.stdout[out.stdout.len() - 32..]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+fn build(opts: &BuildOpts) -> Result<()> {
+    let root = root_dir();
+
+    let stage2_features: &[&str] = if opts.silent_stage2 {
+        &["stage2-silent"]
+    } else {
+        &[]
+    };
+    let mut stage2_envs = Vec::new();
+    if let Some(key) = &opts.sign_key {
+        stage2_envs.push(("SVSM_KERNEL_SIGNING_KEY", signing_pubkey(key)?));
+    }
+    let stage2 = cargo_build(opts, "stage2", stage2_features, &stage2_envs)?;
+    objcopy(&["-O", "binary"], &stage2, "stage1/stage2.bin")?;
+
+    let kernel = cargo_build(opts, "svsm", &[], &[])?;
+    objcopy(
+        &["-O", "elf64-x86-64", "--strip-unneeded"],
+        &kernel,
+        "stage1/kernel.elf",
+    )?;
+
+    // stage1.S includes the file-system image unconditionally
+    let fs_bin = root.join("stage1/svsm-fs.bin");
+    match &opts.fs_file {
+        Some(file) => fs::copy(file, &fs_bin).map(|_| ()),
+        None => fs::write(&fs_bin, []),
+    }
+    .map_err(|e| format!("failed to create {}: {}", fs_bin.display(), e))?;
+
+    // stage1.S includes the signature unconditionally too, stage2 ignores
+    // it when it was built without a key
+    match &opts.sign_key {
+        Some(key) => {
+            run_cmd(Command::new("openssl").args([
+                "dgst",
+                "-sha384",
+                "-binary",
+                "-out",
+                "stage1/kernel.digest",
+                "stage1/kernel.elf",
+            ]))?;
+            run_cmd(
+                Command::new("openssl")
+                    .args(["pkeyutl", "-sign", "-rawin", "-inkey"])
+                    .arg(key)
+                    .args(["-in", "stage1/kernel.digest", "-out", "stage1/kernel.sig"]),
+            )?;
+        }
+        None => fs::write(root.join("stage1/kernel.sig"), [])
+            .map_err(|e| format!("failed to create stage1/kernel.sig: {}", e))?,
+    }
+
+    run_cmd(Command::new("cc").args(["-O3", "-Wall", "-o", "utils/gen_meta", "utils/gen_meta.c"]))?;
+    run_cmd(Command::new("./utils/gen_meta").arg("stage1/meta.bin"))?;
+
+    run_cmd(Command::new("cc").args(["-c", "-o", "stage1/stage1.o", "stage1/stage1.S"]))?;
+    run_cmd(Command::new("cc").args(["-c", "-o", "stage1/reset.o", "stage1/reset.S"]))?;
+    run_cmd(Command::new("cc").args([
+        "-o",
+        "stage1/stage1",
+        "stage1/stage1.o",
+        "stage1/reset.o",
+        "-nostdlib",
+        "-Wl,--build-id=none",
+        "-Wl,-Tstage1/stage1.lds",
+    ]))?;
+
+    objcopy(&["-O", "binary"], Path::new("stage1/stage1"), "svsm.bin")?;
+    eprintln!("Built {}", root.join("svsm.bin").display());
+
+    Ok(())
+}
+
+fn pflash(unit: u32, file: &Path, extra: &str) -> OsString {
+    let mut arg = OsString::from(format!("if=pflash,format=raw,unit={},file=", unit));
+    arg.push(file);
+    arg.push(extra);
+    arg
+}
+
+fn qemu_command(opts: &RunOpts, svsm_bin: &Path) -> Result<Command> {
+    let ovmf_code = opts.ovmf_code.as_ref().ok_or("--ovmf-code is required")?;
+
+    let mut cmd = Command::new(&opts.qemu);
+    cmd.args(["-enable-kvm", "-cpu", "EPYC-v4"])
+        .args([
+            "-machine",
+            "q35,confidential-guest-support=sev0,memory-backend=ram1,kvm-type=protected",
+        ])
+        .arg("-object")
+        .arg(format!(
+            "memory-backend-memfd-private,id=ram1,size={},share=true",
+            opts.mem
+        ))
+        .args([
+            "-object",
+            "sev-snp-guest,id=sev0,cbitpos=51,reduced-phys-bits=1,svsm=on",
+        ])
+        .arg("-smp")
+        .arg(opts.smp.to_string())
+        .args(["-no-reboot", "-serial", "stdio", "-display", "none"]);
+
+    cmd.arg("-drive").arg(pflash(0, ovmf_code, ",readonly=on"));
+    if let Some(vars) = &opts.ovmf_vars {
+        cmd.arg("-drive").arg(pflash(1, vars, ",snapshot=on"));
+    }
+    cmd.arg("-drive").arg(pflash(2, svsm_bin, ",readonly=on"));
+
+    if let Some(image) = &opts.image {
+        let mut drive = OsString::from("file=");
+        drive.push(image);
+        drive.push(",if=none,id=disk0,format=qcow2,snapshot=off");
+        cmd.arg("-drive")
+            .arg(drive)
+            .args([
+                "-device",
+                "virtio-scsi-pci,id=scsi0,disable-legacy=on,iommu_platform=on",
+            ])
+            .args(["-device", "scsi-hd,drive=disk0,bootindex=0"]);
+    }
+
+    cmd.args(&opts.extra);
+
+    Ok(cmd)
+}
+
+fn clean() -> Result<()> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    run_cmd(Command::new(cargo).arg("clean"))?;
+
+    for file in [
+        "svsm.bin",
+        "stage1/stage1",
+        "stage1/stage1.o",
+        "stage1/reset.o",
+        "stage1/stage2.bin",
+        "stage1/kernel.elf",
+        "stage1/svsm-fs.bin",
+        "stage1/kernel.sig",
+        "stage1/kernel.digest",
+        "stage1/meta.bin",
+        "utils/gen_meta",
+    ] {
+        let _ = fs::remove_file(root_dir().join(file));
+    }
+
+    Ok(())
+}
+
+fn value(args: &mut impl Iterator<Item = OsString>, opt: &str) -> Result<OsString> {
+    args.next().ok_or(format!("{} needs a value", opt))
+}
+
+fn string_value(args: &mut impl Iterator<Item = OsString>, opt: &str) -> Result<String> {
+    value(args, opt)?
+        .into_string()
+        .map_err(|_| format!("{} value is not valid UTF-8", opt))
+}
+
+fn parse_args(
+    mut args: impl Iterator<Item = OsString>,
+    build: &mut BuildOpts,
+    run: &mut RunOpts,
+) -> Result<()> {
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--release") => build.release = true,
+            Some("--features") => build.features = Some(string_value(&mut args, "--features")?),
+            Some("--silent-stage2") => build.silent_stage2 = true,
+            Some("--fs") => build.fs_file = Some(value(&mut args, "--fs")?.into()),
+            Some("--sign-key") => build.sign_key = Some(value(&mut args, "--sign-key")?.into()),
+            Some("--qemu") => run.qemu = value(&mut args, "--qemu")?.into(),
+            Some("--ovmf-code") => run.ovmf_code = Some(value(&mut args, "--ovmf-code")?.into()),
+            Some("--ovmf-vars") => run.ovmf_vars = Some(value(&mut args, "--ovmf-vars")?.into()),
+            Some("--image") => run.image = Some(value(&mut args, "--image")?.into()),
+            Some("--mem") => run.mem = string_value(&mut args, "--mem")?,
+            Some("--smp") => {
+                let smp = string_value(&mut args, "--smp")?;
+                run.smp = smp
+                    .parse()
+                    .map_err(|_| format!("invalid CPU count {}", smp))?;
+            }
+            Some("--") => {
+                run.extra.extend(args);
+                break;
+            }
+            _ => return Err(format!("unknown option {:?}\n\n{}", arg, USAGE)),
+        }
+    }
+
+    Ok(())
+}
+
+fn xtask() -> Result<()> {
+    let mut args = env::args_os().skip(1);
+    let Some(command) = args.next() else {
+        return Err(USAGE.to_string());
+    };
+
+    let mut build_opts = BuildOpts::default();
+    let mut run_opts = RunOpts::default();
+    parse_args(args, &mut build_opts, &mut run_opts)?;
+
+    match command.to_str() {
+        Some("build") => build(&build_opts),
+        Some("run") => {
+            build(&build_opts)?;
+            run_cmd(&mut qemu_command(&run_opts, &root_dir().join("svsm.bin"))?)
+        }
+        Some("clean") => clean(),
+        Some("help") | Some("--help") | Some("-h") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command {:?}\n\n{}", command, USAGE)),
+    }
+}
+
+fn main() {
+    if let Err(e) = xtask() {
+        eprintln!("xtask: {}", e);
+        exit(1);
+    }
+}